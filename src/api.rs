@@ -1,25 +1,53 @@
-use crate::mmap_heap::*;
 use crate::utils::*;
-use crate::{bump_heap, generic_heap, Ptr, Size, NULL_PTR, utils};
+use crate::{arena, bump_heap, config, extents, fork, generic_heap, journal, layout, profile, reclaim, sampling, small_heap, stats, strict, tenant, thread_mode, tuning, nursery, partition, Ptr, Size, NULL_PTR, utils};
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
-use lfmap::{Map, WordMap};
+use core::mem;
 use libc::*;
-use std::alloc::{Alloc, AllocErr};
-use std::ptr::{null_mut, NonNull};
+use std::alloc::{Alloc, AllocErr, CannotReallocInPlace};
+use std::ptr::{self, null_mut, NonNull};
+use std::slice;
+
+use crate::extents::{ExtentOp, ExtentReason, NUM_EXTENT_REASONS};
+
+pub use crate::stats::HeapStats;
+pub use crate::stats::StatsSnapshot;
+pub use crate::tenant::TenantStats;
+pub use crate::nursery::{NurseryComparison, NurseryStats};
+pub use crate::partition::IsolatedPool;
+pub use crate::arena::Arena;
+pub use crate::profile::{AllocEvent, AllocHook, TopAllocator};
+
+// Layout of mallinfo2 from glibc. Fields without a counterpart in this allocator are zero
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NuMallinfo {
+    // bytes mapped from the OS
+    pub arena: size_t,
+    // live objects
+    pub ordblks: size_t,
+    pub smblks: size_t,
+    // number of mappings
+    pub hblks: size_t,
+    pub hblkhd: size_t,
+    pub usmblks: size_t,
+    pub fsmblks: size_t,
+    // bytes of live objects
+    pub uordblks: size_t,
+    // free bytes held in per-CPU superblocks
+    pub fordblks: size_t,
+    pub keepcost: size_t,
+}
 
 thread_local! {
     pub static INNER_CALL: Cell<bool> = Cell::new(false);
 }
-lazy_static! {
-    static ref RUST_ADDR_MAPPING: lfmap::WordMap<MmapAllocator, AddressHasher> =
-        lfmap::WordMap::with_capacity(256);
-}
 
 pub unsafe fn nu_malloc(size: Size) -> Ptr {
     if size == 0 {
         return null_mut();
     } // The C standard (C17 7.22.3/1)
+    fork::audit_vfork();
     INNER_CALL.with(|is_inner| {
         if !is_inner.get() {
             is_inner.set(true);
@@ -32,10 +60,99 @@ pub unsafe fn nu_malloc(size: Size) -> Ptr {
         }
     })
 }
+// Allocate with flags. Supported flags are the alignment from nu_mallocx_align, and
+// NU_MALLOCX_TCACHE_NONE to bypass per-CPU caches for transient buffers
+pub unsafe fn nu_mallocx(size: Size, flags: usize) -> Ptr {
+    let align = 1 << (flags & NU_MALLOCX_LG_ALIGN_MASK);
+    if size == 0 {
+        return null_mut();
+    }
+    if align > CACHE_LINE_SIZE {
+        // never cached in per-CPU superblocks
+        return nu_aligned_alloc(align, size);
+    }
+    // small objects are aligned to their size up to a cache line
+    let size = size.max(align);
+    if flags & NU_MALLOCX_TCACHE_NONE == 0 {
+        return nu_malloc(size);
+    }
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_uncached(size);
+            is_inner.set(false);
+            res
+        } else {
+            bump_heap::malloc(size)
+        }
+    })
+}
+
+// Allocate `size` bytes aligned to `align`, which must be a power of two. Null on zero size
+pub unsafe fn nu_aligned_alloc(align: Size, size: Size) -> Ptr {
+    if size == 0 || !align.is_power_of_two() {
+        return null_mut();
+    }
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::aligned_malloc(size, align);
+            is_inner.set(false);
+            res
+        } else {
+            bump_heap::aligned_malloc(size, align)
+        }
+    })
+}
+
+// posix_memalign(3), the alignment must be a power of two multiple of the pointer size
+pub unsafe fn nu_posix_memalign(memptr: *mut Ptr, align: Size, size: Size) -> c_int {
+    if align < mem::size_of::<Ptr>() || !align.is_power_of_two() {
+        return EINVAL;
+    }
+    if size == 0 {
+        *memptr = NULL_PTR;
+        return 0;
+    }
+    let ptr = nu_aligned_alloc(align, size);
+    if ptr == NULL_PTR {
+        return ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+// Allocate with pages on the NUMA node, null if the node does not exist. Objects are freed by
+// nu_free from any thread and return to the node they came from
+pub unsafe fn nu_malloc_on_node(size: Size, node: usize) -> Ptr {
+    if size == 0 || node >= nu_numa_nodes() {
+        return null_mut();
+    }
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_on_node(size, node as u16);
+            is_inner.set(false);
+            res
+        } else {
+            bump_heap::malloc(size)
+        }
+    })
+}
+
+// Number of NUMA nodes, nodes are numbered from 0
+pub fn nu_numa_nodes() -> usize {
+    *NUM_NUMA_NODES as usize
+}
+
 pub unsafe fn nu_free(ptr: Ptr) {
     if ptr == null_mut() {
         return;
     }
+    fork::audit_vfork();
     let is_inner = INNER_CALL.with(|is_inner| is_inner.get());
     if !is_inner {
         generic_heap::free(ptr);
@@ -45,8 +162,60 @@ pub unsafe fn nu_free(ptr: Ptr) {
     }
 }
 
+// Free the `n` objects of the array at once, null pointers among them are skipped. Objects of the
+// same superblock go back to it together, so destructors of trees and graphs freeing thousands of
+// nodes allocated together are much faster than with nu_free one by one
+pub unsafe fn nu_free_batch(ptrs: *const Ptr, n: Size) {
+    if ptrs.is_null() || n == 0 {
+        return;
+    }
+    fork::audit_vfork();
+    let ptrs = slice::from_raw_parts(ptrs, n);
+    let is_inner = INNER_CALL.with(|is_inner| is_inner.get());
+    if !is_inner {
+        generic_heap::free_batch(ptrs);
+    } else {
+        for ptr in ptrs.iter().filter(|ptr| !ptr.is_null()) {
+            bump_heap::free(*ptr);
+        }
+    }
+}
+
+// Allocate uninitialized memory for a value of the type, with the size class resolved at compile
+// time. Zero sized types are rejected at compile time
+pub unsafe fn nu_alloc_typed<T>() -> *mut T {
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_typed::<T>();
+            is_inner.set(false);
+            res as *mut T
+        } else {
+            bump_heap::aligned_malloc(mem::size_of::<T>(), mem::align_of::<T>()) as *mut T
+        }
+    })
+}
+
+// Free memory from nu_alloc_typed of the same type, the value is not dropped
+pub unsafe fn nu_free_typed<T>(ptr: *mut T) {
+    if ptr.is_null() {
+        return;
+    }
+    fork::audit_vfork();
+    let is_inner = INNER_CALL.with(|is_inner| is_inner.get());
+    if !is_inner {
+        generic_heap::free_typed::<T>(ptr as Ptr);
+    } else {
+        bump_heap::free(ptr as Ptr);
+    }
+}
+
 pub unsafe fn nu_calloc(nmemb: Size, size: Size) -> Ptr {
-    let total_size = nmemb * size;
+    let total_size = match nmemb.checked_mul(size) {
+        Some(total_size) => total_size,
+        None => return NULL_PTR,
+    };
     let ptr = nu_malloc(total_size);
     if ptr != NULL_PTR {
         // zero-initialize is required
@@ -55,7 +224,58 @@ pub unsafe fn nu_calloc(nmemb: Size, size: Size) -> Ptr {
     ptr
 }
 
+// Allocate from the partition of the name, registering it on first use. Objects of different
+// partitions never share pages, free them with nu_free, see partition
+pub unsafe fn nu_partition_malloc(name: &str, size: Size) -> Ptr {
+    if size == 0 {
+        return null_mut();
+    }
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if is_inner.get() {
+            // the allocator itself has no use of partitions
+            return bump_heap::malloc(size);
+        }
+        is_inner.set(true);
+        let res = match partition::register(name) {
+            Some(id) => partition::allocate(id, size),
+            None => NULL_PTR,
+        };
+        is_inner.set(false);
+        res
+    })
+}
+
+// Hand a large object to the arena without copying, from the arena the thread has entered or
+// from the general heap. Only objects with mappings of their own can move, which are those of at
+// least HEAP_VIRT_SIZE bytes (128MB); false for smaller objects, which stay where they are. Free
+// it through the arena once it returns true, see arena
+pub fn nu_transfer(ptr: Ptr, dst: &Arena) -> bool {
+    arena::transfer(ptr as *mut u8, dst)
+}
+
+// Copy of the C string in one allocation
+pub unsafe fn nu_strdup(s: *const c_char) -> *mut c_char {
+    copy_string(s, strlen(s))
+}
+
+// Copy of at most `n` bytes of the C string, always terminated. Bytes past the terminator are
+// not read
+pub unsafe fn nu_strndup(s: *const c_char, n: Size) -> *mut c_char {
+    copy_string(s, strnlen(s, n))
+}
+
+unsafe fn copy_string(s: *const c_char, len: usize) -> *mut c_char {
+    let copy = nu_malloc(len + 1) as *mut c_char;
+    if !copy.is_null() {
+        ptr::copy_nonoverlapping(s, copy, len);
+        *copy.add(len) = 0;
+    }
+    copy
+}
+
 pub unsafe fn nu_realloc(ptr: Ptr, size: Size) -> Ptr {
+    fork::audit_vfork();
     INNER_CALL.with(|is_inner| {
         if !is_inner.get() {
             is_inner.set(true);
@@ -68,25 +288,358 @@ pub unsafe fn nu_realloc(ptr: Ptr, size: Size) -> Ptr {
     })
 }
 
+// Reallocate to `size` bytes and fill the last `srclen` of them from `src` in the same pass, for
+// appending to buffers. Null on failure, with the original object untouched, see
+// generic_heap::realloc_copyfrom
+pub unsafe fn nu_realloc_copyfrom(ptr: Ptr, size: Size, src: *const c_void, srclen: Size) -> Ptr {
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::realloc_copyfrom(ptr, size, src, srclen);
+            is_inner.set(false);
+            res
+        } else if size == 0 || srclen > size {
+            NULL_PTR
+        } else {
+            bump_heap::realloc_copyfrom(ptr, size, src, srclen)
+        }
+    })
+}
+
+// Bytes usable in the object, which may be more than requested. Zero for null and unknown objects
+pub unsafe fn nu_malloc_usable_size(ptr: Ptr) -> Size {
+    if ptr == NULL_PTR {
+        return 0;
+    }
+    generic_heap::size_of(ptr).unwrap_or(0)
+}
+
+// Same as nu_realloc, except that the original object is never freed unless it was moved
+// Null is returned on failure, including zero size, and the caller still owns the original object
+pub unsafe fn nu_try_realloc(ptr: Ptr, size: Size) -> Ptr {
+    fork::audit_vfork();
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::try_realloc(ptr, size);
+            is_inner.set(false);
+            res
+        } else if size == 0 {
+            NULL_PTR
+        } else {
+            bump_heap::realloc(ptr, size)
+        }
+    })
+}
+
+// Declare current thread as the only thread of the program to use allocation fast paths without
+// atomic read-modify-write operations. The mode ends when any other thread starts to allocate
+pub fn nu_declare_single_thread() -> bool {
+    thread_mode::declare()
+}
+
+// Rebuild allocator state in the child process after fork() without exec, must be called
+// before any allocation in the child when the parent has more than one thread
+pub unsafe fn nu_postfork_child_reinit() {
+    fork::child_reinit()
+}
+
+// Abort with a diagnostic message on allocation in vfork/posix_spawn children before exec
+pub fn nu_enable_vfork_audit() -> bool {
+    fork::enable_vfork_audit()
+}
+
+// Flags of nu_mallocx and nu_nallocx, the low 6 bits are log2 of the alignment, use
+// nu_mallocx_align
+pub const NU_MALLOCX_LG_ALIGN_MASK: usize = 0x3f;
+pub const NU_MALLOCX_TCACHE_NONE: usize = 1 << 8;
+
+pub fn nu_mallocx_align(align: usize) -> usize {
+    align.trailing_zeros() as usize & NU_MALLOCX_LG_ALIGN_MASK
+}
+
+// Size that an allocation of `size` bytes with the flags would get under the current class
+// table, without allocating. Zero if the request cannot be satisfied
+pub fn nu_nallocx(size: Size, flags: usize) -> Size {
+    let align = 1 << (flags & NU_MALLOCX_LG_ALIGN_MASK);
+    generic_heap::allocation_size(size, align).unwrap_or(0)
+}
+
+// Generation of the span holding the object, zero for objects out of spans. Debugging tools can
+// capture it along with the pointer, in fat pointers or quarantine records, and check it with
+// nu_free_checked to catch stale pointers from before the span was recycled for another size class
+pub fn nu_generation_of(ptr: Ptr) -> usize {
+    generic_heap::generation_of(ptr).unwrap_or(0) as usize
+}
+
+// Free the object unless the span has been recycled since the generation was captured. Returns
+// false for stale pointers, which are left untouched. A generation of zero frees unchecked
+pub unsafe fn nu_free_checked(ptr: Ptr, generation: usize) -> bool {
+    if generation != 0 {
+        let current = nu_generation_of(ptr);
+        if current != generation {
+            warn!(
+                "Stale pointer {:x} of generation {}, span is now of generation {}",
+                ptr as usize, generation, current
+            );
+            return false;
+        }
+    }
+    nu_free(ptr);
+    true
+}
+
+// Tiered memory reclamation on application request, level 1 (low) to 3 (critical)
+// Returns the number of bytes released to the OS
+pub fn nu_notify_memory_pressure(level: usize) -> usize {
+    reclaim::PressureLevel::from_usize(level)
+        .map(reclaim::notify_memory_pressure)
+        .unwrap_or(0)
+}
+
+// Release all free memory the allocator can give back to the OS, like malloc_trim(3). Free
+// objects and superblocks are purged whatever the pad is, the allocator keeps no top chunk
+// Returns 1 when any memory was released
+pub fn nu_malloc_trim(_pad: usize) -> c_int {
+    (reclaim::reclaim() > 0) as c_int
+}
+
+// Memory accounted to the tenant label, None for unknown labels
+pub fn nu_tenant_stats(label: &str) -> Option<TenantStats> {
+    tenant::find(label).map(tenant::stats)
+}
+
+// Limit live bytes of the tenant, zero for unlimited. Registers the label if it is new
+pub fn nu_set_tenant_limit(label: &str, limit: usize) -> bool {
+    tenant::register(label)
+        .map(|id| tenant::set_limit(id, limit))
+        .is_some()
+}
+
+// Bytes the allocator holds for its own metadata
+pub fn nu_metadata_bytes() -> usize {
+    stats::get(&stats::METADATA_BYTES)
+}
+
+pub fn nu_heap_stats() -> HeapStats {
+    generic_heap::heap_stats()
+}
+
+// Copy all counters into the plain struct, for monitoring agents in other languages
+pub fn nu_stats_snapshot(snapshot: &mut StatsSnapshot) {
+    *snapshot = stats::publish(nu_heap_stats);
+}
+
+pub fn nu_mallinfo() -> NuMallinfo {
+    let heap = nu_heap_stats();
+    NuMallinfo {
+        arena: heap.mapped_bytes,
+        ordblks: heap.live_objects.iter().sum::<usize>() + heap.large_objects,
+        hblks: heap.mapped_segments,
+        uordblks: heap.allocated_bytes,
+        fordblks: heap.cpu_cache_bytes.iter().sum(),
+        ..NuMallinfo::default()
+    }
+}
+
+// Print heap statistics to stderr, like malloc_stats(3)
+pub fn nu_malloc_stats() {
+    let heap = nu_heap_stats();
+    eprintln!("allocated bytes: {}", heap.allocated_bytes);
+    eprintln!("resident bytes:  {}", heap.resident_bytes);
+    eprintln!("mapped bytes:    {} in {} segments", heap.mapped_bytes, heap.mapped_segments);
+    eprintln!("metadata bytes:  {}", heap.metadata_bytes);
+    eprintln!(
+        "reclaimed bytes: {}, {} still released",
+        heap.reclaimed_bytes, heap.decommitted_bytes
+    );
+    eprintln!("large objects:   {}", heap.large_objects);
+    if heap.numa_degraded {
+        eprintln!("NUMA system calls refused, running as on a single node");
+    }
+    for (tier, objects) in heap.live_objects.iter().enumerate() {
+        eprintln!("class {:>6}: {} live objects", 2 << tier, objects);
+    }
+    for (cpu, cached) in heap.cpu_cache_bytes.iter().enumerate() {
+        eprintln!("cpu {:>4}: {} free bytes cached", cpu, cached);
+    }
+    eprintln!("failed bytes:    {}", heap.failed_bytes);
+    for (tier, failed) in heap.failed_small.iter().enumerate().filter(|(_, n)| **n > 0) {
+        eprintln!("class {:>6}: {} failed allocations", 2 << tier, failed);
+    }
+    for (bucket, failed) in heap.failed_large.iter().enumerate().filter(|(_, n)| **n > 0) {
+        let bound = generic_heap::MAXIMUM_SMALL_SIZE << (bucket + 1);
+        if bucket + 1 < stats::LARGE_FAILURE_BUCKETS {
+            eprintln!("large <= {:>10}: {} failed allocations", bound, failed);
+        } else {
+            eprintln!("large >  {:>10}: {} failed allocations", bound >> 1, failed);
+        }
+    }
+    for reason in (0..NUM_EXTENT_REASONS).filter_map(ExtentReason::from_usize) {
+        let bytes = |op: ExtentOp| heap.extent_bytes[op as usize][reason as usize];
+        eprintln!(
+            "extents for {:<12}: {} mapped, {} unmapped, {} released",
+            reason.name(),
+            bytes(ExtentOp::Map),
+            bytes(ExtentOp::Unmap),
+            bytes(ExtentOp::Release)
+        );
+    }
+}
+
+// Print the heap statistics followed by the utilization of every size class, which tells the
+// classes fragmenting by many partial or empty spans at low occupancy
+pub fn nu_print_stats_detailed() {
+    nu_malloc_stats();
+    let _ = stats::write_class_report(&nu_heap_stats(), &mut std::io::stderr());
+}
+
+// Write the extent manifest of the fixed layout mode to the file
+pub fn nu_dump_layout_manifest(path: &str) -> bool {
+    std::fs::File::create(path)
+        .and_then(|mut file| layout::write_manifest(&mut file))
+        .is_ok()
+}
+
+// Write the recent heap operations of all threads to the file descriptor, without allocating.
+// Nothing is recorded unless the journal option is set, see journal
+pub fn nu_dump_journal(fd: c_int) {
+    journal::dump(fd);
+}
+
+// Run the control command, returns false for unknown commands. Commands:
+//   log.extents: write the last extents mapped and released with their reasons to stderr,
+//                see extents
+pub fn nu_ctl(name: &str) -> bool {
+    match name {
+        "log.extents" => extents::dump(STDERR_FILENO),
+        _ => return false,
+    }
+    true
+}
+
+// Version of the library, for programs loading whichever build is installed
+pub fn nu_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+// Whether the build and the system support the feature, so programs can adapt to builds with
+// other cargo features instead of calling what is missing. Features:
+//   stats:     heap statistics and snapshots
+//   numa:      placement on NUMA nodes, false where the NUMA system calls are refused and in
+//              strict mode
+//   hugepages: huge pages for large mappings, see mmap::HugePages
+//   hardened, profiling, usdt, bump_heap_only: builds with the cargo feature
+//   etw:       builds with the cargo feature, on Windows
+pub fn nu_has_feature(name: &str) -> bool {
+    match name {
+        "stats" => true,
+        "numa" => {
+            // probed with the topology
+            let _ = *NUM_NUMA_NODES;
+            stats::get(&stats::NUMA_DEGRADED) == 0 && !strict::is_enabled()
+        }
+        "hugepages" => cfg!(target_os = "linux"),
+        "hardened" => cfg!(feature = "hardened"),
+        "profiling" => cfg!(feature = "profiling"),
+        "usdt" => cfg!(feature = "usdt"),
+        "etw" => cfg!(all(feature = "etw", windows)),
+        "bump_heap_only" => cfg!(feature = "bump_heap_only"),
+        _ => false,
+    }
+}
+
+// Initialize the heaps and create the superblocks of the prereserve options up front, instead of
+// on the first allocations. Returns the number of superblocks created
+pub fn nu_init() -> usize {
+    INNER_CALL.with(|is_inner| {
+        let was_inner = is_inner.replace(true);
+        config::ensure_loaded();
+        let created = small_heap::prereserve();
+        is_inner.set(was_inner);
+        created
+    })
+}
+
+// Call the hook on every malloc, free and realloc of the general heap, None to remove it.
+// Returns false when built without the `profiling` feature, see profile
+pub fn nu_set_alloc_hook(hook: Option<AllocHook>) -> bool {
+    profile::set_hook(hook)
+}
+
+// Write the sampled heap profile in the gperftools format read by pprof. Returns false when
+// built without the `profiling` feature or the file cannot be written, see profile
+pub fn nu_dump_heap_profile(path: &str) -> bool {
+    profile::dump_heap_profile(path)
+}
+
+// Call sites with the most live bytes among sampled objects of the heap profiler, at most `n`
+// of them, most bytes first. Empty unless built with the `profiling` feature and the
+// profile_interval option is set, see profile
+pub fn nu_top_allocators(n: usize) -> Vec<TopAllocator> {
+    profile::top_allocators(n)
+}
+
+// Objects served by per-thread nurseries of the `nursery` experiment so far, see nursery
+pub fn nu_nursery_stats() -> NurseryStats {
+    nursery::stats()
+}
+
+// Run a stack ordered workload of tiny objects `rounds` times on nurseries and on superblocks,
+// with L1 data cache misses where perf events are allowed. None unless the `nursery` option was
+// set
+pub fn nu_compare_nursery(rounds: usize) -> Option<NurseryComparison> {
+    nursery::compare(rounds)
+}
+
+// Sample request sizes for `window_ms` milliseconds to tune size classes with
+// nu_suggest_size_classes, see tuning
+pub fn nu_sample_sizes(window_ms: usize) {
+    tuning::start_warmup(window_ms)
+}
+
+// Superblock sizes and prereserved superblocks suggested from the sampled request sizes, as
+// options of NULLOC_CONF. Empty before any sizes are sampled
+pub fn nu_suggest_size_classes() -> String {
+    tuning::to_conf(&tuning::suggest())
+}
+
+// Sample one allocation per `interval` bytes on average into the shared memory ring for
+// continuous profilers, zero to disable. See sampling for the layout of the ring
+pub fn nu_set_sample_interval(interval: usize) -> bool {
+    sampling::enable(interval)
+}
+
+// Set runtime option, same keys and values as the NULLOC_CONF environment variable
+pub fn nu_set_option(key: &str, value: &str) -> bool {
+    config::set_option(key, value)
+}
+
 // Allocator for rust itself for internal heaps
 pub struct SkyhooksAllocator;
 
+// Objects are freed by address and know their own usable size, so dealloc and realloc accept any
+// layout that fits the object, not only the one it was allocated with
 unsafe impl GlobalAlloc for SkyhooksAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
-        let actual_size = size + align - 1;
-        let base_addr = nu_malloc(actual_size) as usize;
-        let align_padding = align_padding(base_addr, align);
-        let rust_addr = base_addr + align_padding;
-        RUST_ADDR_MAPPING.insert(rust_addr, base_addr);
-        rust_addr as *mut u8
+        nu_aligned_alloc(layout.align(), layout.size()) as *mut u8
     }
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let addr = ptr as usize;
-        if let Some(base_addr) = RUST_ADDR_MAPPING.remove(addr) {
-            nu_free(base_addr as Ptr)
+        nu_free(ptr as Ptr)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size <= nu_malloc_usable_size(ptr as Ptr) {
+            // the object was aligned for the layout already
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+            self.dealloc(ptr, layout);
         }
+        new_ptr
     }
 }
 
@@ -98,10 +651,155 @@ impl Default for SkyhooksAllocator {
 
 unsafe impl Alloc for SkyhooksAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        Ok(NonNull::new((self as &mut GlobalAlloc).alloc(layout)).unwrap())
+        NonNull::new((self as &mut GlobalAlloc).alloc(layout)).ok_or(AllocErr)
     }
 
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
         (self as &mut GlobalAlloc).dealloc(ptr.as_ptr(), layout)
     }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        let size = layout.size();
+        let usable = generic_heap::allocation_size(size, layout.align()).unwrap_or(size);
+        (size, usable)
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        NonNull::new((self as &mut GlobalAlloc).realloc(ptr.as_ptr(), layout, new_size))
+            .ok_or(AllocErr)
+    }
+
+    unsafe fn grow_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        _layout: Layout,
+        new_size: usize,
+    ) -> Result<(), CannotReallocInPlace> {
+        if new_size <= nu_malloc_usable_size(ptr.as_ptr() as Ptr) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
+    unsafe fn shrink_in_place(
+        &mut self,
+        _ptr: NonNull<u8>,
+        _layout: Layout,
+        _new_size: usize,
+    ) -> Result<(), CannotReallocInPlace> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::*;
+    use rand::{thread_rng, Rng};
+    use std::collections::HashSet;
+    use std::ffi::CStr;
+
+    #[test]
+    pub fn duplicate_strings() {
+        unsafe {
+            let source = b"nulloc\0";
+            let copy = nu_strdup(source.as_ptr() as *const c_char);
+            assert_eq!(CStr::from_ptr(copy).to_bytes(), b"nulloc");
+            nu_free(copy as Ptr);
+            let copy = nu_strndup(source.as_ptr() as *const c_char, 3);
+            assert_eq!(CStr::from_ptr(copy).to_bytes(), b"nul");
+            nu_free(copy as Ptr);
+            // unterminated, only `n` bytes are read
+            let unterminated = [b'x'; 8];
+            let copy = nu_strndup(unterminated.as_ptr() as *const c_char, 8);
+            assert_eq!(CStr::from_ptr(copy).to_bytes(), b"xxxxxxxx");
+            nu_free(copy as Ptr);
+        }
+    }
+
+    #[test]
+    pub fn free_batch() {
+        let mut rng = thread_rng();
+        unsafe {
+            let mut ptrs = (0..1000).map(|_| nu_malloc(64)).collect::<Vec<_>>();
+            // out of superblocks, and nothing
+            ptrs.push(nu_malloc(64 << 20));
+            ptrs.push(NULL_PTR);
+            for i in 1..ptrs.len() {
+                ptrs.swap(i, rng.gen_range(0, i + 1));
+            }
+            nu_free_batch(ptrs.as_ptr(), ptrs.len());
+            // slots are back in their superblocks for the next allocations
+            let freed = ptrs.iter().map(|ptr| *ptr as usize).collect::<HashSet<_>>();
+            let again = (0..1000).map(|_| nu_malloc(64)).collect::<Vec<_>>();
+            let reused = again.iter().filter(|ptr| freed.contains(&(**ptr as usize))).count();
+            assert!(reused >= 500, "{} reused", reused);
+            nu_free_batch(again.as_ptr(), again.len());
+        }
+    }
+
+    #[test]
+    pub fn feature_detection() {
+        assert_eq!(nu_version().split('.').count(), 3);
+        assert!(nu_has_feature("stats"));
+        assert_eq!(nu_has_feature("hardened"), cfg!(feature = "hardened"));
+        assert_eq!(nu_has_feature("etw"), cfg!(all(feature = "etw", windows)));
+        assert!(!nu_has_feature("time_travel"));
+    }
+
+    #[test]
+    pub fn realloc_copyfrom() {
+        unsafe {
+            let mut buf = NULL_PTR;
+            let mut len = 0;
+            for i in 0..2000u32 {
+                let chunk = [i as u8; 100];
+                buf = nu_realloc_copyfrom(buf, len + 100, chunk.as_ptr() as Ptr, 100);
+                assert!(!buf.is_null());
+                len += 100;
+            }
+            let bytes = std::slice::from_raw_parts(buf as *const u8, len);
+            assert!(bytes.chunks(100).enumerate().all(|(i, c)| c.iter().all(|b| *b == i as u8)));
+            // the source may be in the object
+            let moved = nu_realloc_copyfrom(buf, len + 100, buf, 100);
+            assert_eq!(*(moved as *const u8).add(len), 0);
+            assert!(nu_realloc_copyfrom(moved, 10, moved, 20).is_null());
+            nu_free(moved);
+        }
+    }
+
+    #[test]
+    pub fn layout_round_trip() {
+        let mut rng = thread_rng();
+        let mut allocator = SkyhooksAllocator;
+        for _ in 0..1000 {
+            let size = rng.gen_range(1, 1 << rng.gen_range(1, 20));
+            let align = 1 << rng.gen_range(0, 13);
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = Alloc::alloc(&mut allocator, layout).unwrap();
+                let addr = ptr.as_ptr() as usize;
+                assert_eq!(addr % align, 0, "size {} align {}", size, align);
+                let usable = nu_malloc_usable_size(addr as Ptr);
+                assert!(usable >= size);
+                assert!(allocator.usable_size(&layout).0 <= usable);
+                memset(addr as Ptr, 7, size);
+                assert!(allocator.grow_in_place(ptr, layout, usable).is_ok());
+                let new_size = size * 2 + 1;
+                let new_ptr = Alloc::realloc(&mut allocator, ptr, layout, new_size).unwrap();
+                assert_eq!(new_ptr.as_ptr() as usize % align, 0);
+                for offset in 0..size {
+                    assert_eq!(*new_ptr.as_ptr().add(offset), 7);
+                }
+                // dealloc with a compatible layout of a smaller size and alignment
+                let compatible = Layout::from_size_align(size, 1).unwrap();
+                Alloc::dealloc(&mut allocator, new_ptr, compatible);
+            }
+        }
+    }
 }
@@ -0,0 +1,829 @@
+// Arenas, heaps of their own for objects that die together
+// Every arena has its own address spaces and free lists, apart from the general heap and other
+// arenas, so servers can allocate everything of a request in an arena and release it at once by
+// resetting or dropping the arena instead of freeing objects one by one. Objects can still be
+// freed individually to be reused by the arena.
+//
+// Arenas implement GlobalAlloc, and Alloc by reference. Collections taking `A: Alloc + Default`
+// such as lflist and FixedVec create their allocators by themselves, for them ArenaAlloc serves
+// from the arena the thread has entered:
+//
+//   let arena = Arena::new();
+//   let _entered = arena.enter();
+//   let list = WordList::<ArenaAlloc>::new();
+//
+// Objects of an arena are gone with it, collections using it must be dropped before it is
+// reset or dropped. Frees through ArenaAlloc outside of the arena the object came from leave the
+// object to the reset of its arena.
+//
+// Registered arenas hand every address space to a callback when it is mapped, so I/O heavy
+// applications can register them as fixed buffers with io_uring and allocate buffers right into
+// registered memory. Address spaces are numbered from 0 in the order they are mapped, and
+// `alloc_registered` returns the number along with the object for the buffer index of fixed
+// reads and writes. Buffers shall be unregistered before the arena is reset or dropped, the
+// callback is called again from index 0 after a reset.
+//
+// Pinned arenas serve page-locked host memory for device transfers. They pin every address space
+// with the `pin` hook as it is mapped, by cudaHostRegister or alike, and unpin it with `unpin`
+// before it is unmapped on reset or drop. Address spaces are pinned as a whole, HEAP_VIRT_SIZE
+// bytes at a time, which is charged to the locked memory of the process by most drivers.
+//
+// Objects too large for an address space get mappings of their own, unless the arena has extent
+// hooks, which only cover address spaces. Such objects can change hands without being copied:
+// `transfer` hands them to another arena, and nu_transfer takes them from the arena the thread
+// has entered or from the general heap, so objects made for a request survive it by moving to a
+// long-lived arena. Pages move along to the NUMA node of the new arena, if it has one. Smaller
+// objects are part of address spaces and stay with their arena.
+//
+// Cloneable arenas back their address spaces with memory files, so `clone_cow` can fork the state
+// of the arena into a new one copy-on-write, for snapshot style services. The clone has the
+// objects and free objects of the source at the same offsets in its address spaces, `translate`
+// finds the copy of an object. Objects with mappings of their own are not part of clones.
+// `free_list_checkpoint` records the order free objects of an arena are reused in, and a clone
+// restoring it reuses the copies in the same order, so replays on clones allocate at the same
+// offsets as the source, see checkpoint.
+//
+// Tenant arenas account their objects to a tenant label, see nu_tenant_stats, and allocations
+// fail over the limit of the tenant. Objects are discharged as they are freed, or all at once
+// when the arena is reset or dropped.
+//
+// Arenas for secrets such as keys keep their contents out of core dumps with `dontdump`, or out
+// of swap with `noswap`.
+//
+// Live objects of walkable arenas can be listed in address order by `walk`, for compacting
+// collectors and heap analyzers to stream the heap sequentially instead of chasing pointers.
+// Walkable arenas log the blocks they bump and the objects in them, at the cost of a map update
+// on every allocation and free.
+
+use crate::bump_heap::{
+    AllocatorInstance, CowMapping, ExtentHook, ExtentHooks, HEAP_VIRT_SIZE, INSTANCE_CLONEABLE,
+    INSTANCE_DONTDUMP, INSTANCE_NOSWAP, INSTANCE_WALKABLE,
+};
+use crate::checkpoint::FreeListCheckpoint;
+use crate::extents::{self, ExtentOp, ExtentReason};
+use crate::large_heap;
+use crate::mmap::move_to_node;
+use crate::mmap_heap::{MmapAllocator, NodeMmapAllocator};
+use crate::tenant;
+use crate::utils::{align_padding, AddressHasher, SYS_PAGE_SIZE};
+use crate::{Ptr, NULL_PTR};
+use core::alloc::{Alloc, AllocErr, GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use lfmap::Map;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+thread_local! {
+    static CURRENT_ARENA: Cell<*const Arena> = Cell::new(ptr::null());
+}
+
+pub struct Arena {
+    instance: AllocatorInstance<MmapAllocator>,
+    // objects with mappings of their own, address to size
+    large: lfmap::WordMap<MmapAllocator, AddressHasher>,
+}
+
+// Arena of page-locked memory, see above
+pub struct PinnedArena {
+    arena: Arena,
+}
+
+// Copy-on-write clone of an arena, an arena of its own, see above
+pub struct ArenaClone {
+    arena: Arena,
+    mapping: CowMapping,
+}
+
+// Arena of the thread for the lifetime of the guard, the arena entered before is restored after
+pub struct EnteredArena<'a> {
+    previous: *const Arena,
+    shadow: PhantomData<&'a Arena>,
+}
+
+// Allocator of the arena entered by the thread. Fails without one
+#[derive(Clone, Copy, Default)]
+pub struct ArenaAlloc;
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::with_instance(AllocatorInstance::new())
+    }
+
+    // Arena placing its pages on the NUMA node
+    pub fn on_node(node: u16) -> Self {
+        Self::with_instance(AllocatorInstance::with_node(node))
+    }
+
+    // Arena whose live objects can be walked, see above
+    pub fn walkable() -> Self {
+        Self::with_instance(AllocatorInstance::with_flags(INSTANCE_WALKABLE))
+    }
+
+    // Arena that can be cloned copy-on-write, see above
+    pub fn cloneable() -> Self {
+        Self::with_instance(AllocatorInstance::with_flags(INSTANCE_CLONEABLE))
+    }
+
+    // Arena for secrets, its contents are left out of core dumps
+    pub fn dontdump() -> Self {
+        Self::with_instance(AllocatorInstance::with_flags(INSTANCE_DONTDUMP))
+    }
+
+    // Arena for secrets, its pages are locked from swap once touched. Address spaces are locked as
+    // a whole, which needs a memlock limit of HEAP_VIRT_SIZE bytes per address space, or the
+    // arena works unlocked
+    pub fn noswap() -> Self {
+        Self::with_instance(AllocatorInstance::with_flags(INSTANCE_NOSWAP))
+    }
+
+    // Arena calling `register` with the index, base and size of its address spaces
+    pub fn registered(register: ExtentHook) -> Self {
+        Self::with_extent_hooks(ExtentHooks {
+            map: register,
+            unmap: None,
+        })
+    }
+
+    // Arena accounting its objects to the tenant label, registering the label if it is new. None
+    // when the label cannot be registered
+    pub fn for_tenant(label: &str) -> Option<Self> {
+        let tenant = tenant::register(label)?;
+        Some(Self::with_instance(AllocatorInstance::with_tenant(tenant)))
+    }
+
+    pub fn with_extent_hooks(hooks: ExtentHooks) -> Self {
+        Self::with_instance(AllocatorInstance::with_extent_hooks(hooks))
+    }
+
+    fn with_instance(instance: AllocatorInstance<MmapAllocator>) -> Self {
+        Self {
+            instance,
+            large: lfmap::WordMap::with_capacity(16),
+        }
+    }
+
+    pub fn node(&self) -> Option<u16> {
+        self.instance.node()
+    }
+
+    // Allocate from a registered arena, returns the object with the index of its address space
+    pub fn alloc_registered(&self, layout: Layout) -> Option<(*mut u8, usize)> {
+        self.instance.extent_hooks()?;
+        let ptr = unsafe { GlobalAlloc::alloc(self, layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        self.instance
+            .extent_index(ptr as usize)
+            .map(|index| (ptr, index))
+    }
+
+    // Index of the address space of an object of a registered arena
+    pub fn buffer_index(&self, ptr: *const u8) -> Option<usize> {
+        self.instance.extent_index(ptr as usize)
+    }
+
+    // Release all objects of the arena along with their address spaces, the arena stays usable
+    pub fn reset(&mut self) {
+        self.release_large();
+        let instance = &self.instance;
+        self.instance = match (instance.node(), instance.extent_hooks(), instance.tenant()) {
+            (Some(node), _, _) => AllocatorInstance::with_node(node),
+            (None, Some(hooks), _) => AllocatorInstance::with_extent_hooks(hooks),
+            (None, None, Some(tenant)) => AllocatorInstance::with_tenant(tenant),
+            (None, None, None) => AllocatorInstance::with_flags(instance.flags()),
+        };
+    }
+
+    // Clone of the arena, None unless it is cloneable. Nothing shall allocate or free in the arena
+    // meanwhile
+    pub fn clone_cow(&self) -> Option<ArenaClone> {
+        let clone = self.instance.clone_cow()?;
+        Some(ArenaClone {
+            arena: Self::with_instance(clone.instance),
+            mapping: clone.mapping,
+        })
+    }
+
+    // Free objects in the order they would be reused, see above. Nothing shall allocate or free
+    // in the arena meanwhile
+    pub fn free_list_checkpoint(&self) -> FreeListCheckpoint {
+        self.instance.free_list_checkpoint()
+    }
+
+    pub fn enter(&self) -> EnteredArena<'_> {
+        let previous = CURRENT_ARENA.with(|current| current.replace(self));
+        EnteredArena {
+            previous,
+            shadow: PhantomData,
+        }
+    }
+
+    // Hand an object with a mapping of its own to the other arena, which frees it from then on.
+    // False for objects of address spaces and arenas with extent hooks
+    pub fn transfer(&self, ptr: *mut u8, dst: &Arena) -> bool {
+        if !dst.takes_large() {
+            return false;
+        }
+        let size = match self.large.get(ptr as usize) {
+            Some(size) => size,
+            None => return false,
+        };
+        if !dst.charge_large(size) {
+            return false;
+        }
+        match self.large.remove(ptr as usize) {
+            Some(size) => {
+                self.discharge_large(size);
+                dst.own_large(ptr as usize, size);
+                true
+            }
+            None => {
+                dst.discharge_large(size);
+                false
+            }
+        }
+    }
+
+    // Call `f` with the address and usable size of every live object in address order, objects
+    // with mappings of their own included. False unless the arena is walkable. Nothing shall
+    // allocate or free in the arena meanwhile
+    pub fn walk<F: FnMut(*mut u8, usize)>(&self, mut f: F) -> bool {
+        let mut objects = match self.instance.live_objects() {
+            Some(objects) => objects,
+            None => return false,
+        };
+        objects.extend(self.large.entries());
+        objects.sort_unstable();
+        for (addr, size) in objects {
+            f(addr as *mut u8, size);
+        }
+        true
+    }
+
+    fn takes_large(&self) -> bool {
+        self.instance.extent_hooks().is_none()
+    }
+
+    // Objects beyond address spaces, aligned to pages at most
+    unsafe fn map_large(&self, layout: Layout) -> *mut u8 {
+        let page_size = *SYS_PAGE_SIZE;
+        if layout.align() > page_size || !self.takes_large() {
+            return NULL_PTR as *mut u8;
+        }
+        let size = layout.size() + align_padding(layout.size(), page_size);
+        if !self.charge_large(size) {
+            return NULL_PTR as *mut u8;
+        }
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let res = match self.node() {
+            Some(node) => NodeMmapAllocator { node }.alloc(layout),
+            None => MmapAllocator.alloc(layout),
+        };
+        match res {
+            Ok(ptr) => {
+                let addr = ptr.as_ptr() as usize;
+                extents::record(ExtentOp::Map, ExtentReason::Arena, addr, size);
+                self.large.insert(addr, size);
+                ptr.as_ptr()
+            }
+            Err(_) => {
+                self.discharge_large(size);
+                NULL_PTR as *mut u8
+            }
+        }
+    }
+
+    fn own_large(&self, addr: usize, size: usize) {
+        if let Some(node) = self.node() {
+            // best effort, pages stay where they are otherwise
+            move_to_node(addr as Ptr, size, node);
+        }
+        self.large.insert(addr, size);
+    }
+
+    fn release_large(&self) {
+        for (addr, _) in self.large.entries() {
+            if let Some(size) = self.large.remove(addr) {
+                self.discharge_large(size);
+                unsafe { unmap_large(addr, size) };
+            }
+        }
+    }
+
+    // Objects with mappings of their own count against the tenant of the arena as well
+    fn charge_large(&self, size: usize) -> bool {
+        self.instance
+            .tenant()
+            .map_or(true, |tenant| tenant::charge(tenant, size))
+    }
+
+    fn discharge_large(&self, size: usize) {
+        if let Some(tenant) = self.instance.tenant() {
+            tenant::discharge(tenant, size);
+        }
+    }
+}
+
+// Hand an object with a mapping of its own to the arena, from the arena the thread has entered or
+// from the general heap. False for objects neither of them can hand over, see above
+pub fn transfer(ptr: *mut u8, dst: &Arena) -> bool {
+    let entered = CURRENT_ARENA.with(|current| current.get());
+    if !entered.is_null() && unsafe { &*entered }.transfer(ptr, dst) {
+        return true;
+    }
+    if !dst.takes_large() {
+        return false;
+    }
+    let size = match large_heap::size_of(ptr as Ptr) {
+        Some(size) => size,
+        None => return false,
+    };
+    if !dst.charge_large(size) {
+        return false;
+    }
+    match large_heap::take_mapped(ptr as Ptr) {
+        Some(size) => {
+            dst.own_large(ptr as usize, size);
+            true
+        }
+        None => {
+            dst.discharge_large(size);
+            false
+        }
+    }
+}
+
+unsafe fn unmap_large(addr: usize, size: usize) {
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    MmapAllocator.dealloc(NonNull::new(addr as *mut u8).unwrap(), layout);
+    extents::record(ExtentOp::Unmap, ExtentReason::Arena, addr, size);
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        self.release_large();
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PinnedArena {
+    pub fn new(pin: ExtentHook, unpin: ExtentHook) -> Self {
+        Self {
+            arena: Arena::with_extent_hooks(ExtentHooks {
+                map: pin,
+                unmap: Some(unpin),
+            }),
+        }
+    }
+
+    // Unpin and release all objects, the arena stays usable
+    pub fn reset(&mut self) {
+        self.arena.reset()
+    }
+}
+
+impl Deref for PinnedArena {
+    type Target = Arena;
+
+    fn deref(&self) -> &Arena {
+        &self.arena
+    }
+}
+
+impl ArenaClone {
+    // Copy in the clone of an object of the source arena, until the clone is reset
+    pub fn translate(&self, ptr: *const u8) -> Option<*mut u8> {
+        self.mapping.translate(ptr as usize).map(|addr| addr as *mut u8)
+    }
+
+    // Reuse free objects in the order of a checkpoint of the source arena. False with free lists
+    // untouched for checkpoints of other arenas. Nothing shall allocate or free in the clone
+    // meanwhile
+    pub fn restore_free_lists(&self, checkpoint: &FreeListCheckpoint) -> bool {
+        self.mapping.restore_free_lists(&self.arena.instance, checkpoint)
+    }
+}
+
+impl Deref for ArenaClone {
+    type Target = Arena;
+
+    fn deref(&self) -> &Arena {
+        &self.arena
+    }
+}
+
+impl<'a> Drop for EnteredArena<'a> {
+    fn drop(&mut self) {
+        CURRENT_ARENA.with(|current| current.set(self.previous));
+    }
+}
+
+unsafe impl GlobalAlloc for Arena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // address spaces are the largest objects an arena can bump
+        if layout.size() + layout.align() > HEAP_VIRT_SIZE {
+            return self.map_large(layout);
+        }
+        self.instance.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.large.remove(ptr as usize) {
+            Some(size) => {
+                self.discharge_large(size);
+                unmap_large(ptr as usize, size)
+            }
+            None => self.instance.dealloc(ptr, layout),
+        }
+    }
+}
+
+unsafe impl<'a> Alloc for &'a Arena {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        NonNull::new(GlobalAlloc::alloc(*self, layout)).ok_or(AllocErr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        GlobalAlloc::dealloc(*self, ptr.as_ptr(), layout)
+    }
+}
+
+unsafe impl Alloc for ArenaAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let arena = CURRENT_ARENA.with(|current| current.get());
+        if arena.is_null() {
+            return Err(AllocErr);
+        }
+        (&*arena).alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let arena = CURRENT_ARENA.with(|current| current.get());
+        if !arena.is_null() {
+            // objects of other arenas are unknown to the arena and left alone
+            (&*arena).dealloc(ptr, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arena::*;
+    use crate::collections::fixvec::FixedVec;
+    use crate::collections::lflist::WordList;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    static REGISTERED: AtomicUsize = AtomicUsize::new(0);
+    // address spaces pinned by the mock hooks
+    static PINNED: AtomicUsize = AtomicUsize::new(0);
+    static PINNED_BASE: AtomicUsize = AtomicUsize::new(0);
+
+    fn register(index: usize, base: *mut u8, size: usize) {
+        assert_eq!(size, HEAP_VIRT_SIZE);
+        assert!(!base.is_null());
+        assert_eq!(REGISTERED.fetch_add(1, Relaxed), index);
+    }
+
+    fn pin(index: usize, base: *mut u8, _size: usize) {
+        if index == 0 {
+            PINNED_BASE.store(base as usize, Relaxed);
+        }
+        PINNED.fetch_add(1, Relaxed);
+    }
+
+    fn unpin(_index: usize, _base: *mut u8, _size: usize) {
+        PINNED.fetch_sub(1, Relaxed);
+    }
+
+    fn hook_nothing(_index: usize, _base: *mut u8, _size: usize) {}
+
+    #[test]
+    pub fn reset_arena() {
+        let mut arena = Arena::new();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&arena, layout);
+            ptr.write_bytes(42, 256);
+            GlobalAlloc::dealloc(&arena, ptr, layout);
+            // freed objects are reused by the arena
+            assert_eq!(GlobalAlloc::alloc(&arena, layout), ptr);
+            // mapped on its own, but not aligned beyond pages
+            let too_large = Layout::from_size_align(HEAP_VIRT_SIZE, 8).unwrap();
+            let large = GlobalAlloc::alloc(&arena, too_large);
+            large.write_bytes(42, 256);
+            assert!(arena.large.get(large as usize).is_some());
+            let too_aligned = Layout::from_size_align(HEAP_VIRT_SIZE, HEAP_VIRT_SIZE).unwrap();
+            assert!(GlobalAlloc::alloc(&arena, too_aligned).is_null());
+            arena.reset();
+            let ptr = GlobalAlloc::alloc(&arena, layout);
+            ptr.write_bytes(42, 256);
+        }
+    }
+
+    #[test]
+    pub fn transfer_large_objects() {
+        let request = Arena::new();
+        let long_lived = Arena::new();
+        let layout = Layout::from_size_align(HEAP_VIRT_SIZE, 8).unwrap();
+        let small = Layout::from_size_align(256, 8).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&request, layout);
+            ptr.write_bytes(42, 256);
+            assert!(request.transfer(ptr, &long_lived));
+            assert!(!request.transfer(ptr, &long_lived));
+            let obj = GlobalAlloc::alloc(&request, small);
+            assert!(!request.transfer(obj, &long_lived));
+            // survives the request arena
+            drop(request);
+            assert_eq!(*ptr.add(255), 42);
+            // from the general heap, guarded objects of hardened mode stay there
+            let mapped = crate::api::nu_malloc(HEAP_VIRT_SIZE);
+            if transfer(mapped as *mut u8, &long_lived) {
+                assert!(large_heap::size_of(mapped).is_none());
+            } else {
+                crate::api::nu_free(mapped);
+            }
+            GlobalAlloc::dealloc(&long_lived, ptr, layout);
+            assert!(long_lived.large.get(ptr as usize).is_none());
+        }
+        // unmaps what came from the general heap
+        drop(long_lived);
+    }
+
+    #[test]
+    pub fn large_objects_moved_back() {
+        let arena = Arena::walkable();
+        let other = Arena::new();
+        let layout = Layout::from_size_align(HEAP_VIRT_SIZE, 8).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&arena, layout);
+            assert!(arena.transfer(ptr, &other));
+            assert!(other.transfer(ptr, &arena));
+            let mut walked = vec![];
+            assert!(arena.walk(|ptr, size| walked.push((ptr, size))));
+            assert_eq!(walked, vec![(ptr, HEAP_VIRT_SIZE)]);
+            GlobalAlloc::dealloc(&arena, ptr, layout);
+        }
+        assert!(arena.large.entries().is_empty());
+        assert!(other.large.entries().is_empty());
+        let mut walked = 0;
+        assert!(arena.walk(|_, _| walked += 1));
+        assert_eq!(walked, 0);
+    }
+
+    #[test]
+    pub fn walk_in_address_order() {
+        let mut rng = rand::thread_rng();
+        let arena = Arena::walkable();
+        assert!(!Arena::new().walk(|_, _| {}));
+        let mut live = BTreeMap::new();
+        let mut freed = vec![];
+        unsafe {
+            for i in 0..2000 {
+                let size = rng.gen_range(1, 1 << rng.gen_range(1, 16));
+                let layout = Layout::from_size_align(size, 1 << rng.gen_range(0, 7)).unwrap();
+                let ptr = GlobalAlloc::alloc(&arena, layout);
+                if i % 3 == 0 {
+                    freed.push((ptr, layout));
+                } else {
+                    live.insert(ptr as usize, size);
+                }
+            }
+            let large = Layout::from_size_align(HEAP_VIRT_SIZE, 8).unwrap();
+            live.insert(GlobalAlloc::alloc(&arena, large) as usize, HEAP_VIRT_SIZE);
+            for (ptr, layout) in freed {
+                GlobalAlloc::dealloc(&arena, ptr, layout);
+            }
+        }
+        let mut walked = vec![];
+        assert!(arena.walk(|ptr, size| walked.push((ptr as usize, size))));
+        assert_eq!(walked.len(), live.len());
+        for ((addr, size), (live_addr, requested)) in walked.iter().zip(live.iter()) {
+            assert_eq!(addr, live_addr);
+            assert!(size >= requested);
+        }
+        // sequential and apart
+        assert!(walked.windows(2).all(|pair| pair[0].0 + pair[0].1 <= pair[1].0));
+    }
+
+    #[test]
+    pub fn clone_arena() {
+        let source = Arena::cloneable();
+        assert!(Arena::new().clone_cow().is_none());
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&source, layout);
+            ptr.write_bytes(42, 1024);
+            let clone = source.clone_cow().unwrap();
+            let copy = clone.translate(ptr).unwrap();
+            assert_eq!(*copy.add(1023), 42);
+            copy.write_bytes(24, 1024);
+            assert_eq!(*ptr.add(1023), 42);
+            // the copy is an object of the clone
+            GlobalAlloc::dealloc(&*clone, copy, layout);
+            assert_eq!(GlobalAlloc::alloc(&*clone, layout), copy);
+        }
+    }
+
+    #[test]
+    pub fn restore_clone_free_lists() {
+        let source = Arena::cloneable();
+        let layouts: Vec<_> = [16, 100, 4096]
+            .iter()
+            .map(|size| Layout::from_size_align(*size, 8).unwrap())
+            .collect();
+        unsafe {
+            let objects: Vec<_> = (0..300)
+                .map(|i| {
+                    let layout = layouts[i % layouts.len()];
+                    (GlobalAlloc::alloc(&source, layout), layout)
+                })
+                .collect();
+            for (ptr, layout) in objects.iter().step_by(2).rev() {
+                GlobalAlloc::dealloc(&source, *ptr, *layout);
+            }
+            let checkpoint = source.free_list_checkpoint();
+            let clone = source.clone_cow().unwrap();
+            assert!(!Arena::cloneable().clone_cow().unwrap().restore_free_lists(&checkpoint));
+            assert!(clone.restore_free_lists(&checkpoint));
+            for i in 0..300 {
+                let layout = layouts[i * 7 % layouts.len()];
+                let expected = GlobalAlloc::alloc(&source, layout);
+                let restored = GlobalAlloc::alloc(&*clone, layout);
+                assert_eq!(clone.translate(expected), Some(restored));
+            }
+        }
+    }
+
+    #[test]
+    pub fn tenant_arena() {
+        let label = "test-tenant-arena";
+        let mut arena = Arena::for_tenant(label).unwrap();
+        assert!(Arena::for_tenant("").is_none());
+        let live_bytes = || crate::api::nu_tenant_stats(label).unwrap().live_bytes;
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        let large = Layout::from_size_align(HEAP_VIRT_SIZE, 8).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&arena, layout);
+            assert!(live_bytes() >= 1000);
+            GlobalAlloc::dealloc(&arena, ptr, layout);
+            assert_eq!(live_bytes(), 0);
+            GlobalAlloc::alloc(&arena, layout);
+            assert!(!GlobalAlloc::alloc(&arena, large).is_null());
+            assert!(live_bytes() >= HEAP_VIRT_SIZE + 1000);
+            crate::api::nu_set_tenant_limit(label, HEAP_VIRT_SIZE * 2);
+            assert!(GlobalAlloc::alloc(&arena, large).is_null());
+            // discharged by the reset, the arena stays with the tenant
+            arena.reset();
+            assert_eq!(live_bytes(), 0);
+            GlobalAlloc::alloc(&arena, layout);
+            assert!(live_bytes() >= 1000);
+        }
+        drop(arena);
+        assert_eq!(live_bytes(), 0);
+    }
+
+    // VmFlags of the mapping of the address in smaps
+    #[cfg(target_os = "linux")]
+    fn vm_flags_of(addr: usize) -> Vec<String> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            let first = line.split_whitespace().next().unwrap_or("");
+            let mut bounds = first.splitn(2, '-').map(|bound| usize::from_str_radix(bound, 16));
+            if let (Some(Ok(start)), Some(Ok(end))) = (bounds.next(), bounds.next()) {
+                inside = addr >= start && addr < end;
+            } else if inside && line.starts_with("VmFlags:") {
+                return line["VmFlags:".len()..]
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect();
+            }
+        }
+        panic!("no mapping at {:x}", addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn sensitive_arenas() {
+        use crate::mmap::{lock_on_fault, mmap_without_fd, munmap_memory};
+        let layout = Layout::from_size_align(4096, 4096).unwrap();
+        let flags_of_object = |arena: &Arena| {
+            let ptr = unsafe { GlobalAlloc::alloc(arena, layout) };
+            assert!(!ptr.is_null());
+            vm_flags_of(ptr as usize)
+        };
+        let has = |flags: &Vec<String>, flag: &str| flags.iter().any(|f| f == flag);
+        let plain = flags_of_object(&Arena::new());
+        assert!(!has(&plain, "dd"));
+        assert!(!has(&plain, "lf"));
+        assert!(has(&flags_of_object(&Arena::dontdump()), "dd"));
+        // locked only with a memlock limit for a whole address space, or CAP_IPC_LOCK
+        let probe = mmap_without_fd(HEAP_VIRT_SIZE);
+        let lockable = lock_on_fault(probe, HEAP_VIRT_SIZE);
+        munmap_memory(probe, HEAP_VIRT_SIZE);
+        assert_eq!(has(&flags_of_object(&Arena::noswap()), "lf"), lockable);
+    }
+
+    #[test]
+    pub fn collections_in_arena() {
+        let arena = Arena::new();
+        let outer = Arena::new();
+        let _outer_entered = outer.enter();
+        {
+            let _entered = arena.enter();
+            let list = WordList::<ArenaAlloc>::new();
+            for i in 1..1000 {
+                list.push(i);
+            }
+            assert_eq!(list.count(), 999);
+            let mut vec = FixedVec::<usize, ArenaAlloc>::new(100);
+            vec[99] = 42;
+            assert_eq!(vec[99], 42);
+        }
+        // back in the outer arena
+        let ptr = unsafe { ArenaAlloc.alloc(Layout::new::<u64>()) }.unwrap();
+        assert!(CURRENT_ARENA.with(|current| current.get() == &outer as *const Arena));
+        unsafe { ArenaAlloc.dealloc(ptr, Layout::new::<u64>()) };
+    }
+
+    #[test]
+    pub fn registered_buffers() {
+        let mut arena = Arena::registered(register);
+        assert_eq!(REGISTERED.load(Relaxed), 1);
+        let small = Layout::from_size_align(4096, 4096).unwrap();
+        let (ptr, index) = arena.alloc_registered(small).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(arena.buffer_index(ptr), Some(0));
+        // fill the first address space so the next object is in another one
+        let large = Layout::from_size_align(HEAP_VIRT_SIZE / 2, 4096).unwrap();
+        let mut indices = vec![];
+        for _ in 0..3 {
+            let (ptr, index) = arena.alloc_registered(large).unwrap();
+            assert_eq!(arena.buffer_index(ptr), Some(index));
+            indices.push(index);
+        }
+        assert_eq!(indices.last(), Some(&(REGISTERED.load(Relaxed) - 1)));
+        assert!(REGISTERED.load(Relaxed) >= 2);
+        assert!(Arena::new().alloc_registered(small).is_none());
+        REGISTERED.store(0, Relaxed);
+        arena.reset();
+        assert_eq!(REGISTERED.load(Relaxed), 1);
+    }
+
+    #[test]
+    pub fn hooked_pages_kept() {
+        let hooked = Arena::with_extent_hooks(ExtentHooks {
+            map: hook_nothing,
+            unmap: Some(hook_nothing),
+        });
+        let plain = Arena::new();
+        let layout = Layout::from_size_align(64 * 1024, 4096).unwrap();
+        unsafe {
+            for arena in [&hooked, &plain].iter() {
+                let ptr = GlobalAlloc::alloc(*arena, layout);
+                ptr.write_bytes(42, layout.size());
+                GlobalAlloc::dealloc(*arena, ptr, layout);
+            }
+            assert!(plain.instance.purge() > 0);
+            assert_eq!(hooked.instance.unpurged_bytes(), 0);
+            assert_eq!(hooked.instance.purge(), 0);
+            // the free object is still resident with its contents
+            let ptr = GlobalAlloc::alloc(&hooked, layout);
+            assert_eq!(*ptr.add(layout.size() - 1), 42);
+        }
+    }
+
+    #[test]
+    pub fn pinned_arena() {
+        let mut arena = PinnedArena::new(pin, unpin);
+        assert_eq!(PINNED.load(Relaxed), 1);
+        let layout = Layout::from_size_align(1 << 20, 4096).unwrap();
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&*arena, layout) as usize;
+            let base = PINNED_BASE.load(Relaxed);
+            assert!(ptr >= base && ptr + (1 << 20) <= base + HEAP_VIRT_SIZE);
+            assert_eq!(arena.buffer_index(ptr as *const u8), Some(0));
+        }
+        let large = Layout::from_size_align(HEAP_VIRT_SIZE / 2, 4096).unwrap();
+        for _ in 0..3 {
+            arena.alloc_registered(large).unwrap();
+        }
+        assert!(PINNED.load(Relaxed) >= 2);
+        // the fresh address space is pinned before the old ones are unpinned
+        arena.reset();
+        assert_eq!(PINNED.load(Relaxed), 1);
+        drop(arena);
+        assert_eq!(PINNED.load(Relaxed), 0);
+    }
+}
@@ -0,0 +1,88 @@
+// Log of huge allocations
+// RSS blowups in production mostly come down to a few huge allocations. With the
+// `huge_alloc_log` option, every allocation of at least that many bytes writes a line to stderr
+// with its size, the object, and the return addresses of its call site, innermost first:
+//
+//   nulloc: huge allocation of 1073741824 bytes at 0x7f3a40000000 from 0x55d2c1a0 0x55d2c0f1
+//
+// Failed allocations are logged with a null object. Lines are formatted on the stack and written
+// without allocating. The threshold must be beyond the small size classes, whose allocations
+// skip the slow paths logging them.
+
+use crate::generic_heap::MAXIMUM_SMALL_SIZE;
+use crate::journal::write_line;
+use crate::sampling::backtrace;
+use crate::strict;
+use crate::{Ptr, NULL_PTR};
+use libc::{c_int, c_void};
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+pub const CALL_SITE_FRAMES: usize = 4;
+// frames of the allocator itself
+const SKIP_FRAMES: usize = 4;
+// file descriptor of stderr, libc has no STDERR_FILENO on Windows
+const STDERR: c_int = 2;
+
+// bytes from which allocations are logged, 0 when disabled
+static THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+
+// Returns false for thresholds within the small size classes, or in strict mode, which allows no
+// writes to stderr
+pub fn set_threshold(bytes: usize) -> bool {
+    if bytes != 0 && (bytes <= MAXIMUM_SMALL_SIZE || strict::is_enabled()) {
+        return false;
+    }
+    THRESHOLD.store(bytes, Relaxed);
+    true
+}
+
+#[inline]
+pub fn on_malloc(ptr: Ptr, size: usize) {
+    let threshold = THRESHOLD.load(Relaxed);
+    if threshold != 0 && size >= threshold {
+        log_huge(ptr, size);
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn log_huge(ptr: Ptr, size: usize) {
+    let mut frames = [NULL_PTR; CALL_SITE_FRAMES + SKIP_FRAMES];
+    let depth = unsafe { backtrace(frames.as_mut_ptr(), frames.len() as c_int) } as usize;
+    let mut call_site = [0; CALL_SITE_FRAMES];
+    for (i, frame) in frames[..depth].iter().skip(SKIP_FRAMES).enumerate() {
+        call_site[i] = *frame as usize;
+    }
+    write_entry(ptr, size, &call_site, &mut |line: &[u8]| unsafe {
+        libc::write(STDERR, line.as_ptr() as *const c_void, line.len() as _);
+    });
+}
+
+fn write_entry<S: FnMut(&[u8])>(ptr: Ptr, size: usize, call_site: &[usize], sink: &mut S) {
+    write_line(sink, |line| {
+        write!(line, "nulloc: huge allocation of {} bytes at {:#x} from", size, ptr as usize)?;
+        for frame in call_site.iter().filter(|frame| **frame != 0) {
+            write!(line, " {:#x}", frame)?;
+        }
+        write!(line, "\n")
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audit::*;
+
+    #[test]
+    pub fn huge_allocation_line() {
+        assert!(!set_threshold(4096));
+        let mut output = vec![];
+        let mut sink = |line: &[u8]| output.extend_from_slice(line);
+        write_entry(0x7f00_0000_0000 as Ptr, 1 << 30, &[0x1234, 0x5678, 0, 0], &mut sink);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "nulloc: huge allocation of 1073741824 bytes at 0x7f0000000000 from 0x1234 0x5678\n"
+        );
+    }
+}
@@ -0,0 +1,439 @@
+// Owned values and vectors placed by an allocator of choice, so Rust users can put objects into
+// an arena or region without touching raw pointers. The allocator defaults to the general heap
+//
+// CBuf holds bytes from nu_malloc for buffers handed to C libraries, which release them with
+// free() when the allocator is interposed. Its capacity is the usable size of the object, so
+// appending fills the slack of the size class before reallocating.
+
+use crate::api::{nu_free, nu_malloc, nu_malloc_usable_size, nu_try_realloc, SkyhooksAllocator};
+use crate::Ptr;
+use core::alloc::Layout;
+use core::{fmt, mem, ptr, slice};
+use std::alloc::{handle_alloc_error, Alloc};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+pub struct NuBox<T, A: Alloc = SkyhooksAllocator> {
+    ptr: NonNull<T>,
+    alloc: A,
+}
+
+pub struct NuVec<T, A: Alloc = SkyhooksAllocator> {
+    ptr: NonNull<T>,
+    capacity: usize,
+    len: usize,
+    alloc: A,
+}
+
+// Bytes in a nu_malloc object, null while nothing is allocated
+pub struct CBuf {
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+}
+
+impl<T, A: Alloc + Default> NuBox<T, A> {
+    pub fn new(value: T) -> Self {
+        Self::new_in(value, A::default())
+    }
+}
+
+impl<T, A: Alloc> NuBox<T, A> {
+    pub fn new_in(value: T, mut alloc: A) -> Self {
+        let ptr = allocate_array::<T, A>(&mut alloc, 1);
+        unsafe {
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Self { ptr, alloc }
+    }
+
+    pub fn into_inner(self) -> T {
+        let mut this = mem::ManuallyDrop::new(self);
+        unsafe {
+            let value = ptr::read(this.ptr.as_ptr());
+            deallocate_array(&mut this.alloc, this.ptr, 1);
+            ptr::drop_in_place(&mut this.alloc);
+            value
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+}
+
+impl<T, A: Alloc> Deref for NuBox<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, A: Alloc> DerefMut for NuBox<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug, A: Alloc> fmt::Debug for NuBox<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T, A: Alloc> Drop for NuBox<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            deallocate_array(&mut self.alloc, self.ptr, 1);
+        }
+    }
+}
+
+unsafe impl<T: Send, A: Alloc + Send> Send for NuBox<T, A> {}
+unsafe impl<T: Sync, A: Alloc + Sync> Sync for NuBox<T, A> {}
+
+impl<T, A: Alloc + Default> NuVec<T, A> {
+    pub fn new() -> Self {
+        Self::new_in(A::default())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_in(capacity, A::default())
+    }
+}
+
+impl<T, A: Alloc> NuVec<T, A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_capacity_in(0, alloc)
+    }
+
+    pub fn with_capacity_in(capacity: usize, mut alloc: A) -> Self {
+        let (ptr, capacity) = if mem::size_of::<T>() == 0 {
+            (NonNull::dangling(), usize::max_value())
+        } else if capacity == 0 {
+            (NonNull::dangling(), 0)
+        } else {
+            (allocate_array::<T, A>(&mut alloc, capacity), capacity)
+        };
+        Self {
+            ptr,
+            capacity,
+            len: 0,
+            alloc,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.len == self.capacity {
+            self.grow();
+        }
+        unsafe {
+            ptr::write(self.ptr.as_ptr().add(self.len), value);
+        }
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { ptr::read(self.ptr.as_ptr().add(self.len)) })
+    }
+
+    pub fn clear(&mut self) {
+        let len = self.len;
+        // leak rather than double drop if a destructor panics
+        self.len = 0;
+        unsafe {
+            ptr::drop_in_place(slice::from_raw_parts_mut(self.ptr.as_ptr(), len));
+        }
+    }
+
+    fn grow(&mut self) {
+        debug_assert_ne!(mem::size_of::<T>(), 0);
+        let new_capacity = if self.capacity == 0 {
+            4
+        } else {
+            self.capacity.checked_mul(2).expect("capacity overflow")
+        };
+        let new_ptr = allocate_array::<T, A>(&mut self.alloc, new_capacity);
+        unsafe {
+            ptr::copy_nonoverlapping(self.ptr.as_ptr(), new_ptr.as_ptr(), self.len);
+            deallocate_array(&mut self.alloc, self.ptr, self.capacity);
+        }
+        self.ptr = new_ptr;
+        self.capacity = new_capacity;
+    }
+}
+
+impl<T, A: Alloc> Deref for NuVec<T, A> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T, A: Alloc> DerefMut for NuVec<T, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: fmt::Debug, A: Alloc> fmt::Debug for NuVec<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T, A: Alloc> Drop for NuVec<T, A> {
+    fn drop(&mut self) {
+        self.clear();
+        unsafe {
+            deallocate_array(&mut self.alloc, self.ptr, self.capacity);
+        }
+    }
+}
+
+unsafe impl<T: Send, A: Alloc + Send> Send for NuVec<T, A> {}
+unsafe impl<T: Sync, A: Alloc + Sync> Sync for NuVec<T, A> {}
+
+impl CBuf {
+    pub fn new() -> Self {
+        Self {
+            ptr: ptr::null_mut(),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut buf = Self::new();
+        buf.reserve(capacity);
+        buf
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut buf = Self::with_capacity(data.len());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    // Take ownership of an object from nu_malloc or malloc under interposition, with `len`
+    // initialized bytes. Null takes nothing
+    pub unsafe fn from_raw(ptr: *mut u8, len: usize) -> Self {
+        let capacity = nu_malloc_usable_size(ptr as Ptr);
+        debug_assert!(len <= capacity);
+        Self { ptr, len, capacity }
+    }
+
+    // Hand the object over to C code, which shall free it. Null when nothing was allocated
+    pub fn into_raw(self) -> *mut u8 {
+        let this = mem::ManuallyDrop::new(self);
+        this.ptr
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.reserve(data.len());
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), data.len());
+        }
+        self.len += data.len();
+    }
+
+    pub fn resize(&mut self, len: usize, value: u8) {
+        if len > self.len {
+            self.reserve(len - self.len);
+            unsafe {
+                ptr::write_bytes(self.ptr.add(self.len), value, len - self.len);
+            }
+        }
+        self.len = len;
+    }
+
+    // Make room for `additional` more bytes, doubling the capacity at least
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("capacity overflow");
+        if needed <= self.capacity {
+            return;
+        }
+        let size = needed.max(self.capacity.saturating_mul(2));
+        let ptr = unsafe {
+            if self.ptr.is_null() {
+                nu_malloc(size)
+            } else {
+                nu_try_realloc(self.ptr as Ptr, size)
+            }
+        };
+        if ptr.is_null() {
+            handle_alloc_error(Layout::from_size_align(size, 1).unwrap());
+        }
+        self.ptr = ptr as *mut u8;
+        self.capacity = unsafe { nu_malloc_usable_size(ptr) };
+    }
+}
+
+impl Default for CBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for CBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for CBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        if self.ptr.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl fmt::Debug for CBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for CBuf {
+    fn drop(&mut self) {
+        unsafe {
+            nu_free(self.ptr as Ptr);
+        }
+    }
+}
+
+unsafe impl Send for CBuf {}
+unsafe impl Sync for CBuf {}
+
+// Zero sized types and empty arrays are never allocated
+fn allocate_array<T, A: Alloc>(alloc: &mut A, count: usize) -> NonNull<T> {
+    let layout = Layout::array::<T>(count).expect("capacity overflow");
+    if layout.size() == 0 {
+        return NonNull::dangling();
+    }
+    match unsafe { alloc.alloc(layout) } {
+        Ok(ptr) => ptr.cast(),
+        Err(_) => handle_alloc_error(layout),
+    }
+}
+
+unsafe fn deallocate_array<T, A: Alloc>(alloc: &mut A, ptr: NonNull<T>, count: usize) {
+    let layout = Layout::array::<T>(count).unwrap();
+    if layout.size() != 0 {
+        alloc.dealloc(ptr.cast(), layout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::boxed::*;
+    use crate::mmap_heap::MmapAllocator;
+
+    #[test]
+    pub fn boxed() {
+        let mut value = NuBox::<_>::new([1u64; 32]);
+        value[3] = 5;
+        assert_eq!(value[3], 5);
+        assert_eq!(value.into_inner()[4], 1);
+        let unit = NuBox::<_>::new(());
+        assert_eq!(*unit, ());
+        let mapped = NuBox::new_in(String::from("mapped"), MmapAllocator);
+        assert_eq!(mapped.as_str(), "mapped");
+    }
+
+    #[test]
+    pub fn vector() {
+        let mut vec = NuVec::<_>::new();
+        for i in 0..1000 {
+            vec.push(i.to_string());
+        }
+        assert_eq!(vec.len(), 1000);
+        assert_eq!(vec[999], "999");
+        assert_eq!(vec.pop(), Some("999".to_string()));
+        assert_eq!(vec.iter().filter(|s| s.len() == 1).count(), 10);
+        let mut units = NuVec::with_capacity_in(0, MmapAllocator);
+        units.push(());
+        assert_eq!(units.len(), 1);
+    }
+
+    #[test]
+    pub fn c_buffer() {
+        let mut buf = CBuf::new();
+        assert!(buf.is_empty());
+        assert!(buf.as_ptr().is_null());
+        buf.extend_from_slice(b"hello");
+        // the slack of the size class is usable
+        assert!(buf.capacity() >= 8);
+        for _ in 0..1000 {
+            buf.extend_from_slice(b", world");
+        }
+        assert_eq!(buf.len(), 7005);
+        assert_eq!(&buf[..12], b"hello, world");
+        buf.resize(8000, 0);
+        assert_eq!(buf[7999], 0);
+        let ptr = buf.into_raw();
+        unsafe {
+            // as C code would get and give it back
+            let buf = CBuf::from_raw(ptr, 5);
+            assert_eq!(&*buf, b"hello");
+            assert!(buf.capacity() >= 8000);
+            nu_free(buf.into_raw() as Ptr);
+        }
+        assert!(CBuf::new().into_raw().is_null());
+        assert_eq!(&*CBuf::from_slice(&[1, 2, 3]), &[1, 2, 3]);
+    }
+}
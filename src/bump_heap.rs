@@ -4,19 +4,35 @@
 // If the virtual address space is full and an allocation cannot been done on current address space,
 // new address space will be allocated from the system
 
+use crate::checkpoint::{FreeEntry, FreeListCheckpoint};
+use crate::collections::backoff::Backoff;
 use crate::collections::lflist;
+use crate::extents::{self, ExtentOp, ExtentReason};
 use crate::generic_heap::{size_class_index_from_size, NUM_SIZE_CLASS};
-use crate::mmap::{dealloc_regional, mmap_without_fd, munmap_memory};
+use crate::mmap::{
+    bind_to_node, close_file, commit_memory, dealloc_pages_within, dealloc_regional, dont_dump,
+    dump_pages_within, lock_on_fault, mmap_file, mmap_file_private, munmap_memory,
+    reserve_without_fd, try_reserve_without_fd,
+};
 use crate::mmap_heap::*;
+use crate::os::COMMITS_ON_TOUCH;
+use crate::perf_map;
+use crate::config;
+use crate::growth::GrowthMeter;
+use crate::reclaim;
+use crate::stats;
+use crate::tenant;
+use crate::tenant::TenantId;
+use crate::thread_mode;
 use crate::utils::*;
 use crate::{Ptr, Size, NULL_PTR};
 use core::alloc::{Alloc, AllocErr, GlobalAlloc, Layout};
-use core::sync::atomic::Ordering::Relaxed;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::{mem, ptr};
-use crossbeam::utils::Backoff;
 use lfmap::Map;
 use libc::*;
+use std::cmp::min;
 use std::mem::MaybeUninit;
 
 const BUMP_SIZE_CLASS: usize = NUM_SIZE_CLASS << 1;
@@ -24,48 +40,439 @@ const BUMP_SIZE_CLASS: usize = NUM_SIZE_CLASS << 1;
 type SizeClasses<A: Alloc + Default> = [SizeClass<A>; BUMP_SIZE_CLASS];
 
 lazy_static! {
-    static ref ALLOC_INNER: AllocatorInstance<MmapAllocator> = AllocatorInstance::new();
+    static ref ALLOC_INNER: AllocatorInstance<MmapAllocator> = {
+        let instance =
+            AllocatorInstance::with_reason(INSTANCE_UNLIMITED, None, ExtentReason::LargeAlloc);
+        ALLOC_INNER_READY.store(true, Relaxed);
+        instance
+    };
     static ref MALLOC_SIZE: lfmap::WordMap<MmapAllocator, AddressHasher> =
         lfmap::WordMap::<MmapAllocator, AddressHasher>::with_capacity(256);
+    // alignment of objects aligned beyond a cache line, needed to free them
+    static ref MALLOC_ALIGN: lfmap::WordMap<MmapAllocator, AddressHasher> =
+        lfmap::WordMap::<MmapAllocator, AddressHasher>::with_capacity(64);
     static ref MAXIMUM_FREE_LIST_COVERED_SIZE: usize = maximum_free_list_covered_size();
 }
 
 pub struct AllocatorInstance<A: Alloc + Default> {
     tail: AtomicUsize,
     base: AtomicUsize,
+    // pages of the current address space are committed below this address, see commit_bumped
+    committed: AtomicUsize,
     address_map: lfmap::WordMap<A, AddressHasher>,
     sizes: SizeClasses<A>,
+    // base addresses of all address spaces owned by this instance
+    extents: lflist::WordList<A>,
+    flags: usize,
+    tenant: Option<TenantId>,
+    // bytes of live objects charged to the tenant, discharged when the instance is dropped
+    tenant_bytes: AtomicUsize,
+    // some size class went over the free list budget, see SizeClass::over_budget
+    overflowed: AtomicBool,
+    // NUMA node pages of address spaces are placed on
+    node: Option<u16>,
+    growth: GrowthMeter,
+    // reason address spaces of the instance are logged with, see extents
+    reason: ExtentReason,
+    registration: Option<Registration<A>>,
+    // objects of walkable instances
+    log: Option<ObjectLog<A>>,
+    // memory files of address spaces of cloneable instances, base address to the file plus one
+    files: Option<lfmap::WordMap<A, AddressHasher>>,
+}
+
+// Called with the index, base and size of an address space of an instance. Indexes count from 0
+// in the order address spaces are mapped, so they can be registered as fixed buffers of io_uring
+// at the same index
+pub type ExtentHook = fn(index: usize, base: *mut u8, size: usize);
+
+#[derive(Clone, Copy)]
+pub struct ExtentHooks {
+    // after an address space is mapped, before objects are allocated from it
+    pub map: ExtentHook,
+    // before an address space is unmapped with the instance
+    pub unmap: Option<ExtentHook>,
+}
+
+struct Registration<A: Alloc + Default> {
+    hooks: ExtentHooks,
+    count: AtomicUsize,
+    // base address of address spaces to their index plus one
+    indices: lfmap::WordMap<A, AddressHasher>,
+}
+
+// Blocks and live objects of walkable instances. Blocks are bumped in address order within an
+// address space and keep their start and size when they are reused, so the live ones cover the
+// objects of the instance
+struct ObjectLog<A: Alloc + Default> {
+    // start of every block bumped, with its size
+    blocks: lflist::List<usize, A>,
+    // start of blocks of live objects to the addresses of the objects, which may be aligned
+    live: lfmap::WordMap<A, AddressHasher>,
 }
 
+// Copy-on-write clone of an allocator instance. Objects are at the same offsets in their address
+// spaces as in the source instance
+pub struct CowClone<A: Alloc + Default> {
+    pub instance: AllocatorInstance<A>,
+    pub mapping: CowMapping,
+}
+
+// (source base, clone base) of address spaces of a clone
+#[derive(Clone, Debug, Default)]
+pub struct CowMapping(Vec<(usize, usize)>);
+
 struct SizeClass<A: Alloc + Default> {
     size: usize,
     free_list: lflist::WordList<A>,
+    // free objects in the list when it was purged last time
+    purged_count: AtomicUsize,
+    purging: AtomicBool,
+    // over the free list budget, to be purged by the next allocation of the instance
+    overflowed: AtomicBool,
 }
 
 pub const HEAP_VIRT_SIZE: usize = 128 * 1024 * 1024; // 128MB
 
-fn allocate_address_space() -> Ptr {
-    mmap_without_fd(HEAP_VIRT_SIZE)
+// bytes of address spaces committed at a time where pages are not committed on first touch
+const COMMIT_GRANULE: usize = 1024 * 1024;
+
+// Flags of allocator instances for sensitive heaps
+// Contents of the instance shall not land readable in core dumps
+pub const INSTANCE_DONTDUMP: usize = 1;
+// Contents of the instance shall not land readable in swap
+pub const INSTANCE_NOSWAP: usize = 1 << 1;
+// Growth of the instance is not limited by the growth_limit option, see growth
+pub const INSTANCE_UNLIMITED: usize = 1 << 2;
+// Live objects of the instance can be listed in address order, see live_objects
+pub const INSTANCE_WALKABLE: usize = 1 << 3;
+// Address spaces are backed by memory files so the instance can be cloned copy-on-write, see
+// clone_cow. Pages of free objects are not released until the instance is dropped
+pub const INSTANCE_CLONEABLE: usize = 1 << 4;
+
+static ALLOC_INNER_READY: AtomicBool = AtomicBool::new(false);
+
+fn allocate_address_space(reason: ExtentReason) -> Ptr {
+    let address = reserve_without_fd(HEAP_VIRT_SIZE);
+    extents::record(ExtentOp::Map, reason, address as usize, HEAP_VIRT_SIZE);
+    address
+}
+
+// for swapping address space of an instance already in use, which can reclaim memory on failure
+fn reallocate_address_space(reason: ExtentReason) -> Ptr {
+    match reclaim::retry_on_oom(|| try_reserve_without_fd(HEAP_VIRT_SIZE)) {
+        Some(address) => {
+            extents::record(ExtentOp::Map, reason, address as usize, HEAP_VIRT_SIZE);
+            address
+        }
+        None => allocate_address_space(reason),
+    }
+}
+
+// Address space backed by a memory file recorded in `files`, None where memory files cannot be
+// mapped copy-on-write
+fn allocate_file_space<A: Alloc + Default>(
+    reason: ExtentReason,
+    files: &lfmap::WordMap<A, AddressHasher>,
+) -> Option<Ptr> {
+    let (address, fd) = mmap_file(HEAP_VIRT_SIZE)?;
+    files.insert(address as usize, fd as usize + 1);
+    extents::record(ExtentOp::Map, reason, address as usize, HEAP_VIRT_SIZE);
+    Some(address)
+}
+
+fn protect_address_space(address: Ptr, flags: usize, node: Option<u16>) {
+    if let Some(node) = node {
+        if !bind_to_node(address, HEAP_VIRT_SIZE, node) {
+            warn!("Cannot bind address space {:x} to node {}", address as usize, node);
+        }
+    }
+    if flags & INSTANCE_DONTDUMP != 0 && !dont_dump(address, HEAP_VIRT_SIZE) {
+        warn!("Cannot exclude address space {:x} from core dumps", address as usize);
+    }
+    if flags & INSTANCE_NOSWAP != 0 && !lock_on_fault(address, HEAP_VIRT_SIZE) {
+        warn!("Cannot lock address space {:x} from swap", address as usize);
+    }
 }
 
 // dealloc address space only been used when CAS base failed
 // Even noop will be fine, we still want to return the space the the OS because we can
-fn dealloc_address_space(address: Ptr) {
+fn dealloc_address_space(address: Ptr, reason: ExtentReason) {
     munmap_memory(address, HEAP_VIRT_SIZE);
+    extents::record(ExtentOp::Unmap, reason, address as usize, HEAP_VIRT_SIZE);
 }
 
 impl<A: Alloc + Default> AllocatorInstance<A> {
     pub fn new() -> Self {
-        let addr = allocate_address_space();
+        Self::with_flags(0)
+    }
+
+    // Instance accounting its objects to the tenant, allocations fail over the tenant limit
+    pub fn with_tenant(tenant: TenantId) -> Self {
+        let mut instance = Self::new();
+        instance.tenant = Some(tenant);
+        instance
+    }
+
+    // Instance placing its pages on the NUMA node
+    pub fn with_node(node: u16) -> Self {
+        Self::with_reason(0, Some(node), ExtentReason::Arena)
+    }
+
+    pub fn with_flags(flags: usize) -> Self {
+        Self::with_reason(flags, None, ExtentReason::Arena)
+    }
+
+    // Instance for the heaps, logging its address spaces with the reason they serve
+    pub fn with_reason(flags: usize, node: Option<u16>, reason: ExtentReason) -> Self {
+        let files = if flags & INSTANCE_CLONEABLE != 0 {
+            Some(lfmap::WordMap::with_capacity(16))
+        } else {
+            None
+        };
+        let addr = files
+            .as_ref()
+            .and_then(|files| allocate_file_space(reason, files))
+            .unwrap_or_else(|| allocate_address_space(reason));
+        protect_address_space(addr, flags, node);
+        let extents = lflist::WordList::with_capacity(16);
+        extents.push(addr as usize);
         Self {
             base: AtomicUsize::new(addr as usize),
             tail: AtomicUsize::new(addr as usize),
+            committed: AtomicUsize::new(addr as usize),
             address_map: lfmap::WordMap::with_capacity(4096),
             sizes: size_classes(),
+            extents,
+            flags,
+            tenant: None,
+            tenant_bytes: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+            node,
+            growth: GrowthMeter::new(),
+            reason,
+            registration: None,
+            log: if flags & INSTANCE_WALKABLE != 0 {
+                Some(ObjectLog {
+                    blocks: lflist::List::new(64),
+                    live: lfmap::WordMap::with_capacity(4096),
+                })
+            } else {
+                None
+            },
+            files,
+        }
+    }
+
+    // Instance handing every address space to the hooks when it is mapped and unmapped
+    pub fn with_extent_hooks(hooks: ExtentHooks) -> Self {
+        let mut instance = Self::new();
+        let registration = Registration {
+            hooks,
+            count: AtomicUsize::new(0),
+            indices: lfmap::WordMap::with_capacity(16),
+        };
+        registration.register(instance.base.load(Relaxed));
+        instance.registration = Some(registration);
+        instance
+    }
+
+    pub fn extent_hooks(&self) -> Option<ExtentHooks> {
+        self.registration.as_ref().map(|r| r.hooks)
+    }
+
+    // Whether the address is in an address space of the instance
+    pub fn owns(&self, addr: usize) -> bool {
+        self.extents
+            .iter()
+            .any(|(base, _)| addr >= base && addr < base + HEAP_VIRT_SIZE)
+    }
+
+    // Index of the address space containing the address, for instances with an extent hook
+    pub fn extent_index(&self, addr: usize) -> Option<usize> {
+        let registration = self.registration.as_ref()?;
+        self.extents
+            .iter()
+            .map(|(base, _)| base)
+            .find(|base| addr >= *base && addr < *base + HEAP_VIRT_SIZE)
+            .and_then(|base| registration.indices.get(base))
+            .map(|index| index - 1)
+    }
+
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+
+    pub fn tenant(&self) -> Option<TenantId> {
+        self.tenant
+    }
+
+    pub fn node(&self) -> Option<u16> {
+        self.node
+    }
+
+    // Keep free objects out of core dumps when configured, caller must own the object
+    #[inline]
+    fn retag_dump(&self, addr: usize, size: usize, dump: bool) {
+        if size >= *SYS_PAGE_SIZE
+            && self.flags & INSTANCE_DONTDUMP == 0
+            && config::options().dontdump_free()
+        {
+            dump_pages_within(addr as Ptr, size, dump);
         }
     }
 
+    // Duplicate the instance for snapshot style services to fork a read-mostly heap state, None
+    // unless the instance is cloneable. Address spaces of the clone are private mappings of the
+    // memory files of the source, pages are only copied when the clone writes them. Pages the
+    // clone has not written yet still show what the source writes after the clone, so the source
+    // should leave objects it shares with clones untouched. Source instance should not be
+    // allocating or freeing during the clone. Free lists and objects are carried over to the
+    // clone, objects copied from the source can be freed in the clone or are released along with
+    // it. The clone is not accounted to the tenant of the source, and is not cloneable itself
+    pub fn clone_cow(&self) -> Option<CowClone<A>> {
+        let files = self.files.as_ref()?;
+        // oldest first, for the clone to list its address spaces in the order of the source
+        let mut sources = self
+            .extents
+            .iter()
+            .map(|(base, _)| files.get(base).map(|fd| (base, fd - 1)))
+            .collect::<Option<Vec<_>>>()?;
+        sources.reverse();
+        let current_base = self.base.load(Relaxed);
+        let current_tail = self.tail.load(Relaxed);
+        let extents = lflist::WordList::with_capacity(16);
+        let mut mapping = Vec::new();
+        for (src_base, fd) in sources {
+            let dst_base = match mmap_file_private(fd as i32, HEAP_VIRT_SIZE) {
+                Some(address) => address as usize,
+                None => {
+                    for (_, dst_base) in mapping {
+                        dealloc_address_space(dst_base as Ptr, self.reason);
+                    }
+                    return None;
+                }
+            };
+            extents::record(ExtentOp::Map, self.reason, dst_base, HEAP_VIRT_SIZE);
+            protect_address_space(dst_base as Ptr, self.flags, self.node);
+            extents.push(dst_base);
+            mapping.push((src_base, dst_base));
+        }
+        let mapping = CowMapping(mapping);
+        let clone_base = mapping.translate(current_base)?;
+        let instance = Self {
+            base: AtomicUsize::new(clone_base),
+            tail: AtomicUsize::new(clone_base + (current_tail - current_base)),
+            // memory files are committed
+            committed: AtomicUsize::new(clone_base + HEAP_VIRT_SIZE),
+            address_map: lfmap::WordMap::with_capacity(4096),
+            sizes: size_classes(),
+            extents,
+            flags: self.flags & !(INSTANCE_CLONEABLE | INSTANCE_WALKABLE),
+            tenant: None,
+            tenant_bytes: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+            node: self.node,
+            growth: GrowthMeter::new(),
+            reason: self.reason,
+            registration: None,
+            // objects of the source are not tracked in the clone
+            log: None,
+            files: None,
+        };
+        for (addr, origin_addr) in self.address_map.entries() {
+            if let (Some(addr), Some(origin_addr)) =
+                (mapping.translate(addr), mapping.translate(origin_addr))
+            {
+                instance.address_map.insert(addr, origin_addr);
+            }
+        }
+        for (src_class, dst_class) in self.sizes.iter().zip(instance.sizes.iter()) {
+            for (addr, _) in src_class.free_list.iter() {
+                if let Some(dst_addr) = mapping.translate(addr) {
+                    dst_class.free_list.push(dst_addr);
+                }
+            }
+        }
+        Some(CowClone { instance, mapping })
+    }
+
+    // Free objects of all size classes as offsets in the address spaces, see checkpoint. Like
+    // clone_cow, the instance should not be allocating or freeing meanwhile
+    pub fn free_list_checkpoint(&self) -> FreeListCheckpoint {
+        // the list yields the latest address space first
+        let mut extents: Vec<usize> = self.extents.iter().map(|(base, _)| base).collect();
+        extents.reverse();
+        let mut entries = Vec::new();
+        for (class, size_class) in self.sizes.iter().enumerate() {
+            // from the head of the list, the order objects are popped in
+            for (addr, _) in size_class.free_list.iter() {
+                let extent = extents
+                    .iter()
+                    .position(|base| addr >= *base && addr < *base + HEAP_VIRT_SIZE);
+                if let Some(extent) = extent {
+                    entries.push(FreeEntry {
+                        class: class as u32,
+                        extent: extent as u32,
+                        offset: (addr - extents[extent]) as u64,
+                    });
+                }
+            }
+        }
+        FreeListCheckpoint { extents, entries }
+    }
+
+    // Replace the free lists with the ones in the checkpoint. `bases` are the address spaces of
+    // this instance in place of the address spaces of the checkpoint, index by index. Returns
+    // false with free lists untouched when the checkpoint does not fit the instance
+    pub fn restore_free_lists(&self, checkpoint: &FreeListCheckpoint, bases: &[usize]) -> bool {
+        let owned = |base: &usize| self.extents.iter().any(|(owned, _)| owned == *base);
+        if bases.len() != checkpoint.extents.len() || !bases.iter().all(owned) {
+            return false;
+        }
+        let fits = checkpoint.entries.iter().all(|entry| {
+            (entry.class as usize) < self.sizes.len()
+                && (entry.extent as usize) < bases.len()
+                && entry.offset as usize + self.sizes[entry.class as usize].size <= HEAP_VIRT_SIZE
+        });
+        if !fits {
+            return false;
+        }
+        for size_class in self.sizes.iter() {
+            while size_class.free_list.pop().is_some() {}
+        }
+        // backwards, for the first entry of a class to be popped first
+        for entry in checkpoint.entries.iter().rev() {
+            let addr = bases[entry.extent as usize] + entry.offset as usize;
+            self.sizes[entry.class as usize].free_list.push(addr);
+        }
+        true
+    }
+
+    // Bump allocate, waiting when the instance grows too fast
     pub fn bump_allocate(&self, size: usize) -> usize {
+        self.admit_growth(size, false);
+        self.bump(size)
+    }
+
+    #[inline]
+    fn admit_growth(&self, size: usize, can_fail: bool) -> bool {
+        self.flags & INSTANCE_UNLIMITED != 0 || self.growth.admit(size, can_fail)
+    }
+
+    fn bump(&self, size: usize) -> usize {
+        let addr = self.bump_range(size);
+        if !COMMITS_ON_TOUCH {
+            self.commit_bumped(addr, size);
+        }
+        addr
+    }
+
+    fn bump_range(&self, size: usize) -> usize {
+        if let Some(addr) = thread_mode::exclusive(|| self.exclusive_bump_allocate(size)) {
+            debug_validate(addr as Ptr, size);
+            return addr;
+        }
         let backoff = Backoff::new();
         loop {
             let base = self.base.load(Relaxed);
@@ -94,6 +501,49 @@ impl<A: Alloc + Default> AllocatorInstance<A> {
         }
     }
 
+    // bump allocate without CAS, only for single thread mode
+    fn exclusive_bump_allocate(&self, size: usize) -> usize {
+        loop {
+            let base = self.base.load(Relaxed);
+            let current_tail = self.tail.load(Relaxed);
+            let new_tail = current_tail + size;
+            if new_tail > base + HEAP_VIRT_SIZE {
+                self.swap_memory(base);
+                continue;
+            }
+            self.tail.store(new_tail, Relaxed);
+            return current_tail;
+        }
+    }
+
+    // Commit pages of the bumped range where the system does not commit them on first touch.
+    // Pages are committed a granule at a time from the committed mark of the current address
+    // space, which only moves past pages committed by the thread moving it
+    #[cold]
+    fn commit_bumped(&self, addr: usize, size: usize) {
+        let end = addr + size;
+        loop {
+            let base = self.base.load(Acquire);
+            let committed = self.committed.load(Acquire);
+            let upper_bound = base + HEAP_VIRT_SIZE;
+            if addr < base || end > upper_bound || committed < base || committed > upper_bound {
+                // address space swapped meanwhile, commit the range on its own
+                if !commit_memory(addr as Ptr, size) {
+                    panic!("Cannot commit {} bytes at {:x}", size, addr);
+                }
+                return;
+            }
+            if end <= committed {
+                return;
+            }
+            let new_committed = min(end + align_padding(end, COMMIT_GRANULE), upper_bound);
+            if !commit_memory(committed as Ptr, new_committed - committed) {
+                panic!("Cannot commit {} bytes at {:x}", new_committed - committed, committed);
+            }
+            self.committed.compare_and_swap(committed, new_committed, Release);
+        }
+    }
+
     fn size_of_object(&self, layout: &Layout) -> (usize, usize) {
         let align = layout.align();
         let size = layout.size();
@@ -110,8 +560,86 @@ impl<A: Alloc + Default> AllocatorInstance<A> {
         (actual_size, size_class_index)
     }
 
+    // Live objects with their usable sizes in address order, None unless the instance is
+    // walkable. Like clone_cow, the instance should not be allocating or freeing meanwhile
+    pub fn live_objects(&self) -> Option<Vec<(usize, usize)>> {
+        let log = self.log.as_ref()?;
+        let mut objects: Vec<(usize, usize)> = log
+            .blocks
+            .iter()
+            .filter_map(|(start, size)| log.live.get(start).map(|addr| (addr, start + size - addr)))
+            .collect();
+        objects.sort_unstable();
+        Some(objects)
+    }
+
+    // Release pages of free objects back to the OS, returns released bytes
+    // Objects are claimed from the free lists during purging so no one can reuse them meanwhile
+    pub fn purge(&self) -> usize {
+        if !self.decommits() {
+            return 0;
+        }
+        let page_size = *SYS_PAGE_SIZE;
+        self.sizes
+            .iter()
+            .filter(|sc| sc.size >= page_size << 1)
+            .map(|sc| sc.purge())
+            .sum()
+    }
+
+    // Pages of address spaces handed to extent hooks stay as they were registered, pinned or
+    // registered memory shall not be decommitted under the device or the kernel
+    #[inline]
+    fn decommits(&self) -> bool {
+        self.registration.is_none()
+    }
+
+    // Purge size classes frees left over the free list budget, see SizeClass::over_budget
+    #[cold]
+    fn purge_overflowed(&self) {
+        if self.overflowed.swap(false, Relaxed) {
+            self.sizes.iter().for_each(|sc| sc.purge_overflowed());
+        }
+    }
+
+    // Bytes of free objects purge would release, listed since the last purge of their classes
+    pub fn unpurged_bytes(&self) -> usize {
+        if !self.decommits() {
+            return 0;
+        }
+        let page_size = *SYS_PAGE_SIZE;
+        self.sizes
+            .iter()
+            .filter(|sc| sc.size >= page_size << 1)
+            .map(|sc| sc.unpurged() * sc.size)
+            .sum()
+    }
+
+    pub unsafe fn reinit_after_fork(&self) {
+        for size_class in self.sizes.iter() {
+            size_class.free_list.reinit_after_fork();
+        }
+        self.extents.reinit_after_fork();
+        reinit_map_after_fork(&self.address_map);
+        if let Some(registration) = &self.registration {
+            reinit_map_after_fork(&registration.indices);
+        }
+        if let Some(log) = &self.log {
+            log.blocks.reinit_after_fork();
+            reinit_map_after_fork(&log.live);
+        }
+        if let Some(files) = &self.files {
+            reinit_map_after_fork(files);
+        }
+    }
+
     fn swap_memory(&self, old_base: usize) {
-        let new_base = allocate_address_space();
+        let new_base = self
+            .files
+            .as_ref()
+            .and_then(|files| allocate_file_space(self.reason, files))
+            .unwrap_or_else(|| reallocate_address_space(self.reason));
+        protect_address_space(new_base, self.flags, self.node);
         if self
             .base
             .compare_and_swap(old_base, new_base as usize, Ordering::Relaxed)
@@ -119,26 +647,137 @@ impl<A: Alloc + Default> AllocatorInstance<A> {
         {
             // CAS base address failed, give up and release allocated address space
             // Other thread is also trying to allocate address space and succeeded
-            dealloc_address_space(new_base);
+            self.release_address_space(new_base);
         } else {
+            // registered before any object of the address space is handed out
+            if let Some(registration) = &self.registration {
+                registration.register(new_base as usize);
+            }
+            self.committed.store(new_base as usize, Release);
+            self.extents.push(new_base as usize);
             // update tail by store. This will fail all ongoing allocation and retry
             self.tail.store(new_base as usize, Ordering::SeqCst);
+            perf_map::annotate(
+                new_base as usize,
+                HEAP_VIRT_SIZE,
+                format_args!("nulloc::bump_heap::extent"),
+            );
         }
     }
+
+    // Unmap the address space along with its memory file, if any
+    fn release_address_space(&self, address: Ptr) {
+        if let Some(fd) = self.files.as_ref().and_then(|files| files.remove(address as usize)) {
+            close_file((fd - 1) as i32);
+        }
+        dealloc_address_space(address, self.reason);
+    }
+}
+
+impl<A: Alloc + Default> Registration<A> {
+    fn register(&self, base: usize) {
+        let index = self.count.fetch_add(1, Relaxed);
+        self.indices.insert(base, index + 1);
+        (self.hooks.map)(index, base as *mut u8, HEAP_VIRT_SIZE);
+    }
+
+    fn unregister(&self, base: usize) {
+        if let (Some(unmap), Some(index)) = (self.hooks.unmap, self.indices.get(base)) {
+            unmap(index - 1, base as *mut u8, HEAP_VIRT_SIZE);
+        }
+    }
+}
+
+impl<A: Alloc + Default> Drop for AllocatorInstance<A> {
+    fn drop(&mut self) {
+        if let Some(tenant) = self.tenant {
+            // objects die with the instance
+            tenant::discharge(tenant, self.tenant_bytes.load(Relaxed));
+        }
+        for (base, _) in self.extents.iter() {
+            if let Some(registration) = &self.registration {
+                registration.unregister(base);
+            }
+            self.release_address_space(base as Ptr);
+        }
+    }
+}
+
+impl CowMapping {
+    // Address in the clone of an address in the source instance
+    pub fn translate(&self, addr: usize) -> Option<usize> {
+        self.0
+            .iter()
+            .find(|(src_base, _)| addr >= *src_base && addr < *src_base + HEAP_VIRT_SIZE)
+            .map(|(src_base, dst_base)| addr - src_base + dst_base)
+    }
+
+    // Restore free lists of the clone from a checkpoint of the source instance, with the address
+    // spaces of the clone in place of the ones of the source
+    pub fn restore_free_lists<A: Alloc + Default>(
+        &self,
+        clone: &AllocatorInstance<A>,
+        checkpoint: &FreeListCheckpoint,
+    ) -> bool {
+        let bases: Option<Vec<usize>> = checkpoint
+            .extents
+            .iter()
+            .map(|base| self.translate(*base))
+            .collect();
+        bases.map_or(false, |bases| clone.restore_free_lists(checkpoint, &bases))
+    }
+}
+
+impl<A: Alloc + Default> CowClone<A> {
+    pub fn translate(&self, addr: usize) -> Option<usize> {
+        self.mapping.translate(addr)
+    }
+
+    // Restore free lists from a checkpoint of the source instance
+    pub fn restore_free_lists(&self, checkpoint: &FreeListCheckpoint) -> bool {
+        self.mapping.restore_free_lists(&self.instance, checkpoint)
+    }
 }
 
 unsafe impl<A: Alloc + Default> GlobalAlloc for AllocatorInstance<A> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.overflowed.load(Relaxed) {
+            self.purge_overflowed();
+        }
         let align = layout.align();
         let (actual_size, size_class_index) = self.size_of_object(&layout);
-        let origin_addr = self
+        if let Some(tenant) = self.tenant {
+            if !tenant::charge(tenant, actual_size) {
+                return NULL_PTR as *mut u8;
+            }
+            self.tenant_bytes.fetch_add(actual_size, Relaxed);
+        }
+        let reused = self
             .sizes
             .get(size_class_index)
-            .and_then(|sc| sc.free_list.pop())
-            .unwrap_or_else(|| self.bump_allocate(actual_size));
+            .and_then(|sc| sc.free_list.pop());
+        let origin_addr = if let Some(addr) = reused {
+            self.retag_dump(addr, actual_size, true);
+            addr
+        } else if self.admit_growth(actual_size, true) {
+            let addr = self.bump(actual_size);
+            if let Some(log) = &self.log {
+                log.blocks.push(addr, actual_size);
+            }
+            addr
+        } else {
+            if let Some(tenant) = self.tenant {
+                tenant::discharge(tenant, actual_size);
+                self.tenant_bytes.fetch_sub(actual_size, Relaxed);
+            }
+            return NULL_PTR as *mut u8;
+        };
         let align_padding = align_padding(origin_addr, align);
         let final_addr = origin_addr + align_padding;
         self.address_map.insert(final_addr, origin_addr);
+        if let Some(log) = &self.log {
+            log.live.insert(origin_addr, final_addr);
+        }
         debug_validate(final_addr as Ptr, actual_size);
         return final_addr as *mut u8;
     }
@@ -147,14 +786,31 @@ unsafe impl<A: Alloc + Default> GlobalAlloc for AllocatorInstance<A> {
         let (actual_size, size_class_index) = self.size_of_object(&layout);
         let addr = ptr as usize;
         if let Some(actual_addr) = self.address_map.get(addr) {
+            if let Some(tenant) = self.tenant {
+                tenant::discharge(tenant, actual_size);
+                self.tenant_bytes.fetch_sub(actual_size, Relaxed);
+            }
+            if let Some(log) = &self.log {
+                log.live.remove(actual_addr);
+            }
             let size_class_index = size_class_index_from_size(actual_size);
             if size_class_index < self.sizes.len() {
                 debug_validate(ptr as Ptr, actual_size);
-                self.sizes[size_class_index].free_list.push(actual_addr);
+                // tag before the object is visible to others in the free list
+                self.retag_dump(actual_addr, actual_size, false);
+                let size_class = &self.sizes[size_class_index];
+                size_class.free_list.push(actual_addr);
+                if self.decommits() && size_class.over_budget() {
+                    self.overflowed.store(true, Relaxed);
+                }
             } else {
                 // this may be a problem
                 self.address_map.remove(addr);
-                dealloc_regional(actual_addr as Ptr, actual_size);
+                self.retag_dump(actual_addr, actual_size, false);
+                if self.decommits() {
+                    dealloc_regional(actual_addr as Ptr, actual_size);
+                    extents::record(ExtentOp::Release, self.reason, actual_addr, actual_size);
+                }
             }
         }
     }
@@ -175,7 +831,58 @@ impl<A: Alloc + Default> SizeClass<A> {
         Self {
             size,
             free_list: lflist::WordList::new(),
+            purged_count: AtomicUsize::new(0),
+            purging: AtomicBool::new(false),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    fn purge(&self) -> usize {
+        if self.free_list.count() == 0 || self.purging.compare_and_swap(false, true, Acquire) {
+            return 0;
+        }
+        let mut released = 0;
+        let purged = lflist::WordList::<A>::with_capacity(64);
+        self.free_list.drop_out_with(|addr| {
+            released += dealloc_pages_within(addr as Ptr, self.size);
+            purged.push(addr);
+        });
+        self.free_list.prepend_with(&purged);
+        self.purged_count.store(self.free_list.count(), Relaxed);
+        self.purging.store(false, Release);
+        released
+    }
+
+    fn unpurged(&self) -> usize {
+        let count = self.free_list.count();
+        count - min(count, self.purged_count.load(Relaxed))
+    }
+
+    // Bound resident bytes of free objects by the free list budget. Objects listed since the last
+    // purge are purged together once they exceed the budget, by the next allocation instead of
+    // the free going over. Classes smaller than two pages share pages with live objects and are
+    // not bounded
+    #[inline]
+    fn over_budget(&self) -> bool {
+        let budget = config::options().free_list_budget();
+        if budget == 0
+            || self.size < *SYS_PAGE_SIZE << 1
+            || self.unpurged() * self.size <= budget
+        {
+            return false;
         }
+        self.overflowed.store(true, Relaxed);
+        true
+    }
+
+    fn purge_overflowed(&self) {
+        if !self.overflowed.swap(false, Relaxed) {
+            return;
+        }
+        let released = self.purge();
+        extents::record(ExtentOp::Release, ExtentReason::CacheSpill, 0, released);
+        stats::incr(&stats::OVERFLOW_PURGES);
+        stats::add(&stats::RECLAIMED_BYTES, released);
     }
 }
 
@@ -201,18 +908,63 @@ pub unsafe fn malloc(size: Size) -> Ptr {
     let layout = Layout::from_size_align(size, CACHE_LINE_SIZE).unwrap();
     let ptr = BumpAllocator.alloc(layout) as Ptr;
     MALLOC_SIZE.insert(ptr as usize, size as usize);
+    count_object(size);
     ptr
 }
+pub unsafe fn aligned_malloc(size: Size, align: usize) -> Ptr {
+    if align <= CACHE_LINE_SIZE {
+        return malloc(size);
+    }
+    let layout = Layout::from_size_align(size, align).unwrap();
+    let ptr = BumpAllocator.alloc(layout) as Ptr;
+    MALLOC_SIZE.insert(ptr as usize, size as usize);
+    MALLOC_ALIGN.insert(ptr as usize, align);
+    count_object(size);
+    ptr
+}
+
+#[inline]
+fn count_object(size: usize) {
+    stats::incr(&stats::LARGE_OBJECTS);
+    stats::add(&stats::LARGE_BYTES, size);
+}
 pub unsafe fn free(ptr: Ptr) -> bool {
     if let Some(size) = MALLOC_SIZE.remove(ptr as usize) {
-        let layout = Layout::from_size_align(size, CACHE_LINE_SIZE).unwrap();
+        let align = MALLOC_ALIGN
+            .remove(ptr as usize)
+            .unwrap_or(CACHE_LINE_SIZE);
+        let layout = Layout::from_size_align(size, align).unwrap();
         BumpAllocator.dealloc(ptr as *mut u8, layout);
+        stats::sub(&stats::LARGE_OBJECTS, 1);
+        stats::sub(&stats::LARGE_BYTES, size);
         true
     } else {
         false
     }
 }
 
+pub unsafe fn reinit_after_fork() {
+    ALLOC_INNER.reinit_after_fork();
+    reinit_map_after_fork(&MALLOC_SIZE);
+    reinit_map_after_fork(&MALLOC_ALIGN);
+}
+
+pub fn purge() -> usize {
+    if ALLOC_INNER_READY.load(Relaxed) {
+        ALLOC_INNER.purge()
+    } else {
+        0
+    }
+}
+
+pub fn unpurged_bytes() -> usize {
+    if ALLOC_INNER_READY.load(Relaxed) {
+        ALLOC_INNER.unpurged_bytes()
+    } else {
+        0
+    }
+}
+
 fn size_classes<A: Alloc + Default>() -> SizeClasses<A> {
     let mut data: [MaybeUninit<SizeClass<A>>; BUMP_SIZE_CLASS] =
         unsafe { MaybeUninit::uninit().assume_init() };
@@ -258,14 +1010,50 @@ pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
         return ptr;
     }
     let new_ptr = malloc(size);
+    if new_ptr == NULL_PTR {
+        return NULL_PTR;
+    }
     memcpy(new_ptr, ptr, old_size);
     free(ptr);
     new_ptr
 }
 
+// Like realloc, filling the last `srclen` bytes from `src` before the object is freed when it
+// moves, for `src` may point into it
+pub unsafe fn realloc_copyfrom(ptr: Ptr, size: Size, src: *const c_void, srclen: Size) -> Ptr {
+    debug_assert!(size != 0 && srclen <= size);
+    let old_size = if ptr == NULL_PTR {
+        0
+    } else if let Some(size) = MALLOC_SIZE.get(ptr as usize) {
+        size
+    } else {
+        warn!("Cannot determinate old object");
+        return NULL_PTR;
+    };
+    if ptr != NULL_PTR && old_size >= size {
+        memmove((ptr as usize + size - srclen) as Ptr, src, srclen);
+        return ptr;
+    }
+    let new_ptr = malloc(size);
+    if new_ptr == NULL_PTR {
+        return NULL_PTR;
+    }
+    if ptr != NULL_PTR {
+        memcpy(new_ptr, ptr, old_size);
+    }
+    memcpy((new_ptr as usize + size - srclen) as Ptr, src, srclen);
+    if ptr != NULL_PTR {
+        free(ptr);
+    }
+    new_ptr
+}
+
 #[cfg(test)]
 mod test {
-    use crate::bump_heap::BumpAllocator;
+    use crate::bump_heap::{AllocatorInstance, BumpAllocator, INSTANCE_CLONEABLE};
+    use crate::config::{self, TestOption};
+    use crate::mmap_heap::MmapAllocator;
+    use crate::stats;
     use crate::utils::AddressHasher;
     use crate::Ptr;
     use lfmap::Map;
@@ -308,6 +1096,86 @@ mod test {
         }
     }
 
+    #[test]
+    pub fn clone_cow() {
+        unsafe {
+            assert!(AllocatorInstance::<MmapAllocator>::new().clone_cow().is_none());
+            let instance = AllocatorInstance::<MmapAllocator>::with_flags(INSTANCE_CLONEABLE);
+            let layout = Layout::from_size_align(128, 8).unwrap();
+            let addr = instance.alloc(layout);
+            libc::memset(addr as Ptr, 42, 128);
+            let freed = instance.alloc(layout);
+            instance.dealloc(freed, layout);
+            let clone = instance.clone_cow().unwrap();
+            let clone_addr = clone.translate(addr as usize).unwrap();
+            assert_ne!(clone_addr, addr as usize);
+            for add in clone_addr..clone_addr + 128 {
+                assert_eq!(*(add as *const u8), 42);
+            }
+            libc::memset(clone_addr as Ptr, 24, 128);
+            for add in addr as usize..addr as usize + 128 {
+                assert_eq!(*(add as *const u8), 42);
+            }
+            // freed object in the source is also free in the clone
+            let clone_reused = clone.instance.alloc(layout) as usize;
+            assert_eq!(clone.translate(freed as usize), Some(clone_reused));
+            // live objects of the source can be freed in the clone
+            clone.instance.dealloc(clone_addr as *mut u8, layout);
+            assert_eq!(clone.instance.alloc(layout) as usize, clone_addr);
+        }
+    }
+
+    #[test]
+    pub fn free_list_budget() {
+        unsafe {
+            let instance = AllocatorInstance::<MmapAllocator>::new();
+            let size = 64 * 1024;
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let objects = (0..16).map(|_| instance.alloc(layout)).collect::<Vec<_>>();
+            for &ptr in &objects {
+                libc::memset(ptr as Ptr, 42, size);
+            }
+            let purges = stats::get(&stats::OVERFLOW_PURGES);
+            let previous = config::options().free_list_budget().to_string();
+            let _budget = TestOption::set("free_list_budget", "256K", previous);
+            for &ptr in &objects {
+                instance.dealloc(ptr, layout);
+            }
+            assert!(instance.unpurged_bytes() > 256 * 1024);
+            // purged by the next allocation, not by frees
+            let ptr = instance.alloc(Layout::from_size_align(16, 8).unwrap());
+            assert!(stats::get(&stats::OVERFLOW_PURGES) > purges);
+            assert_eq!(instance.unpurged_bytes(), 0);
+            instance.dealloc(ptr, Layout::from_size_align(16, 8).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn realloc_copyfrom_within() {
+        unsafe {
+            let ptr = super::malloc(64) as *mut u8;
+            for i in 0..64 {
+                *ptr.add(i) = i as u8;
+            }
+            // the source is in the object, which moves
+            let src = ptr.add(8) as *const libc::c_void;
+            let moved = super::realloc_copyfrom(ptr as Ptr, 4096, src, 16) as *mut u8;
+            assert_ne!(moved, ptr);
+            for i in 0..64 {
+                assert_eq!(*moved.add(i), i as u8);
+            }
+            for i in 0..16 {
+                assert_eq!(*moved.add(4096 - 16 + i), 8 + i as u8);
+            }
+            // in place
+            let src = moved as *const libc::c_void;
+            assert_eq!(super::realloc_copyfrom(moved as Ptr, 64, src, 4) as *mut u8, moved);
+            assert_eq!(*moved.add(60), 0);
+            assert_eq!(*moved.add(63), 3);
+            assert!(super::free(moved as Ptr));
+        }
+    }
+
     #[test]
     pub fn application() {
         let map = lfmap::WordMap::<BumpAllocator, AddressHasher>::with_capacity(1024);
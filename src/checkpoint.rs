@@ -0,0 +1,144 @@
+// Checkpoints of free lists of allocator instances
+// A snapshot of the live data of an instance, such as a CowClone, restores objects at the same
+// offsets in their address spaces, but not the order free objects are reused in. Checkpoints
+// record the free objects of every size class as offsets in the address spaces of the instance,
+// in the order they would be reused, so the restored instance hands out the same addresses
+// relative to its address spaces as the source would, for deterministic replays.
+//
+// Serialized layout, all fields are little endian:
+//   0   magic, "NUFREELS" in ASCII
+//   8   u64 version, currently 1
+//   16  u64 number of address spaces, then their base addresses in the source as u64
+//   ..  u64 number of entries, then per entry u32 size class, u32 address space, u64 offset
+
+use std::io::{self, Read, Write};
+
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"NUFREELS";
+pub const CHECKPOINT_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreeEntry {
+    pub class: u32,
+    // index of the address space in the checkpoint
+    pub extent: u32,
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FreeListCheckpoint {
+    // base addresses of address spaces of the source, in the order they were mapped
+    pub extents: Vec<usize>,
+    // free objects in the order they would be reused, size class by size class
+    pub entries: Vec<FreeEntry>,
+}
+
+impl FreeListCheckpoint {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(CHECKPOINT_MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.extents.len() as u64).to_le_bytes())?;
+        for base in &self.extents {
+            writer.write_all(&(*base as u64).to_le_bytes())?;
+        }
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            writer.write_all(&entry.class.to_le_bytes())?;
+            writer.write_all(&entry.extent.to_le_bytes())?;
+            writer.write_all(&entry.offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC || read_u64(reader)? != CHECKPOINT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a free list checkpoint",
+            ));
+        }
+        let num_extents = read_u64(reader)? as usize;
+        let mut extents = Vec::new();
+        for _ in 0..num_extents {
+            extents.push(read_u64(reader)? as usize);
+        }
+        let num_entries = read_u64(reader)? as usize;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            let class = read_u32(reader)?;
+            let extent = read_u32(reader)?;
+            let offset = read_u64(reader)?;
+            if extent as usize >= num_extents {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "entry out of address spaces",
+                ));
+            }
+            entries.push(FreeEntry {
+                class,
+                extent,
+                offset,
+            });
+        }
+        Ok(Self { extents, entries })
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bump_heap::{AllocatorInstance, INSTANCE_CLONEABLE};
+    use crate::checkpoint::*;
+    use crate::mmap_heap::MmapAllocator;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    pub fn restore_free_lists() {
+        let source = AllocatorInstance::<MmapAllocator>::with_flags(INSTANCE_CLONEABLE);
+        let layouts: Vec<_> = [16, 24, 100, 4096, 20000]
+            .iter()
+            .map(|size| Layout::from_size_align(*size, 8).unwrap())
+            .collect();
+        unsafe {
+            let mut objects = vec![];
+            for i in 0..500 {
+                let layout = layouts[i % layouts.len()];
+                objects.push((source.alloc(layout), layout));
+            }
+            // free every third object, out of allocation order
+            for (i, (ptr, layout)) in objects.iter().enumerate().rev() {
+                if i % 3 == 0 {
+                    source.dealloc(*ptr, *layout);
+                }
+            }
+            let checkpoint = source.free_list_checkpoint();
+            assert!(!checkpoint.entries.is_empty());
+            let mut bytes = vec![];
+            checkpoint.write_to(&mut bytes).unwrap();
+            let read = FreeListCheckpoint::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(read, checkpoint);
+            assert!(FreeListCheckpoint::read_from(&mut &bytes[8..]).is_err());
+
+            let clone = source.clone_cow().unwrap();
+            assert!(clone.restore_free_lists(&read));
+            for i in 0..500 {
+                let layout = layouts[i * 7 % layouts.len()];
+                let expected = source.alloc(layout) as usize;
+                let restored = clone.instance.alloc(layout) as usize;
+                assert_eq!(clone.translate(expected), Some(restored));
+            }
+        }
+    }
+}
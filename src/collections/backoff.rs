@@ -0,0 +1,145 @@
+// Backoff of the spin loops of the allocator, with a global policy
+// Loops retrying a CAS or waiting for other threads first spin, with twice as many pause
+// instructions every round, for `backoff_spins` rounds. Then they yield the CPU on every round.
+// Loops waiting for a word of a structure to change, such as reference counts of list buffers
+// being spliced or dropped out, go on to park on the word with a futex after `backoff_yields`
+// yields, and are woken when the word is released or after `backoff_park_us`. On machines with
+// more threads than cores, threads waiting for a preempted one then stop taking its CPU time.
+//
+// Parking is off by default, the waits it serves are short unless threads are preempted. It
+// needs the futex system call, so it is refused and switched off in strict mode.
+
+use crate::os;
+use crate::strict;
+use std::cell::Cell;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::{self, AtomicUsize};
+use std::thread;
+
+// longest spin of a round is 2^MAX_SPIN_SHIFT pauses
+const MAX_SPIN_SHIFT: usize = 10;
+
+static SPINS: AtomicUsize = AtomicUsize::new(6);
+// yields before parking, 0 to never park
+static YIELDS: AtomicUsize = AtomicUsize::new(0);
+static PARK_US: AtomicUsize = AtomicUsize::new(100);
+// threads parked on any word
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Backoff {
+    step: Cell<usize>,
+}
+
+pub fn set_spins(rounds: usize) {
+    SPINS.store(rounds, Relaxed);
+}
+
+// Returns false in strict mode unless parking is turned off
+pub fn set_yields(yields: usize) -> bool {
+    if yields != 0 && strict::is_enabled() {
+        return false;
+    }
+    YIELDS.store(yields, Relaxed);
+    true
+}
+
+pub fn set_park_us(us: usize) {
+    PARK_US.store(us.max(1), Relaxed);
+}
+
+// Wake threads parked on the word, after the word is changed. Called on hot paths, without a
+// fence, a thread parking meanwhile may be missed until its park times out
+#[inline]
+pub fn wake(word: &AtomicUsize) {
+    if PARKED.load(SeqCst) != 0 {
+        os::unpark_all(low_half(word));
+    }
+}
+
+impl Backoff {
+    #[inline]
+    pub fn new() -> Self {
+        Self { step: Cell::new(0) }
+    }
+
+    // Back off after a failed CAS
+    #[inline]
+    pub fn spin(&self) {
+        let step = self.step.get();
+        if step < SPINS.load(Relaxed) {
+            for _ in 0..1 << step.min(MAX_SPIN_SHIFT) {
+                atomic::spin_loop_hint();
+            }
+        } else {
+            thread::yield_now();
+        }
+        self.step.set(step + 1);
+    }
+
+    // Back off while waiting for other threads, the same as spin
+    #[inline]
+    pub fn snooze(&self) {
+        self.spin()
+    }
+
+    // Back off while the word holds `seen`, parking on it when the policy says so
+    pub fn wait_on(&self, word: &AtomicUsize, seen: usize) {
+        let yields = YIELDS.load(Relaxed);
+        if yields == 0 || self.step.get() < SPINS.load(Relaxed) + yields {
+            return self.spin();
+        }
+        park(word, seen);
+    }
+}
+
+#[cold]
+fn park(word: &AtomicUsize, seen: usize) {
+    PARKED.fetch_add(1, SeqCst);
+    // returns at once when the word has changed since it was seen
+    os::park(low_half(word), seen as u32, PARK_US.load(Relaxed));
+    PARKED.fetch_sub(1, Relaxed);
+}
+
+// Futexes are 32 bits, parked threads wait on the low half of the word. Changes of the high half
+// only are seen when the park times out
+#[inline]
+fn low_half(word: &AtomicUsize) -> *const u32 {
+    let addr = word as *const AtomicUsize as usize;
+    if cfg!(target_endian = "big") {
+        (addr + std::mem::size_of::<usize>() - 4) as *const u32
+    } else {
+        addr as *const u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::backoff::*;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn park_until_released() {
+        let word = Arc::new(AtomicUsize::new(3));
+        let releaser = {
+            let word = word.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                word.store(2, Relaxed);
+                wake(&word);
+            })
+        };
+        let start = Instant::now();
+        loop {
+            let seen = word.load(Relaxed);
+            if seen <= 2 {
+                break;
+            }
+            park(&word, seen);
+        }
+        releaser.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        // the word changed already
+        park(&word, 3);
+    }
+}
@@ -0,0 +1,216 @@
+// Epoch based reclamation for buffers of lock-free lists
+// Threads pin the global epoch while they may hold pointers loaded from shared buffers. Buffers
+// unlinked from their lists are retired with the epoch observed after unlinking, and only
+// deallocated once the global epoch is two ahead of it. Advancing from epoch E to E + 1 requires
+// no thread pinned in E - 1, so after two advances no thread pinned before the retirement is
+// left, and no pointer to the buffer can be dereferenced or win a CAS anymore.
+// Pins are counted in striped counters instead of per-thread records, so pinning needs neither
+// registration nor allocation and is safe to use inside the allocator.
+// Epochs are the only reclamation scheme of the collections, there is no barrier waiting for all
+// threads to pass through the allocator. Page map nodes are only deallocated with the map, and
+// arenas are reset or dropped by their only owner, so neither has readers left to wait for.
+
+use crate::utils::current_thread_id;
+use core::intrinsics;
+use std::ptr::null_mut;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+
+const EPOCHS: usize = 3;
+const STRIPES: usize = 64;
+
+#[derive(Clone, Copy)]
+#[repr(align(64))]
+struct Stripe([usize; EPOCHS]);
+
+// pins of each epoch modulo EPOCHS, accessed with atomic intrinsics
+static mut PINS: [Stripe; STRIPES] = [Stripe([0; EPOCHS]); STRIPES];
+static GLOBAL_EPOCH: AtomicUsize = AtomicUsize::new(0);
+static BAGS: [AtomicPtr<Retired>; EPOCHS] = [
+    AtomicPtr::new(null_mut()),
+    AtomicPtr::new(null_mut()),
+    AtomicPtr::new(null_mut()),
+];
+// retired and not yet deallocated
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// Embedded in retired objects, linking them into the bags
+pub struct Retired {
+    next: *mut Retired,
+    epoch: usize,
+    addr: usize,
+    size: usize,
+    free: fn(usize, usize),
+}
+
+pub struct Guard {
+    counter: *mut usize,
+}
+
+impl Retired {
+    pub fn new() -> Self {
+        Self {
+            next: null_mut(),
+            epoch: 0,
+            addr: 0,
+            size: 0,
+            free: noop_free,
+        }
+    }
+}
+
+pub fn pin() -> Guard {
+    let stripe = stripe_of(current_thread_id());
+    loop {
+        let epoch = GLOBAL_EPOCH.load(SeqCst);
+        let counter = pin_counter(stripe, epoch % EPOCHS);
+        unsafe {
+            intrinsics::atomic_xadd(counter, 1);
+        }
+        // the epoch may have advanced before the pin became visible
+        if GLOBAL_EPOCH.load(SeqCst) == epoch {
+            return Guard { counter };
+        }
+        unsafe {
+            intrinsics::atomic_xsub_rel(counter, 1);
+        }
+    }
+}
+
+// Deallocate the object of `size` bytes at `addr` by `free` once no pinned thread can see it.
+// The object must be unlinked from everywhere other threads may load it before retiring, and
+// `node` must live inside the object
+pub unsafe fn retire(node: *mut Retired, addr: usize, size: usize, free: fn(usize, usize)) {
+    let retired = &mut *node;
+    retired.addr = addr;
+    retired.size = size;
+    retired.free = free;
+    retired.epoch = GLOBAL_EPOCH.load(SeqCst);
+    PENDING.fetch_add(1, Relaxed);
+    push_to_bag(node);
+    try_advance();
+}
+
+// Advance the global epoch if no thread is pinned in the previous one, and deallocate objects
+// retired two epochs before
+pub fn try_advance() -> bool {
+    let epoch = GLOBAL_EPOCH.load(SeqCst);
+    let previous = (epoch + EPOCHS - 1) % EPOCHS;
+    for stripe in 0..STRIPES {
+        if unsafe { intrinsics::atomic_load(pin_counter(stripe, previous)) } != 0 {
+            return false;
+        }
+    }
+    if GLOBAL_EPOCH.compare_and_swap(epoch, epoch + 1, SeqCst) != epoch {
+        return false;
+    }
+    collect(epoch + 1);
+    true
+}
+
+pub fn epoch() -> usize {
+    GLOBAL_EPOCH.load(Relaxed)
+}
+
+pub fn pending() -> usize {
+    PENDING.load(Relaxed)
+}
+
+// Only for the child process after fork, threads holding pins are gone
+pub unsafe fn reinit_after_fork() {
+    for stripe in 0..STRIPES {
+        for epoch in 0..EPOCHS {
+            intrinsics::atomic_store_relaxed(pin_counter(stripe, epoch), 0);
+        }
+    }
+}
+
+// Objects may be pushed late into a bag of a later epoch of the same index, so every object is
+// checked against its own epoch, and put back if it is not old enough
+fn collect(current: usize) {
+    let bag = &BAGS[(current + 1) % EPOCHS];
+    let mut node = bag.swap(null_mut(), Acquire);
+    while !node.is_null() {
+        let retired = unsafe { &*node };
+        let next = retired.next;
+        if retired.epoch + 2 <= current {
+            PENDING.fetch_sub(1, Relaxed);
+            (retired.free)(retired.addr, retired.size);
+        } else {
+            push_to_bag(node);
+        }
+        node = next;
+    }
+}
+
+fn push_to_bag(node: *mut Retired) {
+    let bag = &BAGS[unsafe { &*node }.epoch % EPOCHS];
+    loop {
+        let head = bag.load(Relaxed);
+        unsafe {
+            (*node).next = head;
+        }
+        if bag.compare_and_swap(head, node, Release) == head {
+            return;
+        }
+    }
+}
+
+#[inline]
+fn stripe_of(thread_id: usize) -> usize {
+    // thread ids are aligned addresses, take high bits of the product
+    (thread_id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) as usize >> 58
+}
+
+#[inline]
+fn pin_counter(stripe: usize, epoch: usize) -> *mut usize {
+    unsafe { &mut PINS[stripe].0[epoch] as *mut usize }
+}
+
+fn noop_free(_addr: usize, _size: usize) {}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        unsafe {
+            intrinsics::atomic_xsub_rel(self.counter, 1);
+        }
+        if PENDING.load(Relaxed) != 0 {
+            try_advance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::epoch::*;
+    use std::sync::atomic::AtomicBool;
+
+    static FREED: AtomicBool = AtomicBool::new(false);
+
+    fn mark_freed(_addr: usize, _size: usize) {
+        FREED.store(true, Relaxed);
+    }
+
+    #[test]
+    pub fn deferred_until_unpinned() {
+        let mut node = Box::new(Retired::new());
+        let guard = pin();
+        unsafe {
+            retire(&mut *node, 0, 0, mark_freed);
+        }
+        for _ in 0..8 {
+            try_advance();
+        }
+        // cannot advance twice past a pinned thread
+        assert!(!FREED.load(Relaxed));
+        drop(guard);
+        for _ in 0..8 {
+            try_advance();
+        }
+        // other tests may hold pins for a while
+        while !FREED.load(Relaxed) {
+            try_advance();
+            std::thread::yield_now();
+        }
+    }
+}
@@ -1,4 +1,9 @@
 // a boring fixed sized vector, for index only
+// Elements start zeroed, as the memory comes from alloc_mem, and are never moved. Vectors made
+// with `spilling` take indices past the first block from a second block of their own, allocated
+// zeroed the first time one of them is touched, so tables that may need a few more slots than
+// planned still stay off the global allocator. The second block is installed by a CAS, the
+// loser of a race deallocates its block and uses the winner's.
 
 use crate::utils::{alloc_mem, dealloc_mem};
 use core::alloc::Layout;
@@ -7,45 +12,153 @@ use std::alloc::{Alloc, Global, GlobalAlloc};
 use std::marker::PhantomData;
 use std::ops::{Index, IndexMut};
 use std::ptr::null_mut;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
 
 pub struct FixedVec<T, A: Alloc + Default = Global> {
     ptr: *mut T,
     capacity: usize,
+    // second block, null until touched
+    spill: AtomicPtr<T>,
+    spill_capacity: usize,
     shadow: PhantomData<A>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfRange {
+    pub index: usize,
+    pub capacity: usize,
+}
+
+pub struct Iter<'a, T, A: Alloc + Default> {
+    vec: &'a FixedVec<T, A>,
+    index: usize,
+}
+
 impl<T, A: Alloc + Default> FixedVec<T, A> {
     pub fn new(cap: usize) -> Self {
+        Self::spilling(cap, 0)
+    }
+
+    // Vector of `cap` elements growing into a second block of `spill_cap` when needed
+    pub fn spilling(cap: usize, spill_cap: usize) -> Self {
         let heap_size = total_size::<T>(cap);
         Self {
             ptr: unsafe { alloc_mem::<A>(heap_size) } as *mut T,
             capacity: cap,
+            spill: AtomicPtr::new(null_mut()),
+            spill_capacity: spill_cap,
             shadow: PhantomData,
         }
     }
+
+    // Elements of both blocks, spilled or not
     pub fn capacity(&self) -> usize {
-        self.capacity
+        self.capacity + self.spill_capacity
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        !self.spill.load(Acquire).is_null()
+    }
+
+    pub fn get(&self, index: usize) -> Result<&T, OutOfRange> {
+        self.slot(index).map(|ptr| unsafe { &*ptr })
     }
+
+    pub fn get_mut(&mut self, index: usize) -> Result<&mut T, OutOfRange> {
+        self.slot(index).map(|ptr| unsafe { &mut *ptr })
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, A> {
+        Iter {
+            vec: self,
+            index: 0,
+        }
+    }
+
     fn object_ptr(&self, index: usize) -> usize {
         self.ptr as usize + index * mem::size_of::<T>()
     }
+
+    fn slot(&self, index: usize) -> Result<*mut T, OutOfRange> {
+        if index < self.capacity {
+            return Ok(self.object_ptr(index) as *mut T);
+        }
+        let spill_index = index - self.capacity;
+        if spill_index >= self.spill_capacity {
+            return Err(OutOfRange {
+                index,
+                capacity: self.capacity(),
+            });
+        }
+        let spill = self.spill_block() as usize;
+        Ok((spill + spill_index * mem::size_of::<T>()) as *mut T)
+    }
+
+    #[cold]
+    fn spill_block(&self) -> *mut T {
+        let spill = self.spill.load(Acquire);
+        if !spill.is_null() {
+            return spill;
+        }
+        let heap_size = total_size::<T>(self.spill_capacity);
+        let block = alloc_mem::<A>(heap_size) as *mut T;
+        let installed = self.spill.compare_and_swap(null_mut(), block, AcqRel);
+        if installed.is_null() {
+            block
+        } else {
+            dealloc_mem::<A>(block as usize, heap_size);
+            installed
+        }
+    }
+}
+
+impl<'a, T, A: Alloc + Default> Iterator for Iter<'a, T, A> {
+    type Item = &'a T;
+
+    // Elements of the second block only once it is spilled
+    fn next(&mut self) -> Option<&'a T> {
+        let end = if self.vec.is_spilled() {
+            self.vec.capacity()
+        } else {
+            self.vec.capacity
+        };
+        if self.index >= end {
+            return None;
+        }
+        self.index += 1;
+        self.vec.get(self.index - 1).ok()
+    }
+}
+
+impl<'a, T, A: Alloc + Default> IntoIterator for &'a FixedVec<T, A> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, A>;
+
+    fn into_iter(self) -> Iter<'a, T, A> {
+        self.iter()
+    }
 }
 
 impl<T, A: Alloc + Default> Index<usize> for FixedVec<T, A> {
     type Output = T;
 
     fn index(&self, index: usize) -> &Self::Output {
-        debug_assert!(index < self.capacity);
-        let obj_ptr = self.object_ptr(index);
-        return unsafe { &*(obj_ptr as *mut T) };
+        if index < self.capacity {
+            let obj_ptr = self.object_ptr(index);
+            return unsafe { &*(obj_ptr as *mut T) };
+        }
+        self.get(index).expect("index out of range")
     }
 }
 
 impl<T, A: Alloc + Default> IndexMut<usize> for FixedVec<T, A> {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        debug_assert!(index < self.capacity);
-        let obj_ptr = self.object_ptr(index);
-        return unsafe { &mut *(obj_ptr as *mut T) };
+        if index < self.capacity {
+            let obj_ptr = self.object_ptr(index);
+            return unsafe { &mut *(obj_ptr as *mut T) };
+        }
+        self.get_mut(index).expect("index out of range")
     }
 }
 
@@ -54,24 +167,60 @@ fn total_size<T>(cap: usize) -> usize {
     object_size * cap
 }
 
-unsafe impl<T> Send for FixedVec<T> {}
-unsafe impl<T> Sync for FixedVec<T> {}
+unsafe impl<T, A: Alloc + Default> Send for FixedVec<T, A> {}
+unsafe impl<T, A: Alloc + Default> Sync for FixedVec<T, A> {}
 
 impl<T, A: Alloc + Default> Drop for FixedVec<T, A> {
     fn drop(&mut self) {
+        let spill = *self.spill.get_mut();
+        if !spill.is_null() {
+            drop_block::<T, A>(spill, self.spill_capacity);
+        }
         if self.ptr == null_mut() {
             return;
         }
         debug_assert_ne!(self.capacity, 0);
-        let heap_size = total_size::<T>(self.capacity);
-        debug_assert_ne!(heap_size, 0);
-        if mem::needs_drop::<T>() {
-            for i in 0..self.capacity {
-                let object_ptr = self.object_ptr(i);
-                let object = unsafe { ptr::read(object_ptr as *mut T) };
-                drop(object)
-            }
+        drop_block::<T, A>(self.ptr, self.capacity);
+    }
+}
+
+fn drop_block<T, A: Alloc + Default>(block: *mut T, cap: usize) {
+    let heap_size = total_size::<T>(cap);
+    debug_assert_ne!(heap_size, 0);
+    if mem::needs_drop::<T>() {
+        for i in 0..cap {
+            let object = unsafe { ptr::read(block.add(i)) };
+            drop(object)
         }
-        dealloc_mem::<A>(self.ptr as usize, heap_size)
+    }
+    dealloc_mem::<A>(block as usize, heap_size)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::fixvec::*;
+
+    #[test]
+    pub fn checked_and_spilling() {
+        let mut vec = FixedVec::<usize>::new(4);
+        vec[3] = 3;
+        assert_eq!(vec.get(3), Ok(&3));
+        assert_eq!(
+            vec.get_mut(4).map(|_| ()),
+            Err(OutOfRange {
+                index: 4,
+                capacity: 4
+            })
+        );
+        assert_eq!(vec.iter().cloned().collect::<Vec<_>>(), vec![0, 0, 0, 3]);
+        let mut vec = FixedVec::<usize>::spilling(4, 8);
+        assert_eq!(vec.capacity(), 12);
+        assert_eq!(vec.iter().count(), 4);
+        assert!(!vec.is_spilled());
+        vec[10] = 10;
+        assert!(vec.is_spilled());
+        assert_eq!(vec.iter().count(), 12);
+        assert_eq!(vec.iter().sum::<usize>(), 10);
+        assert!(vec.get(12).is_err());
     }
 }
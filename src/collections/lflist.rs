@@ -1,5 +1,8 @@
 // usize lock-free, wait free paged linked list stack
 
+use crate::collections::backoff::{self, Backoff};
+use crate::collections::epoch;
+use crate::collections::epoch::Retired;
 use crate::collections::fixvec::FixedVec;
 use crate::rand::XorRand;
 use crate::utils::*;
@@ -7,7 +10,6 @@ use core::alloc::Alloc;
 use core::ptr;
 use core::{intrinsics, mem};
 use crossbeam::atomic::AtomicCell;
-use crossbeam::utils::Backoff;
 use rand::prelude::*;
 use rand_xoshiro::Xoroshiro64StarStar;
 use std::alloc::Global;
@@ -15,24 +17,43 @@ use std::borrow::{Borrow, BorrowMut};
 use std::cell::{Cell, UnsafeCell};
 use std::cmp::{max, min};
 use std::hint::unreachable_unchecked;
+use std::io::{self, Write};
 use std::intrinsics::size_of;
 use std::marker::PhantomData;
 use std::mem::transmute;
 use std::ops::{Add, Deref};
 use std::ptr::null_mut;
-use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
 use std::sync::atomic::{fence, AtomicPtr, AtomicUsize};
 use std::time::Instant;
 use smallvec::SmallVec;
 
 const EMPTY_SLOT: usize = 0;
 const SENTINEL_SLOT: usize = 1;
+// set on the head position of a buffer being spliced, no slot can be claimed or released in it
+const SPLICING_BUFFER: usize = !(!0 >> 1);
+// set on the head position of buffers of a sealed list, no slot can be claimed in it
+const SEALED_BUFFER: usize = SPLICING_BUFFER >> 1;
+const BUFFER_FLAGS: usize = SPLICING_BUFFER | SEALED_BUFFER;
+// reference count of retired buffers, borrows racing with the retirement stay far from zero
+const DEAD_BUFFER: usize = SEALED_BUFFER >> 1;
 
 const EXCHANGE_EMPTY: usize = 0;
 const EXCHANGE_WAITING: usize = 1;
 const EXCHANGE_BUSY: usize = 2;
 const EXCHANGE_SPIN_WAIT_NS: usize = 150;
 const MAXIMUM_EXCHANGE_SLOTS: usize = 16;
+// slots claimed by one CAS of a buffer head in batch pops, items are kept on stack until then
+const BATCH_POP_SLOTS: usize = 32;
+
+// Words of word lists packing a small tag with a value, 15 and 48 bits on 64-bit targets, 7 and
+// 24 bits on 32-bit targets. The top bit is always set, so packed words never collide with the
+// empty and sentinel flags and values can be 0 or 1
+pub const WORD_VALUE_BITS: usize = mem::size_of::<usize>() * 8 / 4 * 3;
+pub const WORD_TAG_BITS: usize = mem::size_of::<usize>() * 8 - WORD_VALUE_BITS - 1;
+const WORD_VALUE_MASK: usize = (1 << WORD_VALUE_BITS) - 1;
+const WORD_TAG_MASK: usize = (1 << WORD_TAG_BITS) - 1;
+const WORD_PACKED: usize = !(!0 >> 1);
 
 type ExchangeData<T> = Option<(usize, T)>;
 type ExchangeArrayVec<T> = SmallVec<[ExchangeSlot<T>; MAXIMUM_EXCHANGE_SLOTS]>;
@@ -45,59 +66,104 @@ struct BufferMeta<T: Default, A: Alloc + Default> {
     lower_bound: usize,
     tuple_size: usize,
     total_size: usize,
+    retired: Retired,
 }
 
-pub struct ExchangeSlot<T: Default + Copy> {
+pub struct ExchangeSlot<T: Default> {
     state: AtomicUsize,
     data: UnsafeCell<Option<ExchangeData<T>>>,
     data_state: AtomicUsize,
 }
 
-pub struct ExchangeArray<T: Default + Copy, A: Alloc + Default> {
+pub struct ExchangeArray<T: Default, A: Alloc + Default> {
     rand: XorRand,
     shadow: PhantomData<A>,
     capacity: usize,
     slots: ExchangeArrayVec<T>,
 }
 
-pub struct List<T: Default + Copy, A: Alloc + Default = Global> {
+// Ownership of items
+// Items are moved into slots by pushes and moved out only by the thread that claims the slot by
+// swapping its flag to empty, in pop, batch pop and drop out. Exchanges hand items over between
+// threads and splices relink whole buffers, so every pushed item leaves the list exactly once.
+// Items still in the list when it is dropped are dropped with their buffers. Iterators read
+// slots without claiming them, so they are only provided for Copy items. Lists are shared
+// between threads only for Send items
+//
+// Reclamation of buffers
+// Operations pin the epoch for as long as they work on buffers loaded from the list, and
+// buffers whose reference count drops to zero are retired to the epoch collector instead of
+// being deallocated. A thread that loaded a head pointer before the buffer was unlinked can
+// still borrow it, and the address cannot be reused under a CAS of the head, see epoch
+pub struct List<T: Default, A: Alloc + Default = Global> {
     head: AtomicPtr<BufferMeta<T, A>>,
+    // last buffer in the chain, it is never removed by pops
+    tail: AtomicPtr<BufferMeta<T, A>>,
     count: AtomicUsize,
     buffer_cap: usize,
     exchange: ExchangeArray<T, A>,
+    // list taking pushes after this list is sealed
+    fallback: AtomicPtr<List<T, A>>,
 }
 
-pub struct ListIterator<T: Default + Copy, A: Alloc + Default> {
+pub struct ListIterator<T: Default, A: Alloc + Default> {
     buffer: BufferRef<T, A>,
     current: usize,
+    // dropped after the buffer
+    _guard: epoch::Guard,
 }
 
-impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
+impl<T: Default, A: Alloc + Default> List<T, A> {
     pub fn new(buffer_cap: usize) -> Self {
         let first_buffer = BufferMeta::new(buffer_cap);
         Self {
             head: AtomicPtr::new(first_buffer),
+            tail: AtomicPtr::new(first_buffer),
             count: AtomicUsize::new(0),
             exchange: ExchangeArray::new(),
+            fallback: AtomicPtr::new(null_mut()),
             buffer_cap,
         }
     }
 
     pub fn push(&self, flag: usize, data: T) {
-        self.do_push(flag, data);
-        self.count.fetch_add(1, Relaxed);
+        let _guard = epoch::pin();
+        if self.do_push(flag, data) {
+            self.count.fetch_add(1, Relaxed);
+        }
     }
 
-    fn do_push(&self, mut flag: usize, mut data: T) {
+    // Returns false when the item was diverted to the fallback of the sealed list
+    fn do_push(&self, mut flag: usize, mut data: T) -> bool {
         debug_assert_ne!(flag, EMPTY_SLOT);
         debug_assert_ne!(flag, SENTINEL_SLOT);
         let backoff = Backoff::new();
         loop {
             let obj_size = mem::size_of::<T>();
-            let head_ptr = self.head.load(Relaxed);
-            let page = BufferMeta::borrow(head_ptr);
+            let (head_ptr, page) = match self.borrow_head() {
+                Some(head) => head,
+                None => continue,
+            };
             let slot_pos = page.head.load(Relaxed);
             let next_pos = slot_pos + 1;
+            if slot_pos & SPLICING_BUFFER != 0 {
+                // the buffer is being spliced to another list, head will change soon
+                backoff.spin();
+                continue;
+            }
+            if slot_pos & SEALED_BUFFER != 0 {
+                // paired with sealing the buffer in seal
+                fence(Acquire);
+                let fallback = self.fallback.load(Relaxed);
+                if !fallback.is_null() {
+                    unsafe { &*fallback }.push(flag, data);
+                    return false;
+                }
+                // buffer of a sealed list spliced into this one
+                page.head
+                    .compare_and_swap(slot_pos, slot_pos & !SEALED_BUFFER, Relaxed);
+                continue;
+            }
             if next_pos > self.buffer_cap {
                 // buffer overflow, make new and link to last buffer
                 let new_head = BufferMeta::new(self.buffer_cap);
@@ -131,7 +197,7 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                             slot_flag
                         );
                     }
-                    return;
+                    return true;
                 }
             }
             match self.exchange.exchange(Some((flag, data))) {
@@ -142,13 +208,14 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                 }
                 Ok(None) | Err(None) => {
                     // pushed to other popping thread
-                    return;
+                    return true;
                 }
             }
         }
     }
 
     pub fn exclusive_push(&self, flag: usize, data: T) {
+        let _guard = epoch::pin();
         // user ensure the push is exclusive, thus no CAS except for header
         let backoff = Backoff::new();
         let obj_size = mem::size_of::<T>();
@@ -157,6 +224,16 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
             let page = BufferMeta::borrow(head_ptr);
             let slot_pos = page.head.load(Relaxed);
             let next_pos = slot_pos + 1;
+            if slot_pos & SEALED_BUFFER != 0 {
+                fence(Acquire);
+                let fallback = self.fallback.load(Relaxed);
+                if !fallback.is_null() {
+                    unsafe { &*fallback }.push(flag, data);
+                    return;
+                }
+                page.head.store(slot_pos & !SEALED_BUFFER, Relaxed);
+                continue;
+            }
             if next_pos > self.buffer_cap {
                 // buffer overflow, make new and link to last buffer
                 let new_head = BufferMeta::new(self.buffer_cap);
@@ -187,13 +264,21 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
         if self.count.load(Relaxed) == 0 {
             return None;
         }
+        let _guard = epoch::pin();
         let backoff = Backoff::new();
-        let obj_size = mem::size_of::<T>();
         loop {
-            let head_ptr = self.head.load(Relaxed);
-            let page = BufferMeta::borrow(head_ptr);
+            let (head_ptr, page) = match self.borrow_head() {
+                Some(head) => head,
+                None => continue,
+            };
             let slot = page.head.load(Relaxed);
-            let obj_size = mem::size_of::<T>();
+            if slot & SPLICING_BUFFER != 0 {
+                backoff.spin();
+                continue;
+            }
+            // pops on sealed buffers keep them sealed
+            let sealed = slot & SEALED_BUFFER;
+            let slot = slot & !SEALED_BUFFER;
             let next_buffer_ptr = page.next.load(Relaxed);
             if slot == 0 && next_buffer_ptr == null_mut() {
                 // empty buffer chain
@@ -202,6 +287,12 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
             if slot == 0 && next_buffer_ptr != null_mut() {
                 // last item, need to remove this head and swap to the next one
                 // CAS page head to four times of the upper bound indicates this buffer is obsolete
+                if sealed != 0 {
+                    // next buffer becomes the head, keep the list sealed
+                    unsafe { &*next_buffer_ptr }
+                        .head
+                        .fetch_or(SEALED_BUFFER, Release);
+                }
                 if self
                     .head
                     .compare_and_swap(head_ptr, next_buffer_ptr, Relaxed)
@@ -216,8 +307,12 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                     let dropped_next = BufferMeta::drop_out(
                         head_ptr,
                         &mut Some(|(flag, data)| {
-                            if flag != EMPTY_SLOT && flag != SENTINEL_SLOT {
-                                self.do_push(flag, data); // push without bump counter
+                            // push without bump counter, unless diverted to the fallback
+                            if flag != EMPTY_SLOT
+                                && flag != SENTINEL_SLOT
+                                && !self.do_push(flag, data)
+                            {
+                                self.count.fetch_sub(1, Relaxed);
                             }
                         }),
                         &mut 0,
@@ -229,7 +324,6 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                 }
                 continue;
             }
-            let mut res = None;
             if slot > 0 {
                 unsafe {
                     let new_slot = slot - 1;
@@ -239,21 +333,24 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                         // first things first, swap the slot to zero if it is not zero
                         && intrinsics::atomic_cxchg_relaxed(new_slot_ptr, new_slot_flag, EMPTY_SLOT).1
                     {
-                        res = Some((new_slot_flag, T::default()));
-                        if obj_size != 0 && new_slot_flag != SENTINEL_SLOT {
-                            res.as_mut().map(|(_, obj)| unsafe {
-                                let obj_ptr = page.object_ptr_of(new_slot_ptr) as *mut T;
-                                *obj = ptr::read(obj_ptr as *mut T)
-                            });
-                        }
-                        let swapped = page.head.compare_and_swap(slot, new_slot, Relaxed);
+                        // the slot is claimed, this pop owns its item
+                        let res = if new_slot_flag != SENTINEL_SLOT {
+                            Some((new_slot_flag, ptr::read(page.object_ptr_of(new_slot_ptr))))
+                        } else {
+                            None
+                        };
+                        let swapped = page.head.compare_and_swap(
+                            slot | sealed,
+                            new_slot | sealed,
+                            Relaxed,
+                        );
                         debug_assert!(
-                            swapped >= slot,
+                            swapped & !BUFFER_FLAGS >= slot,
                             "Exclusive pop failed, {} expect {}",
                             swapped,
                             slot
                         );
-                        if swapped != slot {
+                        if swapped != slot | sealed {
                             // Swap page head failed
                             // The only possible scenario is that there was a push for
                             // pop will back off if flag is detected as zero
@@ -261,14 +358,14 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                             // this slot does not have any useful information, should pop again
                             intrinsics::atomic_store(new_slot_ptr, SENTINEL_SLOT);
                         }
-                        if new_slot_flag != SENTINEL_SLOT {
+                        if res.is_some() {
                             self.count.fetch_sub(1, Relaxed);
                             return res;
                         }
                     }
                 }
             } else {
-                return res;
+                return None;
             }
             match self.exchange.exchange(None) {
                 Ok(Some(tuple)) | Err(Some(tuple)) => {
@@ -282,6 +379,192 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
             }
         }
     }
+    // Push all items with one CAS of the list head. Items are filled into a private buffer chain
+    // first, which is then linked in front of the list. The first buffer of the chain may be
+    // partially filled and takes later pushes
+    pub fn push_batch<I>(&self, items: I)
+    where
+        I: IntoIterator<Item = (usize, T)>,
+    {
+        let _guard = epoch::pin();
+        let fallback = self.fallback.load(Acquire);
+        if !fallback.is_null() {
+            unsafe { &*fallback }.push_batch(items);
+            return;
+        }
+        let obj_size = mem::size_of::<T>();
+        let mut chain_head: *mut BufferMeta<T, A> = null_mut();
+        let mut chain_tail = null_mut();
+        let mut pushed = 0;
+        for (flag, data) in items {
+            debug_assert_ne!(flag, EMPTY_SLOT);
+            debug_assert_ne!(flag, SENTINEL_SLOT);
+            if chain_head.is_null() || unsafe { &*chain_head }.head.load(Relaxed) == self.buffer_cap
+            {
+                let buffer = BufferMeta::new(self.buffer_cap);
+                unsafe { &*buffer }.next.store(chain_head, Relaxed);
+                if chain_head.is_null() {
+                    chain_tail = buffer;
+                }
+                chain_head = buffer;
+            }
+            // no one else can see the chain yet
+            let buffer = unsafe { &*chain_head };
+            let pos = buffer.head.load(Relaxed);
+            let slot_ptr = buffer.flag_ptr_of(pos);
+            unsafe {
+                if obj_size != 0 {
+                    ptr::write(buffer.object_ptr_of(slot_ptr), data);
+                }
+                intrinsics::atomic_store_relaxed(slot_ptr, flag);
+            }
+            buffer.head.store(pos + 1, Relaxed);
+            pushed += 1;
+        }
+        if pushed > 0 {
+            self.link_chain(chain_head, chain_tail, pushed);
+        }
+    }
+
+    // Link a private buffer chain of `count` items in front of the list. The current head is
+    // borrowed across the CAS, so a concurrent seal waits for it and then seals the new head
+    fn link_chain(
+        &self,
+        chain_head: *mut BufferMeta<T, A>,
+        chain_tail: *mut BufferMeta<T, A>,
+        count: usize,
+    ) {
+        let backoff = Backoff::new();
+        loop {
+            let head_ptr = self.head.load(Relaxed);
+            let page = BufferMeta::borrow(head_ptr);
+            let pos = page.head.load(Relaxed);
+            if pos & SPLICING_BUFFER != 0 {
+                backoff.spin();
+                continue;
+            }
+            if pos & SEALED_BUFFER != 0 {
+                fence(Acquire);
+                let fallback = self.fallback.load(Relaxed);
+                if !fallback.is_null() {
+                    // sealed after the chain was built, move items one by one for the fallback
+                    // may have buffers of another capacity
+                    drop(page);
+                    let fallback = unsafe { &*fallback };
+                    unsafe { &*chain_tail }.next.store(null_mut(), Relaxed);
+                    let mut buffer_ptr = chain_head;
+                    let mut moved = 0;
+                    while !buffer_ptr.is_null() {
+                        buffer_ptr = BufferMeta::drop_out(
+                            buffer_ptr,
+                            &mut Some(|(flag, data)| fallback.push(flag, data)),
+                            &mut moved,
+                        )
+                        .unwrap_or(null_mut());
+                    }
+                    debug_assert_eq!(moved, count);
+                    return;
+                }
+                page.head
+                    .compare_and_swap(pos, pos & !SEALED_BUFFER, Relaxed);
+                continue;
+            }
+            unsafe { &*chain_tail }.next.store(head_ptr, Relaxed);
+            if self.head.compare_and_swap(head_ptr, chain_head, Relaxed) == head_ptr {
+                self.count.fetch_add(count, Relaxed);
+                return;
+            }
+            backoff.spin();
+        }
+    }
+
+    // Pop up to `n` items to the callback and return the number of items popped. Runs of filled
+    // slots on the top of the head buffer are claimed together and released by one CAS of the
+    // buffer head. Items are passed to the callback after the CAS, so it can push them elsewhere
+    pub fn pop_n_with<F>(&self, n: usize, mut f: F) -> usize
+    where
+        F: FnMut(usize, T),
+    {
+        if n == 0 || self.count.load(Relaxed) == 0 {
+            return 0;
+        }
+        let _guard = epoch::pin();
+        let backoff = Backoff::new();
+        let mut popped = 0;
+        while popped < n {
+            let (head_ptr, page) = match self.borrow_head() {
+                Some(head) => head,
+                None => continue,
+            };
+            let pos = page.head.load(Relaxed);
+            if pos & SPLICING_BUFFER != 0 {
+                backoff.spin();
+                continue;
+            }
+            let sealed = pos & SEALED_BUFFER;
+            let slot = pos & !SEALED_BUFFER;
+            if slot == 0 {
+                // empty head buffer, pop swaps to the next one or finds the list empty
+                drop(page);
+                match self.pop() {
+                    Some((flag, data)) => {
+                        f(flag, data);
+                        popped += 1;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            let mut items = SmallVec::<[(usize, T); BATCH_POP_SLOTS]>::new();
+            let mut claimed = 0;
+            // stop at slots still being pushed or taken by other pops
+            while claimed < slot && items.len() < min(n - popped, BATCH_POP_SLOTS) {
+                let slot_ptr = page.flag_ptr_of(slot - claimed - 1);
+                unsafe {
+                    let flag = intrinsics::atomic_load_relaxed(slot_ptr);
+                    if flag == EMPTY_SLOT
+                        || !intrinsics::atomic_cxchg_relaxed(slot_ptr, flag, EMPTY_SLOT).1
+                    {
+                        break;
+                    }
+                    claimed += 1;
+                    if flag != SENTINEL_SLOT {
+                        items.push((flag, ptr::read(page.object_ptr_of(slot_ptr))));
+                    }
+                }
+            }
+            if claimed == 0 {
+                backoff.spin();
+                continue;
+            }
+            let new_slot = slot - claimed;
+            let swapped = page
+                .head
+                .compare_and_swap(slot | sealed, new_slot | sealed, Relaxed);
+            if swapped != slot | sealed {
+                // pushes moved the head, leave holes for pops to skip, same as pop
+                for i in new_slot..slot {
+                    unsafe {
+                        intrinsics::atomic_store(page.flag_ptr_of(i), SENTINEL_SLOT);
+                    }
+                }
+            }
+            drop(page);
+            self.count.fetch_sub(items.len(), Relaxed);
+            popped += items.len();
+            for (flag, data) in items {
+                f(flag, data);
+            }
+        }
+        popped
+    }
+
+    pub fn pop_n(&self, n: usize) -> Vec<(usize, T)> {
+        let mut items = Vec::with_capacity(min(n, self.count()));
+        self.pop_n_with(n, |flag, data| items.push((flag, data)));
+        items
+    }
+
     pub fn drop_out_all<F>(&self, mut retain: Option<F>)
     where
         F: FnMut((usize, T)),
@@ -290,6 +573,7 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
         if count == 0 {
             return;
         }
+        let _guard = epoch::pin();
         let retain = retain.borrow_mut();
         let pop_threshold = min(self.buffer_cap >> 1, 64);
         if count < pop_threshold {
@@ -306,8 +590,9 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
                 }
             }
         }
-        let new_head_buffer = BufferMeta::new(self.buffer_cap);
+        let new_head_buffer = self.new_head_buffer();
         let mut buffer_ptr = self.head.swap(new_head_buffer, Relaxed);
+        self.tail.store(new_head_buffer, Relaxed);
         let null = null_mut();
         let mut counter = 0;
         while buffer_ptr != null {
@@ -316,50 +601,246 @@ impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
         self.count.fetch_sub(counter, Relaxed);
     }
 
+    // Stream all items to the callback. Replacement buffers come from the list allocator, so it
+    // is safe to use inside the allocator as long as the callback does not allocate
+    pub fn drop_out_with<F>(&self, mut f: F)
+    where
+        F: FnMut(usize, T),
+    {
+        self.drop_out_all(Some(|(flag, item)| f(flag, item)));
+    }
+
+    // Move all items to the target by relinking buffers, without visiting items one by one
+    pub fn drop_out_into(&self, target: &Self) {
+        target.prepend_with(self);
+    }
+
+    // Move all items of other to the front of this list. Both lists may have concurrent pushes
+    // and pops during the splice.
+    // 1. Detach the buffer chain of other by swapping in an empty head, new pushes go there
+    // 2. Mark the detached head as splicing so operations that borrowed it before the swap cannot
+    //    claim or release slots anymore, and wait for those in flight to finish. Operations that
+    //    borrow it after the swap find the head of other moved and retry, see borrow_head
+    // 3. Count items in the detached chain while finding its tail. Only these items are moved
+    //    between the counters, other may still be counting pushes finished before the swap
+    // 4. Link the tail to this head and CAS this head to the detached head, which is splicing
+    //    until the moved items are counted in this list
     pub fn prepend_with(&self, other: &Self) {
         if other.count.load(Relaxed) == 0 {
             return;
         }
-        let other_head = other.head.swap(BufferMeta::new(self.buffer_cap), Relaxed);
-        let other_count = other.count.swap(0, Relaxed);
+        let _guard = epoch::pin();
+        let other_new_head = other.new_head_buffer();
+        let other_head = other.head.swap(other_new_head, AcqRel);
+        other.tail.store(other_new_head, Relaxed);
+        // paired with the fence in borrow_head
+        fence(SeqCst);
+        let backoff = Backoff::new();
+        {
+            let head = BufferMeta::borrow(other_head);
+            head.head.fetch_or(SPLICING_BUFFER, Relaxed);
+            // one reference from the list and one from here
+            loop {
+                let refs = head.refs.load(Relaxed);
+                if refs <= 2 {
+                    break;
+                }
+                backoff.wait_on(&head.refs, refs);
+            }
+            // items of a sealed list are welcomed here
+            head.head.fetch_and(!SEALED_BUFFER, Relaxed);
+        }
+        let mut moved = 0;
         let mut other_tail = BufferMeta::borrow(other_head);
-        // probe the last buffer in other link
         loop {
-            while other_tail.refs.load(Relaxed) > 2 {}
+            moved += other_tail.num_items();
             let next_ptr = other_tail.next.load(Relaxed);
             if next_ptr == null_mut() {
                 break;
             }
             other_tail = BufferMeta::borrow(next_ptr);
         }
+        other.count.fetch_sub(moved, Relaxed);
+        loop {
+            let this_head = self.head.load(Acquire);
+            other_tail.next.store(this_head, Release);
+            if self.head.compare_and_swap(this_head, other_head, AcqRel) == this_head {
+                break;
+            }
+            backoff.spin();
+        }
+        self.count.fetch_add(moved, Relaxed);
+        // operations on this list wait on the splicing head until now
+        unsafe { &*other_head }
+            .head
+            .fetch_and(!SPLICING_BUFFER, Release);
+    }
 
-        // CAS this head to other head then reset other tail next buffer to this head
+    // Move all items to dst in O(1) by linking the whole buffer chain in front of dst, such as
+    // migrating the cache of a dead thread to a global pool. This list must not be used by other
+    // threads meanwhile, dst can be
+    pub fn transfer_all(&self, dst: &Self) {
+        let count = self.count.swap(0, Relaxed);
+        if count == 0 {
+            return;
+        }
+        let _guard = epoch::pin();
+        let new_head = self.new_head_buffer();
+        let head_ptr = self.head.swap(new_head, Relaxed);
+        let tail_ptr = self.tail.swap(new_head, Relaxed);
+        // items of a sealed list are welcomed in dst
+        unsafe { &*head_ptr }
+            .head
+            .fetch_and(!SEALED_BUFFER, Relaxed);
+        let backoff = Backoff::new();
         loop {
-            let this_head = self.head.load(Relaxed);
-            if self.head.compare_and_swap(this_head, other_head, Relaxed) != this_head {
-                continue;
-            } else {
-                other_tail.next.store(this_head, Relaxed);
+            let dst_head = dst.head.load(Relaxed);
+            unsafe { &*tail_ptr }.next.store(dst_head, Relaxed);
+            if dst.head.compare_and_swap(dst_head, head_ptr, Relaxed) == dst_head {
                 break;
             }
+            backoff.spin();
+        }
+        dst.count.fetch_add(count, Relaxed);
+    }
+
+    // Reject further pushes to the list, they go to the fallback instead. Items in the list stay
+    // and can be popped or dropped out. After return, no push can land in this list, so teardown
+    // paths can drain it without racing new insertions
+    pub fn seal(&self, fallback: &'static Self) {
+        debug_assert!(!ptr::eq(self, fallback));
+        self.fallback
+            .store(fallback as *const Self as *mut Self, Release);
+        let _guard = epoch::pin();
+        let backoff = Backoff::new();
+        loop {
+            let head_ptr = self.head.load(Relaxed);
+            {
+                let head = BufferMeta::borrow(head_ptr);
+                head.head.fetch_or(SEALED_BUFFER, SeqCst);
+                // wait for pushes claimed slots before sealing, or may install a new head
+                loop {
+                    let refs = head.refs.load(Relaxed);
+                    if refs <= 2 {
+                        break;
+                    }
+                    backoff.wait_on(&head.refs, refs);
+                }
+            }
+            if self.head.load(Relaxed) == head_ptr {
+                return;
+            }
+        }
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        !self.fallback.load(Relaxed).is_null()
+    }
+
+    // Head buffer borrowed, None when the head moved after it was loaded. Buffers only leave the
+    // list by the swap of the head in prepend_with, which waits for borrows before it, so slots
+    // of the borrowed buffer are claimed and released while it is in this list
+    #[inline]
+    fn borrow_head(&self) -> Option<(*mut BufferMeta<T, A>, BufferRef<T, A>)> {
+        let head_ptr = self.head.load(Acquire);
+        let page = BufferMeta::borrow(head_ptr);
+        // paired with the fence in prepend_with
+        fence(SeqCst);
+        if self.head.load(Relaxed) != head_ptr {
+            return None;
         }
-        self.count.fetch_add(other_count, Relaxed);
+        Some((head_ptr, page))
+    }
+
+    fn new_head_buffer(&self) -> *mut BufferMeta<T, A> {
+        let buffer = BufferMeta::new(self.buffer_cap);
+        if self.is_sealed() {
+            unsafe { &*buffer }.head.store(SEALED_BUFFER, Relaxed);
+        }
+        buffer
     }
 
     pub fn count(&self) -> usize {
         self.count.load(Relaxed)
     }
 
+    // Textual summary of the buffer chain for bug reports, one line per buffer from the head with
+    // its address, head position and flags, reference count and filled slots. Every buffer is
+    // borrowed while it is read, so the dump is safe under concurrent operations, but only a
+    // snapshot of a list nobody works on is consistent
+    pub fn debug_dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let _guard = epoch::pin();
+        writeln!(
+            writer,
+            "list count {} buffer capacity {} sealed {}",
+            self.count(),
+            self.buffer_cap,
+            self.is_sealed()
+        )?;
+        let mut buffer = BufferMeta::borrow(self.head.load(Acquire));
+        let mut buffers = 0;
+        let mut items = 0;
+        loop {
+            let pos = buffer.head.load(Relaxed);
+            let filled = buffer.num_items();
+            writeln!(
+                writer,
+                "  buffer {} at {:#x}: head {}{}{} refs {} items {}",
+                buffers,
+                buffer.ptr as usize,
+                pos & !BUFFER_FLAGS,
+                if pos & SPLICING_BUFFER != 0 { " splicing" } else { "" },
+                if pos & SEALED_BUFFER != 0 { " sealed" } else { "" },
+                // without the borrow of the dump
+                buffer.refs.load(Relaxed) - 1,
+                filled
+            )?;
+            buffers += 1;
+            items += filled;
+            let next_ptr = buffer.next.load(Acquire);
+            if next_ptr.is_null() {
+                break;
+            }
+            buffer = BufferMeta::borrow(next_ptr);
+        }
+        writeln!(writer, "  {} buffers, {} items", buffers, items)
+    }
+
+    // Only for the child process after fork, when all other threads are gone.
+    // Release buffer references and exchange slots held by threads that no longer exist, and mark
+    // slots they have claimed but never filled so pop will skip them instead of spinning
+    pub unsafe fn reinit_after_fork(&self) {
+        let mut buffer_ptr = self.head.load(Relaxed);
+        while buffer_ptr != null_mut() {
+            let buffer = &*buffer_ptr;
+            buffer.refs.store(1, Relaxed);
+            // splicing thread is gone
+            let pos = buffer.head.fetch_and(!SPLICING_BUFFER, Relaxed) & !BUFFER_FLAGS;
+            for i in 0..min(pos, self.buffer_cap) {
+                let flag_ptr = buffer.flag_ptr_of(i);
+                if intrinsics::atomic_load_relaxed(flag_ptr) == EMPTY_SLOT {
+                    intrinsics::atomic_store_relaxed(flag_ptr, SENTINEL_SLOT);
+                }
+            }
+            buffer_ptr = buffer.next.load(Relaxed);
+        }
+        self.exchange.reinit_after_fork();
+    }
+}
+
+impl<T: Default + Copy, A: Alloc + Default> List<T, A> {
     pub fn iter(&self) -> ListIterator<T, A> {
+        let guard = epoch::pin();
         let buffer = BufferMeta::borrow(self.head.load(Relaxed));
         ListIterator {
-            current: buffer.head.load(Relaxed),
+            current: buffer.head.load(Relaxed) & !BUFFER_FLAGS,
             buffer,
+            _guard: guard,
         }
     }
 }
 
-impl<T: Default + Copy, A: Alloc + Default> Drop for List<T, A> {
+impl<T: Default, A: Alloc + Default> Drop for List<T, A> {
     fn drop(&mut self) {
         unsafe {
             let mut node_ptr = self.head.load(Relaxed);
@@ -403,6 +884,7 @@ impl<T: Default, A: Alloc + Default> BufferMeta<T, A> {
                     lower_bound: slots_start,
                     tuple_size,
                     total_size,
+                    retired: Retired::new(),
                 },
             );
         }
@@ -410,22 +892,31 @@ impl<T: Default, A: Alloc + Default> BufferMeta<T, A> {
     }
 
     pub fn unref(buffer: *mut Self) {
-        let rc = {
-            let buffer = unsafe { &*buffer };
-            buffer.refs.fetch_sub(1, Relaxed)
-        };
-        if rc == 1 {
+        let buffer_ref = unsafe { &*buffer };
+        let rc = buffer_ref.refs.fetch_sub(1, Relaxed);
+        // splices and drop outs may be parked for the last references
+        backoff::wake(&buffer_ref.refs);
+        // a borrow may come and go after the count reached zero, only one of them retires
+        if rc == 1 && buffer_ref.refs.compare_and_swap(0, DEAD_BUFFER, Relaxed) == 0 {
             Self::gc(buffer);
         }
     }
 
     fn gc(buffer: *mut Self) {
-        let buffer_ref = unsafe { &*buffer };
+        let buffer_ref = unsafe { &mut *buffer };
         let total_size = buffer_ref.total_size;
         if mem::needs_drop::<T>() {
-            Self::flush_buffer(buffer_ref, &mut Some(|x| drop(x)), &mut 0);
+            // items left in buffers of a dropped list, see ownership of items on List
+            Self::flush_buffer(buffer_ref, &mut None::<fn((usize, T))>, &mut 0);
+        }
+        unsafe {
+            epoch::retire(
+                &mut buffer_ref.retired,
+                buffer as usize,
+                total_size,
+                dealloc_mem::<A>,
+            );
         }
-        dealloc_mem::<A>(buffer as usize, total_size)
     }
 
     // only use when the buffer is about to be be dead
@@ -434,8 +925,7 @@ impl<T: Default, A: Alloc + Default> BufferMeta<T, A> {
     where
         F: FnMut((usize, T)),
     {
-        let size_of_obj = mem::size_of::<T>();
-        let data_bound = buffer.head.load(Relaxed);
+        let data_bound = buffer.head.load(Relaxed) & !BUFFER_FLAGS;
         let mut slot_addr = buffer.lower_bound;
         debug_assert!(
             buffer.refs.load(Relaxed) <= 2 || buffer.refs.load(Relaxed) >= 256,
@@ -443,12 +933,14 @@ impl<T: Default, A: Alloc + Default> BufferMeta<T, A> {
         );
         for _ in 0..data_bound {
             unsafe {
-                let slot = intrinsics::atomic_load_relaxed(slot_addr as *const usize);
-                if slot != EMPTY_SLOT && slot != SENTINEL_SLOT {
-                    let mut rest = (slot, T::default());
-                    if size_of_obj > 0 {
-                        rest.1 = ptr::read((slot_addr + mem::size_of::<usize>()) as *const T);
-                    }
+                let slot_ptr = slot_addr as *mut usize;
+                let slot = intrinsics::atomic_load_relaxed(slot_ptr);
+                // claim the slot like pops, a pop racing in late cannot take the item again
+                if slot != EMPTY_SLOT
+                    && slot != SENTINEL_SLOT
+                    && intrinsics::atomic_cxchg_relaxed(slot_ptr, slot, EMPTY_SLOT).1
+                {
+                    let rest = (slot, ptr::read(buffer.object_ptr_of(slot_ptr)));
                     if let Some(retain) = retain {
                         retain(rest);
                     }
@@ -505,10 +997,22 @@ impl<T: Default, A: Alloc + Default> BufferMeta<T, A> {
                 BufferMeta::unref(buffer_ptr);
                 return Some(next_ptr);
             }
-            backoff.spin();
+            backoff.wait_on(&buffer.refs, rc | flag);
         }
     }
 
+    // Filled slots below head, only exact when no one is working on the buffer
+    fn num_items(&self) -> usize {
+        let mut items = 0;
+        for i in 0..self.head.load(Relaxed) & !BUFFER_FLAGS {
+            let flag = unsafe { intrinsics::atomic_load_relaxed(self.flag_ptr_of(i)) };
+            if flag != EMPTY_SLOT && flag != SENTINEL_SLOT {
+                items += 1;
+            }
+        }
+        items
+    }
+
     fn borrow(buffer: *mut Self) -> BufferRef<T, A> {
         {
             let buffer = unsafe { &*buffer };
@@ -544,7 +1048,7 @@ impl<T: Default, A: Alloc + Default> Deref for BufferRef<T, A> {
     }
 }
 
-impl<T: Default + Clone + Copy, A: Alloc + Default> Iterator for ListIterator<T, A> {
+impl<T: Default, A: Alloc + Default> Iterator for ListIterator<T, A> {
     type Item = (usize, T);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -555,25 +1059,36 @@ impl<T: Default + Clone + Copy, A: Alloc + Default> Iterator for ListIterator<T,
                     return None;
                 } else {
                     self.buffer = BufferMeta::borrow(next_buffer_ptr);
-                    self.current = self.buffer.head.load(Relaxed);
+                    self.current = self.buffer.head.load(Relaxed) & !BUFFER_FLAGS;
                     continue;
                 }
             }
             let current_flag_ptr = self.buffer.flag_ptr_of(self.current - 1);
             unsafe {
-                let mut result = (*current_flag_ptr, T::default());
-                if mem::size_of::<T>() > 0 {
-                    result.1 = (*self.buffer.object_ptr_of(current_flag_ptr)).clone()
-                }
+                let flag = *current_flag_ptr;
                 self.current -= 1;
-                if result.0 != EMPTY_SLOT && result.0 != SENTINEL_SLOT {
-                    return Some(result);
+                if flag != EMPTY_SLOT && flag != SENTINEL_SLOT {
+                    return Some((flag, *self.buffer.object_ptr_of(current_flag_ptr)));
                 }
             };
         }
     }
 }
 
+#[inline]
+pub fn encode_word(tag: usize, value: usize) -> usize {
+    debug_assert!(tag <= WORD_TAG_MASK, "tag {} out of range", tag);
+    debug_assert!(value <= WORD_VALUE_MASK, "value {:x} out of range", value);
+    WORD_PACKED | (tag << WORD_VALUE_BITS) | value
+}
+
+// Tag and value of a word from encode_word
+#[inline]
+pub fn decode_word(word: usize) -> (usize, usize) {
+    debug_assert_ne!(word & WORD_PACKED, 0);
+    ((word >> WORD_VALUE_BITS) & WORD_TAG_MASK, word & WORD_VALUE_MASK)
+}
+
 pub struct WordList<A: Alloc + Default = Global> {
     inner: List<(), A>,
 }
@@ -600,6 +1115,34 @@ impl<A: Alloc + Default> WordList<A> {
     pub fn pop(&self) -> Option<usize> {
         self.inner.pop().map(|(data, _)| data)
     }
+    // Push and pop tagged values, see encode_word. Lists shall not mix them with plain words
+    pub fn push_tagged(&self, tag: usize, value: usize) {
+        self.inner.push(encode_word(tag, value), ())
+    }
+    pub fn pop_tagged(&self) -> Option<(usize, usize)> {
+        self.inner.pop().map(|(data, _)| decode_word(data))
+    }
+    pub fn push_batch<I>(&self, items: I)
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        self.inner.push_batch(items.into_iter().map(|data| {
+            debug_assert_ne!(data, 0);
+            debug_assert_ne!(data, 1);
+            (data, ())
+        }))
+    }
+    pub fn pop_n_with<F>(&self, n: usize, mut f: F) -> usize
+    where
+        F: FnMut(usize),
+    {
+        self.inner.pop_n_with(n, |data, _| f(data))
+    }
+    pub fn pop_n(&self, n: usize) -> Vec<usize> {
+        let mut items = Vec::with_capacity(min(n, self.count()));
+        self.pop_n_with(n, |data| items.push(data));
+        items
+    }
 
     pub fn drop_out_all<F>(&self, retain: Option<F>)
     where
@@ -607,22 +1150,46 @@ impl<A: Alloc + Default> WordList<A> {
     {
         self.inner.drop_out_all(retain);
     }
+    pub fn drop_out_with<F>(&self, mut f: F)
+    where
+        F: FnMut(usize),
+    {
+        self.inner.drop_out_with(|data, _| f(data));
+    }
+    pub fn drop_out_into(&self, target: &Self) {
+        self.inner.drop_out_into(&target.inner)
+    }
     pub fn prepend_with(&self, other: &Self) {
         self.inner.prepend_with(&other.inner)
     }
+    pub fn transfer_all(&self, dst: &Self) {
+        self.inner.transfer_all(&dst.inner)
+    }
+    pub fn seal(&self, fallback: &'static Self) {
+        self.inner.seal(&fallback.inner)
+    }
+    pub fn is_sealed(&self) -> bool {
+        self.inner.is_sealed()
+    }
     pub fn count(&self) -> usize {
         self.inner.count()
     }
+    pub fn debug_dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.inner.debug_dump(writer)
+    }
     pub fn iter(&self) -> ListIterator<(), A> {
         self.inner.iter()
     }
+    pub unsafe fn reinit_after_fork(&self) {
+        self.inner.reinit_after_fork()
+    }
 }
 
-pub struct ObjectList<T: Default + Copy, A: Alloc + Default = Global> {
+pub struct ObjectList<T: Default, A: Alloc + Default = Global> {
     inner: List<T, A>,
 }
 
-impl<T: Default + Copy, A: Alloc + Default> ObjectList<T, A> {
+impl<T: Default, A: Alloc + Default> ObjectList<T, A> {
     pub fn with_capacity(cap: usize) -> Self {
         Self {
             inner: List::new(cap),
@@ -640,6 +1207,23 @@ impl<T: Default + Copy, A: Alloc + Default> ObjectList<T, A> {
     pub fn pop(&self) -> Option<T> {
         self.inner.pop().map(|(_, obj)| obj)
     }
+    pub fn push_batch<I>(&self, items: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.inner.push_batch(items.into_iter().map(|data| (!0, data)))
+    }
+    pub fn pop_n_with<F>(&self, n: usize, mut f: F) -> usize
+    where
+        F: FnMut(T),
+    {
+        self.inner.pop_n_with(n, |_, obj| f(obj))
+    }
+    pub fn pop_n(&self, n: usize) -> Vec<T> {
+        let mut items = Vec::with_capacity(min(n, self.count()));
+        self.pop_n_with(n, |obj| items.push(obj));
+        items
+    }
 
     pub fn drop_out_all<F>(&self, retain: Option<F>)
     where
@@ -647,19 +1231,46 @@ impl<T: Default + Copy, A: Alloc + Default> ObjectList<T, A> {
     {
         self.inner.drop_out_all(retain)
     }
+    pub fn drop_out_with<F>(&self, mut f: F)
+    where
+        F: FnMut(T),
+    {
+        self.inner.drop_out_with(|_, obj| f(obj));
+    }
+    pub fn drop_out_into(&self, target: &Self) {
+        self.inner.drop_out_into(&target.inner)
+    }
 
     pub fn prepend_with(&self, other: &Self) {
         self.inner.prepend_with(&other.inner)
     }
+    pub fn transfer_all(&self, dst: &Self) {
+        self.inner.transfer_all(&dst.inner)
+    }
+    pub fn seal(&self, fallback: &'static Self) {
+        self.inner.seal(&fallback.inner)
+    }
+    pub fn is_sealed(&self) -> bool {
+        self.inner.is_sealed()
+    }
     pub fn count(&self) -> usize {
         self.inner.count()
     }
+    pub fn debug_dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.inner.debug_dump(writer)
+    }
+    pub unsafe fn reinit_after_fork(&self) {
+        self.inner.reinit_after_fork()
+    }
+}
+
+impl<T: Default + Copy, A: Alloc + Default> ObjectList<T, A> {
     pub fn iter(&self) -> ListIterator<T, A> {
         self.inner.iter()
     }
 }
 
-impl<T: Default + Copy> ExchangeSlot<T> {
+impl<T: Default> ExchangeSlot<T> {
     fn new() -> Self {
         Self {
             state: AtomicUsize::new(EXCHANGE_EMPTY),
@@ -762,6 +1373,12 @@ impl<T: Default + Copy> ExchangeSlot<T> {
         self.data_state.store(self.state.load(Relaxed), Relaxed);
     }
 
+    unsafe fn reinit_after_fork(&self) {
+        ptr::write(self.data.get(), None);
+        self.state.store(EXCHANGE_EMPTY, Relaxed);
+        self.data_state.store(EXCHANGE_EMPTY, Relaxed);
+    }
+
     fn wait_state_data_until(&self, expecting: usize, backoff: &Backoff) {
         while self.data_state.load(Relaxed) != expecting {
             backoff.spin();
@@ -780,10 +1397,10 @@ impl<T: Default + Copy> ExchangeSlot<T> {
     }
 }
 
-unsafe impl<T: Default + Copy> Sync for ExchangeSlot<T> {}
-unsafe impl<T: Default + Copy> Send for ExchangeSlot<T> {}
+unsafe impl<T: Default + Send> Sync for ExchangeSlot<T> {}
+unsafe impl<T: Default + Send> Send for ExchangeSlot<T> {}
 
-impl<T: Default + Copy, A: Alloc + Default> ExchangeArray<T, A> {
+impl<T: Default, A: Alloc + Default> ExchangeArray<T, A> {
     pub fn new() -> Self {
         let num_cpus = *NUM_CPU;
         let default_capacity = num_cpus >> 3;
@@ -812,20 +1429,32 @@ impl<T: Default + Copy, A: Alloc + Default> ExchangeArray<T, A> {
     pub fn worth_exchange(&self, rc: usize) -> bool {
         rc >= self.slots.capacity()
     }
+
+    unsafe fn reinit_after_fork(&self) {
+        for slot in self.slots.iter() {
+            slot.reinit_after_fork();
+        }
+    }
 }
 
-unsafe impl<T: Default + Copy, A: Alloc + Default> Send for ExchangeArray<T, A> {}
-unsafe impl<T: Default + Copy, A: Alloc + Default> Sync for ExchangeArray<T, A> {}
+unsafe impl<T: Default + Send, A: Alloc + Default> Send for ExchangeArray<T, A> {}
+unsafe impl<T: Default + Send, A: Alloc + Default> Sync for ExchangeArray<T, A> {}
 
 #[cfg(test)]
 mod test {
+    use crate::collections::epoch;
     use crate::collections::lflist::*;
     use crate::utils::SYS_PAGE_SIZE;
-    use std::alloc::Global;
-    use std::collections::BTreeSet;
+    use rand::{thread_rng, Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use std::alloc::{AllocErr, Global, Layout};
+    use std::collections::{BTreeSet, HashSet};
+    use std::ptr::NonNull;
+    use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::Relaxed;
     use std::sync::{Arc, Mutex};
     use std::thread;
+    use std::time::Instant;
 
     #[test]
     pub fn general() {
@@ -854,6 +1483,374 @@ mod test {
         assert_eq!(list.count(), 0);
     }
 
+    #[test]
+    pub fn drop_out_streaming() {
+        let list = ObjectList::<usize, Global>::with_capacity(16);
+        for i in 0..100 {
+            list.push(i);
+        }
+        let mut sum = 0;
+        let mut count = 0;
+        list.drop_out_with(|obj| {
+            sum += obj;
+            count += 1;
+        });
+        assert_eq!(count, 100);
+        assert_eq!(sum, (0..100).sum());
+        assert_eq!(list.count(), 0);
+        assert_eq!(list.pop(), None);
+
+        let source = WordList::<Global>::with_capacity(16);
+        let target = WordList::<Global>::with_capacity(16);
+        for i in 2..102 {
+            source.push(i);
+        }
+        target.push(500);
+        source.drop_out_into(&target);
+        assert_eq!(source.count(), 0);
+        assert_eq!(source.pop(), None);
+        assert_eq!(target.count(), 101);
+        let mut items = vec![];
+        while let Some(item) = target.pop() {
+            items.push(item);
+        }
+        items.sort();
+        assert_eq!(items, (2..102).chain(500..501).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn prepend_with_concurrent_producers() {
+        let per_thread = 10000;
+        let num_producers = 4;
+        let a = Arc::new(WordList::<Global>::with_capacity(32));
+        let b = Arc::new(WordList::<Global>::with_capacity(32));
+        let done = Arc::new(AtomicUsize::new(0));
+        let producers = (0..num_producers)
+            .map(|t| {
+                let list = if t % 2 == 0 { a.clone() } else { b.clone() };
+                let done = done.clone();
+                thread::spawn(move || {
+                    let from = 2 + t * per_thread;
+                    for i in from..from + per_thread {
+                        list.push(i);
+                    }
+                    done.fetch_add(1, Relaxed);
+                })
+            })
+            .collect::<Vec<_>>();
+        let consumer = {
+            let b = b.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut popped = vec![];
+                while done.load(Relaxed) < num_producers {
+                    if let Some(item) = b.pop() {
+                        popped.push(item);
+                    }
+                }
+                popped
+            })
+        };
+        while done.load(Relaxed) < num_producers {
+            a.prepend_with(&b);
+        }
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut items = consumer.join().unwrap();
+        a.prepend_with(&b);
+        assert_eq!(b.count(), 0);
+        assert_eq!(b.pop(), None);
+        let popped = items.len();
+        assert_eq!(a.count(), num_producers * per_thread - popped);
+        while let Some(item) = a.pop() {
+            items.push(item);
+        }
+        assert_eq!(a.count(), 0);
+        items.sort();
+        assert_eq!(
+            items,
+            (2..2 + num_producers * per_thread).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn prepend_with_counts_moved_items() {
+        let per_thread = 10000;
+        let num_producers = 4;
+        let a = Arc::new(WordList::<Global>::with_capacity(32));
+        let b = Arc::new(WordList::<Global>::with_capacity(32));
+        let done = Arc::new(AtomicUsize::new(0));
+        let producers = (0..num_producers)
+            .map(|t| {
+                let b = b.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let from = 2 + t * per_thread;
+                    for i in from..from + per_thread {
+                        b.push(i);
+                    }
+                    done.fetch_add(1, Relaxed);
+                })
+            })
+            .collect::<Vec<_>>();
+        // pushes racing with the splices land on either side, never on both or neither counter
+        while done.load(Relaxed) < num_producers {
+            a.prepend_with(&b);
+        }
+        for t in producers {
+            t.join().unwrap();
+        }
+        let counted = a.count() + b.count();
+        let mut items = vec![];
+        while let Some(item) = a.pop() {
+            items.push(item);
+        }
+        while let Some(item) = b.pop() {
+            items.push(item);
+        }
+        assert_eq!(counted, items.len());
+        assert_eq!(items.len(), num_producers * per_thread);
+        assert_eq!(a.count() + b.count(), 0);
+    }
+
+    #[test]
+    pub fn exactly_once() {
+        // small buffers to go through buffer swapping, drop out and re-pushing paths
+        let list = Arc::new(ObjectList::<usize, Global>::with_capacity(4));
+        let num_items = 20000;
+        let num_threads = 4;
+        let delivered = Arc::new((0..num_items).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let threads = (0..num_threads)
+            .map(|t| {
+                let list = list.clone();
+                let delivered = delivered.clone();
+                thread::spawn(move || {
+                    let per_thread = num_items / num_threads;
+                    for i in t * per_thread..(t + 1) * per_thread {
+                        list.push(i);
+                        if i % 3 == 0 {
+                            if let Some(item) = list.pop() {
+                                delivered[item].fetch_add(1, Relaxed);
+                            }
+                        }
+                        if i % 1000 == 0 {
+                            list.drop_out_with(|item| {
+                                delivered[item].fetch_add(1, Relaxed);
+                            });
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        while let Some(item) = list.pop() {
+            delivered[item].fetch_add(1, Relaxed);
+        }
+        assert_eq!(list.count(), 0);
+        for (item, times) in delivered.iter().enumerate() {
+            assert_eq!(times.load(Relaxed), 1, "item {}", item);
+        }
+    }
+
+    #[test]
+    pub fn buffers_reclaimed() {
+        // buffers dropped out and swapped under concurrent readers are only freed after them
+        let list = Arc::new(ObjectList::<usize, Global>::with_capacity(2));
+        let threads = (0..4)
+            .map(|_| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..5000 {
+                        list.push(i);
+                        if i % 2 == 0 {
+                            list.pop();
+                        }
+                        if i % 100 == 0 {
+                            list.drop_out_with(|_| {});
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        while list.pop().is_some() {}
+        // other tests may hold pins for a while
+        while epoch::pending() != 0 {
+            epoch::try_advance();
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    pub fn transfer_all() {
+        let src = ObjectList::<usize, Global>::with_capacity(8);
+        let dst = ObjectList::<usize, Global>::with_capacity(8);
+        for i in 0..100 {
+            src.push(i);
+        }
+        dst.push(1000);
+        src.transfer_all(&dst);
+        assert_eq!(src.count(), 0);
+        assert_eq!(src.pop(), None);
+        assert_eq!(dst.count(), 101);
+        // source is still usable
+        src.push(2000);
+        src.transfer_all(&dst);
+        let mut items = vec![];
+        while let Some(item) = dst.pop() {
+            items.push(item);
+        }
+        assert_eq!(dst.count(), 0);
+        items.sort();
+        assert_eq!(items, (0..100).chain(vec![1000, 2000]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn debug_dump() {
+        let list = WordList::<Global>::with_capacity(4);
+        for i in 2..12 {
+            list.push(i);
+        }
+        list.pop();
+        let mut dump = vec![];
+        list.debug_dump(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "list count 9 buffer capacity 4 sealed false");
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].ends_with("head 1 refs 1 items 1"), "{}", dump);
+        assert!(lines[3].ends_with("head 4 refs 1 items 4"), "{}", dump);
+        assert_eq!(lines[4], "  3 buffers, 9 items");
+    }
+
+    #[test]
+    pub fn tagged_words() {
+        let max_tag = (1 << WORD_TAG_BITS) - 1;
+        let max_value = (1 << WORD_VALUE_BITS) - 1;
+        for &(tag, value) in &[(0, 0), (0, 1), (3, 0), (max_tag, max_value), (1, 0xdead_beef)] {
+            let word = encode_word(tag, value);
+            assert!(word > 1);
+            assert_eq!(decode_word(word), (tag, value));
+        }
+        let list = WordList::<Global>::with_capacity(4);
+        for i in 0..10 {
+            list.push_tagged(i % 3, i);
+        }
+        let mut items = vec![];
+        while let Some(item) = list.pop_tagged() {
+            items.push(item);
+        }
+        items.sort_by_key(|&(_, value)| value);
+        assert_eq!(items, (0..10).map(|i| (i % 3, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn batch() {
+        let list = WordList::<Global>::with_capacity(16);
+        list.push(2);
+        list.push_batch(10..110);
+        assert_eq!(list.count(), 101);
+        let popped = list.pop_n(40);
+        assert_eq!(popped.len(), 40);
+        assert_eq!(list.count(), 61);
+        list.push(3);
+        let mut rest = list.pop_n(1000);
+        assert_eq!(list.count(), 0);
+        assert_eq!(list.pop_n(10), vec![]);
+        rest.extend(popped);
+        rest.sort();
+        let expected = vec![2, 3].into_iter().chain(10..110).collect::<Vec<_>>();
+        assert_eq!(rest, expected);
+        // sealed lists divert batches to the fallback
+        let fallback: &'static WordList<Global> = Box::leak(Box::new(WordList::with_capacity(8)));
+        list.seal(fallback);
+        list.push_batch(10..30);
+        assert_eq!(list.count(), 0);
+        assert_eq!(fallback.count(), 20);
+    }
+
+    #[test]
+    pub fn batch_exactly_once() {
+        let list = Arc::new(ObjectList::<usize, Global>::with_capacity(32));
+        let num_items = 40000;
+        let num_threads = 4;
+        let delivered = Arc::new((0..num_items).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let threads = (0..num_threads)
+            .map(|t| {
+                let list = list.clone();
+                let delivered = delivered.clone();
+                thread::spawn(move || {
+                    let per_thread = num_items / num_threads;
+                    let start = t * per_thread;
+                    for batch in (start..start + per_thread).step_by(50) {
+                        if batch % 100 == 0 {
+                            list.push_batch(batch..batch + 50);
+                        } else {
+                            for i in batch..batch + 50 {
+                                list.push(i);
+                            }
+                        }
+                        list.pop_n_with(30, |item| {
+                            delivered[item].fetch_add(1, Relaxed);
+                        });
+                        if let Some(item) = list.pop() {
+                            delivered[item].fetch_add(1, Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        list.pop_n_with(num_items, |item| {
+            delivered[item].fetch_add(1, Relaxed);
+        });
+        assert_eq!(list.count(), 0);
+        for (item, times) in delivered.iter().enumerate() {
+            assert_eq!(times.load(Relaxed), 1, "item {}", item);
+        }
+    }
+
+    #[test]
+    pub fn seal() {
+        let fallback: &'static WordList<Global> = Box::leak(Box::new(WordList::with_capacity(16)));
+        let list = Arc::new(WordList::<Global>::with_capacity(16));
+        for i in 2..50 {
+            list.push(i);
+        }
+        let pushers = (0..4)
+            .map(|t| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        list.push(1000 + t * 1000 + i);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        list.seal(fallback);
+        assert!(list.is_sealed());
+        let mut in_list = 0;
+        while list.pop().is_some() {
+            in_list += 1;
+        }
+        for t in pushers {
+            t.join().unwrap();
+        }
+        // nothing lands in the list after sealed
+        assert_eq!(list.pop(), None);
+        list.push(10000);
+        assert_eq!(list.count(), 0);
+        assert_eq!(list.pop(), None);
+        assert_eq!(in_list + fallback.count(), 48 + 4000 + 1);
+    }
+
     #[test]
     pub fn parallel_insertion() {}
 
@@ -972,4 +1969,318 @@ mod test {
             );
         }
     }
+
+    // Elements seal their ids, garbage read from a slot breaks the seal, and an element handed
+    // out twice or never pushed is caught by the ids outstanding
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct Tracked {
+        id: u64,
+        seal: u64,
+    }
+
+    impl Tracked {
+        fn new(id: u64) -> Self {
+            Self {
+                id,
+                seal: !id.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            }
+        }
+
+        fn check(self, outstanding: &mut HashSet<u64>) -> u64 {
+            assert_eq!(self, Tracked::new(self.id), "corrupted element");
+            assert!(outstanding.remove(&self.id), "element {} aliased", self.id);
+            self.id
+        }
+    }
+
+    // buffers held by lists of CountingAlloc, to check that dropped lists leak none
+    static LIVE_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct CountingAlloc;
+
+    unsafe impl Alloc for CountingAlloc {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+            let ptr = Global.alloc(layout)?;
+            LIVE_BUFFERS.fetch_add(1, Relaxed);
+            Ok(ptr)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            LIVE_BUFFERS.fetch_sub(1, Relaxed);
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    // Reference of a list, a stack of groups of ids. Batches are pushed as groups with their
+    // order left open, pops take from the top group in any order
+    #[derive(Default)]
+    struct Model {
+        groups: Vec<Vec<u64>>,
+    }
+
+    impl Model {
+        fn len(&self) -> usize {
+            self.groups.iter().map(|group| group.len()).sum()
+        }
+
+        fn push(&mut self, group: Vec<u64>) {
+            if !group.is_empty() {
+                self.groups.push(group);
+            }
+        }
+
+        fn prepend(&mut self, other: &mut Model) {
+            self.groups.append(&mut other.groups);
+        }
+
+        // Items popped together must be the top ones of the stack
+        fn take(&mut self, ids: &[u64]) {
+            let mut bottom = self.groups.len();
+            let mut covered = 0;
+            while covered < ids.len() {
+                bottom -= 1;
+                covered += self.groups[bottom].len();
+            }
+            for id in ids {
+                let group = self.groups[bottom..]
+                    .iter_mut()
+                    .find(|group| group.contains(id))
+                    .expect("popped below the top");
+                group.retain(|item| item != id);
+            }
+            let above = self.groups.len().min(bottom + 1);
+            assert!(self.groups[above..].iter().all(|group| group.is_empty()), "out of order");
+            self.groups.retain(|group| !group.is_empty());
+        }
+
+        fn clear(&mut self) -> Vec<u64> {
+            let mut ids = self.groups.drain(..).flatten().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        }
+    }
+
+    fn run_sequence(seed: u64, steps: usize) {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let main = ObjectList::<Tracked, CountingAlloc>::with_capacity(rng.gen_range(2, 64));
+        let side = ObjectList::<Tracked, CountingAlloc>::with_capacity(rng.gen_range(2, 64));
+        let (mut main_model, mut side_model) = (Model::default(), Model::default());
+        let mut outstanding = HashSet::new();
+        let mut next_id = 0;
+        let mut fresh = |outstanding: &mut HashSet<u64>| {
+            next_id += 1;
+            outstanding.insert(next_id);
+            Tracked::new(next_id)
+        };
+        for step in 0..steps {
+            match rng.gen_range(0, 9) {
+                0 | 1 => {
+                    let item = fresh(&mut outstanding);
+                    main.push(item);
+                    main_model.push(vec![item.id]);
+                }
+                2 => {
+                    let item = fresh(&mut outstanding);
+                    side.push(item);
+                    side_model.push(vec![item.id]);
+                }
+                3 => {
+                    let items = (0..rng.gen_range(1, 100))
+                        .map(|_| fresh(&mut outstanding))
+                        .collect::<Vec<_>>();
+                    main.push_batch(items.iter().cloned());
+                    main_model.push(items.iter().map(|item| item.id).collect());
+                }
+                4 => match main.pop() {
+                    Some(item) => main_model.take(&[item.check(&mut outstanding)]),
+                    None => assert_eq!(main_model.len(), 0, "seed {} step {}", seed, step),
+                },
+                5 => {
+                    let n = rng.gen_range(0, 80);
+                    let popped = main.pop_n(n);
+                    assert_eq!(popped.len(), n.min(main_model.len()), "seed {}", seed);
+                    let ids = popped
+                        .into_iter()
+                        .map(|item| item.check(&mut outstanding))
+                        .collect::<Vec<_>>();
+                    main_model.take(&ids);
+                }
+                6 => {
+                    let mut dropped = vec![];
+                    if rng.gen() {
+                        main.drop_out_all(Some(|(_, item)| dropped.push(item)));
+                    } else {
+                        main.drop_out_with(|item| dropped.push(item));
+                    }
+                    let mut ids = dropped
+                        .into_iter()
+                        .map(|item| item.check(&mut outstanding))
+                        .collect::<Vec<_>>();
+                    ids.sort();
+                    assert_eq!(ids, main_model.clear(), "seed {} step {}", seed, step);
+                }
+                7 => {
+                    main.prepend_with(&side);
+                    main_model.prepend(&mut side_model);
+                }
+                _ => {
+                    side.transfer_all(&main);
+                    main_model.prepend(&mut side_model);
+                }
+            }
+            assert_eq!(main.count(), main_model.len(), "seed {} step {}", seed, step);
+            assert_eq!(side.count(), side_model.len(), "seed {} step {}", seed, step);
+        }
+        while let Some(item) = main.pop() {
+            main_model.take(&[item.check(&mut outstanding)]);
+        }
+        // dropped without looking at them
+        side.drop_out_all::<fn((usize, Tracked))>(None);
+        for id in side_model.clear() {
+            outstanding.remove(&id);
+        }
+        assert_eq!(side.count(), 0);
+        assert!(outstanding.is_empty(), "seed {}: {} elements lost", seed, outstanding.len());
+    }
+
+    #[test]
+    pub fn random_sequences_against_model() {
+        let base = thread_rng().gen::<u64>();
+        for seed in base..base + 64 {
+            run_sequence(seed, 1000);
+        }
+        // dropped lists retire their buffers to the epoch collector
+        let start = Instant::now();
+        while LIVE_BUFFERS.load(Relaxed) != 0 {
+            assert!(start.elapsed().as_secs() < 60, "buffers leaked, base seed {}", base);
+            epoch::try_advance();
+            thread::yield_now();
+        }
+    }
+
+    // Items with a destructor counting drops of their id, each must be dropped exactly once
+    // whatever path takes it out of the list
+    #[derive(Default)]
+    struct Counted {
+        id: usize,
+        drops: Option<Arc<Vec<AtomicUsize>>>,
+    }
+
+    impl Counted {
+        fn new(id: usize, drops: &Arc<Vec<AtomicUsize>>) -> Self {
+            Self {
+                id,
+                drops: Some(drops.clone()),
+            }
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            if let Some(drops) = &self.drops {
+                drops[self.id].fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    fn drop_counters(num_items: usize) -> Arc<Vec<AtomicUsize>> {
+        Arc::new((0..num_items).map(|_| AtomicUsize::new(0)).collect())
+    }
+
+    fn total_drops(drops: &[AtomicUsize]) -> usize {
+        drops.iter().map(|times| times.load(Relaxed)).sum()
+    }
+
+    fn assert_dropped_once(drops: &[AtomicUsize]) {
+        for (id, times) in drops.iter().enumerate() {
+            assert_eq!(times.load(Relaxed), 1, "item {}", id);
+        }
+    }
+
+    #[test]
+    pub fn drops_exactly_once() {
+        let num_items = 2000;
+        let drops = drop_counters(num_items);
+        {
+            let list = ObjectList::<Counted, Global>::with_capacity(4);
+            let other = ObjectList::<Counted, Global>::with_capacity(4);
+            let mut ids = 0..num_items;
+            for id in ids.by_ref().take(300) {
+                list.push(Counted::new(id, &drops));
+            }
+            for id in ids.by_ref().take(100) {
+                list.exclusive_push(Counted::new(id, &drops));
+            }
+            list.push_batch(ids.by_ref().take(200).map(|id| Counted::new(id, &drops)));
+            // popped items are dropped by the caller
+            for _ in 0..50 {
+                assert!(list.pop().is_some());
+            }
+            assert_eq!(list.pop_n(70).len(), 70);
+            assert_eq!(list.pop_n_with(30, drop), 30);
+            assert_eq!(total_drops(&drops), 150);
+            for id in ids.by_ref().take(400) {
+                other.push(Counted::new(id, &drops));
+            }
+            other.drop_out_with(drop);
+            assert_eq!(total_drops(&drops), 550);
+            for id in ids.by_ref().take(300) {
+                other.push(Counted::new(id, &drops));
+            }
+            // splices move items without dropping them
+            list.prepend_with(&other);
+            list.transfer_all(&other);
+            assert_eq!(other.count(), 750);
+            assert_eq!(total_drops(&drops), 550);
+            for id in ids.by_ref().take(300) {
+                list.push(Counted::new(id, &drops));
+            }
+            other.drop_out_all::<fn((usize, Counted))>(None);
+            assert_eq!(total_drops(&drops), 1300);
+            for id in ids {
+                other.push(Counted::new(id, &drops));
+            }
+            assert_eq!(list.count() + other.count(), 700);
+        }
+        // items left in the lists are dropped with them
+        assert_dropped_once(&drops);
+    }
+
+    #[test]
+    pub fn concurrent_drops_exactly_once() {
+        // small buffers to go through buffer swapping, drop out and re-pushing paths
+        let num_items = 20000;
+        let num_threads = 4;
+        let drops = drop_counters(num_items);
+        let list = Arc::new(ObjectList::<Counted, Global>::with_capacity(4));
+        let threads = (0..num_threads)
+            .map(|t| {
+                let list = list.clone();
+                let drops = drops.clone();
+                thread::spawn(move || {
+                    let per_thread = num_items / num_threads;
+                    for i in t * per_thread..(t + 1) * per_thread {
+                        list.push(Counted::new(i, &drops));
+                        if i % 3 == 0 {
+                            list.pop();
+                        }
+                        if i % 7 == 0 {
+                            list.pop_n(3);
+                        }
+                        if i % 1000 == 0 {
+                            list.drop_out_with(drop);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let left = list.count();
+        assert_eq!(total_drops(&drops), num_items - left);
+        drop(list);
+        assert_dropped_once(&drops);
+    }
 }
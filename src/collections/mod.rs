@@ -1,5 +1,9 @@
 // a set of lock-free, wait free data structures
 
+pub mod backoff;
+pub mod epoch;
 pub mod evmap;
 pub mod fixvec;
 pub mod lflist;
+pub mod pagemap;
+pub mod seqlock;
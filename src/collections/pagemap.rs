@@ -0,0 +1,347 @@
+// Lock-free radix tree from page numbers to words
+// Nodes are arrays of 512 words. Interior nodes hold addresses of children and leaves hold the
+// values, 0 for absent. The tree starts with a single leaf and grows on demand without stopping
+// lookups:
+//   - Missing nodes on the path of an insertion are allocated zeroed and installed by a CAS of
+//     the empty slot, the loser of a race deallocates its node and follows the winner
+//   - Keys beyond the range of the tree add a level on top. The new root takes the old root as
+//     its first child and is installed by a CAS of the root word, which packs the root address
+//     with the height so lookups always see a consistent pair
+// Keys already in the tree keep their paths through growth, a lookup racing with it finds them
+// from either root. Nodes are only deallocated when the map is dropped, so lookups need neither
+// references nor epochs.
+//
+// Ranges are inserted with covering entries: an interior slot whose whole subtree is in the range
+// holds the value itself, tagged by the lowest bit that addresses of nodes never have, so a range
+// takes a store per slot it covers rather than per key. Keys under a covering entry that are
+// inserted or removed one by one split it into a child node first, filled with the entry.
+
+use crate::utils::*;
+use core::alloc::Alloc;
+use core::marker::PhantomData;
+use core::mem;
+use std::cmp::min;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+
+const NODE_BITS: usize = 9;
+const NODE_SLOTS: usize = 1 << NODE_BITS;
+const NODE_MASK: usize = NODE_SLOTS - 1;
+const NODE_SIZE: usize = NODE_SLOTS * mem::size_of::<usize>();
+// nodes are 16 bytes aligned, the height lives in the low bits of the root word
+const HEIGHT_MASK: usize = 0xf;
+const MAXIMUM_HEIGHT: usize = (mem::size_of::<usize>() * 8 + NODE_BITS - 1) / NODE_BITS;
+// interior slots holding values of ranges, which are shifted above the tag
+const COVERING: usize = 1;
+
+pub struct PageMap<A: Alloc + Default> {
+    root: AtomicUsize,
+    shadow: PhantomData<A>,
+}
+
+impl<A: Alloc + Default> PageMap<A> {
+    pub fn new() -> Self {
+        Self {
+            root: AtomicUsize::new(alloc_mem::<A>(NODE_SIZE) | 1),
+            shadow: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: usize) -> Option<usize> {
+        let (mut node, height) = unpack(self.root.load(Acquire));
+        if !covers(height, key) {
+            return None;
+        }
+        for level in (1..height).rev() {
+            node = slot_of(node, key, level).load(Acquire);
+            if node == 0 {
+                return None;
+            }
+            if node & COVERING != 0 {
+                return Some(node >> 1);
+            }
+        }
+        match slot_of(node, key, 0).load(Acquire) {
+            0 => None,
+            value => Some(value),
+        }
+    }
+
+    pub fn insert(&self, key: usize, value: usize) {
+        debug_assert_ne!(value, 0);
+        slot_of(self.leaf_of(key), key, 0).store(value, Release);
+    }
+
+    // Map all keys in [from, to) to the value, which shall fit in a word shifted by a bit. The
+    // tree grows once for the last key, whole subtrees in the range take covering entries and only
+    // keys at the ends of the range are stored one by one in leaves
+    pub fn insert_range(&self, from: usize, to: usize, value: usize) {
+        debug_assert_ne!(value, 0);
+        debug_assert_eq!(value >> (mem::size_of::<usize>() * 8 - 1), 0);
+        if from >= to {
+            return;
+        }
+        let (root, height) = self.grow_to(to - 1);
+        self.fill(root, height - 1, from, to, value);
+    }
+
+    // Map keys in [from, to), all under the node at the level
+    fn fill(&self, node: usize, level: usize, from: usize, to: usize, value: usize) {
+        if level == 0 {
+            for key in from..to {
+                slot_of(node, key, 0).store(value, Release);
+            }
+            return;
+        }
+        let mask = (1 << (level * NODE_BITS)) - 1;
+        let mut key = from;
+        while key < to {
+            let slot_end = (key | mask).checked_add(1).map_or(to, |end| min(end, to));
+            let slot = slot_of(node, key, level);
+            let entry = slot.load(Acquire);
+            let whole = key & mask == 0 && key | mask < to;
+            let covered = whole
+                && (entry == 0 || entry & COVERING != 0)
+                && slot.compare_and_swap(entry, value << 1 | COVERING, AcqRel) == entry;
+            if !covered {
+                let child = self.child_of(slot, level);
+                self.fill(child, level - 1, key, slot_end, value);
+            }
+            key = slot_end;
+        }
+    }
+
+    pub fn remove(&self, key: usize) -> Option<usize> {
+        let (mut node, height) = unpack(self.root.load(Acquire));
+        if !covers(height, key) {
+            return None;
+        }
+        for level in (1..height).rev() {
+            let slot = slot_of(node, key, level);
+            if slot.load(Acquire) == 0 {
+                return None;
+            }
+            node = self.child_of(slot, level);
+        }
+        match slot_of(node, key, 0).swap(0, AcqRel) {
+            0 => None,
+            value => Some(value),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        unpack(self.root.load(Relaxed)).1
+    }
+
+    // Leaf holding the key, growing the tree and creating missing nodes on the way
+    fn leaf_of(&self, key: usize) -> usize {
+        let (mut node, height) = self.grow_to(key);
+        for level in (1..height).rev() {
+            node = self.child_of(slot_of(node, key, level), level);
+        }
+        node
+    }
+
+    // Root node and height of a tree covering the key, adding levels on top as needed
+    fn grow_to(&self, key: usize) -> (usize, usize) {
+        loop {
+            let root = self.root.load(Acquire);
+            let (node, height) = unpack(root);
+            if covers(height, key) {
+                return (node, height);
+            }
+            let new_root = alloc_mem::<A>(NODE_SIZE);
+            slot_of(new_root, 0, 0).store(node, Relaxed);
+            if self.root.compare_and_swap(root, new_root | (height + 1), AcqRel) != root {
+                dealloc_mem::<A>(new_root, NODE_SIZE);
+            }
+        }
+    }
+
+    // Child node in a slot of a node at the level, creating it or splitting the covering entry
+    // in the slot into it
+    fn child_of(&self, slot: &AtomicUsize, level: usize) -> usize {
+        loop {
+            let entry = slot.load(Acquire);
+            if entry != 0 && entry & COVERING == 0 {
+                return entry;
+            }
+            let new_child = alloc_mem::<A>(NODE_SIZE);
+            if entry != 0 {
+                // leaves hold plain values, interior nodes the covering entries of their subtrees
+                let split = if level == 1 { entry >> 1 } else { entry };
+                for i in 0..NODE_SLOTS {
+                    slot_of(new_child, i, 0).store(split, Relaxed);
+                }
+            }
+            if slot.compare_and_swap(entry, new_child, AcqRel) == entry {
+                return new_child;
+            }
+            dealloc_mem::<A>(new_child, NODE_SIZE);
+        }
+    }
+}
+
+impl<A: Alloc + Default> Drop for PageMap<A> {
+    fn drop(&mut self) {
+        let (node, height) = unpack(self.root.load(Relaxed));
+        dealloc_node::<A>(node, height - 1);
+    }
+}
+
+fn dealloc_node<A: Alloc + Default>(node: usize, level: usize) {
+    if level > 0 {
+        for i in 0..NODE_SLOTS {
+            let child = slot_of(node, i, 0).load(Relaxed);
+            if child != 0 && child & COVERING == 0 {
+                dealloc_node::<A>(child, level - 1);
+            }
+        }
+    }
+    dealloc_mem::<A>(node, NODE_SIZE);
+}
+
+#[inline]
+fn unpack(root: usize) -> (usize, usize) {
+    (root & !HEIGHT_MASK, root & HEIGHT_MASK)
+}
+
+#[inline]
+fn covers(height: usize, key: usize) -> bool {
+    height >= MAXIMUM_HEIGHT || key >> (height * NODE_BITS) == 0
+}
+
+#[inline]
+fn slot_of(node: usize, key: usize, level: usize) -> &'static AtomicUsize {
+    let index = (key >> (level * NODE_BITS)) & NODE_MASK;
+    unsafe { &*((node + index * mem::size_of::<usize>()) as *const AtomicUsize) }
+}
+
+unsafe impl<A: Alloc + Default> Send for PageMap<A> {}
+unsafe impl<A: Alloc + Default> Sync for PageMap<A> {}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::pagemap::*;
+    use std::alloc::Global;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn insert_and_grow() {
+        let map = PageMap::<Global>::new();
+        assert_eq!(map.height(), 1);
+        map.insert(1, 10);
+        assert_eq!(map.get(1), Some(10));
+        assert_eq!(map.get(2), None);
+        assert_eq!(map.get(1 << 40), None);
+        map.insert(1 << 40, 20);
+        assert_eq!(map.height(), 5);
+        assert_eq!(map.get(1), Some(10));
+        assert_eq!(map.get(1 << 40), Some(20));
+        map.insert(!0, 30);
+        assert_eq!(map.height(), MAXIMUM_HEIGHT);
+        assert_eq!(map.get(!0), Some(30));
+        assert_eq!(map.remove(1), Some(10));
+        assert_eq!(map.remove(1), None);
+        assert_eq!(map.get(1), None);
+        assert_eq!(map.get(1 << 40), Some(20));
+    }
+
+    #[test]
+    pub fn insert_range() {
+        let map = PageMap::<Global>::new();
+        let from = (1 << 30) - 700;
+        let to = (1 << 30) + 1500;
+        map.insert_range(from, to, 7);
+        assert_eq!(map.height(), 4);
+        assert_eq!(map.get(from - 1), None);
+        assert_eq!(map.get(to), None);
+        for key in from..to {
+            assert_eq!(map.get(key), Some(7));
+        }
+        map.insert_range(!0 - 3, !0, 8);
+        assert_eq!(map.get(!0 - 1), Some(8));
+        assert_eq!(map.get(!0), None);
+        map.insert_range(5, 5, 9);
+        assert_eq!(map.get(5), None);
+    }
+
+    #[test]
+    pub fn covering_entries() {
+        let map = PageMap::<Global>::new();
+        let subtree = NODE_SLOTS * NODE_SLOTS;
+        // two whole subtrees of the root and partial leaves at both ends
+        let from = subtree - 3;
+        let to = subtree * 3 + 5;
+        map.insert_range(from, to, 7);
+        assert_eq!(map.height(), 3);
+        let (root, _) = unpack(map.root.load(Relaxed));
+        assert_eq!(slot_of(root, subtree, 2).load(Relaxed), 7 << 1 | COVERING);
+        assert_eq!(slot_of(root, subtree * 2, 2).load(Relaxed), 7 << 1 | COVERING);
+        for key in (from - 2..to + 2).step_by(97).chain(vec![from, to - 1]) {
+            let expected = if key >= from && key < to { Some(7) } else { None };
+            assert_eq!(map.get(key), expected, "key {}", key);
+        }
+        // split into nodes by single keys, keeping the rest of the range
+        assert_eq!(map.remove(subtree + 700), Some(7));
+        map.insert(subtree * 2 + 3, 8);
+        assert_eq!(map.get(subtree + 700), None);
+        assert_eq!(map.get(subtree + 699), Some(7));
+        assert_eq!(map.get(subtree * 2 + 3), Some(8));
+        assert_eq!(map.get(subtree * 2 + 4), Some(7));
+        assert_eq!(map.get(subtree * 3 - 1), Some(7));
+    }
+
+    #[test]
+    pub fn lookups_during_growth() {
+        let map = Arc::new(PageMap::<Global>::new());
+        let done = Arc::new(AtomicBool::new(false));
+        // present before any growth, must be found all the time
+        for key in 0..NODE_SLOTS {
+            map.insert(key, key + 1);
+        }
+        let readers = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Relaxed) {
+                        for key in 0..NODE_SLOTS {
+                            assert_eq!(map.get(key), Some(key + 1));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let writers = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for shift in NODE_BITS..48 {
+                        for i in 0..64 {
+                            let key = (1 << shift) + i * 4 + t;
+                            map.insert(key, key);
+                            assert_eq!(map.get(key), Some(key));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in writers {
+            t.join().unwrap();
+        }
+        done.store(true, Relaxed);
+        for t in readers {
+            t.join().unwrap();
+        }
+        for shift in NODE_BITS..48 {
+            for i in 0..256 {
+                let key = (1 << shift) + i;
+                assert_eq!(map.get(key), Some(key));
+            }
+        }
+    }
+}
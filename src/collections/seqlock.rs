@@ -0,0 +1,93 @@
+// Sequence lock for read-mostly tables
+// Readers copy the data out without any read-modify-write and retry if a writer was in the
+// middle of an update. Writers are serialized and publish consistent snapshots. Only for small
+// Copy data read much more often than written, such as tables changed by configuration
+//
+// The span size table of size classes in config is the only such table. Arenas are owned by the
+// application and never registered globally, and the node and CPU lists of small_heap are built
+// once on first use and never change, so their readers take no read-modify-write already
+
+use crate::collections::backoff::Backoff;
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{fence, AtomicUsize};
+
+pub struct SeqLock<T: Copy> {
+    // odd while a writer is updating
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        let backoff = Backoff::new();
+        loop {
+            let seq = self.seq.load(Acquire);
+            if seq & 1 == 0 {
+                let data = unsafe { ptr::read_volatile(self.data.get()) };
+                fence(Acquire);
+                if self.seq.load(Relaxed) == seq {
+                    return data;
+                }
+            }
+            backoff.snooze();
+        }
+    }
+
+    pub fn write<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let backoff = Backoff::new();
+        let seq = loop {
+            let seq = self.seq.load(Relaxed);
+            if seq & 1 == 0 && self.seq.compare_and_swap(seq, seq + 1, Acquire) == seq {
+                break seq;
+            }
+            backoff.snooze();
+        };
+        fence(Release);
+        let res = f(unsafe { &mut *self.data.get() });
+        self.seq.store(seq + 2, Release);
+        res
+    }
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::seqlock::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn consistent_snapshots() {
+        let lock = Arc::new(SeqLock::new([0usize; 8]));
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for i in 1..10000 {
+                    lock.write(|data| {
+                        for slot in data.iter_mut() {
+                            *slot = i;
+                        }
+                    });
+                }
+            })
+        };
+        for _ in 0..10000 {
+            let data = lock.read();
+            assert!(data.iter().all(|&slot| slot == data[0]));
+        }
+        writer.join().unwrap();
+        assert_eq!(lock.read(), [9999; 8]);
+    }
+}
@@ -0,0 +1,493 @@
+// Runtime options of the allocator
+// Options are read from the NULLOC_CONF environment variable on first use, in the format of
+// `key:value,key:value`, and can be changed at runtime by `set_option`
+//
+//   realloc_zero:  free | minimal, see ReallocZero
+//   single_thread: on | auto | off, see thread_mode
+//   fork_reinit:   on, reinitialize the heap in forked children automatically, see fork
+//   vfork_audit:   on | off, abort on allocation in vfork children before exec, see fork
+//   fixed_layout:  base address, map extents at deterministic addresses from it, see layout
+//   dontdump_free: on | off, exclude pages of free objects from core dumps, default off
+//   sample_interval: mean bytes between sampled allocations, 0 to disable, see sampling
+//   perf_map:      on | off, name heap regions in /tmp/perf-<pid>.map, see perf_map
+//   span_size:     bytes of superblocks for all size classes, default 4 times the largest class
+//   span_size.<class>: bytes of superblocks for the size class of `class` bytes, e.g.
+//                  `span_size.16:64K,span_size.64K:2M`. Larger spans mean less metadata and
+//                  fewer bump allocations, smaller spans mean less memory stranded in partially
+//                  used superblocks. Applies to superblocks created afterwards
+//   prereserve.<class>: superblocks of the size class of `class` bytes to create on every NUMA node
+//                  with their pages committed by nu_init, e.g. `prereserve.64:16,prereserve.4K:4`,
+//                  so services with known working sets skip creating them while warming up
+//   growth_limit:  bytes per second each heap instance, and large objects together, may take
+//                  from the OS, 0 for unlimited (default), see growth
+//   growth_policy: fail | block, what allocations over the growth limit do, see growth
+//   stats_segment: milliseconds between stats snapshots published into shared memory for sidecar
+//                  processes, 0 to disable (default), Unix only, see shared_stats
+//   tcache_bypass: allocations of at least these bytes bypass per-CPU superblocks, 0 for off
+//   free_list_budget: bytes of free objects a size class list of the bump heap may keep resident,
+//                  and bytes of superblocks the small heap may leave without live objects,
+//                  before the next allocation purges them, 0 for unbounded (default)
+//   purge_decay:   milliseconds after which free pages are released to the OS lazily, 0 to only
+//                  release them on nu_malloc_trim and memory pressure (default), see reclaim
+//   purge_backpressure: times the live bytes that free pages may take before allocation slow
+//                  paths purge incrementally, 0 to disable (default), see reclaim
+//   huge_pages:    off | madvise | hugetlb, page size of large mappings, see mmap::HugePages
+//   huge_page_threshold: bytes of mappings from which huge pages are used, default 2M
+//   journal:       events of the last heap operations each thread keeps for post-mortem dumps, 0
+//                  to disable (default), see journal
+//   hardened:      on | off, poison freed objects and abort on heap misuse, needs the `hardened`
+//                  feature, see hardened
+//   crash_handler: on | off, diagnose segmentation faults and bus errors on allocator pages before
+//                  handing them to previous handlers, default off, Unix only, see crash
+//   profile_interval: mean bytes between allocations sampled into heap profiles, 0 to disable,
+//                  needs the `profiling` feature, see profile
+//   size_warmup:   milliseconds to sample request sizes for nu_suggest_size_classes from when the
+//                  option is set, see tuning
+//   nursery:       experiment, bytes of per-thread nurseries serving objects of up to 64 bytes in
+//                  stack order, 0 to stop (default), see nursery
+//   max_alloc:     bytes of the largest single allocation, larger ones fail with a message on
+//                  stderr, 0 for no limit (default), see validate
+//   huge_alloc_log: bytes from which allocations are logged to stderr with their call site, beyond
+//                  the small size classes, 0 to disable (default), see audit
+//   unmap_delay:   milliseconds freed large objects stay mapped without access before they are
+//                  unmapped, so stale pointers fault, 0 to unmap at once (default), see quarantine
+//   backoff_spins: rounds of spinning with twice the pauses each before contended loops yield the
+//                  CPU, default 6, see collections::backoff
+//   backoff_yields: yields before loops waiting for other threads park until woken, 0 to never
+//                  park (default)
+//   backoff_park_us: microseconds parked threads sleep at most before checking again, default 100
+//   strict_syscalls: on | off, make no system calls but mmap, munmap and madvise after init for
+//                  seccomp sandboxes, turning off options that need others, see strict
+//
+// Sizes and addresses can be decimal, hexadecimal with 0x prefix, or with K, M, G suffixes
+
+use crate::collections::backoff;
+use crate::collections::seqlock::SeqLock;
+use crate::generic_heap::{size_class_index_from_size, NUM_SIZE_CLASS};
+use crate::growth::GrowthPolicy;
+use crate::utils::is_power_of_2;
+use crate::mmap::{set_huge_page_threshold, set_huge_pages, HugePages};
+use crate::{
+    audit, fork, generic_heap, growth, hardened, journal, layout, nursery, perf_map, profile,
+    quarantine, reclaim, sampling, small_heap, strict, thread_mode, tuning, validate,
+};
+#[cfg(unix)]
+use crate::{crash, shared_stats};
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+
+pub const CONF_ENV: &str = "NULLOC_CONF";
+
+lazy_static! {
+    pub static ref OPTIONS: Options = Options::from_env();
+}
+
+#[cfg(test)]
+lazy_static! {
+    // held by tests changing options, which are shared by all tests running in parallel
+    static ref TEST_OPTIONS: Mutex<()> = Mutex::new(());
+}
+
+// Behaviour of realloc(ptr, 0). C17 leaves it implementation defined and C code in the wild
+// disagrees about what to expect
+//   free:    free the object and return null (glibc behaviour, default)
+//   minimal: free the object and return a minimal sized allocation (BSD behaviour)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReallocZero {
+    Free = 0,
+    Minimal = 1,
+}
+
+pub struct Options {
+    realloc_zero: AtomicUsize,
+    dontdump_free: AtomicBool,
+    // superblock size of each size class, 0 for the default
+    span_sizes: SeqLock<[usize; NUM_SIZE_CLASS]>,
+    // superblocks of each size class to create up front on every node
+    prereserved_spans: [AtomicUsize; NUM_SIZE_CLASS],
+    free_list_budget: AtomicUsize,
+}
+
+impl Options {
+    fn new() -> Self {
+        Self {
+            realloc_zero: AtomicUsize::new(ReallocZero::Free as usize),
+            dontdump_free: AtomicBool::new(false),
+            span_sizes: SeqLock::new([0; NUM_SIZE_CLASS]),
+            prereserved_spans: Default::default(),
+            free_list_budget: AtomicUsize::new(0),
+        }
+    }
+
+    fn from_env() -> Self {
+        let options = Self::new();
+        if let Ok(conf) = env::var(CONF_ENV) {
+            options.parse(&conf);
+        }
+        options
+    }
+
+    pub fn parse(&self, conf: &str) {
+        for pair in conf.split(',').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, ':');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv.next().unwrap_or("").trim();
+            if !self.set(key, value) {
+                warn!("Invalid option {} in {}", pair, CONF_ENV);
+            }
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> bool {
+        self.try_set(key, value).is_some()
+    }
+
+    fn try_set(&self, key: &str, value: &str) -> Option<()> {
+        match key {
+            "realloc_zero" => match value {
+                "free" => self.set_realloc_zero(ReallocZero::Free),
+                "minimal" => self.set_realloc_zero(ReallocZero::Minimal),
+                _ => return None,
+            },
+            "single_thread" => match value {
+                "on" => {
+                    thread_mode::declare();
+                }
+                "auto" => {
+                    thread_mode::detect();
+                }
+                "off" => thread_mode::leave(),
+                _ => return None,
+            },
+            "fork_reinit" => match value {
+                "on" => {
+                    fork::install_atfork_handler();
+                }
+                _ => return None,
+            },
+            "vfork_audit" => {
+                if parse_bool(value)? {
+                    fork::enable_vfork_audit();
+                } else {
+                    fork::disable_vfork_audit();
+                }
+            }
+            "fixed_layout" => layout::set_fixed_base(parse_size(value)?),
+            "dontdump_free" => self.dontdump_free.store(parse_bool(value)?, Relaxed),
+            "sample_interval" => {
+                if !sampling::enable(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "perf_map" => {
+                if !parse_bool(value)? {
+                    perf_map::disable();
+                } else if !perf_map::enable() {
+                    return None;
+                }
+            }
+            "span_size" => {
+                let span = parse_size(value)?;
+                // validate all before publishing, so superblocks never see a partial table
+                for tier in 0..NUM_SIZE_CLASS {
+                    valid_span_size(tier, span)?;
+                }
+                self.span_sizes.write(|spans| *spans = [span; NUM_SIZE_CLASS]);
+            }
+            "free_list_budget" => self.free_list_budget.store(parse_size(value)?, Relaxed),
+            "huge_pages" => match value {
+                "off" => set_huge_pages(HugePages::Off),
+                "madvise" => set_huge_pages(HugePages::Madvise),
+                "hugetlb" => set_huge_pages(HugePages::HugeTlb),
+                _ => return None,
+            },
+            "purge_decay" => reclaim::set_decay(parse_size(value)?),
+            "purge_backpressure" => reclaim::set_backpressure(parse_size(value)?),
+            "huge_page_threshold" => set_huge_page_threshold(parse_size(value)?),
+            "journal" => journal::set_capacity(parse_size(value)?),
+            "hardened" => {
+                if !hardened::enable(parse_bool(value)?) {
+                    return None;
+                }
+            }
+            #[cfg(unix)]
+            "crash_handler" => {
+                if !parse_bool(value)? {
+                    crash::uninstall();
+                } else if !crash::install() {
+                    return None;
+                }
+            }
+            "profile_interval" => {
+                if !profile::set_interval(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "growth_limit" => growth::set_limit(parse_size(value)?),
+            "growth_policy" => match value {
+                "fail" => growth::set_policy(GrowthPolicy::Fail),
+                "block" => growth::set_policy(GrowthPolicy::Block),
+                _ => return None,
+            },
+            #[cfg(unix)]
+            "stats_segment" => {
+                if !shared_stats::enable(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "strict_syscalls" => {
+                if !strict::enable(parse_bool(value)?) {
+                    return None;
+                }
+            }
+            "huge_alloc_log" => {
+                if !audit::set_threshold(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "max_alloc" => validate::set_limit(parse_size(value)?),
+            "unmap_delay" => {
+                if !quarantine::set_delay(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "nursery" => {
+                if !nursery::enable(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "backoff_spins" => backoff::set_spins(parse_size(value)?),
+            "backoff_yields" => {
+                if !backoff::set_yields(parse_size(value)?) {
+                    return None;
+                }
+            }
+            "backoff_park_us" => backoff::set_park_us(parse_size(value)?),
+            "size_warmup" => tuning::start_warmup(parse_size(value)?),
+            "tcache_bypass" => generic_heap::set_bypass_threshold(parse_size(value)?),
+            _ if key.starts_with("span_size.") => {
+                let tier = parse_class(&key["span_size.".len()..])?;
+                self.set_span_size(tier, parse_size(value)?)?;
+            }
+            _ if key.starts_with("prereserve.") => {
+                let tier = parse_class(&key["prereserve.".len()..])?;
+                self.prereserved_spans[tier].store(parse_size(value)?, Relaxed);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    pub fn realloc_zero(&self) -> ReallocZero {
+        match self.realloc_zero.load(Relaxed) {
+            0 => ReallocZero::Free,
+            _ => ReallocZero::Minimal,
+        }
+    }
+
+    pub fn set_realloc_zero(&self, policy: ReallocZero) {
+        self.realloc_zero.store(policy as usize, Relaxed);
+    }
+
+    #[inline]
+    pub fn dontdump_free(&self) -> bool {
+        self.dontdump_free.load(Relaxed)
+    }
+
+    // Configured superblock size of the size class, None for the default
+    #[inline]
+    pub fn span_size(&self, tier: usize) -> Option<usize> {
+        match self.span_sizes.read()[tier] {
+            0 => None,
+            span => Some(span),
+        }
+    }
+
+    pub fn prereserved_spans(&self, tier: usize) -> usize {
+        self.prereserved_spans[tier].load(Relaxed)
+    }
+
+    #[inline]
+    pub fn free_list_budget(&self) -> usize {
+        self.free_list_budget.load(Relaxed)
+    }
+
+    fn set_span_size(&self, tier: usize, span: usize) -> Option<()> {
+        valid_span_size(tier, span)?;
+        self.span_sizes.write(|spans| spans[tier] = span);
+        Some(())
+    }
+}
+
+// Tier of the size class of `class` bytes, a power of two
+fn parse_class(class: &str) -> Option<usize> {
+    let class = parse_size(class)?;
+    if class < 2 || !is_power_of_2(class) {
+        return None;
+    }
+    let tier = size_class_index_from_size(class);
+    if tier >= NUM_SIZE_CLASS {
+        return None;
+    }
+    Some(tier)
+}
+
+// Spans must hold whole objects of the class and fit superblock offsets, and superblocks must fit
+// an address space of the node heaps
+fn valid_span_size(tier: usize, span: usize) -> Option<()> {
+    let class = 2 << tier;
+    if span < class || span % class != 0 || span > small_heap::max_span_size() {
+        return None;
+    }
+    Some(())
+}
+
+#[inline]
+pub fn options() -> &'static Options {
+    &*OPTIONS
+}
+
+// Options with side effects take effect when the configuration is loaded
+#[inline]
+pub fn ensure_loaded() {
+    lazy_static::initialize(&OPTIONS);
+}
+
+pub fn set_option(key: &str, value: &str) -> bool {
+    options().set(key, value)
+}
+
+// Option set by a test until the guard is dropped, which sets the previous value back even when
+// the test fails. Tests changing options run one at a time, so take a single guard per test
+#[cfg(test)]
+pub struct TestOption {
+    key: &'static str,
+    previous: String,
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl TestOption {
+    pub fn set(key: &'static str, value: &str, previous: String) -> Self {
+        let lock = TEST_OPTIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        assert!(set_option(key, value));
+        Self {
+            key,
+            previous,
+            _lock: lock,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestOption {
+    fn drop(&mut self) {
+        set_option(self.key, &self.previous);
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    if value.starts_with("0x") || value.starts_with("0X") {
+        return usize::from_str_radix(&value[2..], 16).ok();
+    }
+    let (digits, unit) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+
+    #[test]
+    pub fn parse() {
+        let options = Options::new();
+        assert_eq!(options.realloc_zero(), ReallocZero::Free);
+        options.parse("realloc_zero:minimal");
+        assert_eq!(options.realloc_zero(), ReallocZero::Minimal);
+        assert!(!options.set("realloc_zero", "unknown"));
+        assert!(!options.set("unknown", "free"));
+        assert_eq!(options.realloc_zero(), ReallocZero::Minimal);
+        options.parse(",realloc_zero:free,");
+        assert_eq!(options.realloc_zero(), ReallocZero::Free);
+    }
+
+    #[test]
+    pub fn span_sizes() {
+        let options = Options::new();
+        assert_eq!(options.span_size(0), None);
+        options.parse("span_size:1M,span_size.16:64K");
+        assert_eq!(options.span_size(0), Some(1 << 20));
+        assert_eq!(options.span_size(3), Some(64 << 10));
+        assert_eq!(options.span_size(15), Some(1 << 20));
+        // not a size class
+        assert!(!options.set("span_size.24", "64K"));
+        // cannot hold whole objects
+        assert!(!options.set("span_size.64K", "96K"));
+        assert!(!options.set("span_size.64K", "32K"));
+        // superblock cannot fit an address space
+        assert!(!options.set("span_size.64K", "128M"));
+        assert!(!options.set("span_size", "256M"));
+        assert_eq!(options.span_size(15), Some(1 << 20));
+    }
+
+    #[test]
+    pub fn span_size_table_snapshots() {
+        let options = std::sync::Arc::new(Options::new());
+        let writer = {
+            let options = options.clone();
+            std::thread::spawn(move || {
+                for i in 0..2000 {
+                    assert!(options.set("span_size", if i % 2 == 0 { "64K" } else { "1M" }));
+                }
+            })
+        };
+        // superblocks of all classes see one setting or the other, never a mix
+        for _ in 0..20000 {
+            let spans = options.span_sizes.read();
+            assert!(spans.iter().all(|span| *span == spans[0]), "{:?}", spans);
+        }
+        writer.join().unwrap();
+        assert_eq!(options.span_size(0), Some(1 << 20));
+    }
+
+    #[test]
+    pub fn prereserved_spans() {
+        let options = Options::new();
+        assert_eq!(options.prereserved_spans(5), 0);
+        options.parse("prereserve.64:16,prereserve.4K:4");
+        assert_eq!(options.prereserved_spans(5), 16);
+        assert_eq!(options.prereserved_spans(11), 4);
+        assert!(!options.set("prereserve.24", "1"));
+        assert!(!options.set("prereserve.1M", "1"));
+    }
+
+    #[test]
+    pub fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("0x1000"), Some(4096));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("2m"), Some(2 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("-1"), None);
+    }
+}
@@ -0,0 +1,179 @@
+// Crash handler telling faults on allocator pages apart from other crashes
+// SIGSEGV and SIGBUS handlers installed by the `crash_handler` option look the fault address up
+// in the heaps and print a diagnosis to stderr, followed by the journal when it is enabled:
+//   nulloc: SIGSEGV at 0x7f5e2c402010, 16 bytes past the end of the object at 0x7f5e24402000
+//   of 134217728 bytes, in its guard page
+// Guard pages of large objects in hardened mode and ranges of freed large objects held in
+// quarantine (see unmap_delay) can be told apart, other faults are reported as outside of them.
+//
+// Handlers installed before are chained and called afterwards, and signals without one take
+// their default action, so the process still dumps core. Nothing here allocates or takes locks.
+
+use crate::{journal, large_heap, quarantine};
+use libc::*;
+use std::io::{self, Write};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{AcqRel, Release};
+
+const SIGNALS: [c_int; 2] = [SIGSEGV, SIGBUS];
+const MESSAGE_BUFFER_SIZE: usize = 256;
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+// actions of the signals before ours, in the order of SIGNALS
+static mut PREVIOUS_ACTIONS: [Option<sigaction>; 2] = [None, None];
+
+type SigInfoHandler = extern "C" fn(c_int, *mut siginfo_t, *mut c_void);
+type SignalHandler = extern "C" fn(c_int);
+
+pub fn install() -> bool {
+    if INSTALLED.compare_and_swap(false, true, AcqRel) {
+        return true;
+    }
+    unsafe {
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as SigInfoHandler as sighandler_t;
+        // faults on stack overflows need the alternate stack, when the thread has one
+        action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+        sigemptyset(&mut action.sa_mask);
+        for (i, &signal) in SIGNALS.iter().enumerate() {
+            let mut previous: sigaction = mem::zeroed();
+            if sigaction(signal, &action, &mut previous) != 0 {
+                // none or all of the handlers are in place
+                restore_previous();
+                INSTALLED.store(false, Release);
+                return false;
+            }
+            PREVIOUS_ACTIONS[i] = Some(previous);
+        }
+    }
+    true
+}
+
+// Put the handlers installed before back
+pub fn uninstall() {
+    if !INSTALLED.compare_and_swap(true, false, AcqRel) {
+        return;
+    }
+    restore_previous();
+}
+
+fn restore_previous() {
+    for (i, &signal) in SIGNALS.iter().enumerate() {
+        unsafe {
+            if let Some(previous) = PREVIOUS_ACTIONS[i].take() {
+                sigaction(signal, &previous, ptr::null_mut());
+            }
+        }
+    }
+}
+
+extern "C" fn handle_fault(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let mut buffer = [0u8; MESSAGE_BUFFER_SIZE];
+    let len = {
+        let mut cursor = &mut buffer[..];
+        let _ = describe(signal, addr, &mut cursor);
+        MESSAGE_BUFFER_SIZE - cursor.len()
+    };
+    unsafe {
+        write(STDERR_FILENO, buffer.as_ptr() as *const c_void, len as _);
+    }
+    if journal::is_enabled() {
+        journal::dump(STDERR_FILENO);
+    }
+    unsafe { chain(signal, info, context) }
+}
+
+pub fn describe<W: Write>(signal: c_int, addr: usize, writer: &mut W) -> io::Result<()> {
+    let name = if signal == SIGBUS { "SIGBUS" } else { "SIGSEGV" };
+    write!(writer, "nulloc: {} at {:#x}, ", name, addr)?;
+    if let Some((start, size)) = quarantine::held_range_of(addr) {
+        return write!(
+            writer,
+            "{} bytes into the freed object at {:#x} of {} bytes, in quarantine\n",
+            addr - start,
+            start,
+            size
+        );
+    }
+    let (start, size) = match large_heap::guarded_object_of(addr) {
+        Some(object) => object,
+        None => return write!(writer, "not in guard pages or quarantine of the allocator\n"),
+    };
+    if addr < start {
+        write!(writer, "{} bytes before the start", start - addr)?;
+    } else {
+        write!(writer, "{} bytes past the end", addr - (start + size))?;
+    }
+    write!(writer, " of the object at {:#x} of {} bytes, in its guard page\n", start, size)
+}
+
+unsafe fn chain(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let index = if signal == SIGBUS { 1 } else { 0 };
+    match PREVIOUS_ACTIONS[index] {
+        Some(previous) if previous.sa_sigaction != SIG_DFL && previous.sa_sigaction != SIG_IGN => {
+            if previous.sa_flags & SA_SIGINFO != 0 {
+                let handler: SigInfoHandler = mem::transmute(previous.sa_sigaction);
+                handler(signal, info, context);
+            } else {
+                let handler: SignalHandler = mem::transmute(previous.sa_sigaction);
+                handler(signal);
+            }
+        }
+        _ => {
+            // delivered with the default action once the handler returns, as the signal is
+            // blocked until then
+            let mut action: sigaction = mem::zeroed();
+            action.sa_sigaction = SIG_DFL;
+            sigaction(signal, &action, ptr::null_mut());
+            raise(signal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::crash::*;
+
+    #[test]
+    pub fn install_and_uninstall() {
+        assert!(install());
+        assert!(install());
+        let mut current: sigaction = unsafe { mem::zeroed() };
+        unsafe { sigaction(SIGSEGV, ptr::null(), &mut current) };
+        assert_eq!(current.sa_sigaction, handle_fault as SigInfoHandler as sighandler_t);
+        uninstall();
+        unsafe { sigaction(SIGSEGV, ptr::null(), &mut current) };
+        assert_ne!(current.sa_sigaction, handle_fault as SigInfoHandler as sighandler_t);
+        let mut message = vec![];
+        describe(SIGBUS, 0x10, &mut message).unwrap();
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "nulloc: SIGBUS at 0x10, not in guard pages or quarantine of the allocator\n"
+        );
+    }
+
+    #[cfg(feature = "hardened")]
+    #[test]
+    pub fn guard_page_diagnosis() {
+        use crate::api::{nu_free, nu_malloc};
+        use crate::bump_heap::HEAP_VIRT_SIZE;
+        use crate::hardened;
+
+        assert!(hardened::enable(true));
+        let ptr = unsafe { nu_malloc(HEAP_VIRT_SIZE) } as usize;
+        let mut message = vec![];
+        describe(SIGSEGV, ptr + HEAP_VIRT_SIZE + 16, &mut message).unwrap();
+        describe(SIGSEGV, ptr - 8, &mut message).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        let lines = message.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with(&format!(
+            "16 bytes past the end of the object at {:#x} of {} bytes, in its guard page",
+            ptr, HEAP_VIRT_SIZE
+        )));
+        assert!(lines[1].contains("8 bytes before the start of the object"));
+        unsafe { nu_free(ptr as _) };
+    }
+}
@@ -0,0 +1,192 @@
+// Log of extents mapped from and returned to the OS, with the reason of each
+// Mappings and RSS move when the heaps take extents from the OS or give pages back. Every such
+// event is counted in the stats by its reason, recorded into the journal of the thread when it
+// is enabled, and kept in a global log of the last LOG_CAPACITY events, which
+// `nu_ctl("log.extents")` writes to stderr:
+//   extent 12 at 5031ms: map 0x7f5e24400000 134217728 bytes for class refill
+//
+// Reasons:
+//   class refill: address spaces the small heap carves superblocks from
+//   large alloc:  mappings of large objects and address spaces of the bump heap serving them
+//   cache spill:  pages of free objects released as free lists exceed their budget
+//   purge:        pages released by reclamation, on memory pressure, decay, trim or OOM
+//   arena:        address spaces of arenas and other allocator instances
+// Releases return pages and keep the mapping. Purges release pages of many objects at once and
+// are logged as one event without an address. Like the journal, the log is written without
+// allocating or taking locks
+
+use crate::journal::{self, write_line, JournalOp};
+use crate::reclaim::elapsed_ms;
+use crate::stats;
+use std::io::Write;
+use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
+
+pub const NUM_EXTENT_OPS: usize = 3;
+pub const NUM_EXTENT_REASONS: usize = 5;
+const LOG_CAPACITY: usize = 256;
+
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+static mut LOG: [ExtentEvent; LOG_CAPACITY] = [ExtentEvent::UNUSED; LOG_CAPACITY];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentOp {
+    Map = 0,
+    Unmap = 1,
+    Release = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentReason {
+    ClassRefill = 0,
+    LargeAlloc = 1,
+    CacheSpill = 2,
+    Purge = 3,
+    Arena = 4,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ExtentEvent {
+    // number of events before this one plus one, 0 for unused slots
+    seq: usize,
+    ms: usize,
+    op: usize,
+    reason: usize,
+    addr: usize,
+    size: usize,
+}
+
+impl ExtentOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExtentOp::Map => "map",
+            ExtentOp::Unmap => "unmap",
+            ExtentOp::Release => "release",
+        }
+    }
+
+    fn journal_op(self) -> JournalOp {
+        match self {
+            ExtentOp::Map => JournalOp::Map,
+            ExtentOp::Unmap => JournalOp::Unmap,
+            ExtentOp::Release => JournalOp::Release,
+        }
+    }
+}
+
+impl ExtentReason {
+    pub fn from_usize(reason: usize) -> Option<Self> {
+        match reason {
+            0 => Some(ExtentReason::ClassRefill),
+            1 => Some(ExtentReason::LargeAlloc),
+            2 => Some(ExtentReason::CacheSpill),
+            3 => Some(ExtentReason::Purge),
+            4 => Some(ExtentReason::Arena),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExtentReason::ClassRefill => "class refill",
+            ExtentReason::LargeAlloc => "large alloc",
+            ExtentReason::CacheSpill => "cache spill",
+            ExtentReason::Purge => "purge",
+            ExtentReason::Arena => "arena",
+        }
+    }
+}
+
+impl ExtentEvent {
+    const UNUSED: Self = Self {
+        seq: 0,
+        ms: 0,
+        op: 0,
+        reason: 0,
+        addr: 0,
+        size: 0,
+    };
+}
+
+pub fn record(op: ExtentOp, reason: ExtentReason, addr: usize, size: usize) {
+    if size == 0 {
+        return;
+    }
+    stats::count_extent(op, reason, size);
+    journal::record_extent(op.journal_op(), addr, size, reason as usize);
+    let seq = WRITTEN.fetch_add(1, AcqRel) + 1;
+    unsafe {
+        let event = &mut LOG[(seq - 1) % LOG_CAPACITY];
+        // readers discard events with the sequence changed while they were read
+        ptr::write_volatile(&mut event.seq, 0);
+        *event = ExtentEvent {
+            seq: 0,
+            ms: elapsed_ms(),
+            op: op as usize,
+            reason: reason as usize,
+            addr,
+            size,
+        };
+        ptr::write_volatile(&mut event.seq, seq);
+    }
+}
+
+// Write the log to the file descriptor, oldest event first
+pub fn dump(fd: libc::c_int) {
+    dump_with(|line| unsafe {
+        libc::write(fd, line.as_ptr() as *const libc::c_void, line.len() as _);
+    });
+}
+
+pub fn dump_with<F: FnMut(&[u8])>(mut sink: F) {
+    let written = WRITTEN.load(Acquire);
+    let start = written.saturating_sub(LOG_CAPACITY);
+    for seq in start + 1..=written {
+        let event = unsafe { ptr::read_volatile(&LOG[(seq - 1) % LOG_CAPACITY]) };
+        if event.seq != seq {
+            // overwritten or still being written
+            continue;
+        }
+        let op = match event.op {
+            0 => ExtentOp::Map,
+            1 => ExtentOp::Unmap,
+            _ => ExtentOp::Release,
+        };
+        let reason = ExtentReason::from_usize(event.reason).map_or("unknown", |r| r.name());
+        write_line(&mut sink, |line| {
+            write!(
+                line,
+                "extent {} at {}ms: {} {:#x} {} bytes for {}\n",
+                seq,
+                event.ms,
+                op.name(),
+                event.addr,
+                event.size,
+                reason
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::extents::*;
+
+    #[test]
+    pub fn log_extents() {
+        let before = stats::extent_bytes(ExtentOp::Map, ExtentReason::LargeAlloc);
+        record(ExtentOp::Map, ExtentReason::LargeAlloc, 0xdead_0000, 1 << 30);
+        // nothing released, nothing logged
+        record(ExtentOp::Release, ExtentReason::Arena, 0xbeef_0000, 0);
+        record(ExtentOp::Release, ExtentReason::Purge, 0, 8192);
+        let after = stats::extent_bytes(ExtentOp::Map, ExtentReason::LargeAlloc);
+        assert!(after >= before + (1 << 30));
+        let mut dump = vec![];
+        dump_with(|line| dump.extend_from_slice(line));
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains(": map 0xdead0000 1073741824 bytes for large alloc\n"));
+        assert!(dump.contains(": release 0x0 8192 bytes for purge\n"));
+        assert!(!dump.contains("0xbeef0000"));
+    }
+}
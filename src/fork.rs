@@ -0,0 +1,168 @@
+// Heap reconstruction in the child process after fork without exec
+// fork only duplicates the calling thread. Any other thread that was in the middle of an
+// allocation leaves references, claimed slots and exchange states behind, which will never be
+// released in the child and may spin the child forever. Python style multi-threaded parents
+// forking workers need to call `child_reinit` in the child (or install the atfork handler)
+// before allocating.
+//
+// vfork (and posix_spawn, which is built on top of it) children share the address space with the
+// parent and do not run atfork handlers. Allocating in such child before exec corrupts the
+// parent heap. In audit mode, the allocator compares the pid of the caller with the pid recorded
+// at the last fork and aborts when it changes without going through the atfork child handler.
+// Neither the atfork handler nor the audit can be installed on systems without fork.
+
+use crate::collections::epoch;
+use crate::{bump_heap, large_heap, small_heap, thread_mode, utils};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+#[cfg(unix)]
+static HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+static REINIT_IN_CHILD: AtomicBool = AtomicBool::new(false);
+static VFORK_AUDIT: AtomicBool = AtomicBool::new(false);
+static PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+const VFORK_ALLOCATION_MESSAGE: &[u8] =
+    b"nulloc: allocation in a vfork/posix_spawn child before exec, aborting\n";
+
+pub unsafe fn child_reinit() {
+    epoch::reinit_after_fork();
+    thread_mode::reinit_after_fork();
+    bump_heap::reinit_after_fork();
+    large_heap::reinit_after_fork();
+    #[cfg(not(feature = "bump_heap_only"))]
+    small_heap::reinit_after_fork();
+    utils::reinit_log_after_fork();
+    #[cfg(unix)]
+    crate::shared_stats::reinit_after_fork();
+}
+
+// Run `child_reinit` automatically in every forked child
+pub fn install_atfork_handler() -> bool {
+    REINIT_IN_CHILD.store(true, Relaxed);
+    register_atfork()
+}
+
+pub fn enable_vfork_audit() -> bool {
+    PROCESS_ID.store(current_pid(), Relaxed);
+    if !register_atfork() {
+        return false;
+    }
+    VFORK_AUDIT.store(true, Relaxed);
+    true
+}
+
+pub fn disable_vfork_audit() {
+    VFORK_AUDIT.store(false, Relaxed);
+}
+
+#[cfg(unix)]
+#[inline]
+pub fn audit_vfork() {
+    if VFORK_AUDIT.load(Relaxed) && current_pid() != PROCESS_ID.load(Relaxed) {
+        unsafe {
+            libc::write(
+                libc::STDERR_FILENO,
+                VFORK_ALLOCATION_MESSAGE.as_ptr() as *const libc::c_void,
+                VFORK_ALLOCATION_MESSAGE.len(),
+            );
+            libc::abort();
+        }
+    }
+}
+
+// No fork elsewhere, the audit cannot be enabled
+#[cfg(not(unix))]
+#[inline]
+pub fn audit_vfork() {}
+
+#[cfg(unix)]
+fn register_atfork() -> bool {
+    if HANDLER_INSTALLED.compare_and_swap(false, true, Relaxed) {
+        return true;
+    }
+    unsafe { libc::pthread_atfork(None, None, Some(atfork_child)) == 0 }
+}
+
+#[cfg(not(unix))]
+fn register_atfork() -> bool {
+    false
+}
+
+fn current_pid() -> usize {
+    std::process::id() as usize
+}
+
+#[cfg(unix)]
+extern "C" fn atfork_child() {
+    PROCESS_ID.store(current_pid(), Relaxed);
+    if REINIT_IN_CHILD.load(Relaxed) {
+        unsafe { child_reinit() }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::api::{nu_free, nu_malloc};
+    use crate::fork::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn vfork_audit_in_parent() {
+        assert!(enable_vfork_audit());
+        // same process, shall pass
+        audit_vfork();
+        disable_vfork_audit();
+    }
+
+    #[test]
+    pub fn allocate_in_forked_child() {
+        let sizes = [16, 1024, 64 << 10, 1 << 20];
+        let stop = Arc::new(AtomicBool::new(false));
+        // other threads are in the middle of allocations when the process forks
+        let workers = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    while !stop.load(Relaxed) {
+                        for size in sizes.iter() {
+                            unsafe { nu_free(nu_malloc(*size)) }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(10));
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                child_reinit();
+                for _ in 0..1000 {
+                    let ptrs = sizes.iter().map(|size| nu_malloc(*size)).collect::<Vec<_>>();
+                    if ptrs.iter().any(|ptr| ptr.is_null()) {
+                        libc::_exit(1);
+                    }
+                    ptrs.into_iter().for_each(|ptr| nu_free(ptr));
+                }
+                libc::_exit(0);
+            }
+        }
+        stop.store(true, Relaxed);
+        workers.into_iter().for_each(|worker| worker.join().unwrap());
+        assert!(pid > 0);
+        // a child stuck on what other threads left behind is killed
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == 0 {
+            if Instant::now() > deadline {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+                panic!("forked child hangs");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+}
@@ -1,10 +1,24 @@
 use super::*;
-use crate::utils::is_power_of_2;
+use crate::config::ReallocZero;
+use crate::journal::JournalOp;
+use crate::stats::HeapStats;
+use crate::utils::{align_padding, is_power_of_2, CACHE_LINE_SIZE, SYS_PAGE_SIZE};
+use core::marker::PhantomData;
 use core::mem;
+use std::cmp::{max, min};
 use libc::*;
 use std::ptr::null_mut;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 
 pub const NUM_SIZE_CLASS: usize = 16;
+pub const MAXIMUM_SMALL_SIZE: usize = 2 << (NUM_SIZE_CLASS - 1);
+
+// Allocations of at least these bytes bypass per-CPU superblocks, 0 for off
+static BYPASS_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+// Sizes below this take the fast path. Folds the bypass threshold and the observers of the slow
+// paths into a word the fast path reads with a single load, 0 while any observer is on
+static FAST_PATH_LIMIT: AtomicUsize = AtomicUsize::new(MAXIMUM_SMALL_SIZE + 1);
 
 #[derive(Clone)]
 pub struct ObjectMeta {
@@ -12,15 +26,191 @@ pub struct ObjectMeta {
     pub tid: usize,
 }
 
+// Fast path for small objects, everything else is in the cold allocate
 #[cfg(not(feature = "bump_heap_only"))]
+#[inline]
 pub unsafe fn malloc(size: Size) -> Ptr {
+    if size < FAST_PATH_LIMIT.load(Relaxed) {
+        let ptr = small_heap::allocate(size);
+        if ptr == NULL_PTR {
+            stats::count_failure(size);
+        }
+        probe_event!(malloc, ptr as usize, size);
+        return ptr;
+    }
+    allocate(size, false)
+}
+
+// Sampling, the journal, profiles, size warmup and nurseries see allocations on the slow paths
+fn is_traced() -> bool {
+    sampling::is_enabled()
+        || journal::is_enabled()
+        || profile::is_active()
+        || tuning::is_warming()
+        || nursery::is_enabled()
+}
+
+fn fast_path_limit_of(traced: bool, bypass_threshold: usize) -> usize {
+    if traced {
+        0
+    } else if bypass_threshold == 0 {
+        MAXIMUM_SMALL_SIZE + 1
+    } else {
+        min(bypass_threshold, MAXIMUM_SMALL_SIZE + 1)
+    }
+}
+
+// Recompute the fast path limit, called after any observer is turned on or off. Repeated until
+// the state is stable, so a refresh racing with another one cannot leave a stale limit behind
+pub fn refresh_fast_path() {
+    loop {
+        let limit = fast_path_limit_of(is_traced(), BYPASS_THRESHOLD.load(SeqCst));
+        FAST_PATH_LIMIT.store(limit, SeqCst);
+        if fast_path_limit_of(is_traced(), BYPASS_THRESHOLD.load(SeqCst)) == limit {
+            return;
+        }
+    }
+}
+
+pub fn set_bypass_threshold(bytes: usize) {
+    BYPASS_THRESHOLD.store(bytes, SeqCst);
+    refresh_fast_path();
+}
+
+pub fn bypass_threshold() -> usize {
+    BYPASS_THRESHOLD.load(Relaxed)
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+#[inline]
+fn bypass_cache(size: usize) -> bool {
+    let threshold = bypass_threshold();
+    threshold != 0 && size >= threshold
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+#[inline]
+fn on_malloc(ptr: Ptr, size: Size) {
+    if ptr == NULL_PTR {
+        stats::count_failure(size);
+    }
+    probe_event!(malloc, ptr as usize, size);
+    journal::record(JournalOp::Malloc, ptr as usize, size);
+    profile::on_malloc(ptr, size);
+    tuning::on_malloc(size);
+    audit::on_malloc(ptr, size);
+}
+
+// Allocate bypassing the per-CPU superblocks, for transient buffers that shall not leave
+// superblocks attached to the CPU after freed
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn malloc_uncached(size: Size) -> Ptr {
+    allocate(size, true)
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+#[cold]
+#[inline(never)]
+unsafe fn allocate(size: Size, uncached: bool) -> Ptr {
+    config::ensure_loaded();
+    reclaim::on_slow_path();
+    sampling::on_allocation(size);
     let max_small_size = *small_heap::MAXIMUM_SIZE;
-    if size > max_small_size {
+    let ptr = if size > max_small_size && validate::size(size).is_err() {
+        NULL_PTR
+    } else if size > max_small_size {
         utils::log("LARGE MALLOC", size);
         large_heap::allocate(size)
+    } else if uncached || bypass_cache(size) {
+        utils::log("SHARED MALLOC", size);
+        small_heap::allocate_shared(size)
+    } else if size <= nursery::MAX_SIZE && nursery::is_enabled() {
+        let ptr = nursery::allocate(size);
+        if ptr.is_null() {
+            small_heap::allocate(size)
+        } else {
+            ptr
+        }
     } else {
         utils::log("SMALL MALLOC", size);
         small_heap::allocate(size)
+    };
+    on_malloc(ptr, size);
+    ptr
+}
+
+// Allocate for a value of the type. Size and alignment are constants, so the size class is
+// resolved at compile time and the checks below fold away
+#[cfg(not(feature = "bump_heap_only"))]
+#[inline]
+pub unsafe fn malloc_typed<T>() -> Ptr {
+    let size = mem::size_of::<T>();
+    let align = mem::align_of::<T>();
+    if size <= MAXIMUM_SMALL_SIZE && align <= CACHE_LINE_SIZE {
+        if size < FAST_PATH_LIMIT.load(Relaxed) {
+            let ptr = small_heap::allocate_class(TypeSizeClass::<T>::INDEX);
+            if ptr == NULL_PTR {
+                stats::count_failure(size);
+            }
+            probe_event!(malloc, ptr as usize, size);
+            return ptr;
+        }
+        return allocate(size, false);
+    }
+    aligned_malloc(size, align)
+}
+
+// Allocate aligned to `align`, a power of two. Small objects are aligned to their size class up
+// to a cache line, larger alignments are served by the large heap
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn aligned_malloc(size: Size, align: usize) -> Ptr {
+    debug_assert!(is_power_of_2(align));
+    if align <= CACHE_LINE_SIZE {
+        return malloc(max(size, align));
+    }
+    config::ensure_loaded();
+    sampling::on_allocation(size);
+    let ptr = if validate::size(size).is_err() {
+        NULL_PTR
+    } else {
+        large_heap::allocate_aligned(size, align)
+    };
+    on_malloc(ptr, size);
+    ptr
+}
+
+// Allocate on the NUMA node, which must be below the number of nodes
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn malloc_on_node(size: Size, node: u16) -> Ptr {
+    config::ensure_loaded();
+    sampling::on_allocation(size);
+    let ptr = if validate::size(size).is_err() {
+        NULL_PTR
+    } else if size > *small_heap::MAXIMUM_SIZE {
+        large_heap::allocate_on_node(size, node)
+    } else {
+        small_heap::allocate_on_node(size, node)
+    };
+    on_malloc(ptr, size);
+    ptr
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+#[inline]
+pub unsafe fn free_typed<T>(ptr: Ptr) {
+    let size = mem::size_of::<T>();
+    if size <= MAXIMUM_SMALL_SIZE
+        && mem::align_of::<T>() <= CACHE_LINE_SIZE
+        && !journal::is_enabled()
+        && !profile::is_active()
+    {
+        probe_event!(free, ptr as usize);
+        if !small_heap::free(ptr) && !nursery::free(ptr) {
+            unknown_object(ptr);
+        }
+        reclaim::on_free();
+    } else {
+        free(ptr);
     }
 }
 
@@ -29,15 +219,89 @@ pub unsafe fn malloc(size: Size) -> Ptr {
     bump_heap::malloc(size)
 }
 
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc_uncached(size: Size) -> Ptr {
+    bump_heap::malloc(size)
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn aligned_malloc(size: Size, align: usize) -> Ptr {
+    bump_heap::aligned_malloc(size, align)
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc_on_node(size: Size, node: u16) -> Ptr {
+    bump_heap::malloc(size)
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc_typed<T>() -> Ptr {
+    bump_heap::aligned_malloc(mem::size_of::<T>(), mem::align_of::<T>())
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn free_typed<T>(ptr: Ptr) {
+    bump_heap::free(ptr);
+}
+
 #[cfg(not(feature = "bump_heap_only"))]
 pub unsafe fn free(ptr: Ptr) {
+    probe_event!(free, ptr as usize);
+    if journal::is_enabled() {
+        journal::record(JournalOp::Free, ptr as usize, size_of(ptr).unwrap_or(0));
+    }
+    // before the address can be reused by another thread
+    profile::on_free(ptr);
     if small_heap::free(ptr) {
         utils::log("SMALL FREE", ptr as usize);
+    } else {
+        free_outside_superblocks(ptr);
+    }
+    reclaim::on_free();
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+unsafe fn free_outside_superblocks(ptr: Ptr) {
+    if nursery::free(ptr) {
+        utils::log("NURSERY FREE", ptr as usize);
     } else if large_heap::free(ptr) {
         utils::log("LARGE FREE", ptr as usize);
+    } else if partition::free(ptr) {
+        utils::log("PARTITION FREE", ptr as usize);
     } else {
-        warn!("Cannot find object to free at {:x?}", ptr as usize);
+        unknown_object(ptr);
+    }
+}
+
+// Free objects of superblocks a superblock at a time, see small_heap::free_batch. Objects are
+// freed one by one when the journal or the profiler has to see each of them
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn free_batch(ptrs: &[Ptr]) {
+    if journal::is_enabled() || profile::is_active() {
+        for ptr in ptrs.iter().filter(|ptr| !ptr.is_null()) {
+            free(*ptr);
+        }
+        return;
+    }
+    for ptr in ptrs {
+        probe_event!(free, *ptr as usize);
     }
+    small_heap::free_batch(ptrs, |ptr| {
+        if !ptr.is_null() {
+            free_outside_superblocks(ptr);
+        }
+    });
+    reclaim::on_frees(ptrs.iter().filter(|ptr| !ptr.is_null()).count());
+}
+
+// Freed twice, or never allocated by the heaps
+#[cfg(not(feature = "bump_heap_only"))]
+#[cold]
+fn unknown_object(ptr: Ptr) {
+    if hardened::is_enabled() {
+        hardened::violation("invalid or double free", ptr as usize);
+    }
+    warn!("Cannot find object to free at {:x?}", ptr as usize);
 }
 
 #[cfg(feature = "bump_heap_only")]
@@ -45,32 +309,176 @@ pub unsafe fn free(ptr: Ptr) {
     bump_heap::free(ptr);
 }
 
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn free_batch(ptrs: &[Ptr]) {
+    for ptr in ptrs.iter().filter(|ptr| !ptr.is_null()) {
+        bump_heap::free(*ptr);
+    }
+}
+
 pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
+    if size == 0 {
+        return realloc_zero(ptr);
+    }
+    if ptr == NULL_PTR {
+        return malloc(size);
+    }
+    let old_size = if let Some(size) = size_of(ptr) {
+        size
+    } else {
+        panic!("Cannot determinate old object");
+    };
+    let new_ptr = resize(ptr, old_size, size);
+    profile::on_realloc(ptr, new_ptr, size);
+    new_ptr
+}
+
+// realloc(ptr, 0) behaves according to the realloc_zero option
+unsafe fn realloc_zero(ptr: Ptr) -> Ptr {
+    match config::options().realloc_zero() {
+        ReallocZero::Free => {
+            if ptr != NULL_PTR {
+                free(ptr);
+            }
+            NULL_PTR
+        }
+        ReallocZero::Minimal => {
+            let new_ptr = malloc(1);
+            if new_ptr != NULL_PTR && ptr != NULL_PTR {
+                free(ptr);
+            }
+            new_ptr
+        }
+    }
+}
+
+// Reallocation that never frees the original object unless it was moved successfully
+// Returns null on failure and for zero size requests, the original object stays valid
+pub unsafe fn try_realloc(ptr: Ptr, size: Size) -> Ptr {
     if ptr == NULL_PTR {
         return malloc(size);
     }
     if size == 0 {
-        free(ptr);
         return NULL_PTR;
     }
-    let old_size = if let Some(size) = small_heap::size_of(ptr) {
+    let old_size = if let Some(size) = size_of(ptr) {
         size
-    } else if let Some(_) = large_heap::size_of(ptr) {
+    } else {
+        warn!("Cannot determinate old object at {:x?}", ptr as usize);
+        return NULL_PTR;
+    };
+    let new_ptr = resize(ptr, old_size, size);
+    profile::on_realloc(ptr, new_ptr, size);
+    new_ptr
+}
+
+// Resize to `size` bytes ending with `srclen` bytes copied from `src`, the bytes before them are
+// kept from the object. A moved object copies only the bytes kept instead of the whole old object,
+// so buffers built by appending are not passed over twice. `src` may point into the object.
+// Returns null on failure with the original object untouched, like try_realloc
+pub unsafe fn realloc_copyfrom(ptr: Ptr, size: Size, src: *const c_void, srclen: Size) -> Ptr {
+    if size == 0 || srclen > size {
+        return NULL_PTR;
+    }
+    let keep = size - srclen;
+    let old_size = if ptr == NULL_PTR {
+        0
+    } else if let Some(size) = size_of(ptr) {
         size
     } else {
-        panic!("Cannot determinate old object");
+        warn!("Cannot determinate old object at {:x?}", ptr as usize);
         return NULL_PTR;
     };
+    if old_size >= size {
+        memmove((ptr as usize + keep) as Ptr, src, srclen);
+        return ptr;
+    }
+    let new_ptr = malloc_beside(ptr, size);
+    if new_ptr == NULL_PTR {
+        return NULL_PTR;
+    }
+    // before the old object is gone, the source may be in it
+    memcpy((new_ptr as usize + keep) as Ptr, src, srclen);
+    if ptr != NULL_PTR {
+        memcpy(new_ptr, ptr, min(keep, old_size));
+        free(ptr);
+        profile::on_realloc(ptr, new_ptr, size);
+    }
+    new_ptr
+}
+
+unsafe fn resize(ptr: Ptr, old_size: Size, size: Size) -> Ptr {
     if old_size >= size {
         info!("old size is larger than requesting size, untouched");
         return ptr;
     }
-    let new_ptr = malloc(size);
+    let new_ptr = malloc_beside(ptr, size);
+    if new_ptr == NULL_PTR {
+        // allocation failed, leave the original object untouched for the caller
+        return NULL_PTR;
+    }
     memcpy(new_ptr, ptr, old_size);
     free(ptr);
     new_ptr
 }
 
+// Object to move an object to on resize, in the same partition if any
+unsafe fn malloc_beside(ptr: Ptr, size: Size) -> Ptr {
+    match partition::of(ptr) {
+        Some(id) => partition::allocate(id, size),
+        None => malloc(size),
+    }
+}
+
+pub fn size_of(ptr: Ptr) -> Option<usize> {
+    small_heap::size_of(ptr)
+        .or_else(|| nursery::size_of(ptr))
+        .or_else(|| large_heap::size_of(ptr))
+        .or_else(|| partition::size_of(ptr))
+}
+
+// Bytes that would be allocated for the request without allocating, None if the alignment is not
+// a power of two. Small objects are aligned to their size up to a cache line, objects of larger
+// sizes or alignments are rounded up to pages
+pub fn allocation_size(size: usize, align: usize) -> Option<usize> {
+    let page_size = *SYS_PAGE_SIZE;
+    if size == 0 || !align.is_power_of_two() {
+        return None;
+    }
+    if align <= CACHE_LINE_SIZE && size <= *small_heap::MAXIMUM_SIZE {
+        return Some(2 << size_class_index_from_size(max(size, align)));
+    }
+    Some(size + align_padding(size, page_size))
+}
+
+pub fn heap_stats() -> HeapStats {
+    let mut heap = HeapStats::default();
+    small_heap::collect_stats(&mut heap);
+    let small_bytes = heap
+        .live_objects
+        .iter()
+        .enumerate()
+        .map(|(tier, objects)| objects * (2 << tier))
+        .sum::<usize>();
+    heap.allocated_bytes = small_bytes + stats::get(&stats::LARGE_BYTES);
+    heap.large_objects = stats::get(&stats::LARGE_OBJECTS);
+    heap.mapped_bytes = stats::get(&stats::MAPPED_BYTES);
+    heap.mapped_segments = stats::get(&stats::MAPPED_SEGMENTS);
+    heap.metadata_bytes = stats::get(&stats::METADATA_BYTES);
+    heap.reclaimed_bytes = stats::get(&stats::RECLAIMED_BYTES);
+    heap.decommitted_bytes = stats::get(&stats::DECOMMITTED_BYTES);
+    heap.resident_bytes = stats::resident_bytes();
+    heap.numa_degraded = stats::get(&stats::NUMA_DEGRADED) != 0;
+    stats::collect_failures(&mut heap);
+    stats::collect_extents(&mut heap);
+    heap
+}
+
+// Only small objects live in spans
+pub fn generation_of(ptr: Ptr) -> Option<u32> {
+    small_heap::generation_of(ptr)
+}
+
 #[inline]
 pub fn size_class_index_from_size(size: usize) -> usize {
     debug_assert!(size > 0);
@@ -85,7 +493,199 @@ pub fn size_class_index_from_size(size: usize) -> usize {
     }
 }
 
+// Same as size_class_index_from_size, without branches for constant evaluation. Zero sizes fail
+// to evaluate
+pub const fn const_size_class_index(size: usize) -> usize {
+    mem::size_of::<usize>() * 8 - ((size - 1) | 1).leading_zeros() as usize - 1
+}
+
+// Size class of values of the type, evaluated once per type at compile time
+struct TypeSizeClass<T>(PhantomData<T>);
+
+impl<T> TypeSizeClass<T> {
+    const INDEX: usize = const_size_class_index(mem::size_of::<T>());
+}
+
 #[inline]
 pub fn log_2_of(num: usize) -> usize {
     mem::size_of::<usize>() * 8 - num.leading_zeros() as usize - 1
 }
+
+#[cfg(test)]
+mod test {
+    use crate::generic_heap::*;
+    use crate::utils::NUM_NUMA_NODES;
+
+    #[test]
+    pub fn fast_path_limit() {
+        assert_eq!(fast_path_limit_of(false, 0), MAXIMUM_SMALL_SIZE + 1);
+        assert_eq!(fast_path_limit_of(false, 4096), 4096);
+        assert_eq!(fast_path_limit_of(false, 1 << 30), MAXIMUM_SMALL_SIZE + 1);
+        // not even empty requests take the fast path while traced
+        assert_eq!(fast_path_limit_of(true, 0), 0);
+        assert_eq!(fast_path_limit_of(true, 4096), 0);
+        let previous = bypass_threshold().to_string();
+        let _bypass = config::TestOption::set("tcache_bypass", "1K", previous);
+        // observers may be turned on by tests running in parallel
+        let limit = FAST_PATH_LIMIT.load(Relaxed);
+        assert!(limit == 1024 || limit == 0, "fast path limit is {}", limit);
+        unsafe {
+            let ptr = malloc(2048);
+            assert!(!ptr.is_null());
+            assert_eq!(size_of(ptr), Some(2048));
+            free(ptr);
+        }
+    }
+
+    #[test]
+    pub fn uncached_allocation() {
+        unsafe {
+            let ptr = malloc_uncached(1000);
+            assert!(!ptr.is_null());
+            memset(ptr, 1, 1000);
+            assert_eq!(size_of(ptr), Some(1024));
+            free(ptr);
+        }
+    }
+
+    #[test]
+    pub fn typed_allocation() {
+        for size in 1..=MAXIMUM_SMALL_SIZE {
+            assert_eq!(const_size_class_index(size), size_class_index_from_size(size));
+        }
+        #[repr(align(128))]
+        struct Aligned([u8; 16]);
+        unsafe {
+            let ptr = malloc_typed::<[u8; 24]>();
+            assert_eq!(size_of(ptr), Some(32));
+            free_typed::<[u8; 24]>(ptr);
+            let ptr = malloc_typed::<u64>();
+            assert_eq!(size_of(ptr), Some(8));
+            free_typed::<u64>(ptr);
+            let ptr = malloc_typed::<Aligned>();
+            assert_eq!(align_padding(ptr as usize, 128), 0);
+            free_typed::<Aligned>(ptr);
+        }
+    }
+
+    #[test]
+    pub fn aligned_allocation() {
+        let page_size = *SYS_PAGE_SIZE;
+        unsafe {
+            for &(size, align) in &[(8, 64), (100, 32), (100, 256), (1 << 20, page_size << 2)] {
+                let ptr = aligned_malloc(size, align);
+                assert!(!ptr.is_null());
+                assert_eq!(align_padding(ptr as usize, align), 0, "align {}", align);
+                memset(ptr, 1, size);
+                assert!(size_of(ptr).unwrap() >= size);
+                free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    pub fn node_allocation() {
+        unsafe {
+            for node in 0..*NUM_NUMA_NODES {
+                for &size in &[100, 1 << 20] {
+                    let ptr = malloc_on_node(size, node);
+                    assert!(!ptr.is_null());
+                    memset(ptr, 1, size);
+                    assert!(size_of(ptr).unwrap() >= size);
+                    free(ptr);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn heap_statistics() {
+        unsafe {
+            let objects = (0..100).map(|_| malloc(256)).collect::<Vec<_>>();
+            let large = malloc(1 << 20);
+            let heap = heap_stats();
+            assert!(heap.live_objects[size_class_index_from_size(256)] >= 100);
+            assert!(heap.large_objects >= 1);
+            assert!(heap.allocated_bytes >= 100 * 256 + (1 << 20));
+            assert!(heap.mapped_segments > 0);
+            assert!(heap.mapped_bytes >= heap.mapped_segments * *SYS_PAGE_SIZE);
+            assert!(heap.resident_bytes > 0);
+            assert!(!heap.cpu_cache_bytes.is_empty());
+            let spans = heap.class_spans[size_class_index_from_size(256)];
+            assert!(spans.total > 0);
+            assert_eq!(spans.total, spans.full + spans.partial + spans.empty);
+            assert!(spans.used_bytes >= 100 * 256);
+            for ptr in objects {
+                free(ptr);
+            }
+            free(large);
+        }
+    }
+
+    #[test]
+    pub fn allocation_sizes() {
+        let page_size = *SYS_PAGE_SIZE;
+        assert_eq!(allocation_size(0, 1), None);
+        assert_eq!(allocation_size(1, 1), Some(2));
+        assert_eq!(allocation_size(24, 1), Some(32));
+        assert_eq!(allocation_size(32, 1), Some(32));
+        assert_eq!(allocation_size(8, 64), Some(64));
+        let max_small = *small_heap::MAXIMUM_SIZE;
+        assert_eq!(allocation_size(max_small, 8), Some(max_small));
+        assert_eq!(
+            allocation_size(max_small + 1, 8),
+            Some(max_small + page_size)
+        );
+        assert_eq!(allocation_size(100, page_size), Some(page_size));
+        assert_eq!(allocation_size(100, page_size << 1), Some(page_size));
+        assert_eq!(allocation_size(100, 3), None);
+    }
+
+    #[test]
+    pub fn realloc_failure_preserves_object() {
+        unsafe {
+            let size = 64;
+            let ptr = malloc(size);
+            memset(ptr, 42, size);
+            // cannot be mapped by the OS
+            assert_eq!(realloc(ptr, 1 << 60), NULL_PTR);
+            assert_eq!(try_realloc(ptr, 1 << 60), NULL_PTR);
+            assert_eq!(try_realloc(ptr, 0), NULL_PTR);
+            for addr in ptr as usize..ptr as usize + size {
+                assert_eq!(*(addr as *const u8), 42);
+            }
+            let new_ptr = try_realloc(ptr, size * 4);
+            assert_ne!(new_ptr, NULL_PTR);
+            for addr in new_ptr as usize..new_ptr as usize + size {
+                assert_eq!(*(addr as *const u8), 42);
+            }
+            free(new_ptr);
+        }
+    }
+
+    #[test]
+    pub fn realloc_zero_policy() {
+        let options = config::options();
+        let previous = match options.realloc_zero() {
+            ReallocZero::Free => "free",
+            ReallocZero::Minimal => "minimal",
+        };
+        let _policy = config::TestOption::set("realloc_zero", "free", previous.to_string());
+        unsafe {
+            options.set_realloc_zero(ReallocZero::Free);
+            assert_eq!(realloc(NULL_PTR, 0), NULL_PTR);
+            let ptr = malloc(32);
+            assert_eq!(realloc(ptr, 0), NULL_PTR);
+
+            options.set_realloc_zero(ReallocZero::Minimal);
+            let min_ptr = realloc(NULL_PTR, 0);
+            assert_ne!(min_ptr, NULL_PTR);
+            let ptr = malloc(32);
+            let new_ptr = realloc(ptr, 0);
+            assert_ne!(new_ptr, NULL_PTR);
+            assert!(size_of(new_ptr).unwrap() >= 1);
+            free(min_ptr);
+            free(new_ptr);
+        }
+    }
+}
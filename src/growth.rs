@@ -0,0 +1,145 @@
+// Growth rate limit of allocator instances
+// Instances meter bytes they bump from their address spaces, which is memory newly taken from
+// the OS rather than reused from free lists, in windows of WINDOW_MS. Past `growth_limit` bytes
+// in a window, requests fail or wait for the next window according to `growth_policy`, so a bug
+// or an attack ballooning one heap cannot take memory from co-tenant processes faster than that.
+//
+// Superblocks of the small heap cannot fail to be created and always wait. The instance serving
+// the allocator itself is not limited, for its failures would take down the allocator with them.
+// Large objects of the general heap are metered together by large_heap instead, at their sizes
+// whether they are mapped or reused. A single request larger than the limit passes alone in a
+// fresh window
+
+use crate::reclaim::elapsed_ms;
+use crate::stats;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::thread;
+use std::time::Duration;
+
+pub const WINDOW_MS: usize = 1000;
+// meters pack the window number above the bytes taken in it, to start windows with a single CAS
+const BYTES_BITS: u32 = 40;
+const BYTES_MASK: u64 = (1 << BYTES_BITS) - 1;
+const WINDOW_MASK: u64 = (1 << (64 - BYTES_BITS)) - 1;
+
+// bytes per window for every instance, 0 for unlimited
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static POLICY: AtomicUsize = AtomicUsize::new(GrowthPolicy::Fail as usize);
+
+// What requests over the limit do, when they are allowed to fail
+//   fail:  fail the allocation (default)
+//   block: wait for the next window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    Fail = 0,
+    Block = 1,
+}
+
+pub struct GrowthMeter {
+    // window number and bytes taken in it, see BYTES_BITS
+    state: AtomicU64,
+}
+
+pub fn set_limit(bytes_per_window: usize) {
+    LIMIT.store(bytes_per_window, Relaxed);
+}
+
+pub fn limit() -> usize {
+    LIMIT.load(Relaxed)
+}
+
+pub fn set_policy(policy: GrowthPolicy) {
+    POLICY.store(policy as usize, Relaxed);
+}
+
+pub fn policy() -> GrowthPolicy {
+    match POLICY.load(Relaxed) {
+        0 => GrowthPolicy::Fail,
+        _ => GrowthPolicy::Block,
+    }
+}
+
+impl GrowthMeter {
+    pub fn new() -> Self {
+        Self {
+            state: AtomicU64::new(0),
+        }
+    }
+
+    // Whether the instance may grow by `size` bytes now. Waits for the window when over the limit
+    // and the request cannot fail or the policy is to block
+    #[inline]
+    pub fn admit(&self, size: usize, can_fail: bool) -> bool {
+        let limit = limit();
+        limit == 0 || self.admit_within(size, limit, can_fail)
+    }
+
+    fn admit_within(&self, size: usize, limit: usize, can_fail: bool) -> bool {
+        loop {
+            let now = elapsed_ms();
+            let window = (now / WINDOW_MS) as u64 & WINDOW_MASK;
+            let state = self.state.load(Relaxed);
+            // bytes of earlier windows are dropped by the same CAS that takes the new bytes
+            let bytes = if state >> BYTES_BITS == window {
+                (state & BYTES_MASK) as usize
+            } else {
+                0
+            };
+            if bytes + size <= limit || bytes == 0 {
+                let taken = ((bytes + size) as u64).min(BYTES_MASK);
+                let new_state = window << BYTES_BITS | taken;
+                if self.state.compare_and_swap(state, new_state, Relaxed) == state {
+                    return true;
+                }
+                continue;
+            }
+            stats::incr(&stats::THROTTLED_GROWTH);
+            if can_fail && policy() == GrowthPolicy::Fail {
+                return false;
+            }
+            thread::sleep(Duration::from_millis((WINDOW_MS - now % WINDOW_MS) as u64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::TestOption;
+    use crate::growth::*;
+    use crate::large_heap;
+
+    #[test]
+    pub fn limit_growth() {
+        let meter = GrowthMeter::new();
+        let limit = 1 << 30;
+        assert!(meter.admit_within(limit / 2, limit, true));
+        assert!(meter.admit_within(limit / 2, limit, true));
+        assert!(!meter.admit_within(1, limit, true));
+        // larger than the limit, alone in its window
+        let meter = GrowthMeter::new();
+        assert!(meter.admit_within(limit * 2, limit, true));
+        assert!(!meter.admit_within(1, limit, true));
+    }
+
+    #[test]
+    pub fn limit_large_objects() {
+        let size = 256 << 20;
+        let throttled = stats::get(&stats::THROTTLED_GROWTH);
+        let _limit = TestOption::set("growth_limit", "512M", limit().to_string());
+        // objects beyond the bump heap are mapped on their own, up to the limit in a window
+        let objects = (0..8)
+            .map(|_| unsafe { large_heap::allocate(size) })
+            .take_while(|ptr| !ptr.is_null())
+            .collect::<Vec<_>>();
+        assert!(objects.len() < 8);
+        assert!(stats::get(&stats::THROTTLED_GROWTH) > throttled);
+        // the next window admits them again
+        thread::sleep(Duration::from_millis(WINDOW_MS as u64));
+        let ptr = unsafe { large_heap::allocate(size) };
+        assert!(!ptr.is_null());
+        for ptr in objects.into_iter().chain(Some(ptr)) {
+            assert!(unsafe { large_heap::free(ptr) });
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Hardened mode for hunting memory bugs of the host program, built with the `hardened` feature
+// and switched on by the `hardened` option. Checks cost a pass over every freed object, so the
+// mode is off by default even when built in
+//
+//   - Freed small objects are filled with a canary word, derived from the address and a per
+//     process key, followed by poison bytes. Slots taken from free lists are checked to be
+//     untouched, catching writes after free
+//   - Freeing a slot still carrying its canary and poison is a double free. Slots smaller than a
+//     word have no room for a canary and are poisoned only
+//   - Freeing pointers into the middle of slots, or unknown to all heaps, is an invalid free
+//   - Pages of free slots released to the OS come back zeroed, slots on them are reused unchecked
+//   - Large objects with mappings of their own have inaccessible guard pages on both sides
+//
+// Violations abort with a message on stderr, without allocating. Objects freed before the mode is
+// switched on carry no canary and are not checked, so it is best set in NULLOC_CONF
+
+use crate::os;
+use crate::strict;
+use core::mem;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+pub const POISON_BYTE: u8 = 0xdf;
+const CANARY_SIZE: usize = mem::size_of::<usize>();
+const MESSAGE_BUFFER_SIZE: usize = 128;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CANARY_KEY: usize = os::random_key()
+        .map_or(0x5bd1_e995_9e37_79b9u64, |key| key[0] ^ key[1]) as usize;
+}
+
+// Returns false when the allocator is built without the feature
+pub fn enable(on: bool) -> bool {
+    if !cfg!(feature = "hardened") || (on && strict::is_enabled()) {
+        return false;
+    }
+    ENABLED.store(on, Relaxed);
+    true
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    cfg!(feature = "hardened") && ENABLED.load(Relaxed)
+}
+
+#[inline]
+fn canary_of(addr: usize) -> usize {
+    addr ^ *CANARY_KEY
+}
+
+#[inline]
+fn has_canary(size: usize) -> bool {
+    size >= CANARY_SIZE
+}
+
+// Fill a freed slot of `size` bytes
+pub unsafe fn poison(addr: usize, size: usize) {
+    let mut poison_from = addr;
+    if has_canary(size) {
+        *(addr as *mut usize) = canary_of(addr);
+        poison_from += CANARY_SIZE;
+    }
+    (poison_from as *mut u8).write_bytes(POISON_BYTE, addr + size - poison_from);
+}
+
+// Whether the slot carries its canary and is poisoned all over, as left by a free
+pub unsafe fn is_poisoned(addr: usize, size: usize) -> bool {
+    has_canary(size)
+        && *(addr as *const usize) == canary_of(addr)
+        && poisoned_after_canary(addr, size)
+}
+
+unsafe fn poisoned_after_canary(addr: usize, size: usize) -> bool {
+    let poisoned = (addr + CANARY_SIZE) as *const u8;
+    (0..size - CANARY_SIZE).all(|i| *poisoned.add(i) == POISON_BYTE)
+}
+
+// Check a slot about to be handed out again from a free list
+pub unsafe fn check_reuse(addr: usize, size: usize) {
+    if has_canary(size)
+        && *(addr as *const usize) == canary_of(addr)
+        && !poisoned_after_canary(addr, size)
+    {
+        violation("write after free", addr);
+    }
+}
+
+// Drop the canary of a free slot whose poison is partly lost, so it is reused unchecked
+pub unsafe fn forget(addr: usize, size: usize) {
+    if has_canary(size) {
+        *(addr as *mut usize) = 0;
+    }
+}
+
+// Check a slot of a small heap superblock being freed, `offset` from the start of the data area
+pub unsafe fn check_free(addr: usize, offset: usize, size: usize) {
+    if offset % size != 0 {
+        violation("free of a pointer inside an object", addr);
+    }
+    if is_poisoned(addr, size) {
+        violation("double free", addr);
+    }
+}
+
+#[cold]
+pub fn violation(what: &str, addr: usize) -> ! {
+    let mut buffer = [0u8; MESSAGE_BUFFER_SIZE];
+    let len = {
+        let mut cursor = &mut buffer[..];
+        let _ = write!(cursor, "nulloc: {} at {:#x}, aborting\n", what, addr);
+        MESSAGE_BUFFER_SIZE - cursor.len()
+    };
+    unsafe {
+        libc::write(2, buffer.as_ptr() as *const libc::c_void, len as _);
+        libc::abort();
+    }
+}
+
+#[cfg(all(test, feature = "hardened"))]
+mod test {
+    use crate::api::{nu_free, nu_malloc};
+    use crate::bump_heap::HEAP_VIRT_SIZE;
+    use crate::hardened::*;
+    use std::cmp::min;
+
+    #[test]
+    pub fn poisoning() {
+        let mut slot = [0usize; 4];
+        let addr = slot.as_mut_ptr() as usize;
+        let size = mem::size_of_val(&slot);
+        unsafe {
+            assert!(!is_poisoned(addr, size));
+            check_free(addr, size * 3, size);
+            poison(addr, size);
+            assert!(is_poisoned(addr, size));
+            check_reuse(addr, size);
+            *((addr + size - 1) as *mut u8) = 0;
+            assert!(!is_poisoned(addr, size));
+            // no canary to tell freed slots apart
+            poison(addr, 4);
+            assert!(!is_poisoned(addr, 4));
+        }
+    }
+
+    #[test]
+    pub fn hardened_heap() {
+        assert!(enable(true));
+        unsafe {
+            // the largest one is mapped with guard pages
+            for &size in &[2, 16, 1000, 64 << 10, 16 << 20, HEAP_VIRT_SIZE] {
+                let ptrs = (0..16).map(|_| nu_malloc(size)).collect::<Vec<_>>();
+                for ptr in &ptrs {
+                    (*ptr as *mut u8).write_bytes(42, min(size, 64 << 10));
+                }
+                for ptr in ptrs {
+                    nu_free(ptr);
+                }
+                // freed slots come back untouched
+                let ptr = nu_malloc(size);
+                (ptr as *mut u8).write_bytes(42, min(size, 64 << 10));
+                nu_free(ptr);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+// Journal of recent heap events for post-mortem analysis
+// Every thread records its last events in a ring of its own, so reports of heap corruption can
+// carry the history of operations around the failure. Rings are mapped outside of the heaps and
+// locked in memory, so they survive a corrupted heap and swapping, and `dump` writes them to a
+// file descriptor without allocating or taking locks, as crash handlers need.
+// Rings of exited threads keep their events until another thread takes the ring over, for the
+// thread that corrupted the heap may be gone by the time of the crash.
+//
+// Rings are enabled with the `journal` option, the number of events kept by each thread. Threads
+// with rings of another capacity keep them until they exit.
+//
+// Extents mapped and released by the thread are recorded too, with the reason in place of the
+// class, see extents.
+
+use crate::extents::ExtentReason;
+use crate::generic_heap::{self, size_class_index_from_size, MAXIMUM_SMALL_SIZE};
+use crate::mmap::{lock_on_fault, try_mmap_without_fd};
+use crate::utils::current_thread_id;
+use core::{mem, ptr};
+use std::cell::Cell;
+use std::io::Write;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+
+// class of objects beyond the small heap
+pub const LARGE_CLASS: usize = !0;
+// bytes of a line of the dump, formatted on stack
+const LINE_SIZE: usize = 128;
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+// all rings ever mapped, rings are never unmapped
+static RINGS: AtomicPtr<Ring> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    static THREAD_RING: RingHandle = RingHandle { ring: Cell::new(ptr::null_mut()) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalOp {
+    Malloc = 1,
+    Free = 2,
+    Map = 3,
+    Unmap = 4,
+    Release = 5,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JournalEvent {
+    // number of events of the ring before this one plus one, 0 for unused slots
+    pub seq: usize,
+    pub op: usize,
+    pub ptr: usize,
+    pub size: usize,
+    pub class: usize,
+}
+
+// Header of a ring, events follow
+#[repr(C)]
+struct Ring {
+    next: *mut Ring,
+    // thread id of the thread recording into the ring, 0 when free
+    owner: AtomicUsize,
+    // thread id of the last owner, for dumps
+    last_owner: AtomicUsize,
+    capacity: usize,
+    written: AtomicUsize,
+}
+
+struct RingHandle {
+    ring: Cell<*mut Ring>,
+}
+
+pub fn set_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Relaxed);
+    generic_heap::refresh_fast_path();
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    CAPACITY.load(Relaxed) != 0
+}
+
+#[inline]
+pub fn record(op: JournalOp, ptr: usize, size: usize) {
+    if is_enabled() {
+        let class = if size > 0 && size <= MAXIMUM_SMALL_SIZE {
+            size_class_index_from_size(size)
+        } else {
+            LARGE_CLASS
+        };
+        append(op, ptr, size, class);
+    }
+}
+
+#[inline]
+pub fn record_extent(op: JournalOp, ptr: usize, size: usize, reason: usize) {
+    if is_enabled() {
+        append(op, ptr, size, reason);
+    }
+}
+
+#[inline(never)]
+fn append(op: JournalOp, ptr: usize, size: usize, class: usize) {
+    let _ = THREAD_RING.try_with(|handle| {
+        let ring = match handle.ring() {
+            Some(ring) => ring,
+            None => return,
+        };
+        let seq = ring.written.load(Relaxed) + 1;
+        unsafe {
+            let event = ring.event((seq - 1) % ring.capacity);
+            // readers discard events with the sequence changed while they were read
+            ptr::write_volatile(&mut (*event).seq, 0);
+            *event = JournalEvent {
+                seq: 0,
+                op: op as usize,
+                ptr,
+                size,
+                class,
+            };
+            ptr::write_volatile(&mut (*event).seq, seq);
+        }
+        ring.written.store(seq, Release);
+    });
+}
+
+// Write the journal of all threads to the file descriptor, oldest event first for every ring
+pub fn dump(fd: libc::c_int) {
+    dump_with(|line| unsafe {
+        libc::write(fd, line.as_ptr() as *const libc::c_void, line.len() as _);
+    });
+}
+
+pub fn dump_with<F: FnMut(&[u8])>(mut sink: F) {
+    let mut ring_ptr = RINGS.load(Acquire);
+    while !ring_ptr.is_null() {
+        let ring = unsafe { &*ring_ptr };
+        let written = ring.written.load(Acquire);
+        let start = written.saturating_sub(ring.capacity);
+        write_line(&mut sink, |line| {
+            write!(
+                line,
+                "journal of thread {:#x}{}, {} events\n",
+                ring.last_owner.load(Relaxed),
+                if ring.owner.load(Relaxed) == 0 { " (exited)" } else { "" },
+                written
+            )
+        });
+        for seq in start + 1..=written {
+            let event = unsafe { ptr::read_volatile(ring.event((seq - 1) % ring.capacity)) };
+            if event.seq != seq {
+                // overwritten while dumping
+                continue;
+            }
+            write_line(&mut sink, |line| {
+                let op = match event.op {
+                    1 => "malloc",
+                    2 => "free",
+                    3 => "map",
+                    4 => "unmap",
+                    5 => "release",
+                    _ => "unknown",
+                };
+                if event.op >= JournalOp::Map as usize {
+                    let reason = ExtentReason::from_usize(event.class);
+                    write!(
+                        line,
+                        "  {} {} {:#x} {} for {}\n",
+                        seq,
+                        op,
+                        event.ptr,
+                        event.size,
+                        reason.map_or("unknown", |reason| reason.name())
+                    )
+                } else if event.class == LARGE_CLASS {
+                    write!(line, "  {} {} {:#x} {} large\n", seq, op, event.ptr, event.size)
+                } else {
+                    write!(
+                        line,
+                        "  {} {} {:#x} {} class {}\n",
+                        seq, op, event.ptr, event.size, event.class
+                    )
+                }
+            });
+        }
+        ring_ptr = ring.next;
+    }
+}
+
+pub fn write_line<S, F>(sink: &mut S, format: F)
+where
+    S: FnMut(&[u8]),
+    F: FnOnce(&mut &mut [u8]) -> std::io::Result<()>,
+{
+    let mut buffer = [0u8; LINE_SIZE];
+    let len = {
+        let mut line = &mut buffer[..];
+        let _ = format(&mut line);
+        LINE_SIZE - line.len()
+    };
+    sink(&buffer[..len]);
+}
+
+impl RingHandle {
+    #[inline]
+    fn ring(&self) -> Option<&Ring> {
+        let ring = self.ring.get();
+        if !ring.is_null() {
+            return Some(unsafe { &*ring });
+        }
+        let ring = Ring::acquire(CAPACITY.load(Relaxed))?;
+        self.ring.set(ring);
+        Some(unsafe { &*ring })
+    }
+}
+
+impl Drop for RingHandle {
+    fn drop(&mut self) {
+        let ring = self.ring.get();
+        if !ring.is_null() {
+            unsafe { &*ring }.owner.store(0, Release);
+        }
+    }
+}
+
+impl Ring {
+    // Take over a free ring of the capacity, or map a new one
+    fn acquire(capacity: usize) -> Option<*mut Ring> {
+        if capacity == 0 {
+            return None;
+        }
+        let tid = current_thread_id();
+        let mut ring_ptr = RINGS.load(Acquire);
+        while !ring_ptr.is_null() {
+            let ring = unsafe { &*ring_ptr };
+            if ring.capacity == capacity && ring.owner.compare_and_swap(0, tid, Acquire) == 0 {
+                ring.last_owner.store(tid, Relaxed);
+                ring.written.store(0, Release);
+                return Some(ring_ptr);
+            }
+            ring_ptr = ring.next;
+        }
+        let size = mem::size_of::<Ring>() + capacity * mem::size_of::<JournalEvent>();
+        let ring_ptr = try_mmap_without_fd(size)? as *mut Ring;
+        lock_on_fault(ring_ptr as _, size);
+        unsafe {
+            ptr::write(
+                ring_ptr,
+                Ring {
+                    next: ptr::null_mut(),
+                    owner: AtomicUsize::new(tid),
+                    last_owner: AtomicUsize::new(tid),
+                    capacity,
+                    written: AtomicUsize::new(0),
+                },
+            );
+            loop {
+                let head = RINGS.load(Relaxed);
+                (*ring_ptr).next = head;
+                if RINGS.compare_and_swap(head, ring_ptr, Release) == head {
+                    break;
+                }
+            }
+        }
+        Some(ring_ptr)
+    }
+
+    #[inline]
+    fn event(&self, index: usize) -> *mut JournalEvent {
+        let events = (self as *const Self as usize + mem::size_of::<Self>()) as *mut JournalEvent;
+        unsafe { events.add(index) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::journal::*;
+
+    #[test]
+    pub fn ring_of_thread() {
+        let ring = Ring::acquire(4).unwrap();
+        let ring_ref = unsafe { &*ring };
+        assert_eq!(ring_ref.owner.load(Relaxed), current_thread_id());
+        // taken, another thread maps another ring
+        let other = Ring::acquire(4).unwrap();
+        assert_ne!(other, ring);
+        ring_ref.owner.store(0, Relaxed);
+        assert_eq!(Ring::acquire(4), Some(ring));
+        for ring in &[ring, other] {
+            unsafe { &**ring }.owner.store(0, Relaxed);
+        }
+    }
+
+    #[test]
+    pub fn dump_events() {
+        set_capacity(4);
+        for i in 1..=6 {
+            record(JournalOp::Malloc, 0x1000 * i, 16 * i);
+        }
+        record(JournalOp::Free, 0x6000, 1 << 20);
+        record_extent(JournalOp::Map, 0x8000, 1 << 20, ExtentReason::LargeAlloc as usize);
+        let mut dump = vec![];
+        dump_with(|line| dump.extend_from_slice(line));
+        let dump = String::from_utf8(dump).unwrap();
+        let tid = current_thread_id();
+        let journal = dump
+            .split("journal of thread ")
+            .find(|ring| ring.starts_with(&format!("{:#x},", tid)))
+            .unwrap();
+        let lines = journal.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], format!("{:#x}, 8 events", tid));
+        assert_eq!(
+            &lines[1..],
+            &[
+                "  5 malloc 0x5000 80 class 6",
+                "  6 malloc 0x6000 96 class 6",
+                "  7 free 0x6000 1048576 large",
+                "  8 map 0x8000 1048576 for large alloc",
+            ]
+        );
+    }
+}
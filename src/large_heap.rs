@@ -1,28 +1,177 @@
 // Heap for large objects exceeds maximum tier of pages
 // Use bump heap
 
-use crate::mmap_heap::MmapAllocator;
+use crate::extents::{self, ExtentOp, ExtentReason};
+use crate::growth::GrowthMeter;
+use crate::hardened;
+use crate::mmap::bind_to_node;
+use crate::mmap_heap::{GuardedMmapAllocator, MmapAllocator, NodeMmapAllocator};
+use crate::quarantine;
+use crate::reclaim;
+use crate::stats;
 use crate::utils::align_padding;
-use crate::utils::SYS_PAGE_SIZE;
-use crate::Ptr;
+use crate::utils::{reinit_map_after_fork, AddressHasher, NUM_NUMA_NODES, SYS_PAGE_SIZE};
+use crate::{Ptr, NULL_PTR};
 use core::alloc::{Alloc, Layout};
+use core::ptr::NonNull;
+use lfmap::Map;
+
+// flag of objects mapped with guard pages, on the lowest bit of their sizes which are whole pages
+const GUARDED_OBJECT: usize = 1;
+// pages to look back for the start of an object from a guard page past its end
+const GUARD_SCAN_PAGES: usize = 1 << 20;
+
+lazy_static! {
+    // sizes of objects with mappings of their own, beyond the bump heap or on NUMA nodes
+    static ref MAPPED_OBJECTS: lfmap::WordMap<MmapAllocator, AddressHasher> =
+        lfmap::WordMap::<MmapAllocator, AddressHasher>::with_capacity(64);
+    // growth of large objects, for their instance in the bump heap is not limited, see growth
+    static ref GROWTH: GrowthMeter = GrowthMeter::new();
+}
 
 pub unsafe fn allocate(size: usize) -> Ptr {
     let page_size = *SYS_PAGE_SIZE;
     let padding = align_padding(size, page_size);
     let total_size = size + padding;
-    if total_size < crate::bump_heap::HEAP_VIRT_SIZE {
+    if !GROWTH.admit(total_size, true) {
+        NULL_PTR
+    } else if total_size < crate::bump_heap::HEAP_VIRT_SIZE {
         crate::bump_heap::malloc(total_size)
     } else {
-        let mut ma = MmapAllocator;
-        ma.alloc(Layout::from_size_align(size, 1).unwrap())
-            .unwrap()
-            .as_ptr() as Ptr
+        map_object(total_size, None)
+    }
+}
+// Objects aligned beyond a cache line. Beyond the bump heap only mmap can serve them, which
+// aligns to pages
+pub unsafe fn allocate_aligned(size: usize, align: usize) -> Ptr {
+    let page_size = *SYS_PAGE_SIZE;
+    let total_size = size + align_padding(size, page_size);
+    let fits_bump_heap = total_size
+        .checked_add(align)
+        .map_or(false, |size| size < crate::bump_heap::HEAP_VIRT_SIZE);
+    if fits_bump_heap {
+        if !GROWTH.admit(total_size, true) {
+            return NULL_PTR;
+        }
+        crate::bump_heap::aligned_malloc(total_size, align)
+    } else if align <= page_size {
+        allocate(size)
+    } else {
+        NULL_PTR
     }
 }
+// Objects on a NUMA node have mappings of their own, for address spaces of the bump heap are
+// shared by all nodes. Nothing to bind on single node machines
+pub unsafe fn allocate_on_node(size: usize, node: u16) -> Ptr {
+    let total_size = size + align_padding(size, *SYS_PAGE_SIZE);
+    if !GROWTH.admit(total_size, true) {
+        return NULL_PTR;
+    }
+    let node = if *NUM_NUMA_NODES > 1 { Some(node) } else { None };
+    map_object(total_size, node)
+}
+// Objects of whole pages mapped on their own. Guard pages in hardened mode, the mappings are
+// bound to the node best effort then
+unsafe fn map_object(size: usize, node: Option<u16>) -> Ptr {
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    let guarded = hardened::is_enabled();
+    let ptr = reclaim::retry_on_oom(|| {
+        let res = match node {
+            _ if guarded => GuardedMmapAllocator.alloc(layout).map(|ptr| {
+                if let Some(node) = node {
+                    bind_to_node(ptr.as_ptr() as Ptr, size, node);
+                }
+                ptr
+            }),
+            Some(node) => NodeMmapAllocator { node }.alloc(layout),
+            None => MmapAllocator.alloc(layout),
+        };
+        res.map(|ptr| ptr.as_ptr() as Ptr).ok()
+    })
+    .unwrap_or(NULL_PTR);
+    if ptr != NULL_PTR {
+        let flags = if guarded { GUARDED_OBJECT } else { 0 };
+        MAPPED_OBJECTS.insert(ptr as usize, size | flags);
+        extents::record(ExtentOp::Map, ExtentReason::LargeAlloc, ptr as usize, size);
+        stats::incr(&stats::LARGE_OBJECTS);
+        stats::add(&stats::LARGE_BYTES, size);
+    }
+    ptr
+}
 pub unsafe fn free(ptr: Ptr) -> bool {
-    crate::bump_heap::free(ptr)
+    if crate::bump_heap::free(ptr) {
+        return true;
+    }
+    if let Some(entry) = MAPPED_OBJECTS.remove(ptr as usize) {
+        let size = entry & !GUARDED_OBJECT;
+        let unmap: quarantine::Unmap = if entry & GUARDED_OBJECT != 0 {
+            unmap_guarded_object
+        } else {
+            unmap_object
+        };
+        // stale pointers fault until the quarantine is over
+        if !quarantine::hold(ptr as usize, size, unmap) {
+            unmap(ptr as usize, size);
+        }
+        stats::sub(&stats::LARGE_OBJECTS, 1);
+        stats::sub(&stats::LARGE_BYTES, size);
+        return true;
+    }
+    false
+}
+// Take an object with a mapping of its own out of the general heap, along with its size, for an
+// arena to own it. None for objects of the bump heap and guarded objects, which stay
+pub fn take_mapped(ptr: Ptr) -> Option<usize> {
+    let entry = MAPPED_OBJECTS.get(ptr as usize)?;
+    if entry & GUARDED_OBJECT != 0 {
+        return None;
+    }
+    MAPPED_OBJECTS.remove(ptr as usize)?;
+    stats::sub(&stats::LARGE_OBJECTS, 1);
+    stats::sub(&stats::LARGE_BYTES, entry);
+    Some(entry)
+}
+
+unsafe fn unmap_object(addr: usize, size: usize) {
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    MmapAllocator.dealloc(NonNull::new(addr as *mut u8).unwrap(), layout);
+    extents::record(ExtentOp::Unmap, ExtentReason::LargeAlloc, addr, size);
+}
+
+unsafe fn unmap_guarded_object(addr: usize, size: usize) {
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    GuardedMmapAllocator.dealloc(NonNull::new(addr as *mut u8).unwrap(), layout);
+    extents::record(ExtentOp::Unmap, ExtentReason::LargeAlloc, addr, size);
+}
+
+// Object with a guard page at the address, and its size. Only looks up objects, so it can be
+// called from signal handlers
+pub fn guarded_object_of(addr: usize) -> Option<(usize, usize)> {
+    let page_size = *SYS_PAGE_SIZE;
+    let page = addr - addr % page_size;
+    let guarded = |start: usize| {
+        MAPPED_OBJECTS
+            .get(start)
+            .filter(|entry| entry & GUARDED_OBJECT != 0)
+            .map(|entry| (start, entry & !GUARDED_OBJECT))
+    };
+    // guard before the start
+    if let Some(object) = guarded(page + page_size) {
+        return Some(object);
+    }
+    // guard past the end
+    (1..=GUARD_SCAN_PAGES)
+        .map(|pages| pages * page_size)
+        .take_while(|&offset| offset <= page)
+        .filter_map(|offset| guarded(page - offset))
+        .find(|&(start, size)| start + size == page)
+}
+
+// Only for the child process after fork
+pub unsafe fn reinit_after_fork() {
+    reinit_map_after_fork(&MAPPED_OBJECTS);
 }
 pub fn size_of(ptr: Ptr) -> Option<usize> {
     crate::bump_heap::size_of(ptr)
+        .or_else(|| MAPPED_OBJECTS.get(ptr as usize).map(|entry| entry & !GUARDED_OBJECT))
 }
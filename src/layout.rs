@@ -0,0 +1,100 @@
+// Deterministic address space layout
+// By default extents are mapped wherever ASLR decides. With a fixed layout base, extents are
+// placed at increasing addresses from the base in mapping order, so record-replay debuggers and
+// CRIU restores find the heap at identical addresses across runs. Placements are recorded in a
+// manifest that can be dumped for restore tooling.
+
+use crate::collections::fixvec::FixedVec;
+use crate::mmap_heap::MmapAllocator;
+use crate::utils::{align_padding, SYS_PAGE_SIZE};
+use crate::Ptr;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+pub const PLACEMENT_ATTEMPTS: usize = 64;
+const MAX_MANIFEST_ENTRIES: usize = 4096;
+
+// zero means ASLR placement
+static LAYOUT_BASE: AtomicUsize = AtomicUsize::new(0);
+static LAYOUT_CURSOR: AtomicUsize = AtomicUsize::new(0);
+static MANIFEST_LEN: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref MANIFEST: FixedVec<ManifestEntry, MmapAllocator> =
+        FixedVec::new(MAX_MANIFEST_ENTRIES);
+}
+
+// all zero for empty entry, size of zero for unmapped extent
+struct ManifestEntry {
+    addr: AtomicUsize,
+    size: AtomicUsize,
+}
+
+pub fn set_fixed_base(base: usize) {
+    let base = base + align_padding(base, *SYS_PAGE_SIZE);
+    // manifest itself is mapped before the mode is on, or recording it will recurse
+    lazy_static::initialize(&MANIFEST);
+    LAYOUT_CURSOR.store(base, Relaxed);
+    LAYOUT_BASE.store(base, Relaxed);
+}
+
+#[inline]
+pub fn is_fixed() -> bool {
+    LAYOUT_BASE.load(Relaxed) != 0
+}
+
+// Reserve the next address range for an extent of the size
+pub fn next_placement(size: usize) -> usize {
+    let size = size + align_padding(size, *SYS_PAGE_SIZE);
+    LAYOUT_CURSOR.fetch_add(size, Relaxed)
+}
+
+pub fn record_map(addr: Ptr, size: usize) {
+    let index = MANIFEST_LEN.fetch_add(1, Relaxed);
+    if index >= MAX_MANIFEST_ENTRIES {
+        warn!("Layout manifest is full, extent at {:x} is not recorded", addr as usize);
+        return;
+    }
+    let entry = &MANIFEST[index];
+    entry.size.store(size, Relaxed);
+    entry.addr.store(addr as usize, Relaxed);
+}
+
+pub fn record_unmap(addr: Ptr, size: usize) {
+    if !is_fixed() {
+        return;
+    }
+    let len = manifest_len();
+    for i in 0..len {
+        let entry = &MANIFEST[i];
+        if entry.addr.load(Relaxed) == addr as usize && entry.size.load(Relaxed) == size {
+            entry.size.store(0, Relaxed);
+            return;
+        }
+    }
+}
+
+// Write live extents, one `address size` pair in hex per line
+pub fn write_manifest<W: Write>(writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "# base {:#x}", LAYOUT_BASE.load(Relaxed))?;
+    for i in 0..manifest_len() {
+        let entry = &MANIFEST[i];
+        let addr = entry.addr.load(Relaxed);
+        let size = entry.size.load(Relaxed);
+        if addr != 0 && size != 0 {
+            writeln!(writer, "{:#x} {:#x}", addr, size)?;
+        }
+    }
+    Ok(())
+}
+
+fn manifest_len() -> usize {
+    let len = MANIFEST_LEN.load(Relaxed);
+    if len > MAX_MANIFEST_ENTRIES {
+        MAX_MANIFEST_ENTRIES
+    } else {
+        len
+    }
+}
@@ -15,15 +15,47 @@ extern crate crossbeam;
 extern crate libc;
 extern crate test;
 
+#[macro_use]
+mod probes;
+
 pub mod api;
+pub mod arena;
+mod audit;
+pub mod boxed;
 mod bump_heap;
+pub mod checkpoint;
+pub mod config;
+#[cfg(unix)]
+mod crash;
+mod extents;
+mod fork;
 mod generic_heap;
+mod growth;
+mod hardened;
+mod journal;
 mod large_heap;
+mod layout;
 mod mmap;
 mod mmap_heap;
+mod nursery;
+mod partition;
+mod os;
+mod perf_map;
+mod profile;
+mod quarantine;
 mod rand;
+mod reclaim;
+mod sampling;
+#[cfg(unix)]
+mod shared_stats;
 mod small_heap;
+mod stats;
+mod strict;
+mod tenant;
+mod thread_mode;
+mod tuning;
 mod utils;
+mod validate;
 
 mod collections;
 
@@ -35,32 +67,172 @@ pub const NULL_PTR: *mut c_void = NULL as *mut c_void;
 
 use crate::api::SkyhooksAllocator;
 use crate::bump_heap::BumpAllocator;
+use crate::stats::StatsSnapshot;
 use core::ffi::c_void;
+use errno::{set_errno, Errno};
+use libc::{c_char, c_int, ENOENT, ENOMEM};
+use std::ffi::CStr;
+
+// C ABI of the allocator, load the cdylib with LD_PRELOAD to replace malloc of a program
+//
+// Allocations of the allocator itself, including lazy initialization of the heaps on the first
+// call, go to the bump heap through the global allocator below and never call back into these
+// functions. Anything libc allocates while we are inside an allocation, such as when reading
+// CPU topology, is served by the bump heap according to INNER_CALL. Such objects can be freed
+// and reallocated through these functions as usual
 
 #[no_mangle]
-pub unsafe fn malloc(size: Size) -> Ptr {
-    api::nu_malloc(size)
+pub unsafe extern "C" fn malloc(size: Size) -> Ptr {
+    match validate::size(size) {
+        Ok(size) => api::nu_malloc(size),
+        Err(err) => fail(err),
+    }
 }
 
 #[no_mangle]
-pub unsafe fn free(ptr: Ptr) {
+pub unsafe extern "C" fn free(ptr: Ptr) {
     api::nu_free(ptr)
 }
 
 #[no_mangle]
-pub unsafe fn calloc(nmemb: Size, size: Size) -> Ptr {
-    api::nu_calloc(nmemb, size)
+pub unsafe extern "C" fn calloc(nmemb: Size, size: Size) -> Ptr {
+    match validate::array(nmemb, size) {
+        Ok(_) => api::nu_calloc(nmemb, size),
+        Err(err) => fail(err),
+    }
 }
 
 #[no_mangle]
-pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
-    api::nu_realloc(ptr, size)
+pub unsafe extern "C" fn realloc(ptr: Ptr, size: Size) -> Ptr {
+    match validate::size(size) {
+        Ok(size) => api::nu_realloc(ptr, size),
+        Err(err) => fail(err),
+    }
 }
 
-//#[global_allocator]
-//#[cfg(not(feature = "bump_heap_only"))]
-//static INNER_ALLOCATOR: SkyhooksAllocator = SkyhooksAllocator;
-//
-//#[cfg(feature = "bump_heap_only")]
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(memptr: *mut Ptr, align: Size, size: Size) -> c_int {
+    match validate::posix_alignment(align).and_then(|_| validate::size(size)) {
+        Ok(size) => api::nu_posix_memalign(memptr, align, size),
+        Err(err) => err,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: Size, size: Size) -> Ptr {
+    match validate::alignment(align).and_then(|_| validate::size(size)) {
+        Ok(size) => api::nu_aligned_alloc(align, size),
+        Err(err) => fail(err),
+    }
+}
+
+// Libcs route strdup through malloc internally, static binaries and some libcs do not
+#[no_mangle]
+pub unsafe extern "C" fn strdup(s: *const c_char) -> *mut c_char {
+    let copy = api::nu_strdup(s);
+    if copy.is_null() {
+        set_errno(Errno(ENOMEM));
+    }
+    copy
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn strndup(s: *const c_char, n: Size) -> *mut c_char {
+    let copy = api::nu_strndup(s, n);
+    if copy.is_null() {
+        set_errno(Errno(ENOMEM));
+    }
+    copy
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: Ptr) -> Size {
+    api::nu_malloc_usable_size(ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_trim(pad: Size) -> c_int {
+    api::nu_malloc_trim(pad)
+}
+
+// Fill the snapshot of allocator counters, see stats::StatsSnapshot for the layout
+#[no_mangle]
+pub unsafe extern "C" fn nu_stats_snapshot(snapshot: *mut StatsSnapshot) {
+    if !snapshot.is_null() {
+        api::nu_stats_snapshot(&mut *snapshot);
+    }
+}
+
+// Run the control command named by the C string, returns 0 or ENOENT for unknown commands like
+// mallctl, see api::nu_ctl
+#[no_mangle]
+pub unsafe extern "C" fn nu_ctl(name: *const c_char) -> c_int {
+    if name.is_null() {
+        return ENOENT;
+    }
+    match CStr::from_ptr(name).to_str() {
+        Ok(name) if api::nu_ctl(name) => 0,
+        _ => ENOENT,
+    }
+}
+
+// Version of the library as a C string, see api::nu_version
+#[no_mangle]
+pub extern "C" fn nu_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+// 1 when the feature named by the C string is supported, 0 otherwise, see api::nu_has_feature
+#[no_mangle]
+pub unsafe extern "C" fn nu_has_feature(name: *const c_char) -> c_int {
+    if name.is_null() {
+        return 0;
+    }
+    match CStr::from_ptr(name).to_str() {
+        Ok(name) if api::nu_has_feature(name) => 1,
+        _ => 0,
+    }
+}
+
+// Allocate from the partition named by the C string, see api::nu_partition_malloc
+#[no_mangle]
+pub unsafe extern "C" fn nu_partition_malloc(name: *const c_char, size: Size) -> Ptr {
+    if name.is_null() {
+        return fail(libc::EINVAL);
+    }
+    let size = match validate::size(size) {
+        Ok(size) => size,
+        Err(err) => return fail(err),
+    };
+    match CStr::from_ptr(name).to_str() {
+        Ok(name) => match api::nu_partition_malloc(name, size) {
+            ptr if ptr.is_null() && size != 0 => fail(ENOMEM),
+            ptr => ptr,
+        },
+        Err(_) => fail(libc::EINVAL),
+    }
+}
+
+// Free the `n` objects of the array at once, see api::nu_free_batch
+#[no_mangle]
+pub unsafe extern "C" fn nu_free_batch(ptrs: *const Ptr, n: Size) {
+    api::nu_free_batch(ptrs, n)
+}
+
+#[cold]
+fn fail(err: c_int) -> Ptr {
+    set_errno(Errno(err));
+    NULL_PTR
+}
+
+// Rust allocations of the allocator itself go to the bump heap, unless the `global` feature
+// installs the general heap for the whole program. In that case allocations made while the heap
+// is initializing or otherwise inside the allocator are routed to the bump heap by INNER_CALL,
+// so binaries only need to enable the feature and shall not declare another global allocator
+#[cfg(feature = "global")]
+#[global_allocator]
+static INNER_ALLOCATOR: SkyhooksAllocator = SkyhooksAllocator;
+
+#[cfg(not(feature = "global"))]
 #[global_allocator]
 static INNER_ALLOCATOR: BumpAllocator = BumpAllocator;
@@ -1,63 +1,244 @@
 use super::*;
-use core::ptr;
+use crate::layout;
+use crate::os::{self, MapError};
+use crate::stats;
+use crate::strict;
+use crate::utils::{align_padding, SYS_PAGE_SIZE};
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
 use errno::errno;
-use libc::*;
 
-const MADV_NOHUGEPAGE: c_int = 14;
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+// Page size for mappings of at least the huge page threshold
+//   off:     regular pages, transparent huge pages are disabled for all mappings (default)
+//   madvise: transparent huge pages, mappings are aligned to huge pages
+//   hugetlb: pages from the hugetlb pool for mappings of whole huge pages, transparent huge
+//            pages for the others or when the pool is exhausted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    Off = 0,
+    Madvise = 1,
+    HugeTlb = 2,
+}
+
+static HUGE_PAGES: AtomicUsize = AtomicUsize::new(HugePages::Off as usize);
+static HUGE_PAGE_THRESHOLD: AtomicUsize = AtomicUsize::new(HUGE_PAGE_SIZE);
+
+pub fn set_huge_pages(mode: HugePages) {
+    HUGE_PAGES.store(mode as usize, Relaxed);
+}
+
+pub fn huge_pages() -> HugePages {
+    match HUGE_PAGES.load(Relaxed) {
+        0 => HugePages::Off,
+        1 => HugePages::Madvise,
+        _ => HugePages::HugeTlb,
+    }
+}
+
+pub fn set_huge_page_threshold(threshold: usize) {
+    HUGE_PAGE_THRESHOLD.store(threshold, Relaxed);
+}
+
+#[inline]
+fn huge_pages_for(size: usize) -> HugePages {
+    if size < HUGE_PAGE_THRESHOLD.load(Relaxed) {
+        HugePages::Off
+    } else {
+        huge_pages()
+    }
+}
 
 pub fn mmap_without_fd(size: usize) -> Ptr {
-    let ptr = unsafe {
-        mmap(
-            ptr::null_mut(),
-            size as size_t,
-            PROT_READ | PROT_WRITE,
-            MAP_ANONYMOUS | MAP_PRIVATE,
-            -1,
-            0,
-        )
-    };
-    if ptr == -1 as isize as *mut c_void {
+    expect_mapped(try_mmap_without_fd(size))
+}
+
+pub fn reserve_without_fd(size: usize) -> Ptr {
+    expect_mapped(try_reserve_without_fd(size))
+}
+
+fn expect_mapped(ptr: Option<Ptr>) -> Ptr {
+    if let Some(ptr) = ptr {
+        ptr
+    } else {
         let err = errno();
         panic!("mmap failed: [{}] {}", err.0, err);
+    }
+}
+
+pub fn try_mmap_without_fd(size: usize) -> Option<Ptr> {
+    let ptr = try_reserve_without_fd(size)?;
+    if !commit_memory(ptr, size) {
+        munmap_memory(ptr, size);
+        return None;
+    }
+    Some(ptr)
+}
+
+// Mapping whose pages need commit_memory before use where the system does not commit them on
+// first touch, so address spaces of the heaps count against the commit limit as they fill up
+pub fn try_reserve_without_fd(size: usize) -> Option<Ptr> {
+    let huge = huge_pages_for(size);
+    let ptr = if layout::is_fixed() {
+        mmap_fixed_layout(size)?
+    } else if huge != HugePages::Off {
+        mmap_huge(size, huge)?
+    } else {
+        os::map(size)?
     };
-    no_huge_page(ptr, size);
-    ptr
+    if huge == HugePages::Off {
+        no_huge_page(ptr, size);
+    } else if layout::is_fixed() {
+        huge_page(ptr, size);
+    }
+    stats::incr(&stats::MAPPED_SEGMENTS);
+    stats::add(&stats::MAPPED_BYTES, size);
+    Some(ptr)
 }
 
-pub fn munmap_memory(address: Ptr, size: usize) {
-    unsafe {
-        munmap(address, size as usize);
+// Mapping backed by a memory file, which mmap_file_private maps again copy-on-write. Such
+// mappings are shared, pages are not released by decommitting them but with the file
+pub fn mmap_file(size: usize) -> Option<(Ptr, i32)> {
+    let (ptr, fd) = os::map_file(size)?;
+    stats::incr(&stats::MAPPED_SEGMENTS);
+    stats::add(&stats::MAPPED_BYTES, size);
+    Some((ptr, fd as i32))
+}
+
+pub fn mmap_file_private(fd: i32, size: usize) -> Option<Ptr> {
+    let ptr = os::map_file_private(fd as _, size)?;
+    stats::incr(&stats::MAPPED_SEGMENTS);
+    stats::add(&stats::MAPPED_BYTES, size);
+    Some(ptr)
+}
+
+pub fn close_file(fd: i32) {
+    os::close_file(fd as _);
+}
+
+fn mmap_fixed_layout(size: usize) -> Option<Ptr> {
+    for _ in 0..layout::PLACEMENT_ATTEMPTS {
+        let hint = layout::next_placement(size);
+        match os::map_at(hint as Ptr, size) {
+            Ok(ptr) => {
+                layout::record_map(ptr, size);
+                return Some(ptr);
+            }
+            Err(MapError::Failed) => return None,
+            // occupied by other mappings, try next placement
+            Err(MapError::Occupied) => {}
+        }
     }
+    warn!("Cannot place extent of {} bytes in fixed layout", size);
+    None
 }
 
-#[cfg(target_os = "linux")]
-#[inline]
-pub fn no_huge_page(ptr: Ptr, size: usize) {
-    unsafe {
-        madvise(ptr, size, MADV_NOHUGEPAGE);
+// Map at a huge page boundary, so superblocks carved from the start of the mapping are backed by
+// huge pages as well
+fn mmap_huge(size: usize, mode: HugePages) -> Option<Ptr> {
+    if mode == HugePages::HugeTlb && size % HUGE_PAGE_SIZE == 0 {
+        if let Some(ptr) = os::map_huge_tlb(size) {
+            return Some(ptr);
+        }
     }
+    let ptr = os::map_aligned(size, HUGE_PAGE_SIZE)?;
+    huge_page(ptr, size);
+    Some(ptr)
 }
 
-#[cfg(not(target_os = "linux"))]
 #[inline]
-pub fn no_huge_page(ptr: Ptr, size: usize) {}
+pub fn commit_memory(ptr: Ptr, size: usize) -> bool {
+    os::commit(ptr, size)
+}
+
+pub fn munmap_memory(address: Ptr, size: usize) {
+    os::unmap(address, size);
+    stats::sub(&stats::MAPPED_SEGMENTS, 1);
+    stats::sub(&stats::MAPPED_BYTES, size);
+    layout::record_unmap(address, size);
+}
 
-#[cfg(target_os = "linux")]
 #[inline]
-pub fn dealloc_regional(addr: Ptr, size: usize) -> usize {
-    unsafe { madvise(addr, size, MADV_FREE) as usize }
+pub fn no_huge_page(ptr: Ptr, size: usize) {
+    os::advise_huge_pages(ptr, size, false);
+}
+
+#[inline]
+pub fn huge_page(ptr: Ptr, size: usize) {
+    os::advise_huge_pages(ptr, size, true);
+}
+
+// Exclude the region from core dumps
+pub fn dont_dump(ptr: Ptr, size: usize) -> bool {
+    os::advise_dump(ptr, size, false)
+}
+
+// Keep pages of the region from being swapped out once they are touched
+// Locking on fault does not commit the whole region up front
+pub fn lock_on_fault(ptr: Ptr, size: usize) -> bool {
+    !strict::is_enabled() && os::lock_on_fault(ptr, size)
+}
+
+// Place pages of the region on the NUMA node when they are touched. Only effective for pages not
+// touched yet, such as fresh mappings. Without the NUMA system calls everything is on node 0
+pub fn bind_to_node(ptr: Ptr, size: usize, node: u16) -> bool {
+    if stats::get(&stats::NUMA_DEGRADED) != 0 {
+        return node == 0;
+    }
+    !strict::is_enabled() && os::bind_to_node(ptr, size, node)
+}
+
+// Same as bind_to_node, and migrate pages of the region touched already to the node
+pub fn move_to_node(ptr: Ptr, size: usize, node: u16) -> bool {
+    if stats::get(&stats::NUMA_DEGRADED) != 0 {
+        return node == 0;
+    }
+    !strict::is_enabled() && os::move_to_node(ptr, size, node)
+}
+
+// Make pages of the region inaccessible, as guards around mappings
+pub fn guard_pages(ptr: Ptr, size: usize) -> bool {
+    os::forbid_access(ptr, size)
+}
+
+// Release all whole pages inside the region, returns the number of bytes released
+pub fn dealloc_pages_within(addr: Ptr, size: usize) -> usize {
+    if let Some((start, len)) = pages_within(addr, size) {
+        dealloc_regional(start, len);
+        len
+    } else {
+        0
+    }
+}
+
+// Exclude (or include back) all whole pages inside the region from core dumps
+pub fn dump_pages_within(addr: Ptr, size: usize, dump: bool) {
+    if let Some((start, len)) = pages_within(addr, size) {
+        os::advise_dump(start, len, dump);
+    }
+}
+
+fn pages_within(addr: Ptr, size: usize) -> Option<(Ptr, usize)> {
+    let page_size = *SYS_PAGE_SIZE;
+    let start = addr as usize + align_padding(addr as usize, page_size);
+    let end = (addr as usize + size) & !(page_size - 1);
+    if end <= start {
+        None
+    } else {
+        Some((start as Ptr, end - start))
+    }
 }
 
-#[cfg(not(target_os = "linux"))]
 #[inline]
 pub fn dealloc_regional(addr: Ptr, size: usize) -> usize {
-    unsafe { madvise(addr, size, MADV_DONTNEED) as usize }
+    os::decommit(addr, size)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::mmap::mmap_without_fd;
-    use core::mem;
+    use crate::mmap::*;
+    use core::{mem, ptr};
 
     #[test]
     pub fn mmap() {
@@ -67,4 +248,22 @@ mod test {
         }
         assert_eq!(val, 99);
     }
+
+    #[test]
+    pub fn huge_page_alignment() {
+        set_huge_pages(HugePages::Madvise);
+        let size = HUGE_PAGE_SIZE * 3 + 4096;
+        let ptr = try_mmap_without_fd(size).unwrap();
+        set_huge_pages(HugePages::Off);
+        assert_eq!(align_padding(ptr as usize, HUGE_PAGE_SIZE), 0);
+        unsafe {
+            ptr::write_bytes(ptr as *mut u8, 1, size);
+        }
+        munmap_memory(ptr, size);
+        // below the threshold
+        set_huge_pages(HugePages::HugeTlb);
+        let ptr = try_mmap_without_fd(4096).unwrap();
+        set_huge_pages(HugePages::Off);
+        munmap_memory(ptr, 4096);
+    }
 }
@@ -1,4 +1,5 @@
-use crate::mmap::{mmap_without_fd, munmap_memory};
+use crate::mmap::{bind_to_node, guard_pages, munmap_memory, try_mmap_without_fd};
+use crate::utils::{align_padding, SYS_PAGE_SIZE};
 use crate::Ptr;
 use core::alloc::{Alloc, AllocErr, Layout};
 use core::ptr;
@@ -8,7 +9,7 @@ pub struct MmapAllocator;
 
 unsafe impl Alloc for MmapAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
-        let addr = mmap_without_fd(layout.size());
+        let addr = try_mmap_without_fd(layout.size()).ok_or(AllocErr)?;
         debug_assert_ne!(addr as usize, 0);
         Ok(ptr::NonNull::new(addr as *mut u8).unwrap())
     }
@@ -24,6 +25,55 @@ impl Default for MmapAllocator {
     }
 }
 
+// Same as MmapAllocator, with pages placed on the NUMA node. Mappings the kernel refuses to bind
+// are released, so callers never get memory from another node silently
+pub struct NodeMmapAllocator {
+    pub node: u16,
+}
+
+unsafe impl Alloc for NodeMmapAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
+        let addr = try_mmap_without_fd(layout.size()).ok_or(AllocErr)?;
+        if !bind_to_node(addr, layout.size(), self.node) {
+            munmap_memory(addr, layout.size());
+            return Err(AllocErr);
+        }
+        Ok(ptr::NonNull::new(addr as *mut u8).unwrap())
+    }
+
+    unsafe fn dealloc(&mut self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        munmap_memory(ptr.as_ptr() as Ptr, layout.size())
+    }
+}
+
+// Same as MmapAllocator, with an inaccessible page on both sides of the mapping, so overflows and
+// underflows of the object fault right away, see hardened
+pub struct GuardedMmapAllocator;
+
+unsafe impl Alloc for GuardedMmapAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
+        let page_size = *SYS_PAGE_SIZE;
+        let size = guarded_size(layout.size());
+        let addr = try_mmap_without_fd(size).ok_or(AllocErr)? as usize;
+        let tail_guard = addr + size - page_size;
+        if !guard_pages(addr as Ptr, page_size) || !guard_pages(tail_guard as Ptr, page_size) {
+            munmap_memory(addr as Ptr, size);
+            return Err(AllocErr);
+        }
+        Ok(ptr::NonNull::new((addr + page_size) as *mut u8).unwrap())
+    }
+
+    unsafe fn dealloc(&mut self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let addr = ptr.as_ptr() as usize - *SYS_PAGE_SIZE;
+        munmap_memory(addr as Ptr, guarded_size(layout.size()))
+    }
+}
+
+fn guarded_size(size: usize) -> usize {
+    let page_size = *SYS_PAGE_SIZE;
+    size + align_padding(size, page_size) + (page_size << 1)
+}
+
 #[cfg(test)]
 mod test {
     use crate::bump_heap::BumpAllocator;
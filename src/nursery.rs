@@ -0,0 +1,441 @@
+// Experiment: per-thread nurseries for tiny objects
+// With the `nursery` option, objects of up to MAX_SIZE bytes are served from a small region of
+// the allocating thread, a slab per size class, instead of the superblocks of the CPU. Freed
+// objects are reused last in, first out, so a thread allocating and freeing in stack order keeps
+// touching the same few cache lines. Objects freed by other threads are pushed to the nursery of
+// their thread and reused after its own. When a slab is used up, allocations fall back to the
+// superblocks.
+//
+// This is for research on cache residency, not production use. Nurseries of all threads live in
+// one reservation of MAX_NURSERIES regions, made when the option is first set, so frees find
+// them by address. Threads past MAX_NURSERIES get none, regions of exited threads are taken over
+// with their objects by new threads. Nursery objects have no generations and are not poisoned
+// in hardened mode. `compare` runs the same workload on the nursery and on the superblocks,
+// counting L1 data cache read misses with perf events where the kernel allows them.
+
+use crate::generic_heap::{self, size_class_index_from_size};
+use crate::mmap::{munmap_memory, try_mmap_without_fd};
+use crate::small_heap;
+use crate::strict;
+use crate::utils::SYS_PAGE_SIZE;
+use crate::{Ptr, NULL_PTR};
+use libc::*;
+use std::cell::Cell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::Instant;
+
+pub const MAX_SIZE: usize = 64;
+pub const MAX_NURSERIES: usize = 256;
+// classes of 8, 16, 32 and 64 bytes
+const NUM_CLASSES: usize = 4;
+const MIN_TIER: usize = 2;
+// nursery of the thread was not looked for yet, or none was left
+const UNCLAIMED: usize = 0;
+const NO_NURSERY: usize = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static REGION_SIZE: AtomicUsize = AtomicUsize::new(0);
+static SLAB_SIZE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_REGION: AtomicUsize = AtomicUsize::new(0);
+
+static BUMPED: AtomicUsize = AtomicUsize::new(0);
+static REUSED: AtomicUsize = AtomicUsize::new(0);
+static FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static NURSERY: Owner = Owner { header: Cell::new(UNCLAIMED) };
+}
+
+// First page of every region, zero when mapped
+#[repr(C)]
+struct Header {
+    owned: AtomicBool,
+    // bytes bumped from the slab of each class, by the owner only
+    tails: [usize; NUM_CLASSES],
+    // freed objects of each class linked through their first word, by the owner only
+    local: [usize; NUM_CLASSES],
+    // objects freed by other threads, taken all at once by the owner
+    remote: [AtomicUsize; NUM_CLASSES],
+}
+
+struct Owner {
+    header: Cell<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NurseryStats {
+    // objects bumped from slabs
+    pub bumped: usize,
+    // freed objects reused
+    pub reused: usize,
+    // allocations left to superblocks for full slabs or threads without nursery
+    pub fallbacks: usize,
+}
+
+// Same workload timed and measured on both allocators. Misses are None without perf events
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NurseryComparison {
+    pub nursery_misses: Option<u64>,
+    pub span_misses: Option<u64>,
+    pub nursery_ns: u64,
+    pub span_ns: u64,
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        let header = self.header.get();
+        if header > NO_NURSERY {
+            unsafe { &*(header as *const Header) }.owned.store(false, Release);
+        }
+    }
+}
+
+// Serve tiny objects from nurseries of `bytes` per thread, 0 to stop. The size cannot change
+// once nurseries are reserved
+pub fn enable(bytes: usize) -> bool {
+    if bytes == 0 {
+        ENABLED.store(false, Relaxed);
+        generic_heap::refresh_fast_path();
+        return true;
+    }
+    if !reserve(bytes) {
+        return false;
+    }
+    ENABLED.store(true, Relaxed);
+    generic_heap::refresh_fast_path();
+    true
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+fn slab_size_for(bytes: usize) -> usize {
+    let page = *SYS_PAGE_SIZE;
+    let slab = (bytes / NUM_CLASSES + page - 1) / page * page;
+    slab.max(page)
+}
+
+// Reserve regions for nurseries of `bytes`, true when they are reserved with the same size
+fn reserve(bytes: usize) -> bool {
+    let slab = slab_size_for(bytes);
+    if BASE.load(Acquire) != 0 {
+        return SLAB_SIZE.load(Relaxed) == slab;
+    }
+    let region = *SYS_PAGE_SIZE + slab * NUM_CLASSES;
+    let base = match try_mmap_without_fd(region * MAX_NURSERIES) {
+        Some(base) => base as usize,
+        None => return false,
+    };
+    if BASE.load(Acquire) != 0 {
+        munmap_memory(base as Ptr, region * MAX_NURSERIES);
+        return SLAB_SIZE.load(Relaxed) == slab;
+    }
+    // a racing reserve is taken care of by the first option setter, options are set in order
+    SLAB_SIZE.store(slab, Relaxed);
+    REGION_SIZE.store(region, Relaxed);
+    BASE.store(base, Release);
+    true
+}
+
+#[inline]
+fn class_index(size: usize) -> usize {
+    size_class_index_from_size(size.max(1)).max(MIN_TIER) - MIN_TIER
+}
+
+// Allocate from the nursery of the thread, null to fall back to superblocks
+pub unsafe fn allocate(size: usize) -> Ptr {
+    debug_assert!(size <= MAX_SIZE);
+    let header = NURSERY
+        .try_with(|owner| match owner.header.get() {
+            UNCLAIMED => {
+                let header = claim();
+                owner.header.set(header);
+                header
+            }
+            header => header,
+        })
+        .unwrap_or(NO_NURSERY);
+    if header == NO_NURSERY {
+        FALLBACKS.fetch_add(1, Relaxed);
+        return NULL_PTR;
+    }
+    let header = &mut *(header as *mut Header);
+    let index = class_index(size);
+    if header.local[index] == 0 {
+        header.local[index] = header.remote[index].swap(0, Acquire);
+    }
+    let head = header.local[index];
+    if head != 0 {
+        header.local[index] = *(head as *const usize);
+        REUSED.fetch_add(1, Relaxed);
+        return head as Ptr;
+    }
+    let class = 8 << index;
+    let slab = SLAB_SIZE.load(Relaxed);
+    if header.tails[index] + class > slab {
+        FALLBACKS.fetch_add(1, Relaxed);
+        return NULL_PTR;
+    }
+    let addr = header as *mut Header as usize + *SYS_PAGE_SIZE + index * slab + header.tails[index];
+    header.tails[index] += class;
+    BUMPED.fetch_add(1, Relaxed);
+    addr as Ptr
+}
+
+// Region of a new or exited thread
+fn claim() -> usize {
+    let base = BASE.load(Acquire);
+    if base == 0 {
+        return NO_NURSERY;
+    }
+    let region = REGION_SIZE.load(Relaxed);
+    let index = NEXT_REGION.fetch_add(1, Relaxed);
+    if index < MAX_NURSERIES {
+        let header = base + index * region;
+        unsafe { &*(header as *const Header) }.owned.store(true, Relaxed);
+        return header;
+    }
+    (0..MAX_NURSERIES)
+        .map(|i| base + i * region)
+        .find(|header| {
+            let owned = &unsafe { &*(*header as *const Header) }.owned;
+            !owned.compare_and_swap(false, true, AcqRel)
+        })
+        .unwrap_or(NO_NURSERY)
+}
+
+// Header and class of a nursery object
+#[inline]
+fn locate(ptr: Ptr) -> Option<(usize, usize)> {
+    let base = BASE.load(Acquire);
+    let addr = ptr as usize;
+    if base == 0 || addr < base {
+        return None;
+    }
+    let region = REGION_SIZE.load(Relaxed);
+    let offset = addr - base;
+    if offset >= region * MAX_NURSERIES {
+        return None;
+    }
+    let within = offset % region;
+    let page = *SYS_PAGE_SIZE;
+    if within < page {
+        return None;
+    }
+    let header = addr - within;
+    Some((header, (within - page) / SLAB_SIZE.load(Relaxed)))
+}
+
+// Returns false for objects not from nurseries
+#[inline]
+pub unsafe fn free(ptr: Ptr) -> bool {
+    let (header, index) = match locate(ptr) {
+        Some(location) => location,
+        None => return false,
+    };
+    let addr = ptr as usize;
+    let current = NURSERY.try_with(|owner| owner.header.get()).unwrap_or(NO_NURSERY);
+    let header = &mut *(header as *mut Header);
+    if current == header as *mut Header as usize {
+        *(addr as *mut usize) = header.local[index];
+        header.local[index] = addr;
+        return true;
+    }
+    loop {
+        let head = header.remote[index].load(Relaxed);
+        *(addr as *mut usize) = head;
+        if header.remote[index].compare_and_swap(head, addr, Release) == head {
+            return true;
+        }
+    }
+}
+
+pub fn size_of(ptr: Ptr) -> Option<usize> {
+    locate(ptr).map(|(_, index)| 8 << index)
+}
+
+pub fn stats() -> NurseryStats {
+    NurseryStats {
+        bumped: BUMPED.load(Relaxed),
+        reused: REUSED.load(Relaxed),
+        fallbacks: FALLBACKS.load(Relaxed),
+    }
+}
+
+// Allocate and free batches of tiny objects in stack order `rounds` times on the nursery and on
+// the superblocks. None until nurseries are reserved
+pub fn compare(rounds: usize) -> Option<NurseryComparison> {
+    if BASE.load(Acquire) == 0 {
+        return None;
+    }
+    let (nursery_misses, nursery_ns) = measure(|| churn(rounds, allocate, free));
+    let (span_misses, span_ns) = measure(|| {
+        churn(rounds, small_heap::allocate, small_heap::free)
+    });
+    Some(NurseryComparison {
+        nursery_misses,
+        span_misses,
+        nursery_ns,
+        span_ns,
+    })
+}
+
+fn churn(rounds: usize, allocate: unsafe fn(usize) -> Ptr, free: unsafe fn(Ptr) -> bool) {
+    let mut objects = [NULL_PTR; 64];
+    for _ in 0..rounds {
+        unsafe {
+            for (i, object) in objects.iter_mut().enumerate() {
+                let size = 8 << (i % NUM_CLASSES);
+                let mut ptr = allocate(size);
+                if ptr.is_null() {
+                    ptr = small_heap::allocate(size);
+                }
+                ptr::write_bytes(ptr as *mut u8, i as u8, size);
+                *object = ptr;
+            }
+            for object in objects.iter().rev() {
+                if !free(*object) {
+                    small_heap::free(*object);
+                }
+            }
+        }
+    }
+}
+
+// L1 data cache read misses and nanoseconds of the closure on the calling thread
+fn measure<F: FnOnce()>(f: F) -> (Option<u64>, u64) {
+    let counter = start_miss_counter();
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    let misses = counter.and_then(stop_miss_counter);
+    let ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    (misses, ns)
+}
+
+// perf_event_attr up to PERF_ATTR_SIZE_VER5
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+const PERF_TYPE_HW_CACHE: u32 = 3;
+// L1D, read, miss
+const L1D_READ_MISS: u64 = 1 << 16;
+// disabled, exclude_kernel, exclude_hv
+const PERF_FLAGS: u64 = 1 | 1 << 5 | 1 << 6;
+const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: c_ulong = 0x2403;
+
+#[cfg(target_os = "linux")]
+fn start_miss_counter() -> Option<c_int> {
+    if strict::is_enabled() {
+        return None;
+    }
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_HW_CACHE,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        config: L1D_READ_MISS,
+        flags: PERF_FLAGS,
+        ..Default::default()
+    };
+    let fd = unsafe { syscall(SYS_perf_event_open, &attr as *const PerfEventAttr, 0, -1, -1, 0) };
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as c_int;
+    unsafe {
+        ioctl(fd, PERF_EVENT_IOC_RESET, 0);
+        ioctl(fd, PERF_EVENT_IOC_ENABLE, 0);
+    }
+    Some(fd)
+}
+
+// Misses counted since start_miss_counter, the counter is closed
+#[cfg(target_os = "linux")]
+fn stop_miss_counter(fd: c_int) -> Option<u64> {
+    let mut misses = 0u64;
+    let read = unsafe {
+        ioctl(fd, PERF_EVENT_IOC_DISABLE, 0);
+        let read = read(fd, &mut misses as *mut u64 as *mut c_void, 8);
+        close(fd);
+        read
+    };
+    if read == 8 {
+        Some(misses)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_miss_counter() -> Option<c_int> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stop_miss_counter(fd: c_int) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nursery::*;
+    use std::thread;
+
+    #[test]
+    pub fn stack_order_reuse() {
+        // reserved without enabling, the heaps of other tests stay as they are
+        assert!(reserve(64 << 10));
+        assert!(!reserve(1 << 20));
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 112);
+        unsafe {
+            let first = allocate(24);
+            assert!(!first.is_null());
+            assert_eq!(size_of(first), Some(32));
+            let second = allocate(30);
+            assert!(free(second));
+            assert!(free(first));
+            // last freed, first reused
+            assert_eq!(allocate(17), first);
+            assert_eq!(allocate(32), second);
+            let tiny = allocate(1);
+            assert_eq!(size_of(tiny), Some(8));
+            // freed by another thread, reused after the local ones
+            let remote = tiny as usize;
+            thread::spawn(move || assert!(free(remote as Ptr))).join().unwrap();
+            assert_eq!(allocate(8), tiny);
+            let outside = small_heap::allocate(16);
+            assert!(!free(outside));
+            small_heap::free(outside);
+        }
+        let comparison = compare(100).unwrap();
+        assert!(comparison.nursery_ns > 0 && comparison.span_ns > 0);
+    }
+}
@@ -0,0 +1,24 @@
+// Operating system primitives of the memory layer
+// The heaps reserve, release and advise address ranges and probe the machine only through these
+// functions. Unix systems go through libc, with Linux specific advice and NUMA policies, and
+// Windows goes through VirtualAlloc and friends. Advice a platform has no counterpart for is a
+// no-op reporting failure, and machines without NUMA APIs are a single node.
+// Windows mappings only reserve address space and need their pages committed before use, and
+// unmapping part of a reservation there decommits the pages of the range instead of releasing it.
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    // the address is taken by another mapping
+    Occupied,
+    Failed,
+}
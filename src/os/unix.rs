@@ -0,0 +1,383 @@
+use crate::os::MapError;
+use crate::utils::align_padding;
+use crate::Ptr;
+use core::{mem, ptr};
+use errno::errno;
+use libc::*;
+
+#[cfg(target_os = "linux")]
+const MADV_HUGEPAGE: c_int = 14;
+#[cfg(target_os = "linux")]
+const MADV_NOHUGEPAGE: c_int = 15;
+#[cfg(target_os = "linux")]
+const MADV_DONTDUMP: c_int = 16;
+#[cfg(target_os = "linux")]
+const MADV_DODUMP: c_int = 17;
+#[cfg(target_os = "linux")]
+const MAP_FIXED_NOREPLACE: c_int = 0x100000;
+#[cfg(target_os = "linux")]
+const MAP_HUGETLB: c_int = 0x40000;
+#[cfg(target_os = "linux")]
+const MLOCK_ONFAULT: c_int = 1;
+#[cfg(target_os = "linux")]
+const MPOL_DEFAULT: c_int = 0;
+#[cfg(target_os = "linux")]
+const MPOL_BIND: c_int = 2;
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: c_uint = 1 << 1;
+#[cfg(target_os = "linux")]
+const MFD_CLOEXEC: c_uint = 1;
+#[cfg(target_os = "linux")]
+const FUTEX_WAIT_PRIVATE: c_int = 128;
+#[cfg(target_os = "linux")]
+const FUTEX_WAKE_PRIVATE: c_int = 128 | 1;
+// bits of node masks passed to mbind
+#[cfg(target_os = "linux")]
+const MAX_NUMA_NODES: usize = 1024;
+
+// Pages of mappings are committed when they are first touched
+pub const COMMITS_ON_TOUCH: bool = true;
+
+#[cfg(not(target_os = "macos"))]
+pub fn page_size() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn cpu_count() -> usize {
+    unsafe { sysconf(_SC_NPROCESSORS_ONLN) as usize }
+}
+
+#[cfg(target_os = "macos")]
+pub fn page_size() -> usize {
+    sysctl_usize(b"hw.pagesize\0").unwrap_or(4096)
+}
+
+#[cfg(target_os = "macos")]
+pub fn cpu_count() -> usize {
+    sysctl_usize(b"hw.logicalcpu\0").unwrap_or(1)
+}
+
+// Integer of the sysctl name, which must be nul terminated
+#[cfg(target_os = "macos")]
+fn sysctl_usize(name: &[u8]) -> Option<usize> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>();
+    let res = unsafe {
+        sysctlbyname(
+            name.as_ptr() as *const c_char,
+            &mut value as *mut c_int as *mut c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if res == 0 && value > 0 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub fn thread_id() -> usize {
+    unsafe { pthread_self() as usize }
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+pub fn current_cpu() -> Option<u16> {
+    Some(unsafe { sched_getcpu() as u16 })
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+pub fn current_cpu() -> Option<u16> {
+    None
+}
+
+// Sleep until woken by unpark_all on the word or for `timeout_us` at most, returns at once when
+// the word no longer holds `expected`
+#[cfg(target_os = "linux")]
+pub fn park(word: *const u32, expected: u32, timeout_us: usize) {
+    let timeout = timespec {
+        tv_sec: (timeout_us / 1_000_000) as time_t,
+        tv_nsec: (timeout_us % 1_000_000 * 1000) as c_long,
+    };
+    unsafe { syscall(SYS_futex, word, FUTEX_WAIT_PRIVATE, expected, &timeout as *const timespec) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn park(word: *const u32, expected: u32, timeout_us: usize) {
+    let timeout = timespec {
+        tv_sec: (timeout_us / 1_000_000) as time_t,
+        tv_nsec: (timeout_us % 1_000_000 * 1000) as c_long,
+    };
+    unsafe { nanosleep(&timeout, ptr::null_mut()) };
+}
+
+#[cfg(target_os = "linux")]
+pub fn unpark_all(word: *const u32) {
+    unsafe { syscall(SYS_futex, word, FUTEX_WAKE_PRIVATE, c_int::max_value()) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unpark_all(word: *const u32) {}
+
+// Random bytes the kernel provides to every process, read without allocating or syscalls
+#[cfg(target_os = "linux")]
+pub fn random_key() -> Option<[u64; 2]> {
+    unsafe {
+        let random = getauxval(AT_RANDOM) as *const u64;
+        if random.is_null() {
+            None
+        } else {
+            Some([ptr::read_unaligned(random), ptr::read_unaligned(random.add(1))])
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn random_key() -> Option<[u64; 2]> {
+    None
+}
+
+pub fn map(size: usize) -> Option<Ptr> {
+    mmap_anonymous(ptr::null_mut(), size, 0)
+}
+
+// Map exactly at the address without replacing existing mappings. Kernels before 4.17 and other
+// systems take the address as a hint only, mappings elsewhere are released as occupied
+pub fn map_at(addr: Ptr, size: usize) -> Result<Ptr, MapError> {
+    #[cfg(target_os = "linux")]
+    let flags = MAP_FIXED_NOREPLACE;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+    match mmap_anonymous(addr, size, flags) {
+        Some(ptr) if ptr == addr => Ok(ptr),
+        Some(ptr) => {
+            unmap(ptr, size);
+            Err(MapError::Occupied)
+        }
+        None if errno().0 == EEXIST => Err(MapError::Occupied),
+        None => Err(MapError::Failed),
+    }
+}
+
+// Map starting at a multiple of `align`, by over-mapping and trimming both ends
+pub fn map_aligned(size: usize, align: usize) -> Option<Ptr> {
+    let mapped_size = size + align;
+    let mapped = mmap_anonymous(ptr::null_mut(), mapped_size, 0)? as usize;
+    let start = mapped + align_padding(mapped, align);
+    let end = start + size + align_padding(size, page_size());
+    if start > mapped {
+        unmap(mapped as Ptr, start - mapped);
+    }
+    if mapped + mapped_size > end {
+        unmap(end as Ptr, mapped + mapped_size - end);
+    }
+    Some(start as Ptr)
+}
+
+// Map pages of the hugetlb pool, the size must be a multiple of huge pages
+#[cfg(target_os = "linux")]
+pub fn map_huge_tlb(size: usize) -> Option<Ptr> {
+    mmap_anonymous(ptr::null_mut(), size, MAP_HUGETLB)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn map_huge_tlb(size: usize) -> Option<Ptr> {
+    None
+}
+
+fn mmap_anonymous(addr: Ptr, size: usize, flags: c_int) -> Option<Ptr> {
+    let ptr = unsafe {
+        mmap(
+            addr,
+            size as size_t,
+            PROT_READ | PROT_WRITE,
+            MAP_ANONYMOUS | MAP_PRIVATE | flags,
+            -1,
+            0,
+        )
+    };
+    if ptr == MAP_FAILED {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+#[inline]
+pub fn commit(addr: Ptr, size: usize) -> bool {
+    true
+}
+
+// Shared mapping of a memory file of the size, along with the file for map_file_private
+#[cfg(target_os = "linux")]
+pub fn map_file(size: usize) -> Option<(Ptr, c_int)> {
+    let name = b"nulloc\0";
+    let fd = unsafe { syscall(SYS_memfd_create, name.as_ptr(), MFD_CLOEXEC) } as c_int;
+    if fd < 0 {
+        return None;
+    }
+    let ptr = unsafe {
+        if ftruncate(fd, size as off_t) != 0 {
+            MAP_FAILED
+        } else {
+            mmap(ptr::null_mut(), size as size_t, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+        }
+    };
+    if ptr == MAP_FAILED {
+        close_file(fd);
+        None
+    } else {
+        Some((ptr, fd))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn map_file(size: usize) -> Option<(Ptr, c_int)> {
+    None
+}
+
+// Copy-on-write mapping of a file from map_file. Pages read as the file until they are written
+pub fn map_file_private(fd: c_int, size: usize) -> Option<Ptr> {
+    let ptr = unsafe {
+        mmap(ptr::null_mut(), size as size_t, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0)
+    };
+    if ptr == MAP_FAILED {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+// Mappings of the file stay valid after it is closed
+pub fn close_file(fd: c_int) {
+    unsafe {
+        close(fd);
+    }
+}
+
+pub fn unmap(addr: Ptr, size: usize) {
+    unsafe {
+        munmap(addr, size);
+    }
+}
+
+// Fault on any access to pages of the region
+pub fn forbid_access(addr: Ptr, size: usize) -> bool {
+    unsafe { mprotect(addr, size, PROT_NONE) == 0 }
+}
+
+// Let the system take pages of the region back, they read as zero or the old contents after
+#[cfg(target_os = "linux")]
+#[inline]
+pub fn decommit(addr: Ptr, size: usize) -> usize {
+    unsafe { madvise(addr, size, MADV_FREE) as usize }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+pub fn decommit(addr: Ptr, size: usize) -> usize {
+    unsafe { madvise(addr, size, MADV_DONTNEED) as usize }
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+pub fn advise_huge_pages(addr: Ptr, size: usize, huge: bool) {
+    let advice = if huge { MADV_HUGEPAGE } else { MADV_NOHUGEPAGE };
+    unsafe {
+        madvise(addr, size, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+pub fn advise_huge_pages(addr: Ptr, size: usize, huge: bool) {}
+
+#[cfg(target_os = "linux")]
+pub fn advise_dump(addr: Ptr, size: usize, dump: bool) -> bool {
+    let advice = if dump { MADV_DODUMP } else { MADV_DONTDUMP };
+    unsafe { madvise(addr, size, advice) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_dump(addr: Ptr, size: usize, dump: bool) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+pub fn lock_on_fault(addr: Ptr, size: usize) -> bool {
+    unsafe { syscall(SYS_mlock2, addr, size, MLOCK_ONFAULT) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_on_fault(addr: Ptr, size: usize) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+pub fn bind_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    mbind(addr, size, node, 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    false
+}
+
+// Bind the range and migrate the pages already touched to the node
+#[cfg(target_os = "linux")]
+pub fn move_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    mbind(addr, size, node, MPOL_MF_MOVE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn move_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn mbind(addr: Ptr, size: usize, node: u16, flags: c_uint) -> bool {
+    let node = node as usize;
+    if node >= MAX_NUMA_NODES {
+        return false;
+    }
+    let word_bits = mem::size_of::<c_ulong>() * 8;
+    let mut mask = [0 as c_ulong; MAX_NUMA_NODES / 64];
+    mask[node / word_bits] |= 1 << (node % word_bits);
+    // the kernel takes one bit less than maxnode
+    let max_node = mask.len() * word_bits + 1;
+    unsafe { syscall(SYS_mbind, addr, size, MPOL_BIND, mask.as_ptr(), max_node, flags) == 0 }
+}
+
+// Whether the NUMA policy system calls work. Seccomp filters of containers refuse them with EPERM
+// and kernels built without NUMA with ENOSYS
+#[cfg(target_os = "linux")]
+pub fn numa_syscalls_available() -> bool {
+    let refused = |res: c_long| {
+        let err = errno().0;
+        res != 0 && (err == EPERM || err == ENOSYS)
+    };
+    let no_policy = ptr::null_mut::<c_int>();
+    let no_mask = ptr::null_mut::<c_ulong>();
+    let no_addr = ptr::null_mut::<c_void>();
+    if refused(unsafe { syscall(SYS_get_mempolicy, no_policy, no_mask, 0, no_addr, 0) }) {
+        return false;
+    }
+    let size = page_size();
+    let addr = match map(size) {
+        Some(addr) => addr,
+        None => return true,
+    };
+    let res = unsafe { syscall(SYS_mbind, addr, size, MPOL_DEFAULT, no_mask, 0, 0) };
+    unmap(addr, size);
+    !refused(res)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn numa_syscalls_available() -> bool {
+    false
+}
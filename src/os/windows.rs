@@ -0,0 +1,223 @@
+use crate::os::MapError;
+use crate::utils::align_padding;
+use crate::Ptr;
+use core::{mem, ptr};
+use libc::c_void;
+
+const MEM_COMMIT: u32 = 0x1000;
+const MEM_RESERVE: u32 = 0x2000;
+const MEM_DECOMMIT: u32 = 0x4000;
+const MEM_RELEASE: u32 = 0x8000;
+const MEM_RESET: u32 = 0x80000;
+const MEM_LARGE_PAGES: u32 = 0x2000_0000;
+const PAGE_NOACCESS: u32 = 0x01;
+const PAGE_READWRITE: u32 = 0x04;
+const ERROR_INVALID_ADDRESS: u32 = 487;
+// Pages of reservations are committed by `commit` before use, see mmap::try_reserve_without_fd
+pub const COMMITS_ON_TOUCH: bool = false;
+// a free range found for an aligned mapping may be taken before it is mapped
+const ALIGNED_MAP_ATTEMPTS: usize = 16;
+
+#[repr(C)]
+struct SystemInfo {
+    processor_architecture: u16,
+    reserved: u16,
+    page_size: u32,
+    minimum_application_address: *mut c_void,
+    maximum_application_address: *mut c_void,
+    active_processor_mask: usize,
+    number_of_processors: u32,
+    processor_type: u32,
+    allocation_granularity: u32,
+    processor_level: u16,
+    processor_revision: u16,
+}
+
+#[repr(C)]
+struct MemoryBasicInformation {
+    base_address: *mut c_void,
+    allocation_base: *mut c_void,
+    allocation_protect: u32,
+    #[cfg(target_pointer_width = "64")]
+    partition_id: u16,
+    region_size: usize,
+    state: u32,
+    protect: u32,
+    kind: u32,
+}
+
+extern "system" {
+    fn VirtualAlloc(addr: *mut c_void, size: usize, allocation_type: u32, protect: u32)
+        -> *mut c_void;
+    fn VirtualFree(addr: *mut c_void, size: usize, free_type: u32) -> i32;
+    fn VirtualProtect(addr: *mut c_void, size: usize, protect: u32, old_protect: *mut u32) -> i32;
+    fn VirtualQuery(addr: *const c_void, info: *mut MemoryBasicInformation, size: usize) -> usize;
+    fn GetSystemInfo(info: *mut SystemInfo);
+    fn GetCurrentThreadId() -> u32;
+    fn GetCurrentProcessorNumber() -> u32;
+    fn GetLastError() -> u32;
+}
+
+fn system_info() -> SystemInfo {
+    unsafe {
+        let mut info: SystemInfo = mem::zeroed();
+        GetSystemInfo(&mut info);
+        info
+    }
+}
+
+pub fn page_size() -> usize {
+    system_info().page_size as usize
+}
+
+pub fn cpu_count() -> usize {
+    system_info().number_of_processors as usize
+}
+
+#[inline]
+pub fn thread_id() -> usize {
+    unsafe { GetCurrentThreadId() as usize }
+}
+
+#[inline]
+pub fn current_cpu() -> Option<u16> {
+    Some(unsafe { GetCurrentProcessorNumber() as u16 })
+}
+
+pub fn random_key() -> Option<[u64; 2]> {
+    None
+}
+
+pub fn map(size: usize) -> Option<Ptr> {
+    virtual_alloc(ptr::null_mut(), size, 0)
+}
+
+pub fn map_at(addr: Ptr, size: usize) -> Result<Ptr, MapError> {
+    match virtual_alloc(addr, size, 0) {
+        Some(ptr) => Ok(ptr),
+        None if unsafe { GetLastError() } == ERROR_INVALID_ADDRESS => Err(MapError::Occupied),
+        None => Err(MapError::Failed),
+    }
+}
+
+// Reservations cannot be trimmed, find a free range large enough, release it and map at the
+// aligned address inside
+pub fn map_aligned(size: usize, align: usize) -> Option<Ptr> {
+    for _ in 0..ALIGNED_MAP_ATTEMPTS {
+        let probe = virtual_alloc(ptr::null_mut(), size + align, 0)? as usize;
+        unmap(probe as Ptr, size + align);
+        let start = probe + align_padding(probe, align);
+        if let Ok(ptr) = map_at(start as Ptr, size) {
+            return Some(ptr);
+        }
+    }
+    None
+}
+
+// Large pages need the lock pages privilege, fails without it. They are committed at once
+pub fn map_huge_tlb(size: usize) -> Option<Ptr> {
+    virtual_alloc(ptr::null_mut(), size, MEM_COMMIT | MEM_LARGE_PAGES)
+}
+
+// Mappings only reserve address space, pages count against the commit limit once committed
+fn virtual_alloc(addr: Ptr, size: usize, flags: u32) -> Option<Ptr> {
+    let ptr = unsafe { VirtualAlloc(addr, size, MEM_RESERVE | flags, PAGE_READWRITE) };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+// Committing pages already committed keeps their contents
+pub fn commit(addr: Ptr, size: usize) -> bool {
+    unsafe { !VirtualAlloc(addr, size, MEM_COMMIT, PAGE_READWRITE).is_null() }
+}
+
+// Memory files are not mapped copy-on-write here, instances are not cloneable
+pub fn map_file(size: usize) -> Option<(Ptr, i32)> {
+    None
+}
+
+pub fn map_file_private(fd: i32, size: usize) -> Option<Ptr> {
+    None
+}
+
+pub fn close_file(fd: i32) {}
+
+// Part of a reservation cannot be released, ranges short of a whole reservation are decommitted
+pub fn unmap(addr: Ptr, size: usize) {
+    unsafe {
+        let size = size + align_padding(size, page_size());
+        if reservation_size(addr).map_or(false, |reserved| reserved <= size) {
+            VirtualFree(addr, 0, MEM_RELEASE);
+        } else {
+            VirtualFree(addr, size, MEM_DECOMMIT);
+        }
+    }
+}
+
+// Size of the reservation starting at the address, None when no reservation starts there
+fn reservation_size(base: Ptr) -> Option<usize> {
+    let mut size = 0;
+    loop {
+        let mut info: MemoryBasicInformation = unsafe { mem::zeroed() };
+        let addr = (base as usize + size) as *const c_void;
+        let queried = unsafe { VirtualQuery(addr, &mut info, mem::size_of_val(&info)) };
+        if queried == 0 || info.allocation_base != base {
+            break;
+        }
+        size += info.region_size;
+    }
+    if size == 0 {
+        None
+    } else {
+        Some(size)
+    }
+}
+
+pub fn forbid_access(addr: Ptr, size: usize) -> bool {
+    let mut old_protect = 0;
+    unsafe { VirtualProtect(addr, size, PAGE_NOACCESS, &mut old_protect) != 0 }
+}
+
+#[inline]
+pub fn decommit(addr: Ptr, size: usize) -> usize {
+    let res = unsafe { VirtualAlloc(addr, size, MEM_RESET, PAGE_READWRITE) };
+    if res.is_null() {
+        !0
+    } else {
+        0
+    }
+}
+
+#[inline]
+pub fn advise_huge_pages(addr: Ptr, size: usize, huge: bool) {}
+
+pub fn advise_dump(addr: Ptr, size: usize, dump: bool) -> bool {
+    false
+}
+
+pub fn lock_on_fault(addr: Ptr, size: usize) -> bool {
+    false
+}
+
+// Windows places pages on nodes when mapping them only, existing ranges cannot be bound
+pub fn bind_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    false
+}
+
+pub fn move_to_node(addr: Ptr, size: usize, node: u16) -> bool {
+    false
+}
+
+// Parked threads sleep out the timeout, the word is not waited on
+pub fn park(word: *const u32, expected: u32, timeout_us: usize) {
+    std::thread::sleep(std::time::Duration::from_micros(timeout_us as u64));
+}
+
+pub fn unpark_all(word: *const u32) {}
+
+pub fn numa_syscalls_available() -> bool {
+    false
+}
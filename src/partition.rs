@@ -0,0 +1,293 @@
+// Named partitions for security partitioning
+// Objects of a partition come from an allocator instance of its own, so objects of different
+// partitions never share a superblock, a page or even an address space, like the partitions of
+// PartitionAlloc. Browsers and sandboxes put object classes into partitions by name to keep an
+// overflow or a use after free in one class from reaching objects of another:
+//
+//   nu_partition_malloc("dom", size)
+//
+// Objects are freed with nu_free and resized with nu_realloc, which keeps them in their
+// partition. Every object carries a header of HEADER_SIZE bytes with its size and a tag of its
+// partition, cleared on free to tell double frees. Objects are bumped within one address space
+// of the instance, so requests over MAX_SIZE fail rather than map address spaces they can never
+// fit in. Partitions are registered on first use and
+// never removed, up to MAX_PARTITIONS names of at most MAX_NAME_LEN bytes.
+//
+// IsolatedPool<T> allocates values of T from a partition of the type, shared by all pools of T,
+// so memory once used for a T is only ever reused for a T. A use after free then finds another
+// T rather than an object of another type, which defeats type confusion exploits. Pools serve
+// arrays of T too, aligned up to HEADER_SIZE:
+//
+//   let pool = IsolatedPool::<Node>::new().unwrap();
+//   let node = pool.boxed(Node::default());
+
+use crate::boxed::NuBox;
+use crate::bump_heap::{AllocatorInstance, HEAP_VIRT_SIZE};
+use crate::collections::backoff::Backoff;
+use crate::collections::fixvec::FixedVec;
+use crate::mmap_heap::MmapAllocator;
+use crate::{Ptr, NULL_PTR};
+use core::alloc::{Alloc, AllocErr, GlobalAlloc, Layout};
+use std::any::TypeId;
+use std::cell::UnsafeCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+
+pub const MAX_PARTITIONS: usize = 64;
+pub const MAX_NAME_LEN: usize = 48;
+pub const HEADER_SIZE: usize = 16;
+// largest object with its header and alignment slack in an address space
+pub const MAX_SIZE: usize = HEAP_VIRT_SIZE - (HEADER_SIZE << 1);
+
+const SLOT_FREE: usize = 0;
+const SLOT_WRITING: usize = 1;
+const SLOT_READY: usize = 2;
+const TAG: usize = 0x5041_5254_0000; // PART
+
+lazy_static! {
+    static ref PARTITIONS: FixedVec<Partition, MmapAllocator> = FixedVec::new(MAX_PARTITIONS);
+}
+
+// all zero for free slot
+struct Partition {
+    state: AtomicUsize,
+    name_len: AtomicUsize,
+    name: UnsafeCell<[u8; MAX_NAME_LEN]>,
+    // set before the slot is ready, never freed
+    instance: AtomicPtr<AllocatorInstance<MmapAllocator>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartitionId(usize);
+
+// Allocator of values of T from the partition of the type
+pub struct IsolatedPool<T: 'static> {
+    id: PartitionId,
+    shadow: PhantomData<fn() -> T>,
+}
+
+impl Partition {
+    fn name(&self) -> &[u8] {
+        let len = self.name_len.load(Relaxed);
+        unsafe { &(*self.name.get())[..len] }
+    }
+
+    fn instance(&self) -> &AllocatorInstance<MmapAllocator> {
+        unsafe { &*self.instance.load(Acquire) }
+    }
+}
+
+// Id of the partition, registering it on first use
+pub fn register(name: &str) -> Option<PartitionId> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > MAX_NAME_LEN {
+        return None;
+    }
+    let backoff = Backoff::new();
+    for index in 0..MAX_PARTITIONS {
+        let partition = &PARTITIONS[index];
+        loop {
+            match partition.state.compare_and_swap(SLOT_FREE, SLOT_WRITING, Acquire) {
+                SLOT_FREE => {
+                    unsafe {
+                        (*partition.name.get())[..bytes.len()].copy_from_slice(bytes);
+                    }
+                    partition.name_len.store(bytes.len(), Relaxed);
+                    let instance = Box::into_raw(Box::new(AllocatorInstance::new()));
+                    partition.instance.store(instance, Release);
+                    partition.state.store(SLOT_READY, Release);
+                    return Some(PartitionId(index));
+                }
+                SLOT_WRITING => backoff.snooze(),
+                _ => break,
+            }
+        }
+        if partition.name() == bytes {
+            return Some(PartitionId(index));
+        }
+    }
+    warn!("Too many partitions, cannot register {}", name);
+    None
+}
+
+fn layout_of(size: usize) -> Option<Layout> {
+    if size > MAX_SIZE {
+        return None;
+    }
+    Layout::from_size_align(size.checked_add(HEADER_SIZE)?, HEADER_SIZE).ok()
+}
+
+pub unsafe fn allocate(id: PartitionId, size: usize) -> Ptr {
+    let layout = match layout_of(size) {
+        Some(layout) => layout,
+        None => return NULL_PTR,
+    };
+    let header = PARTITIONS[id.0].instance().alloc(layout) as *mut usize;
+    if header.is_null() {
+        return NULL_PTR;
+    }
+    ptr::write(header, size);
+    ptr::write(header.add(1), TAG | id.0);
+    header.add(2) as Ptr
+}
+
+// Partition of the object, by the address spaces of partitions
+pub fn of(ptr: Ptr) -> Option<PartitionId> {
+    let addr = ptr as usize;
+    (0..MAX_PARTITIONS)
+        .take_while(|&index| PARTITIONS[index].state.load(Acquire) != SLOT_FREE)
+        .find(|&index| {
+            let partition = &PARTITIONS[index];
+            partition.state.load(Acquire) == SLOT_READY && partition.instance().owns(addr)
+        })
+        .map(PartitionId)
+}
+
+// Header of a live object of the partition
+fn header_of(id: PartitionId, ptr: Ptr) -> Option<*mut usize> {
+    let header = (ptr as usize).checked_sub(HEADER_SIZE)? as *mut usize;
+    if ptr as usize % HEADER_SIZE != 0 || unsafe { *header.add(1) } != TAG | id.0 {
+        return None;
+    }
+    Some(header)
+}
+
+pub fn size_of(ptr: Ptr) -> Option<usize> {
+    let id = of(ptr)?;
+    header_of(id, ptr).map(|header| unsafe { *header })
+}
+
+// Returns false for objects not in partitions, or freed already
+pub unsafe fn free(ptr: Ptr) -> bool {
+    let id = match of(ptr) {
+        Some(id) => id,
+        None => return false,
+    };
+    let header = match header_of(id, ptr) {
+        Some(header) => header,
+        None => return false,
+    };
+    let size = *header;
+    *header.add(1) = 0;
+    PARTITIONS[id.0]
+        .instance()
+        .dealloc(header as *mut u8, layout_of(size).unwrap());
+    true
+}
+
+impl<T: 'static> IsolatedPool<T> {
+    // Pool of the partition of T, None when no partitions are left
+    pub fn new() -> Option<Self> {
+        let mut hasher = DefaultHasher::new();
+        TypeId::of::<T>().hash(&mut hasher);
+        let id = register(&format!("isolated.{:016x}", hasher.finish()))?;
+        Some(Self {
+            id,
+            shadow: PhantomData,
+        })
+    }
+
+    pub fn boxed(&self, value: T) -> NuBox<T, Self> {
+        NuBox::new_in(value, *self)
+    }
+
+    // Layouts of T and arrays of T, nothing else is allocated from the partition
+    fn admits(layout: &Layout) -> bool {
+        let size = mem::size_of::<T>();
+        size != 0 && layout.size() % size == 0 && layout.align() <= HEADER_SIZE
+    }
+}
+
+impl<T: 'static> Clone for IsolatedPool<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for IsolatedPool<T> {}
+
+unsafe impl<T: 'static> Alloc for IsolatedPool<T> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if !Self::admits(&layout) {
+            return Err(AllocErr);
+        }
+        NonNull::new(allocate(self.id, layout.size()) as *mut u8).ok_or(AllocErr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, _layout: Layout) {
+        free(ptr.as_ptr() as Ptr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::partition::*;
+
+    #[test]
+    pub fn isolated_partitions() {
+        let dom = register("test-dom").unwrap();
+        assert_eq!(register("test-dom"), Some(dom));
+        let strings = register("test-strings").unwrap();
+        assert_eq!(register(""), None);
+        unsafe {
+            let a = allocate(dom, 100);
+            let b = allocate(strings, 100);
+            assert_eq!(of(a), Some(dom));
+            assert_eq!(of(b), Some(strings));
+            assert_eq!(size_of(a), Some(100));
+            // never on the same page
+            assert_ne!(a as usize >> 12, b as usize >> 12);
+            assert!(free(a));
+            assert!(!free(a));
+            // reused within the partition only
+            assert_eq!(allocate(dom, 100), a);
+            assert_ne!(allocate(strings, 100), a);
+            let heap = crate::api::nu_malloc(100);
+            assert_eq!(of(heap), None);
+            assert!(!free(heap));
+            crate::api::nu_free(heap);
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Node {
+        next: usize,
+        value: [u64; 3],
+    }
+
+    #[test]
+    pub fn type_isolation() {
+        let pool = IsolatedPool::<Node>::new().unwrap();
+        assert_eq!(IsolatedPool::<Node>::new().unwrap().id, pool.id);
+        let other = IsolatedPool::<[u64; 4]>::new().unwrap();
+        assert_ne!(other.id, pool.id);
+        let node = pool.boxed(Node::default());
+        let addr = &*node as *const Node as usize;
+        assert_eq!(of(addr as Ptr), Some(pool.id));
+        drop(node);
+        // same size, another type, never the memory of the node
+        let array = other.boxed([0u64; 4]);
+        assert_ne!(&*array as *const [u64; 4] as usize, addr);
+        assert_eq!(&*pool.boxed(Node::default()) as *const Node as usize, addr);
+        let mut pool = pool;
+        assert!(unsafe { pool.alloc(Layout::new::<u8>()) }.is_err());
+    }
+
+    #[test]
+    pub fn beyond_address_space() {
+        let huge = register("test-huge").unwrap();
+        unsafe {
+            assert!(allocate(huge, 200 << 20).is_null());
+            assert!(allocate(huge, MAX_SIZE + 1).is_null());
+            let largest = allocate(huge, MAX_SIZE);
+            assert!(!largest.is_null());
+            assert_eq!(size_of(largest), Some(MAX_SIZE));
+            assert!(free(largest));
+        }
+    }
+}
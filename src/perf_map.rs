@@ -0,0 +1,126 @@
+// Symbol map of heap regions for perf
+// perf reads `/tmp/perf-<pid>.map` to symbolize addresses outside of any mapped object, in the
+// format of `START SIZE name` per line with hexadecimal start and size. With the map, `perf mem`
+// and cache miss samples on heap addresses are attributed to allocator regions such as
+// `nulloc::bump_heap::extent` and `nulloc::small_heap::class_64`.
+// Superblocks are carved from bump heap extents, their entries are nested in extent entries.
+// Lines are formatted on the stack and written by a single append, so annotating never allocates
+// and lines from concurrent threads do not interleave. perf only runs on Linux, the map cannot
+// be enabled on systems other than Unix.
+
+use crate::strict;
+use libc::*;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+const LINE_CAPACITY: usize = 128;
+
+// file descriptor of the map plus one, 0 when disabled
+static MAP_FD: AtomicUsize = AtomicUsize::new(0);
+
+struct LineBuf {
+    buf: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > LINE_CAPACITY {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+pub fn map_path() -> String {
+    format!("/tmp/perf-{}.map", std::process::id())
+}
+
+#[cfg(unix)]
+pub fn enable() -> bool {
+    if MAP_FD.load(Relaxed) != 0 {
+        return true;
+    }
+    if strict::is_enabled() {
+        return false;
+    }
+    let path = CString::new(map_path()).unwrap();
+    let fd = unsafe { open(path.as_ptr(), O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC, 0o644) };
+    if fd < 0 {
+        warn!("Cannot open perf map {:?}", path);
+        return false;
+    }
+    if MAP_FD.compare_and_swap(0, fd as usize + 1, Relaxed) != 0 {
+        unsafe {
+            close(fd);
+        }
+    }
+    true
+}
+
+#[cfg(not(unix))]
+pub fn enable() -> bool {
+    false
+}
+
+pub fn disable() {
+    let fd = MAP_FD.swap(0, Relaxed);
+    if fd != 0 {
+        unsafe {
+            close((fd - 1) as c_int);
+        }
+    }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    MAP_FD.load(Relaxed) != 0
+}
+
+#[inline]
+pub fn annotate(addr: usize, size: usize, name: fmt::Arguments) {
+    if is_enabled() {
+        write_line(addr, size, name);
+    }
+}
+
+#[cold]
+fn write_line(addr: usize, size: usize, name: fmt::Arguments) {
+    let fd = MAP_FD.load(Relaxed);
+    if fd == 0 {
+        return;
+    }
+    let mut line = LineBuf {
+        buf: [0; LINE_CAPACITY],
+        len: 0,
+    };
+    if writeln!(line, "{:x} {:x} {}", addr, size, name).is_err() {
+        return;
+    }
+    unsafe {
+        write((fd - 1) as c_int, line.buf.as_ptr() as *const c_void, line.len as _);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::perf_map::*;
+    use std::fs;
+
+    #[test]
+    pub fn annotate_region() {
+        assert!(enable());
+        annotate(0x7f00_dead_0000, 0x1000, format_args!("nulloc::test::{}", "region"));
+        let map = fs::read_to_string(map_path()).unwrap();
+        assert!(map
+            .lines()
+            .any(|line| line == "7f00dead0000 1000 nulloc::test::region"));
+    }
+}
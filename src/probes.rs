@@ -0,0 +1,296 @@
+// Static tracepoints on allocator events
+// With the `usdt` feature, every event is a `nulloc:<event>` USDT (dtrace style) probe on Unix
+// that bpftrace and bcc can attach to in production binaries, e.g.
+// `bpftrace -e 'usdt:./app:nulloc:malloc { @[arg1] = count(); }'`
+// With the `etw` feature, every event is a TraceLogging event of the `Nulloc` ETW provider on
+// Windows, so Windows Performance Analyzer shows them next to system traces. Sessions enable the
+// provider by its id, e.g. `xperf -start nulloc -on bcbc6f27-d185-4385-a2f0-9667c447111b`. Events
+// are self-describing, named after the event with fields named after the arguments below, and
+// filtered by keyword: 0x1 for malloc and free, 0x2 for span_alloc, 0x4 for purge and 0x8 for
+// stats_snapshot. Without the features, events compile to nothing.
+//
+//   malloc(ptr, size)              object allocated by generic heap
+//   free(ptr)                      object freed by generic heap
+//   span_alloc(addr, size, tier)   new superblock created for a size class
+//   purge(bytes)                   bytes released to the OS by reclamation
+//   stats_snapshot(allocated_bytes, resident_bytes, mapped_bytes, metadata_bytes)
+//                                  stats snapshot published, see stats::publish
+
+#[cfg(all(feature = "usdt", unix))]
+macro_rules! probe_event {
+    ($name:ident $(, $arg:expr)*) => {
+        probe::probe!(nulloc, $name $(, $arg)*)
+    };
+}
+
+#[cfg(all(feature = "etw", windows))]
+macro_rules! probe_event {
+    ($name:ident $(, $arg:expr)*) => {
+        crate::probes::etw::write(&crate::probes::etw::$name, &[$($arg as u64),*])
+    };
+}
+
+#[cfg(not(any(all(feature = "usdt", unix), all(feature = "etw", windows))))]
+macro_rules! probe_event {
+    ($name:ident $(, $arg:expr)*) => {};
+}
+
+// TraceLogging provider, written without the TraceLogging macros of the Windows SDK. Metadata of
+// an event is laid out on the stack only when a session listens to it
+#[cfg(all(feature = "etw", windows))]
+#[allow(non_upper_case_globals)]
+pub mod etw {
+    use libc::c_void;
+    use std::ptr;
+    use std::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+
+    const PROVIDER_ID: Guid = Guid {
+        data1: 0xbcbc_6f27,
+        data2: 0xd185,
+        data3: 0x4385,
+        data4: [0xa2, 0xf0, 0x96, 0x67, 0xc4, 0x47, 0x11, 0x1b],
+    };
+    const PROVIDER_NAME: &str = "Nulloc";
+    const CHANNEL_TRACELOGGING: u8 = 11;
+    const LEVEL_INFO: u8 = 4;
+    const LEVEL_VERBOSE: u8 = 5;
+    const IN_UINT64: u8 = 10;
+    const IN_HEXINT64: u8 = 21;
+    const DESCRIPTOR_PROVIDER_METADATA: u32 = 2;
+    const DESCRIPTOR_EVENT_METADATA: u32 = 1;
+    const PROVIDER_SET_TRAITS: u32 = 2;
+    const METADATA_CAPACITY: usize = 128;
+    const MAX_FIELDS: usize = 4;
+
+    const UNREGISTERED: usize = 0;
+    const REGISTERING: usize = 1;
+    const REGISTERED: usize = 2;
+    const FAILED: usize = 3;
+
+    static STATE: AtomicUsize = AtomicUsize::new(UNREGISTERED);
+    static HANDLE: AtomicU64 = AtomicU64::new(0);
+
+    #[repr(C)]
+    struct Guid {
+        data1: u32,
+        data2: u16,
+        data3: u16,
+        data4: [u8; 8],
+    }
+
+    #[repr(C)]
+    pub struct EventDescriptor {
+        id: u16,
+        version: u8,
+        channel: u8,
+        level: u8,
+        opcode: u8,
+        task: u16,
+        keyword: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct EventDataDescriptor {
+        ptr: u64,
+        size: u32,
+        reserved: u32,
+    }
+
+    pub struct Event {
+        name: &'static str,
+        // names and TraceLogging input types of the arguments
+        fields: &'static [(&'static str, u8)],
+        descriptor: EventDescriptor,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn EventRegister(
+            provider: *const Guid,
+            callback: *const c_void,
+            context: *mut c_void,
+            handle: *mut u64,
+        ) -> u32;
+        fn EventSetInformation(handle: u64, class: u32, info: *const c_void, size: u32) -> u32;
+        fn EventEnabled(handle: u64, descriptor: *const EventDescriptor) -> u8;
+        fn EventWriteTransfer(
+            handle: u64,
+            descriptor: *const EventDescriptor,
+            activity: *const Guid,
+            related: *const Guid,
+            count: u32,
+            data: *const EventDataDescriptor,
+        ) -> u32;
+    }
+
+    const fn descriptor(level: u8, keyword: u64) -> EventDescriptor {
+        EventDescriptor {
+            id: 0,
+            version: 0,
+            channel: CHANNEL_TRACELOGGING,
+            level,
+            opcode: 0,
+            task: 0,
+            keyword,
+        }
+    }
+
+    pub static malloc: Event = Event {
+        name: "malloc",
+        fields: &[("ptr", IN_HEXINT64), ("size", IN_UINT64)],
+        descriptor: descriptor(LEVEL_VERBOSE, 0x1),
+    };
+    pub static free: Event = Event {
+        name: "free",
+        fields: &[("ptr", IN_HEXINT64)],
+        descriptor: descriptor(LEVEL_VERBOSE, 0x1),
+    };
+    pub static span_alloc: Event = Event {
+        name: "span_alloc",
+        fields: &[("addr", IN_HEXINT64), ("size", IN_UINT64), ("tier", IN_UINT64)],
+        descriptor: descriptor(LEVEL_INFO, 0x2),
+    };
+    pub static purge: Event = Event {
+        name: "purge",
+        fields: &[("bytes", IN_UINT64)],
+        descriptor: descriptor(LEVEL_INFO, 0x4),
+    };
+    pub static stats_snapshot: Event = Event {
+        name: "stats_snapshot",
+        fields: &[
+            ("allocated_bytes", IN_UINT64),
+            ("resident_bytes", IN_UINT64),
+            ("mapped_bytes", IN_UINT64),
+            ("metadata_bytes", IN_UINT64),
+        ],
+        descriptor: descriptor(LEVEL_INFO, 0x8),
+    };
+
+    // Metadata blob of TraceLogging, led by its size in bytes
+    struct Metadata {
+        buf: [u8; METADATA_CAPACITY],
+        len: usize,
+    }
+
+    impl Metadata {
+        fn new() -> Self {
+            Self {
+                buf: [0; METADATA_CAPACITY],
+                len: 2,
+            }
+        }
+
+        fn push(&mut self, bytes: &[u8]) {
+            self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+
+        fn push_name(&mut self, name: &str) {
+            self.push(name.as_bytes());
+            self.push(&[0]);
+        }
+
+        fn finish(&mut self) -> EventDataDescriptor {
+            let size = (self.len as u16).to_le_bytes();
+            self.buf[..2].copy_from_slice(&size);
+            EventDataDescriptor {
+                ptr: self.buf.as_ptr() as usize as u64,
+                size: self.len as u32,
+                reserved: 0,
+            }
+        }
+    }
+
+    fn provider_traits() -> Metadata {
+        let mut traits = Metadata::new();
+        traits.push_name(PROVIDER_NAME);
+        traits
+    }
+
+    // Handle of the provider, registered on the first event
+    pub(crate) fn provider() -> Option<u64> {
+        match STATE.compare_and_swap(UNREGISTERED, REGISTERING, AcqRel) {
+            UNREGISTERED => {}
+            REGISTERED => return Some(HANDLE.load(Acquire)),
+            // events while registering are dropped
+            _ => return None,
+        }
+        let mut handle = 0;
+        let registered = unsafe {
+            EventRegister(&PROVIDER_ID, ptr::null(), ptr::null_mut(), &mut handle) == 0
+        };
+        if !registered {
+            STATE.store(FAILED, Release);
+            return None;
+        }
+        let mut traits = provider_traits();
+        let traits = traits.finish();
+        unsafe {
+            let info = traits.ptr as usize as *const c_void;
+            EventSetInformation(handle, PROVIDER_SET_TRAITS, info, traits.size);
+        }
+        HANDLE.store(handle, Release);
+        STATE.store(REGISTERED, Release);
+        Some(handle)
+    }
+
+    pub fn write(event: &Event, args: &[u64]) {
+        debug_assert_eq!(event.fields.len(), args.len());
+        let handle = match provider() {
+            Some(handle) => handle,
+            None => return,
+        };
+        if unsafe { EventEnabled(handle, &event.descriptor) } == 0 {
+            return;
+        }
+        let mut traits = provider_traits();
+        let mut metadata = Metadata::new();
+        // no event tags
+        metadata.push(&[0]);
+        metadata.push_name(event.name);
+        for (name, in_type) in event.fields {
+            metadata.push_name(name);
+            metadata.push(&[*in_type]);
+        }
+        let empty = EventDataDescriptor {
+            ptr: 0,
+            size: 0,
+            reserved: 0,
+        };
+        let mut data = [empty; MAX_FIELDS + 2];
+        data[0] = traits.finish();
+        data[0].reserved = DESCRIPTOR_PROVIDER_METADATA;
+        data[1] = metadata.finish();
+        data[1].reserved = DESCRIPTOR_EVENT_METADATA;
+        for (slot, arg) in data[2..].iter_mut().zip(args.iter()) {
+            slot.ptr = arg as *const u64 as usize as u64;
+            slot.size = 8;
+        }
+        let count = 2 + args.len().min(MAX_FIELDS);
+        unsafe {
+            EventWriteTransfer(
+                handle,
+                &event.descriptor,
+                ptr::null(),
+                ptr::null(),
+                count as u32,
+                data.as_ptr(),
+            );
+        }
+    }
+}
+
+#[cfg(all(test, feature = "etw", windows))]
+mod test {
+    use crate::probes::etw::*;
+
+    #[test]
+    pub fn register_provider() {
+        // registered by the first event, whether or not a session listens
+        write(&malloc, &[0x1000, 64]);
+        write(&stats_snapshot, &[1, 2, 3, 4]);
+        assert!(provider().is_some());
+    }
+}
@@ -0,0 +1,384 @@
+// Allocation hooks and sampled heap profiles, built with the `profiling` feature
+// Hooks see every malloc, free and realloc of the general heap. They run inside the allocator,
+// so whatever they allocate is served by the bump heap and never reported back to them.
+// Reallocations moving objects also report the malloc of the new object and the free of the old
+// one before the realloc itself.
+//
+// The heap profiler samples one allocation after an exponentially distributed number of bytes
+// with the mean of the `profile_interval` option, as sampling does, and keeps the call stack of
+// every sampled object until it is freed. Profiles are written in the legacy heap profile format
+// of gperftools, which pprof reads and scales by the sampling interval:
+//   heap profile: <live objects>: <live bytes> [<objects>: <bytes>] @ heap_v2/<interval>
+//   <live objects>: <live bytes> [<objects>: <bytes>] @ <return addresses>
+//   ...
+//   MAPPED_LIBRARIES:
+//   <contents of /proc/self/maps>
+// where totals in brackets count all sampled objects, freed or not. Applications logging their
+// own top talkers can take the call stacks with the most live bytes instead, see top_allocators.
+//
+// Without the feature hooks and profiles cannot be set and all of this compiles to nothing
+
+use crate::Ptr;
+
+pub const PROFILE_FRAMES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocEvent {
+    Malloc { ptr: usize, size: usize },
+    Free { ptr: usize },
+    Realloc { old_ptr: usize, ptr: usize, size: usize },
+}
+
+pub type AllocHook = fn(AllocEvent);
+
+// Sampled objects of a call stack, bytes are not scaled by the sampling interval
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TopAllocator {
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    pub objects: usize,
+    pub bytes: usize,
+    // return addresses, innermost first, zero past the last frame
+    pub stack: [usize; PROFILE_FRAMES],
+}
+
+#[cfg(feature = "profiling")]
+pub use self::enabled::*;
+
+#[cfg(not(feature = "profiling"))]
+pub use self::disabled::*;
+
+#[cfg(feature = "profiling")]
+mod enabled {
+    use super::*;
+    use crate::api::INNER_CALL;
+    use crate::bump_heap::BumpAllocator;
+    use crate::generic_heap;
+    use crate::sampling::{backtrace, next_interval};
+    use crate::strict;
+    use crate::utils::AddressHasher;
+    use crate::NULL_PTR;
+    use lfmap::{Map, WordMap};
+    use libc::c_int;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::io::{self, Write};
+    use std::mem;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+    use std::sync::Mutex;
+
+    // frames of the allocator itself to skip
+    const SKIP_FRAMES: usize = 4;
+
+    type Stack = [usize; PROFILE_FRAMES];
+
+    static HOOK: AtomicUsize = AtomicUsize::new(0);
+    static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+    // sampled objects not freed yet, frees look them up only when there are some
+    static LIVE_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        // bytes to allocate before the next sample
+        static COUNTDOWN: Cell<isize> = Cell::new(0);
+    }
+
+    lazy_static! {
+        // address of sampled objects to their boxed Sample
+        static ref SAMPLED_OBJECTS: WordMap<BumpAllocator, AddressHasher> =
+            WordMap::with_capacity(1024);
+        static ref STACKS: Mutex<HashMap<Stack, StackStats>> = Mutex::new(HashMap::new());
+    }
+
+    struct Sample {
+        size: usize,
+        stack: Stack,
+    }
+
+    #[derive(Default)]
+    struct StackStats {
+        live_objects: usize,
+        live_bytes: usize,
+        objects: usize,
+        bytes: usize,
+    }
+
+    pub fn set_hook(hook: Option<AllocHook>) -> bool {
+        HOOK.store(hook.map_or(0, |hook| hook as usize), Release);
+        generic_heap::refresh_fast_path();
+        true
+    }
+
+    pub fn set_interval(interval: usize) -> bool {
+        if interval != 0 && strict::is_enabled() {
+            return false;
+        }
+        INTERVAL.store(interval, Relaxed);
+        generic_heap::refresh_fast_path();
+        true
+    }
+
+    #[inline]
+    pub fn is_active() -> bool {
+        HOOK.load(Relaxed) != 0 || INTERVAL.load(Relaxed) != 0 || LIVE_SAMPLES.load(Relaxed) != 0
+    }
+
+    pub fn on_malloc(ptr: Ptr, size: usize) {
+        if ptr == NULL_PTR {
+            return;
+        }
+        inside_allocator(|| {
+            call_hook(AllocEvent::Malloc {
+                ptr: ptr as usize,
+                size,
+            });
+            let interval = INTERVAL.load(Relaxed);
+            if interval != 0 && count_down(size, interval) {
+                sample(ptr as usize, size);
+            }
+        });
+    }
+
+    pub fn on_free(ptr: Ptr) {
+        inside_allocator(|| {
+            call_hook(AllocEvent::Free { ptr: ptr as usize });
+            if LIVE_SAMPLES.load(Relaxed) != 0 {
+                if let Some(sample) = SAMPLED_OBJECTS.remove(ptr as usize) {
+                    unsample(sample);
+                }
+            }
+        });
+    }
+
+    pub fn on_realloc(old_ptr: Ptr, ptr: Ptr, size: usize) {
+        if ptr == NULL_PTR {
+            return;
+        }
+        inside_allocator(|| {
+            call_hook(AllocEvent::Realloc {
+                old_ptr: old_ptr as usize,
+                ptr: ptr as usize,
+                size,
+            })
+        });
+    }
+
+    // Frees do not mark the allocator as entered, hooks and profiles shall not allocate from the
+    // general heap in any case
+    fn inside_allocator<F: FnOnce()>(f: F) {
+        INNER_CALL.with(|is_inner| {
+            let was_inner = is_inner.replace(true);
+            f();
+            is_inner.set(was_inner);
+        });
+    }
+
+    fn call_hook(event: AllocEvent) {
+        let hook = HOOK.load(Acquire);
+        if hook != 0 {
+            let hook: AllocHook = unsafe { mem::transmute(hook) };
+            hook(event);
+        }
+    }
+
+    fn count_down(size: usize, interval: usize) -> bool {
+        COUNTDOWN.with(|countdown| {
+            let remains = countdown.get() - size as isize;
+            if remains > 0 {
+                countdown.set(remains);
+                false
+            } else {
+                countdown.set(next_interval(interval) as isize);
+                true
+            }
+        })
+    }
+
+    fn sample(addr: usize, size: usize) {
+        let mut frames = [NULL_PTR; PROFILE_FRAMES + SKIP_FRAMES];
+        let depth = unsafe { backtrace(frames.as_mut_ptr(), frames.len() as c_int) } as usize;
+        let mut stack = [0; PROFILE_FRAMES];
+        for (i, frame) in frames[..depth].iter().skip(SKIP_FRAMES).enumerate() {
+            stack[i] = *frame as usize;
+        }
+        {
+            let mut stacks = STACKS.lock().unwrap();
+            let stats = stacks.entry(stack).or_default();
+            stats.live_objects += 1;
+            stats.live_bytes += size;
+            stats.objects += 1;
+            stats.bytes += size;
+        }
+        let sample = Box::into_raw(Box::new(Sample { size, stack })) as usize;
+        if let Some(replaced) = SAMPLED_OBJECTS.insert(addr, sample) {
+            // freed without being seen, such as by the bump heap
+            unsample(replaced);
+        } else if LIVE_SAMPLES.fetch_add(1, Relaxed) == 0 {
+            generic_heap::refresh_fast_path();
+        }
+    }
+
+    fn unsample(sample: usize) {
+        let sample = unsafe { Box::from_raw(sample as *mut Sample) };
+        if let Some(stats) = STACKS.lock().unwrap().get_mut(&sample.stack) {
+            stats.live_objects -= 1;
+            stats.live_bytes -= sample.size;
+        }
+        if LIVE_SAMPLES.fetch_sub(1, Relaxed) == 1 {
+            generic_heap::refresh_fast_path();
+        }
+    }
+
+    // Call stacks with the most live sampled bytes, at most `n` of them, most bytes first
+    pub fn top_allocators(n: usize) -> Vec<TopAllocator> {
+        // allocated before taking the lock, for samples of this allocation take it as well
+        let mut top = Vec::<TopAllocator>::with_capacity(n);
+        inside_allocator(|| {
+            let stacks = STACKS.lock().unwrap();
+            for (stack, stats) in stacks.iter().filter(|(_, stats)| stats.live_bytes > 0) {
+                let entry = TopAllocator {
+                    live_objects: stats.live_objects,
+                    live_bytes: stats.live_bytes,
+                    objects: stats.objects,
+                    bytes: stats.bytes,
+                    stack: *stack,
+                };
+                if top.len() < n {
+                    top.push(entry);
+                } else if let Some(least) = top.iter_mut().min_by_key(|top| top.live_bytes) {
+                    if least.live_bytes < entry.live_bytes {
+                        *least = entry;
+                    }
+                }
+            }
+        });
+        top.sort_unstable_by(|a, b| b.live_bytes.cmp(&a.live_bytes));
+        top
+    }
+
+    pub fn dump_heap_profile(path: &str) -> bool {
+        let mut profile = vec![];
+        inside_allocator(|| {
+            let _ = write_heap_profile(&mut profile);
+        });
+        std::fs::File::create(path)
+            .and_then(|mut file| {
+                file.write_all(&profile)?;
+                let maps = std::fs::read("/proc/self/maps").unwrap_or_default();
+                file.write_all(&maps)
+            })
+            .is_ok()
+    }
+
+    // Profile without the mapped libraries, which follow the header written last
+    pub fn write_heap_profile<W: Write>(writer: &mut W) -> io::Result<()> {
+        let stacks = STACKS.lock().unwrap();
+        let mut total = StackStats::default();
+        for stats in stacks.values() {
+            total.live_objects += stats.live_objects;
+            total.live_bytes += stats.live_bytes;
+            total.objects += stats.objects;
+            total.bytes += stats.bytes;
+        }
+        writeln!(
+            writer,
+            "heap profile: {}: {} [{}: {}] @ heap_v2/{}",
+            total.live_objects,
+            total.live_bytes,
+            total.objects,
+            total.bytes,
+            INTERVAL.load(Relaxed)
+        )?;
+        for (stack, stats) in stacks.iter() {
+            write!(
+                writer,
+                "{}: {} [{}: {}] @",
+                stats.live_objects, stats.live_bytes, stats.objects, stats.bytes
+            )?;
+            for frame in stack.iter().take_while(|frame| **frame != 0) {
+                write!(writer, " {:#x}", frame)?;
+            }
+            writeln!(writer)?;
+        }
+        writeln!(writer, "\nMAPPED_LIBRARIES:")
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+mod disabled {
+    use super::*;
+
+    pub fn set_hook(_hook: Option<AllocHook>) -> bool {
+        false
+    }
+
+    pub fn set_interval(_interval: usize) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn is_active() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn on_malloc(_ptr: Ptr, _size: usize) {}
+
+    #[inline(always)]
+    pub fn on_free(_ptr: Ptr) {}
+
+    #[inline(always)]
+    pub fn on_realloc(_old_ptr: Ptr, _ptr: Ptr, _size: usize) {}
+
+    pub fn top_allocators(_n: usize) -> Vec<TopAllocator> {
+        Vec::new()
+    }
+
+    pub fn dump_heap_profile(_path: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod test {
+    use crate::api::{nu_free, nu_malloc};
+    use crate::profile::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    static HOOKED_FREES: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_frees(event: AllocEvent) {
+        if let AllocEvent::Free { .. } = event {
+            HOOKED_FREES.fetch_add(1, Relaxed);
+        }
+        // served by the bump heap
+        let _ = vec![0u8; 100];
+    }
+
+    #[test]
+    pub fn hooks_and_profile() {
+        assert!(set_hook(Some(count_frees)));
+        assert!(set_interval(1));
+        unsafe {
+            let ptrs = (0..10).map(|_| nu_malloc(4000)).collect::<Vec<_>>();
+            let mut profile = vec![];
+            write_heap_profile(&mut profile).unwrap();
+            let profile = String::from_utf8(profile).unwrap();
+            assert!(profile.starts_with("heap profile: "));
+            assert!(profile.contains("@ heap_v2/1\n"));
+            // all from the same call stack
+            assert!(profile.lines().any(|line| line.starts_with("10: 40000 [10: 40000] @ 0x")));
+            let top = top_allocators(1);
+            assert_eq!(top.len(), 1);
+            assert!(top[0].live_bytes >= 40000);
+            assert_ne!(top[0].stack[0], 0);
+            for ptr in ptrs {
+                nu_free(ptr);
+            }
+        }
+        assert!(set_hook(None));
+        assert!(set_interval(0));
+        assert!(HOOKED_FREES.load(Relaxed) >= 10);
+    }
+}
@@ -0,0 +1,187 @@
+// Address-space quarantine of large objects
+// With `unmap_delay`, large objects with mappings of their own are not unmapped right away when
+// freed. Their pages are given back to the OS and the range is made inaccessible, then kept
+// reserved for the delay before it is really unmapped. Stale pointers into recently freed large
+// objects fault instead of reading or writing whatever the kernel maps at the same address next.
+//
+// At most CAPACITY mappings are held at a time, objects freed with the quarantine full are
+// unmapped at once. Expired mappings are unmapped by later frees of large objects, at most once
+// every SWEEP_MS, so the last ones of a quiet process stay reserved, without resident pages,
+// until the next free or until the option is set to 0. The range needs mprotect, so the option
+// is refused and switched off in strict mode.
+
+use crate::os;
+use crate::reclaim::elapsed_ms;
+use crate::strict;
+use crate::Ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+
+pub const CAPACITY: usize = 1024;
+const SWEEP_MS: usize = 10;
+// address of slots being filled or released
+const BUSY: usize = 1;
+
+// milliseconds mappings are held, 0 when disabled
+static DELAY_MS: AtomicUsize = AtomicUsize::new(0);
+static LAST_SWEEP_MS: AtomicUsize = AtomicUsize::new(0);
+static HELD_BYTES: AtomicUsize = AtomicUsize::new(0);
+static mut SLOTS: [Held; CAPACITY] = [Held::EMPTY; CAPACITY];
+
+// Unmaps a held mapping, given the address and the size it was held with
+pub type Unmap = unsafe fn(usize, usize);
+
+#[derive(Clone, Copy)]
+struct Held {
+    // 0 for empty slots, only accessed through `state`
+    addr: usize,
+    size: usize,
+    deadline: usize,
+    release: Option<Unmap>,
+}
+
+impl Held {
+    const EMPTY: Self = Self {
+        addr: 0,
+        size: 0,
+        deadline: 0,
+        release: None,
+    };
+}
+
+// Slots are claimed by setting their address to BUSY, the other fields belong to the claimer
+#[inline]
+fn state(held: &Held) -> &AtomicUsize {
+    unsafe { &*(&held.addr as *const usize as *const AtomicUsize) }
+}
+
+// Returns false in strict mode. Setting the delay to 0 unmaps everything held
+pub fn set_delay(ms: usize) -> bool {
+    if ms != 0 && strict::is_enabled() {
+        return false;
+    }
+    DELAY_MS.store(ms, Relaxed);
+    if ms == 0 {
+        sweep(usize::max_value());
+    }
+    true
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    DELAY_MS.load(Relaxed) != 0
+}
+
+// Bytes of mappings held
+pub fn held_bytes() -> usize {
+    HELD_BYTES.load(Relaxed)
+}
+
+// Take the mapping of a freed object into quarantine, `release` unmaps it when the delay is
+// over. Returns false when the caller shall unmap it now
+pub unsafe fn hold(addr: usize, size: usize, release: Unmap) -> bool {
+    let delay = DELAY_MS.load(Relaxed);
+    if delay == 0 {
+        return false;
+    }
+    let now = elapsed_ms();
+    let last = LAST_SWEEP_MS.load(Relaxed);
+    // one thread sweeps for every interval
+    if now.saturating_sub(last) >= SWEEP_MS
+        && LAST_SWEEP_MS.compare_and_swap(last, now, Relaxed) == last
+    {
+        sweep(now);
+    }
+    if !os::forbid_access(addr as Ptr, size) {
+        return false;
+    }
+    os::decommit(addr as Ptr, size);
+    for held in SLOTS.iter_mut() {
+        if state(held).compare_and_swap(0, BUSY, Acquire) != 0 {
+            continue;
+        }
+        held.size = size;
+        held.deadline = now + delay;
+        held.release = Some(release);
+        state(held).store(addr, Release);
+        HELD_BYTES.fetch_add(size, Relaxed);
+        return true;
+    }
+    // full, the range is unmapped anyway
+    false
+}
+
+// Start and size of the held mapping covering the address. Takes no locks and allocates nothing,
+// so it can be called from signal handlers
+pub fn held_range_of(addr: usize) -> Option<(usize, usize)> {
+    unsafe { SLOTS.iter() }
+        .map(|held| (state(held).load(Acquire), held.size))
+        .find(|&(start, size)| start > BUSY && addr >= start && addr < start + size)
+}
+
+// Release mappings with deadlines up to `now`
+fn sweep(now: usize) {
+    for held in unsafe { SLOTS.iter() } {
+        let addr = state(held).load(Acquire);
+        if addr <= BUSY || held.deadline > now {
+            continue;
+        }
+        if state(held).compare_and_swap(addr, BUSY, AcqRel) != addr {
+            continue;
+        }
+        if held.deadline > now {
+            // refilled with the same address since the deadline was read
+            state(held).store(addr, Release);
+            continue;
+        }
+        let (size, release) = (held.size, held.release);
+        state(held).store(0, Release);
+        HELD_BYTES.fetch_sub(size, Relaxed);
+        if let Some(release) = release {
+            unsafe { release(addr, size) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mmap::{munmap_memory, try_mmap_without_fd};
+    use crate::quarantine::*;
+
+    static RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn release(addr: usize, size: usize) {
+        RELEASED.fetch_add(1, Relaxed);
+        munmap_memory(addr as Ptr, size);
+    }
+
+    #[test]
+    pub fn delayed_unmap() {
+        let size = 1 << 20;
+        let addr = try_mmap_without_fd(size).unwrap() as usize;
+        unsafe {
+            // disabled
+            assert!(!hold(addr, size, release));
+            DELAY_MS.store(60_000, Relaxed);
+            assert!(hold(addr, size, release));
+        }
+        assert!(held_bytes() >= size);
+        assert_eq!(held_range_of(addr + 16), Some((addr, size)));
+        assert_eq!(held_range_of(addr + size), None);
+        #[cfg(unix)]
+        {
+            let mut message = vec![];
+            crate::crash::describe(libc::SIGSEGV, addr + 16, &mut message).unwrap();
+            let message = String::from_utf8(message).unwrap();
+            assert!(message.ends_with(&format!(
+                "16 bytes into the freed object at {:#x} of {} bytes, in quarantine\n",
+                addr, size
+            )));
+        }
+        sweep(elapsed_ms());
+        assert_eq!(RELEASED.load(Relaxed), 0);
+        // turning it off releases everything
+        assert!(set_delay(0));
+        assert_eq!(RELEASED.load(Relaxed), 1);
+    }
+}
@@ -0,0 +1,319 @@
+// Memory reclamation when the OS refuses to give more memory
+// Many transient OOMs in containers are survivable by flushing cached frees and returning free
+// pages before trying again
+//
+// Free pages are also returned lazily on a decay timer, see the purge_decay option. Frees count
+// down in every thread and look at the clock once in DECAY_CHECK_FREES frees, purging everything
+// when the decay interval has passed since the last purge. Processes that go idle after a burst
+// stop freeing and purge nothing, they shall call nu_malloc_trim instead
+//
+// Purges on the timer happen all at once in whichever thread frees at the time, and may fall
+// behind threads freeing faster. With the purge_backpressure option, slow paths of allocation
+// share the work instead: each takes the next step of a pass over the bump heap and the size
+// classes of every node and CPU, measuring free pages still resident and live bytes. Once a pass
+// finds free pages over the given multiple of live bytes, the steps of the next pass purge what
+// they visit, so the footprint stays bounded by the threads allocating the most.
+
+use crate::extents::{self, ExtentOp, ExtentReason};
+use crate::stats;
+use crate::{bump_heap, small_heap};
+use std::cell::Cell;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::Instant;
+
+const DECAY_CHECK_FREES: usize = 1024;
+
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+// decay interval in milliseconds, 0 for no lazy purging
+static DECAY_MS: AtomicUsize = AtomicUsize::new(0);
+// milliseconds since START at the last purge
+static LAST_PURGE_MS: AtomicUsize = AtomicUsize::new(0);
+// free bytes allowed for every live byte before slow paths purge, 0 to disable
+static BACKPRESSURE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_STEP: AtomicUsize = AtomicUsize::new(0);
+// free and live bytes seen by the steps of the current pass
+static PASS_FREE: AtomicUsize = AtomicUsize::new(0);
+static PASS_LIVE: AtomicUsize = AtomicUsize::new(0);
+// whether the last pass found free bytes over the limit
+static OVER_LIMIT: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+thread_local! {
+    static FREE_COUNTDOWN: Cell<usize> = Cell::new(DECAY_CHECK_FREES);
+}
+
+// Tiers of reclamation requested by the application, each level includes the previous ones
+//   Low:      flush cached frees back to their superblocks
+//   Medium:   release pages of free objects in the bump heap
+//   Critical: release pages of all superblocks without live objects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    Low = 1,
+    Medium = 2,
+    Critical = 3,
+}
+
+impl PressureLevel {
+    pub fn from_usize(level: usize) -> Option<Self> {
+        match level {
+            1 => Some(PressureLevel::Low),
+            2 => Some(PressureLevel::Medium),
+            3 => Some(PressureLevel::Critical),
+            _ => None,
+        }
+    }
+}
+
+// Reclamation on application request, such as platform memory warnings
+// Returns the number of bytes released to the OS
+pub fn notify_memory_pressure(level: PressureLevel) -> usize {
+    stats::incr(&stats::PRESSURE_NOTIFICATIONS);
+    if RECLAIMING.compare_and_swap(false, true, Acquire) {
+        return 0;
+    }
+    #[cfg(not(feature = "bump_heap_only"))]
+    small_heap::flush_pending_frees();
+    let mut released = 0;
+    if level >= PressureLevel::Medium {
+        released += bump_heap::purge();
+    }
+    #[cfg(not(feature = "bump_heap_only"))]
+    {
+        if level >= PressureLevel::Critical {
+            released += small_heap::purge_empty_superblocks();
+        }
+    }
+    stats::add(&stats::RECLAIMED_BYTES, released);
+    extents::record(ExtentOp::Release, ExtentReason::Purge, 0, released);
+    probe_event!(purge, released);
+    RECLAIMING.store(false, Release);
+    released
+}
+
+// Returns the number of bytes released to the OS
+pub fn reclaim() -> usize {
+    LAST_PURGE_MS.store(elapsed_ms(), Relaxed);
+    if RECLAIMING.compare_and_swap(false, true, Acquire) {
+        // some other thread is reclaiming, or reclamation itself ran out of memory
+        return 0;
+    }
+    #[cfg(not(feature = "bump_heap_only"))]
+    small_heap::flush_pending_frees();
+    let mut released = bump_heap::purge();
+    #[cfg(not(feature = "bump_heap_only"))]
+    {
+        released += small_heap::purge_empty_superblocks();
+    }
+    stats::add(&stats::RECLAIMED_BYTES, released);
+    extents::record(ExtentOp::Release, ExtentReason::Purge, 0, released);
+    probe_event!(purge, released);
+    RECLAIMING.store(false, Release);
+    released
+}
+
+// Try to get memory from the OS, reclaim and retry once on failure
+pub fn retry_on_oom<T, F: Fn() -> Option<T>>(map: F) -> Option<T> {
+    map().or_else(|| {
+        stats::incr(&stats::OOM_EVENTS);
+        reclaim();
+        let res = map();
+        if res.is_some() {
+            stats::incr(&stats::OOM_RECOVERED);
+        }
+        res
+    })
+}
+
+pub fn set_decay(decay_ms: usize) {
+    LAST_PURGE_MS.store(elapsed_ms(), Relaxed);
+    DECAY_MS.store(decay_ms, Relaxed);
+}
+
+#[inline]
+pub fn on_free() {
+    if DECAY_MS.load(Relaxed) != 0 {
+        count_down_free(1);
+    }
+}
+
+// Count frees of a batch at once
+pub fn on_frees(count: usize) {
+    if DECAY_MS.load(Relaxed) != 0 && count != 0 {
+        count_down_free(count);
+    }
+}
+
+#[inline(never)]
+fn count_down_free(count: usize) {
+    let due = FREE_COUNTDOWN
+        .try_with(|countdown| {
+            let left = countdown.get().saturating_sub(count);
+            countdown.set(if left == 0 { DECAY_CHECK_FREES } else { left });
+            left == 0
+        })
+        .unwrap_or(false);
+    if !due {
+        return;
+    }
+    let now = elapsed_ms();
+    let last = LAST_PURGE_MS.load(Relaxed);
+    let decay = DECAY_MS.load(Relaxed);
+    // one thread purges for every interval
+    if decay != 0
+        && now.saturating_sub(last) >= decay
+        && LAST_PURGE_MS.compare_and_swap(last, now, Relaxed) == last
+    {
+        stats::incr(&stats::DECAY_PURGES);
+        reclaim();
+    }
+}
+
+pub fn set_backpressure(multiple: usize) {
+    BACKPRESSURE.store(multiple, Relaxed);
+    if multiple == 0 {
+        OVER_LIMIT.store(false, Relaxed);
+    }
+}
+
+#[inline]
+pub fn on_slow_path() {
+    if BACKPRESSURE.load(Relaxed) != 0 {
+        purge_step();
+    }
+}
+
+#[inline(never)]
+fn purge_step() {
+    #[cfg(not(feature = "bump_heap_only"))]
+    let steps = 1 + small_heap::purge_steps();
+    #[cfg(feature = "bump_heap_only")]
+    let steps = 1;
+    let step = NEXT_STEP.fetch_add(1, Relaxed) % steps;
+    if step == 0 {
+        // a new pass, judged by the last one
+        let free = PASS_FREE.swap(0, Relaxed);
+        let live = PASS_LIVE.swap(0, Relaxed);
+        let multiple = BACKPRESSURE.load(Relaxed);
+        OVER_LIMIT.store(free > live.saturating_mul(multiple), Relaxed);
+    }
+    let purge = OVER_LIMIT.load(Relaxed);
+    let (free, live, released) = match step {
+        0 => {
+            let released = if purge { bump_heap::purge() } else { 0 };
+            (bump_heap::unpurged_bytes(), stats::get(&stats::LARGE_BYTES), released)
+        }
+        #[cfg(not(feature = "bump_heap_only"))]
+        _ => small_heap::purge_step(step - 1, purge),
+        #[cfg(feature = "bump_heap_only")]
+        _ => (0, 0, 0),
+    };
+    PASS_FREE.fetch_add(free, Relaxed);
+    PASS_LIVE.fetch_add(live, Relaxed);
+    if released != 0 {
+        stats::add(&stats::RECLAIMED_BYTES, released);
+        extents::record(ExtentOp::Release, ExtentReason::Purge, 0, released);
+    }
+}
+
+pub fn elapsed_ms() -> usize {
+    START.elapsed().as_millis() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bump_heap;
+    use crate::reclaim::*;
+    use crate::Ptr;
+    use std::thread;
+    use std::time::Duration;
+
+    fn free_bump_object(size: usize) {
+        unsafe {
+            let ptr = bump_heap::malloc(size);
+            (ptr as *mut u8).write_bytes(1, size);
+            assert!(bump_heap::free(ptr));
+        }
+    }
+
+    // other tests may be reclaiming at the same time
+    fn notify_until_released(level: PressureLevel) -> usize {
+        for _ in 0..100 {
+            let released = notify_memory_pressure(level);
+            if released != 0 {
+                return released;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        0
+    }
+
+    #[test]
+    pub fn retry_once_on_oom() {
+        let calls = Cell::new(0);
+        let oom_events = stats::get(&stats::OOM_EVENTS);
+        assert_eq!(retry_on_oom(|| Some(calls.replace(calls.get() + 1))), Some(0));
+        assert_eq!(calls.get(), 1);
+
+        // free pages of the bump heap are purged before the retry
+        let size = 1 << 20;
+        let reclaimed = stats::get(&stats::RECLAIMED_BYTES);
+        let recovered = stats::get(&stats::OOM_RECOVERED);
+        free_bump_object(size);
+        let calls = Cell::new(0);
+        let reclaimed_on_retry = Cell::new(0);
+        let res = retry_on_oom(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                None
+            } else {
+                reclaimed_on_retry.set(stats::get(&stats::RECLAIMED_BYTES));
+                Some(())
+            }
+        });
+        assert_eq!(res, Some(()));
+        assert_eq!(calls.get(), 2);
+        assert!(reclaimed_on_retry.get() >= reclaimed + size);
+        assert!(stats::get(&stats::OOM_EVENTS) > oom_events);
+        assert!(stats::get(&stats::OOM_RECOVERED) > recovered);
+
+        // out of memory for good, tried only once more
+        let calls = Cell::new(0);
+        let oom_events = stats::get(&stats::OOM_EVENTS);
+        let res: Option<()> = retry_on_oom(|| {
+            calls.set(calls.get() + 1);
+            None
+        });
+        assert_eq!(res, None);
+        assert_eq!(calls.get(), 2);
+        assert!(stats::get(&stats::OOM_EVENTS) > oom_events);
+    }
+
+    #[test]
+    pub fn pressure_tiers() {
+        let size = 1 << 20;
+        let notifications = stats::get(&stats::PRESSURE_NOTIFICATIONS);
+        // low only flushes cached frees, releasing nothing
+        free_bump_object(size);
+        assert_eq!(notify_memory_pressure(PressureLevel::Low), 0);
+        assert!(stats::get(&stats::PRESSURE_NOTIFICATIONS) > notifications);
+        // medium releases pages of free objects in the bump heap
+        assert!(notify_until_released(PressureLevel::Medium) >= size);
+        // critical releases superblocks without live objects as well
+        #[cfg(not(feature = "bump_heap_only"))]
+        {
+            let object_size = *small_heap::MAXIMUM_SIZE;
+            let objects = (0..8)
+                .map(|_| small_heap::allocate(object_size))
+                .collect::<Vec<Ptr>>();
+            for ptr in objects {
+                unsafe { (ptr as *mut u8).write_bytes(1, object_size) };
+                assert!(small_heap::free(ptr));
+            }
+            assert!(notify_until_released(PressureLevel::Critical) >= object_size);
+        }
+    }
+}
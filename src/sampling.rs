@@ -0,0 +1,314 @@
+// Allocation sampling for continuous profilers
+// Every thread samples one allocation after an exponentially distributed number of bytes with
+// the configured mean, which makes sampled allocations a Poisson process over allocated bytes
+// and unbiased for large and small objects. Samples are written into a lock-free ring in a
+// shared memory object `/nulloc-samples.<pid>` (on Linux `/dev/shm/nulloc-samples.<pid>`), so
+// external agents can consume them without talking to the process.
+//
+// Layout of the shared memory, all fields are native endian u64:
+//   header, 64 bytes
+//     0   magic, "NULLOCSR" in ASCII read as little endian
+//     8   version, currently 1
+//     16  record size in bytes, 64
+//     24  capacity in records
+//     32  write index, number of records ever claimed by writers
+//     40  sampling interval in bytes
+//   records, `capacity` records of 64 bytes follow the header
+//     0   sequence, index + 1 of the record once completely written, 0 while being written
+//     8   size of the allocation
+//     16  thread id
+//     24  return addresses of the 5 innermost frames above the allocator, 0 for absent frames
+// Record `i` lives at slot `i % capacity`. Readers shall read the sequence, copy the record and
+// read the sequence again, and discard the copy if any of the sequences is not `i + 1`.
+//
+// The ring needs POSIX shared memory and call sites need backtrace(3), sampling cannot be enabled
+// on other systems, where backtraces are empty.
+
+use crate::generic_heap;
+use crate::strict;
+use crate::utils::current_thread_id;
+use crate::os;
+use crate::{Ptr, NULL_PTR};
+#[cfg(unix)]
+use libc::*;
+#[cfg(not(unix))]
+use libc::{c_int, c_void};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{fence, AtomicPtr, AtomicUsize};
+
+pub const RING_MAGIC: u64 = 0x5253_434f_4c4c_554e; // NULLOCSR
+pub const RING_VERSION: u64 = 1;
+pub const RING_CAPACITY: usize = 4096;
+pub const SAMPLE_FRAMES: usize = 5;
+// frames of the allocator itself to skip
+const SKIP_FRAMES: usize = 3;
+
+static SAMPLE_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+static RING: AtomicPtr<RingHeader> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    // bytes to allocate before the next sample
+    static COUNTDOWN: Cell<isize> = Cell::new(0);
+    static RAND_STATE: Cell<u64> = Cell::new(current_thread_id() as u64 | 1);
+}
+
+#[repr(C)]
+struct RingHeader {
+    magic: u64,
+    version: u64,
+    record_size: u64,
+    capacity: u64,
+    write_index: AtomicUsize,
+    interval: AtomicUsize,
+    _padding: [u64; 2],
+}
+
+#[repr(C)]
+pub struct SampleRecord {
+    pub seq: AtomicUsize,
+    pub size: usize,
+    pub tid: usize,
+    pub frames: [usize; SAMPLE_FRAMES],
+}
+
+#[cfg(unix)]
+extern "C" {
+    pub fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+}
+
+#[cfg(not(unix))]
+pub unsafe fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int {
+    0
+}
+
+pub fn enable(interval: usize) -> bool {
+    if interval == 0 {
+        disable();
+        return true;
+    }
+    if strict::is_enabled() {
+        return false;
+    }
+    if RING.load(Acquire).is_null() {
+        let ring = create_ring();
+        if ring.is_null() {
+            return false;
+        }
+        if RING.compare_and_swap(ptr::null_mut(), ring, Release) != ptr::null_mut() {
+            // other thread has mapped the same shared memory object
+            os::unmap(ring as Ptr, ring_size());
+        }
+    }
+    let ring = unsafe { &*RING.load(Acquire) };
+    ring.interval.store(interval, Relaxed);
+    SAMPLE_INTERVAL.store(interval, Relaxed);
+    generic_heap::refresh_fast_path();
+    true
+}
+
+pub fn disable() {
+    SAMPLE_INTERVAL.store(0, Relaxed);
+    generic_heap::refresh_fast_path();
+    let ring = RING.load(Acquire);
+    if !ring.is_null() {
+        unsafe { &*ring }.interval.store(0, Relaxed);
+    }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    SAMPLE_INTERVAL.load(Relaxed) != 0
+}
+
+#[inline]
+pub fn on_allocation(size: usize) {
+    let interval = SAMPLE_INTERVAL.load(Relaxed);
+    if interval == 0 {
+        return;
+    }
+    let sample = COUNTDOWN.with(|countdown| {
+        let remains = countdown.get() - size as isize;
+        if remains > 0 {
+            countdown.set(remains);
+            false
+        } else {
+            countdown.set(next_interval(interval) as isize);
+            true
+        }
+    });
+    if sample {
+        record(size);
+    }
+}
+
+// Read all complete records still in the ring
+pub fn read_samples<F: FnMut(&SampleRecord)>(mut f: F) {
+    let ring_ptr = RING.load(Acquire);
+    if ring_ptr.is_null() {
+        return;
+    }
+    let ring = unsafe { &*ring_ptr };
+    let written = ring.write_index.load(Acquire);
+    let from = if written > RING_CAPACITY {
+        written - RING_CAPACITY
+    } else {
+        0
+    };
+    for index in from..written {
+        let record = record_at(ring_ptr, index);
+        let copy = SampleRecord {
+            seq: AtomicUsize::new(record.seq.load(Acquire)),
+            size: record.size,
+            tid: record.tid,
+            frames: record.frames,
+        };
+        fence(Acquire);
+        if copy.seq.load(Relaxed) == index + 1 && record.seq.load(Relaxed) == index + 1 {
+            f(&copy);
+        }
+    }
+}
+
+fn record(size: usize) {
+    let ring_ptr = RING.load(Acquire);
+    if ring_ptr.is_null() {
+        return;
+    }
+    let ring = unsafe { &*ring_ptr };
+    let index = ring.write_index.fetch_add(1, Relaxed);
+    let record = record_at(ring_ptr, index) as *const SampleRecord as *mut SampleRecord;
+    let mut frames = [NULL_PTR; SAMPLE_FRAMES + SKIP_FRAMES];
+    let depth = unsafe { backtrace(frames.as_mut_ptr(), frames.len() as c_int) } as usize;
+    unsafe {
+        (*record).seq.store(0, Relaxed);
+        fence(Release);
+        (*record).size = size;
+        (*record).tid = current_thread_id();
+        for i in 0..SAMPLE_FRAMES {
+            let frame = i + SKIP_FRAMES;
+            (*record).frames[i] = if frame < depth {
+                frames[frame] as usize
+            } else {
+                0
+            };
+        }
+        (*record).seq.store(index + 1, Release);
+    }
+}
+
+fn record_at(ring_ptr: *mut RingHeader, index: usize) -> &'static SampleRecord {
+    let slot = index % RING_CAPACITY;
+    let addr =
+        ring_ptr as usize + mem::size_of::<RingHeader>() + slot * mem::size_of::<SampleRecord>();
+    unsafe { &*(addr as *const SampleRecord) }
+}
+
+// Exponentially distributed interval with the mean, from a per-thread xorshift generator
+pub fn next_interval(mean: usize) -> usize {
+    let rand = RAND_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    });
+    // uniform in (0, 1]
+    let uniform = ((rand >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    (-uniform.ln() * mean as f64) as usize + 1
+}
+
+fn ring_size() -> usize {
+    mem::size_of::<RingHeader>() + RING_CAPACITY * mem::size_of::<SampleRecord>()
+}
+
+pub fn ring_name() -> String {
+    format!("/nulloc-samples.{}", std::process::id())
+}
+
+#[cfg(unix)]
+fn create_ring() -> *mut RingHeader {
+    let size = ring_size();
+    let name = CString::new(ring_name()).unwrap();
+    let addr = unsafe {
+        let fd = shm_open(name.as_ptr(), O_CREAT | O_RDWR | O_TRUNC, 0o600);
+        if fd < 0 {
+            warn!("Cannot create shared memory for allocation samples");
+            return ptr::null_mut();
+        }
+        let addr = if ftruncate(fd, size as off_t) == 0 {
+            mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd,
+                0,
+            )
+        } else {
+            MAP_FAILED
+        };
+        close(fd);
+        addr
+    };
+    if addr == MAP_FAILED {
+        return ptr::null_mut();
+    }
+    let ring = addr as *mut RingHeader;
+    unsafe {
+        ptr::write(
+            ring,
+            RingHeader {
+                magic: RING_MAGIC,
+                version: RING_VERSION,
+                record_size: mem::size_of::<SampleRecord>() as u64,
+                capacity: RING_CAPACITY as u64,
+                write_index: AtomicUsize::new(0),
+                interval: AtomicUsize::new(0),
+                _padding: [0; 2],
+            },
+        );
+    }
+    ring
+}
+
+#[cfg(not(unix))]
+fn create_ring() -> *mut RingHeader {
+    ptr::null_mut()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sampling::*;
+    use std::ffi::CString;
+
+    #[test]
+    pub fn ring_layout() {
+        assert_eq!(mem::size_of::<RingHeader>(), 64);
+        assert_eq!(mem::size_of::<SampleRecord>(), 64);
+        assert_eq!(&RING_MAGIC.to_le_bytes(), b"NULLOCSR");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn sample_every_allocation() {
+        assert!(enable(1));
+        let size = 123457;
+        on_allocation(size);
+        disable();
+        let mut found = false;
+        read_samples(|record| {
+            if record.size == size && record.tid == current_thread_id() {
+                found = true;
+            }
+        });
+        assert!(found);
+        unsafe {
+            shm_unlink(CString::new(ring_name()).unwrap().as_ptr());
+        }
+    }
+}
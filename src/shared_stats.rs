@@ -0,0 +1,220 @@
+// Stats published into shared memory for sidecar processes
+// With the `stats_segment` option, a background thread takes a stats snapshot every interval and
+// copies it into the shared memory object `/nulloc-stats.<pid>` (on Linux
+// `/dev/shm/nulloc-stats.<pid>`), so an agent can aggregate the allocators of a fleet of workers
+// by mapping their segments, without talking to the processes.
+//
+// Layout of the segment, all fields are native endian u64:
+//   0   magic, "NULLOCST" in ASCII read as little endian
+//   8   version of the header, currently 1
+//   16  pid of the process
+//   24  sequence, odd while the snapshot is being written
+//   32  milliseconds since the process started its allocator, when the snapshot was taken
+//   40  stats::StatsSnapshot, which carries its own version and size
+// Readers shall read the sequence, copy the snapshot and read the sequence again, and discard
+// the copy if the sequence was odd or has changed. The segment is removed by disabling the
+// option, segments of crashed processes are left for the agent to clean up. Forked children stop
+// publishing into the segment of their parent and need the option set again.
+
+use crate::generic_heap;
+use crate::reclaim::elapsed_ms;
+use crate::stats::{self, StatsSnapshot};
+use crate::strict;
+use libc::*;
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+use std::thread;
+use std::time::Duration;
+
+pub const SEGMENT_MAGIC: u64 = 0x5453_434f_4c4c_554e; // NULLOCST
+pub const SEGMENT_VERSION: u64 = 1;
+
+// milliseconds between updates, 0 when disabled
+static INTERVAL_MS: AtomicUsize = AtomicUsize::new(0);
+static PUBLISHER_RUNNING: AtomicBool = AtomicBool::new(false);
+// the publisher is writing into the segment, disable waits for it before unmapping
+static PUBLISHING: AtomicBool = AtomicBool::new(false);
+static SEGMENT: AtomicPtr<Segment> = AtomicPtr::new(ptr::null_mut());
+
+#[repr(C)]
+pub struct Segment {
+    pub magic: u64,
+    pub version: u64,
+    pub pid: u64,
+    pub seq: AtomicUsize,
+    pub updated_ms: u64,
+    pub snapshot: StatsSnapshot,
+}
+
+pub fn enable(interval_ms: usize) -> bool {
+    if interval_ms == 0 {
+        disable();
+        return true;
+    }
+    if strict::is_enabled() {
+        return false;
+    }
+    if SEGMENT.load(Acquire).is_null() {
+        let segment = create_segment();
+        if segment.is_null() {
+            return false;
+        }
+        if SEGMENT.compare_and_swap(ptr::null_mut(), segment, Release) != ptr::null_mut() {
+            unsafe {
+                munmap(segment as *mut c_void, mem::size_of::<Segment>());
+            }
+        }
+    }
+    INTERVAL_MS.store(interval_ms, Relaxed);
+    if !PUBLISHER_RUNNING.compare_and_swap(false, true, Acquire) {
+        let spawned = thread::Builder::new()
+            .name("nulloc-stats".to_string())
+            .spawn(run_publisher);
+        if spawned.is_err() {
+            PUBLISHER_RUNNING.store(false, Release);
+            return false;
+        }
+    }
+    true
+}
+
+// Stop publishing and remove the segment. Mappings of readers stay valid
+pub fn disable() {
+    INTERVAL_MS.store(0, Relaxed);
+    let segment = SEGMENT.swap(ptr::null_mut(), SeqCst);
+    if !segment.is_null() {
+        unsafe {
+            shm_unlink(CString::new(segment_name()).unwrap().as_ptr());
+        }
+        // a snapshot being written into the segment is finished first
+        while PUBLISHING.load(SeqCst) {
+            thread::yield_now();
+        }
+        unsafe {
+            munmap(segment as *mut c_void, mem::size_of::<Segment>());
+        }
+    }
+}
+
+// Only for the child process after fork. The publisher thread is gone and the segment is the one
+// of the parent, which is left to it
+pub unsafe fn reinit_after_fork() {
+    INTERVAL_MS.store(0, Relaxed);
+    PUBLISHER_RUNNING.store(false, Relaxed);
+    PUBLISHING.store(false, Relaxed);
+    let segment = SEGMENT.swap(ptr::null_mut(), Relaxed);
+    if !segment.is_null() {
+        munmap(segment as *mut c_void, mem::size_of::<Segment>());
+    }
+}
+
+pub fn segment_name() -> String {
+    format!("/nulloc-stats.{}", unsafe { getpid() })
+}
+
+fn run_publisher() {
+    loop {
+        let interval = INTERVAL_MS.load(Relaxed);
+        if interval == 0 {
+            break;
+        }
+        publish();
+        thread::sleep(Duration::from_millis(interval as u64));
+    }
+    PUBLISHER_RUNNING.store(false, Release);
+}
+
+// Write a fresh snapshot into the segment, only the publisher thread writes
+fn publish() {
+    let snapshot = stats::publish(generic_heap::heap_stats);
+    PUBLISHING.store(true, SeqCst);
+    let segment = SEGMENT.load(SeqCst);
+    if !segment.is_null() {
+        write_snapshot(unsafe { &mut *segment }, snapshot);
+    }
+    PUBLISHING.store(false, Release);
+}
+
+fn write_snapshot(segment: &mut Segment, snapshot: StatsSnapshot) {
+    let seq = segment.seq.load(Relaxed);
+    segment.seq.store(seq + 1, Relaxed);
+    fence(Release);
+    unsafe {
+        ptr::write_volatile(&mut segment.updated_ms, elapsed_ms() as u64);
+        ptr::write_volatile(&mut segment.snapshot, snapshot);
+    }
+    segment.seq.store(seq + 2, Release);
+}
+
+fn create_segment() -> *mut Segment {
+    let size = mem::size_of::<Segment>();
+    let name = CString::new(segment_name()).unwrap();
+    let addr = unsafe {
+        let fd = shm_open(name.as_ptr(), O_CREAT | O_RDWR | O_TRUNC, 0o644);
+        if fd < 0 {
+            warn!("Cannot create shared memory for stats");
+            return ptr::null_mut();
+        }
+        let addr = if ftruncate(fd, size as off_t) == 0 {
+            mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+        } else {
+            MAP_FAILED
+        };
+        close(fd);
+        addr
+    };
+    if addr == MAP_FAILED {
+        return ptr::null_mut();
+    }
+    let segment = addr as *mut Segment;
+    unsafe {
+        ptr::write(
+            segment,
+            Segment {
+                magic: SEGMENT_MAGIC,
+                version: SEGMENT_VERSION,
+                pid: getpid() as u64,
+                seq: AtomicUsize::new(0),
+                updated_ms: 0,
+                snapshot: StatsSnapshot::default(),
+            },
+        );
+    }
+    segment
+}
+
+#[cfg(test)]
+mod test {
+    use crate::shared_stats::*;
+
+    #[test]
+    pub fn publish_into_segment() {
+        assert!(enable(10));
+        let name = CString::new(segment_name()).unwrap();
+        let size = mem::size_of::<Segment>();
+        unsafe {
+            // as a sidecar would
+            let fd = shm_open(name.as_ptr(), O_RDONLY, 0);
+            assert!(fd >= 0);
+            let addr = mmap(ptr::null_mut(), size, PROT_READ, MAP_SHARED, fd, 0);
+            close(fd);
+            assert_ne!(addr, MAP_FAILED);
+            let segment = &*(addr as *const Segment);
+            assert_eq!(&segment.magic.to_le_bytes(), b"NULLOCST");
+            assert_eq!(segment.pid, getpid() as u64);
+            thread::sleep(Duration::from_millis(100));
+            assert!(segment.seq.load(Acquire) >= 2);
+            assert_eq!(segment.snapshot.size as usize, mem::size_of::<StatsSnapshot>());
+            munmap(addr, size);
+        }
+        let segment = SEGMENT.load(Acquire);
+        disable();
+        // unmapped by disable
+        let mut residency = [0u8; 1];
+        let res = unsafe { mincore(segment as *mut c_void, 1, residency.as_mut_ptr() as _) };
+        assert_eq!(res, -1);
+    }
+}
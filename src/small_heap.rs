@@ -1,21 +1,33 @@
 use super::*;
 use crate::collections::fixvec::FixedVec;
 use crate::collections::lflist::WordList;
+use crate::collections::pagemap::PageMap;
 use crate::collections::{evmap, lflist};
-use crate::generic_heap::{log_2_of, size_class_index_from_size, ObjectMeta, NUM_SIZE_CLASS};
+use crate::config;
+use crate::extents::{self, ExtentOp, ExtentReason};
+use crate::hardened;
+use crate::mmap::{dealloc_pages_within, dump_pages_within};
+use crate::perf_map;
+use crate::stats;
+use crate::stats::HeapStats;
+use crate::generic_heap::{
+    log_2_of, size_class_index_from_size, ObjectMeta, MAXIMUM_SMALL_SIZE, NUM_SIZE_CLASS,
+};
+use crate::thread_mode;
 use crate::utils::*;
 use core::mem;
 use core::mem::MaybeUninit;
 use core::ptr;
-use core::sync::atomic::{AtomicU32, AtomicUsize};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicUsize};
 use crossbeam_queue::SegQueue;
 use lazy_init::Lazy;
 use lfmap::{Map, WordMap};
 use std::alloc::GlobalAlloc;
 use std::cell::{Cell, RefCell};
 use std::clone::Clone;
+use std::cmp::max;
 use std::ops::Deref;
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::sync::Arc;
 use std::thread;
 use smallvec::SmallVec;
@@ -31,18 +43,44 @@ thread_local! {
 lazy_static! {
     static ref PER_NODE_META: PerNodeMeta = gen_numa_node_list();
     static ref PER_CPU_META: PerCPUMeta = gen_core_meta();
+    // default superblock size, see span_size in config
     static ref SUPERBLOCK_SIZE: usize = *MAXIMUM_SIZE << 2;
     pub static ref MAXIMUM_SIZE: usize = maximum_size();
+    // superblocks of pages entirely in their data areas, objects starting on other pages are
+    // recorded one by one in the object maps of nodes
+    static ref PAGE_MAP: PageMap<BumpAllocator> = PageMap::new();
+    static ref PAGE_SHIFT: usize = log_2_of(*SYS_PAGE_SIZE);
 }
 
+// objects sorted by superblock at a time by free_batch, on the stack
+const FREE_BATCH: usize = 256;
+// generations of superblocks are above their reservation offsets, see SuperBlock
+const GENERATION_SHIFT: u64 = 32;
+
+static NODES_READY: AtomicBool = AtomicBool::new(false);
+static CORES_READY: AtomicBool = AtomicBool::new(false);
+// bytes of superblocks left without live objects since the last purge over the free list budget,
+// see purge_over_budget
+static EMPTIED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static OVER_BUDGET: AtomicBool = AtomicBool::new(false);
+
 #[cfg_attr(target_arch = "x86_64", repr(align(128)))]
 #[cfg_attr(not(target_arch = "x86_64"), repr(align(64)))]
 struct SuperBlock {
-    cpu: u16,
+    cpu: AtomicU16,
     numa: u16,
-    size: u32,
-    reservation: AtomicU32,
+    // object size, changed when the superblock is recycled for another size class
+    size: AtomicU32,
+    // bytes of the data area
+    span: u32,
+    // offset of the next reservation in the low half, generation in the high half. The generation
+    // is bumped when the superblock is recycled, see recycle, so reservations that loaded the
+    // word before fail their CAS. Superblocks never serve arenas, which have allocator instances
+    // of their own
+    reservation: AtomicU64,
     used: AtomicU32,
+    // bytes of pages released by the last purge, until the next allocation
+    purged: AtomicU32,
     data_base: usize,
     free_list: lflist::WordList<BumpAllocator>,
 }
@@ -50,6 +88,11 @@ struct SuperBlock {
 struct ThreadMeta {
     numa: u16,
     cpu: u16,
+    // size and superblock of the last allocation, so loops allocating the same size skip the
+    // size class lookup. Superblocks never leave the size class list of their CPU, the cached
+    // one is always valid, but its size is checked since recycled superblocks change classes
+    last_size: Cell<usize>,
+    last_block: Cell<usize>,
 }
 
 struct NodeMeta {
@@ -73,37 +116,104 @@ struct CoreMeta {
 }
 
 pub fn allocate(size: usize) -> Ptr {
-    let size_class_index = size_class_index_from_size(size);
-    let max_size = *MAXIMUM_SIZE;
     debug_assert!(size <= *MAXIMUM_SIZE);
-    let (cpu, numa) = THREAD_META.with(|meta| (meta.cpu, meta.numa));
-    let cpu_meta = &PER_CPU_META[cpu as usize];
+    THREAD_META.with(|meta| {
+        if meta.last_size.get() == size {
+            let superblock = unsafe { &*(meta.last_block.get() as *const SuperBlock) };
+            let class_size = max(size, 2).next_power_of_two();
+            if superblock.size() as usize == class_size {
+                if let Some(addr) = superblock.allocate() {
+                    return addr as Ptr;
+                }
+            }
+        }
+        let addr = allocate_in_class(meta, size_class_index_from_size(size));
+        meta.last_size.set(size);
+        addr
+    })
+}
+
+// Allocate in the size class of the index, for callers that have resolved the class already
+pub fn allocate_class(size_class_index: usize) -> Ptr {
+    debug_assert!(size_class_index < NUM_SIZE_CLASS);
+    THREAD_META.with(|meta| {
+        let block = meta.last_block.get();
+        if block != 0 {
+            let superblock = unsafe { &*(block as *const SuperBlock) };
+            if superblock.size() as usize == 2 << size_class_index {
+                if let Some(addr) = superblock.allocate() {
+                    return addr as Ptr;
+                }
+            }
+        }
+        let addr = allocate_in_class(meta, size_class_index);
+        meta.last_size.set(2 << size_class_index);
+        addr
+    })
+}
+
+fn allocate_in_class(meta: &ThreadMeta, size_class_index: usize) -> Ptr {
+    let cpu_meta = &PER_CPU_META[meta.cpu as usize];
     // allocate memory from per-CPU size class list
     let superblock = &cpu_meta.size_class_list[size_class_index];
     let (addr, block) = superblock.allocate();
-    debug_assert_eq!(superblock.numa, numa);
-    debug_assert_eq!(unsafe { &*(block as *const SuperBlock) }.numa, numa);
+    debug_assert_eq!(superblock.numa, meta.numa);
+    debug_assert_eq!(unsafe { &*(block as *const SuperBlock) }.numa, meta.numa);
     if cfg!(debug_assertions) {
+        let size = superblock.size as usize;
         debug_check_cache_aligned(addr, size, 8);
         debug_check_cache_aligned(addr, size, 16);
         debug_check_cache_aligned(addr, size, 32);
         debug_check_cache_aligned(addr, size, CACHE_LINE_SIZE);
     }
-    return addr as Ptr;
+    meta.last_block.set(block);
+    addr as Ptr
+}
+
+// Allocate from superblocks of the NUMA node instead of the CPU. Node superblocks are not
+// attached to any CPU until some CPU runs out of its own superblocks and takes them
+pub fn allocate_shared(size: usize) -> Ptr {
+    let size_class_index = size_class_index_from_size(size);
+    debug_assert!(size <= *MAXIMUM_SIZE);
+    let numa = THREAD_META.with(|meta| meta.numa);
+    let size_class = &PER_NODE_META[numa as usize].size_class_list[size_class_index];
+    let (addr, _) = size_class.allocate_shared();
+    addr as Ptr
+}
+
+// Allocate from superblocks of the given NUMA node, wherever the thread runs. Frees from other
+// nodes are queued back to the node, see free
+pub fn allocate_on_node(size: usize, node: u16) -> Ptr {
+    debug_assert!(size <= *MAXIMUM_SIZE);
+    debug_assert!(node < *NUM_NUMA_NODES);
+    let size_class_index = size_class_index_from_size(size);
+    let size_class = &PER_NODE_META[node as usize].size_class_list[size_class_index];
+    let (addr, _) = size_class.allocate_shared();
+    addr as Ptr
+}
+
+// Allocate from the superblocks of the CPU, wherever the thread runs
+#[cfg(test)]
+pub fn allocate_on_cpu(size: usize, cpu: u16) -> Ptr {
+    let size_class_index = size_class_index_from_size(size);
+    let (addr, _) = PER_CPU_META[cpu as usize].size_class_list[size_class_index].allocate();
+    addr as Ptr
 }
 
 pub fn free(ptr: Ptr) -> bool {
-    let current_numa = THREAD_META.with(|meta| meta.numa);
+    let current_numa = match THREAD_META.try_with(|meta| meta.numa) {
+        Ok(numa) => numa,
+        Err(_) => return free_after_exit(ptr),
+    };
     let numa_meta = &PER_NODE_META[current_numa as usize];
-    numa_meta.pending_free.drop_out_all(Some(|(addr, _)| {
-        if let Some(superblock_addr) = numa_meta.objects.get(addr) {
-            let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
-            superblock_ref.dealloc(addr);
-        }
-    }));
+    flush_pending_free(numa_meta);
     let addr = ptr as usize;
     if let Some(superblock_addr) = get_from_objects(current_numa, addr) {
         let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+        if hardened::is_enabled() {
+            // before the slot is visible to others, remote frees included
+            superblock_ref.check_and_poison(addr);
+        }
         if superblock_ref.numa == current_numa {
             superblock_ref.dealloc(addr);
         } else {
@@ -116,15 +226,338 @@ pub fn free(ptr: Ptr) -> bool {
         return false;
     }
 }
+
+// Free the objects a superblock at a time. Objects are sorted by superblock, up to FREE_BATCH at a
+// time, and those of each superblock go back to its free list in one push, with its usage
+// dropped once, or to the pending frees of its node in one push. Objects out of superblocks,
+// null included, are handed to `other`
+pub fn free_batch<F: FnMut(Ptr)>(ptrs: &[Ptr], mut other: F) {
+    let current_numa = match THREAD_META.try_with(|meta| meta.numa) {
+        Ok(numa) => numa,
+        Err(_) => {
+            for ptr in ptrs.iter().filter(|ptr| !free_after_exit(**ptr)) {
+                other(*ptr);
+            }
+            return;
+        }
+    };
+    flush_pending_free(&PER_NODE_META[current_numa as usize]);
+    // superblock and address of objects
+    let mut batch = [(0, 0); FREE_BATCH];
+    for chunk in ptrs.chunks(FREE_BATCH) {
+        let mut len = 0;
+        for ptr in chunk {
+            match get_from_objects(current_numa, *ptr as usize) {
+                Some(superblock_addr) => {
+                    batch[len] = (superblock_addr, *ptr as usize);
+                    len += 1;
+                }
+                None => other(*ptr),
+            }
+        }
+        let objects = &mut batch[..len];
+        objects.sort_unstable();
+        let mut start = 0;
+        while start < len {
+            let superblock_addr = objects[start].0;
+            let end = start
+                + objects[start..]
+                    .iter()
+                    .take_while(|(block, _)| *block == superblock_addr)
+                    .count();
+            let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+            superblock_ref.dealloc_batch(current_numa, &objects[start..end]);
+            start = end;
+        }
+    }
+}
+
+// Frees from TLS destructors of exiting threads, after the thread meta is gone. Objects are
+// queued to their nodes as remote frees are, so they return to their superblocks on the next
+// free on the node or on reclamation, without recreating the meta during teardown
+#[cold]
+fn free_after_exit(ptr: Ptr) -> bool {
+    let addr = ptr as usize;
+    match get_from_objects(0, addr) {
+        Some(superblock_addr) => {
+            let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+            if hardened::is_enabled() {
+                superblock_ref.check_and_poison(addr);
+            }
+            PER_NODE_META[superblock_ref.numa as usize]
+                .pending_free
+                .push(addr);
+            true
+        }
+        None => false,
+    }
+}
+
+fn flush_pending_free(numa_meta: &NodeMeta) {
+    numa_meta.pending_free.drop_out_with(|addr| {
+        let superblock = PAGE_MAP
+            .get(addr >> *PAGE_SHIFT)
+            .or_else(|| numa_meta.objects.get(addr));
+        if let Some(superblock_addr) = superblock {
+            let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+            superblock_ref.dealloc(addr);
+        }
+    });
+}
+
+// Return objects freed by remote nodes to their superblocks for all initialized nodes
+pub fn flush_pending_frees() {
+    if !NODES_READY.load(Relaxed) {
+        return;
+    }
+    for node in PER_NODE_META.iter() {
+        if let Some(numa_meta) = node.get_if_created() {
+            flush_pending_free(numa_meta);
+        }
+    }
+}
+
+// Create the superblocks of the prereserve options on every node with their pages committed.
+// They wait in the node lists until CPUs run out of superblocks of their own and take them.
+// Returns the number of superblocks created
+pub fn prereserve() -> usize {
+    let mut created = 0;
+    for tier in 0..NUM_SIZE_CLASS {
+        let spans = config::options().prereserved_spans(tier);
+        for numa_meta in PER_NODE_META.iter() {
+            let size_class = &numa_meta.size_class_list[tier];
+            for _ in 0..spans {
+                let block = SuperBlock::new(tier as u32, size_class.size, 0, size_class.numa);
+                unsafe { &*block }.commit();
+                size_class.blocks.push(block as usize);
+                created += 1;
+            }
+        }
+    }
+    created
+}
+
+// Release pages of superblocks without live objects, returns released bytes
+pub fn purge_empty_superblocks() -> usize {
+    if !NODES_READY.load(Relaxed) || !CORES_READY.load(Relaxed) {
+        return 0;
+    }
+    let mut released = 0;
+    for node in PER_NODE_META.iter() {
+        if let Some(numa_meta) = node.get_if_created() {
+            released += purge_size_classes(&numa_meta.size_class_list);
+        }
+    }
+    for core in PER_CPU_META.iter() {
+        if let Some(cpu_meta) = core.get_if_created() {
+            released += purge_size_classes(&cpu_meta.size_class_list);
+        }
+    }
+    released
+}
+
+// Superblocks emptied by frees are bounded by the free list budget like free lists of the bump
+// heap. Frees only take note of the superblocks they empty, and the next allocation slow path
+// purges superblocks without live objects once those emptied since the last purge exceed it
+#[inline]
+fn note_emptied(span: usize) {
+    let budget = config::options().free_list_budget();
+    if budget != 0 && EMPTIED_BYTES.fetch_add(span, Relaxed) + span > budget {
+        OVER_BUDGET.store(true, Relaxed);
+    }
+}
+
+#[inline]
+fn purge_over_budget() {
+    if !OVER_BUDGET.load(Relaxed) || !OVER_BUDGET.swap(false, Relaxed) {
+        return;
+    }
+    EMPTIED_BYTES.store(0, Relaxed);
+    let released = purge_empty_superblocks();
+    extents::record(ExtentOp::Release, ExtentReason::CacheSpill, 0, released);
+    stats::incr(&stats::OVERFLOW_PURGES);
+    stats::add(&stats::RECLAIMED_BYTES, released);
+}
+
+// Steps of incremental purging, see reclaim: the size classes of every node, then of every CPU
+pub fn purge_steps() -> usize {
+    if !NODES_READY.load(Relaxed) || !CORES_READY.load(Relaxed) {
+        return 0;
+    }
+    (PER_NODE_META.len() + PER_CPU_META.len()) * NUM_SIZE_CLASS
+}
+
+// Bytes of superblocks of the step without live objects still resident and bytes of live
+// objects, after purging the superblocks first when asked. Along with the bytes released
+pub fn purge_step(step: usize, purge: bool) -> (usize, usize, usize) {
+    if !NODES_READY.load(Relaxed) || !CORES_READY.load(Relaxed) {
+        return (0, 0, 0);
+    }
+    let (meta, tier) = (step / NUM_SIZE_CLASS, step % NUM_SIZE_CLASS);
+    let num_nodes = PER_NODE_META.len();
+    let size_class = if meta < num_nodes {
+        PER_NODE_META[meta]
+            .get_if_created()
+            .map(|node| &node.size_class_list[tier])
+    } else {
+        PER_CPU_META
+            .get(meta - num_nodes)
+            .and_then(|core| core.get_if_created())
+            .map(|core| &core.size_class_list[tier])
+    };
+    let (mut dirty, mut live, mut released) = (0, 0, 0);
+    let blocks = size_class.into_iter().flat_map(|size_class| size_class.blocks.iter());
+    for (block_addr, _) in blocks {
+        let superblock = unsafe { &*(block_addr as *const SuperBlock) };
+        if purge {
+            released += superblock.purge();
+        }
+        let used = superblock.used.load(Relaxed) as usize;
+        if used == 0 && superblock.purged.load(Relaxed) == 0 {
+            dirty += superblock.reserved() as usize;
+        }
+        live += used;
+    }
+    (dirty, live, released)
+}
+
+// Live objects of each size class and free bytes held by each CPU, by walking the superblocks
+// instead of counting on the allocation path
+pub fn collect_stats(heap: &mut HeapStats) {
+    if !NODES_READY.load(Relaxed) || !CORES_READY.load(Relaxed) {
+        return;
+    }
+    for node in PER_NODE_META.iter() {
+        if let Some(numa_meta) = node.get_if_created() {
+            collect_size_classes(&numa_meta.size_class_list, heap);
+        }
+    }
+    for core in PER_CPU_META.iter() {
+        let cached = core
+            .get_if_created()
+            .map_or(0, |cpu_meta| collect_size_classes(&cpu_meta.size_class_list, heap));
+        heap.cpu_cache_bytes.push(cached);
+    }
+}
+
+// returns free bytes in the superblocks
+fn collect_size_classes(size_classes: &TSizeClasses, heap: &mut HeapStats) -> usize {
+    let mut free = 0;
+    for (tier, size_class) in size_classes.iter().enumerate() {
+        for (block_addr, _) in size_class.blocks.iter() {
+            let superblock = unsafe { &*(block_addr as *const SuperBlock) };
+            let used = superblock.used.load(Relaxed) as usize;
+            let span = superblock.span as usize;
+            let size = superblock.size() as usize;
+            heap.live_objects[tier] += used / size;
+            free += span - used;
+            let spans = &mut heap.class_spans[tier];
+            spans.total += 1;
+            spans.used_bytes += used;
+            spans.span_bytes += span;
+            if used == 0 {
+                spans.empty += 1;
+            } else if span - used < size {
+                spans.full += 1;
+            } else {
+                spans.partial += 1;
+            }
+        }
+    }
+    free
+}
+
+fn purge_size_classes(size_classes: &TSizeClasses) -> usize {
+    let mut released = 0;
+    for size_class in size_classes.iter() {
+        for (block_addr, _) in size_class.blocks.iter() {
+            let superblock = unsafe { &*(block_addr as *const SuperBlock) };
+            released += superblock.purge();
+        }
+    }
+    released
+}
+
+// Node of the thread to look objects up first, any node once the thread meta is gone in TLS
+// destructors, for lookups search the other nodes as well
+fn lookup_numa() -> u16 {
+    THREAD_META.try_with(|meta| meta.numa).unwrap_or(0)
+}
+
 pub fn size_of(ptr: Ptr) -> Option<usize> {
     let addr = ptr as usize;
-    let current_numa = THREAD_META.with(|meta| meta.numa);
+    let current_numa = lookup_numa();
+    get_from_objects(current_numa, addr).map(|superblock_addr| {
+        let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+        superblock_ref.size() as usize
+    })
+}
+
+// Generation of the superblock of the object. Pointers captured with an older generation are
+// from before the superblock was recycled
+pub fn generation_of(ptr: Ptr) -> Option<u32> {
+    let addr = ptr as usize;
+    let current_numa = lookup_numa();
+    get_from_objects(current_numa, addr).map(|superblock_addr| {
+        let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+        superblock_ref.generation()
+    })
+}
+
+pub fn contains(ptr: Ptr) -> bool {
+    let current_numa = lookup_numa();
+    get_from_objects(current_numa, ptr as usize).is_some()
+}
+
+// Metadata of the superblock holding the object
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanMeta {
+    pub size: usize,
+    pub span: usize,
+    pub cpu: u16,
+    pub numa: u16,
+    pub generation: u32,
+}
+
+pub fn meta_of(ptr: Ptr) -> Option<SpanMeta> {
+    let addr = ptr as usize;
+    let current_numa = lookup_numa();
     get_from_objects(current_numa, addr).map(|superblock_addr| {
         let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
-        superblock_ref.size as usize
+        SpanMeta {
+            size: superblock_ref.size() as usize,
+            span: superblock_ref.span as usize,
+            cpu: superblock_ref.cpu.load(Relaxed),
+            numa: superblock_ref.numa,
+            generation: superblock_ref.generation(),
+        }
     })
 }
 
+// Only for the child process after fork, rebuild shared structures other threads may have left
+// in the middle of operations
+pub unsafe fn reinit_after_fork() {
+    for node_meta in PER_NODE_META.iter() {
+        node_meta.bump_allocator.reinit_after_fork();
+        node_meta.pending_free.reinit_after_fork();
+        reinit_map_after_fork(&node_meta.objects);
+        reinit_size_classes_after_fork(&node_meta.size_class_list);
+    }
+    for cpu_meta in PER_CPU_META.iter() {
+        reinit_size_classes_after_fork(&cpu_meta.size_class_list);
+    }
+}
+
+unsafe fn reinit_size_classes_after_fork(size_classes: &TSizeClasses) {
+    for size_class in size_classes.iter() {
+        size_class.blocks.reinit_after_fork();
+        for (block_addr, _) in size_class.blocks.iter() {
+            let superblock = &*(block_addr as *const SuperBlock);
+            superblock.free_list.reinit_after_fork();
+        }
+    }
+}
+
 impl ThreadMeta {
     pub fn new() -> Self {
         let cpu_id = current_cpu();
@@ -133,6 +566,8 @@ impl ThreadMeta {
         Self {
             numa: numa_id,
             cpu: cpu_id,
+            last_size: Cell::new(0),
+            last_block: Cell::new(0),
         }
     }
 }
@@ -159,15 +594,22 @@ impl SizeClass {
                     return (addr, block_addr);
                 }
             }
+            // out of room, a slow path
+            reclaim::on_slow_path();
+            purge_over_budget();
             let new_block = if let Some(numa_common_block) = PER_NODE_META[self.numa as usize]
                 .size_class_list[self.tier as usize]
                 .blocks
                 .pop()
             {
-                let superblock_ref = unsafe { &mut *(numa_common_block as *mut SuperBlock) };
+                let superblock_ref = unsafe { &*(numa_common_block as *const SuperBlock) };
                 debug_assert_eq!(superblock_ref.numa, self.numa);
-                superblock_ref.cpu = self.cpu;
+                superblock_ref.cpu.store(self.cpu, Relaxed);
                 numa_common_block
+            } else if let Some(recycled_block) = recycle_pooled(self.numa, self.tier, self.size) {
+                let superblock_ref = unsafe { &*(recycled_block as *const SuperBlock) };
+                superblock_ref.cpu.store(self.cpu, Relaxed);
+                recycled_block
             } else {
                 debug_assert!(self.size > 1);
                 SuperBlock::new(self.tier, self.size, self.cpu, self.numa) as usize
@@ -175,6 +617,39 @@ impl SizeClass {
             self.blocks.push(new_block);
         }
     }
+
+    // allocate for per node size classes, never take superblocks from others
+    fn allocate_shared(&self) -> (usize, usize) {
+        loop {
+            for (block_addr, _) in self.blocks.iter() {
+                let superblock = unsafe { &*(block_addr as *mut SuperBlock) };
+                if let Some(addr) = superblock.allocate() {
+                    return (addr, block_addr);
+                }
+            }
+            purge_over_budget();
+            let new_block = SuperBlock::new(self.tier, self.size, self.cpu, self.numa) as usize;
+            self.blocks.push(new_block);
+        }
+    }
+}
+
+// Empty superblocks pooled on the node for other size classes, such as prereserved ones of classes
+// that turned out idle, are recycled before new superblocks are created. Only the top of every
+// pool is looked at
+fn recycle_pooled(numa: u16, tier: u32, size: u32) -> Option<usize> {
+    let size_classes = &PER_NODE_META[numa as usize].size_class_list;
+    for size_class in size_classes.iter().filter(|size_class| size_class.tier != tier) {
+        let block = match size_class.blocks.pop() {
+            Some(block) => block,
+            None => continue,
+        };
+        if unsafe { &*(block as *const SuperBlock) }.recycle(size) {
+            return Some(block);
+        }
+        size_class.blocks.push(block);
+    }
+    None
 }
 
 impl SuperBlock {
@@ -182,16 +657,20 @@ impl SuperBlock {
         // created a cache aligned super block
         // super block will not deallocated
         let node_allocator = &PER_NODE_META[numa as usize].bump_allocator;
-        let self_size = mem::size_of::<Self>();
-        let padding = align_padding(self_size, CACHE_LINE_SIZE);
         // Cache align on data
-        let self_size_with_padding = self_size + padding;
-        let chunk_size = self_size_with_padding + *SUPERBLOCK_SIZE;
+        let self_size_with_padding = superblock_header_size();
+        let span = config::options()
+            .span_size(tier as usize)
+            .unwrap_or(*SUPERBLOCK_SIZE);
+        let chunk_size = self_size_with_padding + span;
         // use bump_allocate function for it just allocate, do't record object address
         let addr = node_allocator.bump_allocate(chunk_size);
         let data_base = addr + self_size_with_padding;
         let ptr = addr as *mut Self;
+        stats::add(&stats::METADATA_BYTES, self_size_with_padding);
 
+        probe_event!(span_alloc, addr, chunk_size, tier as usize);
+        perf_map::annotate(addr, chunk_size, format_args!("nulloc::small_heap::class_{}", size));
         // ensure cache aligned
         debug_assert_eq!(align_padding(addr, CACHE_LINE_SIZE), 0);
         debug_assert_eq!(align_padding(data_base, CACHE_LINE_SIZE), 0);
@@ -201,49 +680,226 @@ impl SuperBlock {
                 ptr,
                 Self {
                     numa,
-                    size,
+                    size: AtomicU32::new(size),
+                    span: span as u32,
                     data_base,
-                    cpu,
-                    reservation: AtomicU32::new(0),
+                    cpu: AtomicU16::new(cpu),
+                    reservation: AtomicU64::new(1 << GENERATION_SHIFT),
                     used: AtomicU32::new(0),
+                    purged: AtomicU32::new(0),
                     free_list: lflist::WordList::new(),
                 },
             );
         }
+        let (first_page, end_page) = full_pages(data_base, span);
+        PAGE_MAP.insert_range(first_page, end_page, addr);
 
         return ptr;
     }
 
     fn allocate(&self) -> Option<usize> {
-        let res = self.free_list.pop().or_else(|| loop {
-            let pos = self.reservation.load(Relaxed);
-            let pos_ext = pos as usize;
-            if pos_ext as usize >= *SUPERBLOCK_SIZE {
-                return None;
-            } else {
-                let new_pos = pos + self.size;
-                if self.reservation.compare_and_swap(pos, new_pos, Relaxed) == pos {
-                    // insert to per CPU cache to avoid synchronization
-                    let address = pos_ext + self.data_base;
-                    PER_NODE_META[self.numa as usize]
-                        .objects
-                        .insert(address, self as *const Self as usize);
-                    return Some(address);
-                }
+        let res = self.free_list.pop().map(|addr| {
+            if hardened::is_enabled() {
+                unsafe { hardened::check_reuse(addr, self.size() as usize) };
+            }
+            addr
+        });
+        let res = res.or_else(|| {
+            let pos = thread_mode::exclusive(|| self.exclusive_reserve())
+                .unwrap_or_else(|| self.reserve())?;
+            // insert to per CPU cache to avoid synchronization
+            let address = pos + self.data_base;
+            let (first_page, end_page) = full_pages(self.data_base, self.span as usize);
+            let page = address >> *PAGE_SHIFT;
+            if page < first_page || page >= end_page {
+                PER_NODE_META[self.numa as usize]
+                    .objects
+                    .insert(address, self as *const Self as usize);
             }
+            Some(address)
         });
         if res.is_some() {
-            self.used.fetch_add(self.size, Relaxed);
-            debug_validate(res.unwrap() as Ptr, self.size as usize);
+            if self.purged.load(Relaxed) != 0 {
+                let recommitted = self.purged.swap(0, Relaxed);
+                stats::sub(&stats::DECOMMITTED_BYTES, recommitted as usize);
+            }
+            self.used.fetch_add(self.size(), Relaxed);
+            self.retag_dump(res.unwrap(), true);
+            debug_validate(res.unwrap() as Ptr, self.size() as usize);
         }
         return res;
     }
 
+    fn reserve(&self) -> Option<usize> {
+        loop {
+            let word = self.reservation.load(Acquire);
+            let pos = word as u32;
+            if pos >= self.span {
+                return None;
+            }
+            // the size belongs to the generation of the word unless the superblock was recycled
+            // since, which fails the CAS
+            let new_word = word + self.size() as u64;
+            if self.reservation.compare_and_swap(word, new_word, Relaxed) == word {
+                return Some(pos as usize);
+            }
+        }
+    }
+
+    // reserve without CAS, only for single thread mode
+    fn exclusive_reserve(&self) -> Option<usize> {
+        let word = self.reservation.load(Relaxed);
+        let pos = word as u32;
+        if pos >= self.span {
+            return None;
+        }
+        self.reservation.store(word + self.size() as u64, Relaxed);
+        Some(pos as usize)
+    }
+
+    #[inline]
+    fn size(&self) -> u32 {
+        self.size.load(Relaxed)
+    }
+
+    // bytes reserved from the start of the data area
+    #[inline]
+    fn reserved(&self) -> u32 {
+        self.reservation.load(Relaxed) as u32
+    }
+
+    #[inline]
+    fn generation(&self) -> u32 {
+        (self.reservation.load(Relaxed) >> GENERATION_SHIFT) as u32
+    }
+
+    // Claim all free slots so no one can allocate from this superblock while purging. Pages are
+    // only released when the claimed slots cover everything reserved, which means there is no
+    // live object in the superblock
+    fn purge(&self) -> usize {
+        // released already when purged, nothing was allocated since
+        if self.used.load(Relaxed) != 0 || self.purged.load(Relaxed) != 0 {
+            return 0;
+        }
+        let reserved = self.reserved() as usize;
+        if reserved == 0 {
+            return 0;
+        }
+        let claimed = lflist::WordList::<BumpAllocator>::with_capacity(64);
+        self.free_list.drop_out_into(&claimed);
+        let claimed_size = claimed.count() * self.size() as usize;
+        let released = if claimed_size == reserved {
+            let released = dealloc_pages_within(self.data_base as Ptr, reserved);
+            if released != 0 && hardened::is_enabled() {
+                self.forget_straddling_canary();
+            }
+            self.purged.store(released as u32, Relaxed);
+            stats::add(&stats::DECOMMITTED_BYTES, released);
+            released
+        } else {
+            0
+        };
+        self.free_list.prepend_with(&claimed);
+        released
+    }
+
+    // Turn a superblock without live objects into one of another size class, which bumps its
+    // generation. Claims free slots like purge, with reservations stopped meanwhile by moving the
+    // offset to the end of the span. The caller owns the superblock, taken off its size class
+    fn recycle(&self, size: u32) -> bool {
+        let old_size = self.size();
+        if self.used.load(Relaxed) != 0 || old_size == size || self.span % size != 0 {
+            return false;
+        }
+        let word = self.reservation.load(Relaxed);
+        let generation = word >> GENERATION_SHIFT;
+        let stopped = generation << GENERATION_SHIFT | self.span as u64;
+        if self.reservation.compare_and_swap(word, stopped, Relaxed) != word {
+            return false;
+        }
+        let claimed = lflist::WordList::<BumpAllocator>::with_capacity(64);
+        self.free_list.drop_out_into(&claimed);
+        if claimed.count() * old_size as usize != word as u32 as usize {
+            self.free_list.prepend_with(&claimed);
+            self.reservation.store(word, Relaxed);
+            return false;
+        }
+        self.size.store(size, Relaxed);
+        // reservations start over in the next generation, which publishes the size
+        let recycled = (generation + 1) << GENERATION_SHIFT;
+        self.reservation.store(recycled, Release);
+        true
+    }
+
+    // Fault in all pages of the data area, touching no byte outside of it
+    fn commit(&self) {
+        let page_size = *SYS_PAGE_SIZE;
+        let end = self.data_base + self.span as usize;
+        let mut addr = self.data_base;
+        while addr < end {
+            unsafe { ptr::write_volatile(addr as *mut u8, 0) };
+            addr += page_size - addr % page_size;
+        }
+    }
+
+    // only slots spanning whole pages can be excluded from core dumps
+    #[inline]
+    fn retag_dump(&self, addr: usize, dump: bool) {
+        let size = self.size() as usize;
+        if size >= *SYS_PAGE_SIZE << 1 && config::options().dontdump_free() {
+            dump_pages_within(addr as Ptr, size, dump);
+        }
+    }
+
+    // Released pages come back zeroed. Slots starting on them lose their canaries and are reused
+    // unchecked, but the slot across the start of the released pages keeps its canary with the
+    // rest of its poison gone, so its canary is dropped as well
+    fn forget_straddling_canary(&self) {
+        let size = self.size() as usize;
+        let first_page = self.data_base + align_padding(self.data_base, *SYS_PAGE_SIZE);
+        let slot = self.data_base + (first_page - self.data_base) / size * size;
+        if slot < first_page {
+            unsafe { hardened::forget(slot, size) };
+        }
+    }
+
+    fn check_and_poison(&self, addr: usize) {
+        let size = self.size() as usize;
+        unsafe {
+            hardened::check_free(addr, addr - self.data_base, size);
+            hardened::poison(addr, size);
+        }
+    }
+
     fn dealloc(&self, addr: usize) {
-        debug_assert!(addr >= self.data_base && addr < self.data_base + *SUPERBLOCK_SIZE);
-        debug_assert_eq!((addr - self.data_base) % self.size as usize, 0);
+        let size = self.size();
+        debug_assert!(addr >= self.data_base && addr < self.data_base + self.span as usize);
+        debug_assert_eq!((addr - self.data_base) % size as usize, 0);
+        // tag before the slot is visible to others in the free list
+        self.retag_dump(addr, false);
         self.free_list.push(addr);
-        self.used.fetch_sub(self.size, Relaxed);
+        if self.used.fetch_sub(size, Relaxed) == size {
+            note_emptied(self.span as usize);
+        }
+    }
+
+    // Objects of the superblock along with it, see free_batch
+    fn dealloc_batch(&self, current_numa: u16, objects: &[(usize, usize)]) {
+        let addrs = objects.iter().map(|(_, addr)| *addr);
+        if hardened::is_enabled() {
+            // before the slots are visible to others, remote frees included
+            addrs.clone().for_each(|addr| self.check_and_poison(addr));
+        }
+        if self.numa != current_numa {
+            PER_NODE_META[self.numa as usize].pending_free.push_batch(addrs);
+            return;
+        }
+        addrs.clone().for_each(|addr| self.retag_dump(addr, false));
+        self.free_list.push_batch(addrs);
+        let freed = self.size() * objects.len() as u32;
+        if self.used.fetch_sub(freed, Relaxed) == freed {
+            note_emptied(self.span as usize);
+        }
     }
 }
 
@@ -251,13 +907,21 @@ fn gen_numa_node_list() -> PerNodeMeta {
     let num_nodes = *NUM_NUMA_NODES;
     let mut nodes = PerNodeMeta::with_capacity(num_nodes as usize);
     for i in 0..num_nodes {
-        nodes.push(LazyWrapper::new(Box::new(move || NodeMeta {
-            size_class_list: size_classes(0, i),
-            bump_allocator: bump_heap::AllocatorInstance::new(),
-            pending_free: lflist::WordList::new(),
-            objects: lfmap::WordMap::with_capacity(*SYS_PAGE_SIZE),
+        nodes.push(LazyWrapper::new(Box::new(move || {
+            stats::add(&stats::METADATA_BYTES, mem::size_of::<NodeMeta>());
+            NodeMeta {
+                size_class_list: size_classes(0, i),
+                bump_allocator: bump_heap::AllocatorInstance::with_reason(
+                    0,
+                    if num_nodes > 1 { Some(i) } else { None },
+                    ExtentReason::ClassRefill,
+                ),
+                pending_free: lflist::WordList::new(),
+                objects: lfmap::WordMap::with_capacity(*SYS_PAGE_SIZE),
+            }
         })));
     }
+    NODES_READY.store(true, Relaxed);
     return nodes;
 }
 
@@ -290,19 +954,35 @@ fn min_power_of_2(mut n: usize) -> usize {
 
 #[inline]
 fn maximum_size() -> usize {
-    2 << (NUM_SIZE_CLASS - 1)
+    MAXIMUM_SMALL_SIZE
 }
 
 fn gen_core_meta() -> PerCPUMeta {
     let mut vec = PerCPUMeta::new();
     for cpu_id in 0..*NUM_CPU {
-        vec.push(LazyWrapper::new(Box::new(move || CoreMeta {
-            size_class_list: size_classes(cpu_id, SYS_CPU_NODE[&cpu_id]),
+        vec.push(LazyWrapper::new(Box::new(move || {
+            stats::add(&stats::METADATA_BYTES, mem::size_of::<CoreMeta>());
+            CoreMeta {
+                size_class_list: size_classes(cpu_id, numa_from_cpu_id(cpu_id)),
+            }
         })));
     }
+    CORES_READY.store(true, Relaxed);
     return vec;
 }
 
+// Bytes of the superblock header before its data area, padded to cache lines
+#[inline]
+fn superblock_header_size() -> usize {
+    let self_size = mem::size_of::<SuperBlock>();
+    self_size + align_padding(self_size, CACHE_LINE_SIZE)
+}
+
+// Largest span of a superblock, which is bumped along with its header in one address space
+pub fn max_span_size() -> usize {
+    bump_heap::HEAP_VIRT_SIZE - superblock_header_size()
+}
+
 fn debug_check_cache_aligned(addr: usize, size: usize, align: usize) {
     if size >= align {
         // ensure all address are cache aligned
@@ -310,7 +990,17 @@ fn debug_check_cache_aligned(addr: usize, size: usize, align: usize) {
     }
 }
 
+// Pages entirely in the data area from `base` of `span` bytes, as a range of page numbers
+fn full_pages(base: usize, span: usize) -> (usize, usize) {
+    let first = (base + *SYS_PAGE_SIZE - 1) >> *PAGE_SHIFT;
+    let end = (base + span) >> *PAGE_SHIFT;
+    (first, max(first, end))
+}
+
 fn get_from_objects(current_numa: u16, addr: usize) -> Option<usize> {
+    if let Some(superblock_addr) = PAGE_MAP.get(addr >> *PAGE_SHIFT) {
+        return Some(superblock_addr);
+    }
     let current_numa_ext = current_numa as usize;
     if let Some(addr) = PER_NODE_META[current_numa_ext].objects.get(addr) {
         return Some(addr);
@@ -330,10 +1020,21 @@ fn get_from_objects(current_numa: u16, addr: usize) -> Option<usize> {
 
 #[cfg(test)]
 mod test {
-    use crate::api::SkyhooksAllocator;
-    use crate::small_heap::{allocate, free};
+    use crate::api::{nu_free_checked, SkyhooksAllocator};
+    use crate::small_heap::{
+        allocate, allocate_on_node, contains, free, generation_of, lookup_numa, meta_of,
+        prereserve, purge_over_budget, size_of, superblock_header_size, SuperBlock, MAXIMUM_SIZE,
+        OVER_BUDGET,
+    };
+    use crate::generic_heap::size_class_index_from_size;
+    use crate::config::{self, TestOption};
+    use crate::utils::NUM_NUMA_NODES;
+    use crate::Ptr;
+    use crate::stats;
     use crate::utils::AddressHasher;
     use lfmap::Map;
+    use std::sync::atomic::Ordering::Relaxed;
+    use test::Bencher;
 
     #[test]
     pub fn general() {
@@ -356,6 +1057,163 @@ mod test {
         assert_eq!(ptr, ptr2);
     }
 
+    #[test]
+    pub fn generation() {
+        let ptr = allocate(24);
+        let generation = generation_of(ptr).unwrap();
+        assert!(generation >= 1);
+        assert_eq!(generation_of(0x10 as Ptr), None);
+        free(ptr);
+    }
+
+    #[test]
+    #[cfg(feature = "hardened")]
+    pub fn hardened_purge() {
+        assert!(crate::hardened::enable(true));
+        // slots of 3K straddle pages, take a data area off page boundaries for one to straddle the
+        // start of the released pages
+        let size = 3 << 10;
+        let superblock = (0..2)
+            .map(|_| unsafe { &*SuperBlock::new(0, size, 0, lookup_numa()) })
+            .find(|superblock| superblock.data_base % *crate::utils::SYS_PAGE_SIZE != 0)
+            .unwrap();
+        let slots = (0..8).map(|_| superblock.allocate().unwrap()).collect::<Vec<_>>();
+        for &addr in &slots {
+            superblock.check_and_poison(addr);
+            superblock.dealloc(addr);
+        }
+        assert!(superblock.purge() > 0);
+        // zeroed pages under free slots are no writes after free
+        let slots = (0..8).map(|_| superblock.allocate().unwrap()).collect::<Vec<_>>();
+        for addr in slots {
+            superblock.check_and_poison(addr);
+            superblock.dealloc(addr);
+        }
+    }
+
+    #[test]
+    pub fn stale_generation() {
+        // a superblock in no size class, for no one else to allocate from
+        let superblock = unsafe { &*SuperBlock::new(0, 64, 0, lookup_numa()) };
+        let addr = superblock.allocate().unwrap();
+        let generation = generation_of(addr as Ptr).unwrap();
+        // recycled only without live objects
+        assert!(!superblock.recycle(128));
+        superblock.dealloc(addr);
+        assert!(superblock.recycle(128));
+        assert_eq!(generation_of(addr as Ptr), Some(generation + 1));
+        assert!(!unsafe { nu_free_checked(addr as Ptr, generation as usize) });
+        let addr = superblock.allocate().unwrap();
+        assert_eq!(meta_of(addr as Ptr).unwrap().size, 128);
+        assert_eq!(generation_of(addr as Ptr), Some(generation + 1));
+        superblock.dealloc(addr);
+    }
+
+    #[test]
+    pub fn object_meta() {
+        let ptr = allocate(100);
+        assert!(contains(ptr));
+        assert_eq!(size_of(ptr), Some(128));
+        let meta = meta_of(ptr).unwrap();
+        assert_eq!(meta.size, 128);
+        assert_eq!(meta.span % meta.size, 0);
+        assert_eq!(Some(meta.generation), generation_of(ptr));
+        assert!(!contains(0x10 as Ptr));
+        assert_eq!(meta_of(0x10 as Ptr), None);
+        free(ptr);
+    }
+
+    #[test]
+    pub fn node_allocation() {
+        for node in 0..*NUM_NUMA_NODES {
+            let ptr = allocate_on_node(100, node);
+            assert_eq!(meta_of(ptr).unwrap().numa, node);
+            assert_eq!(size_of(ptr), Some(128));
+            assert!(free(ptr));
+        }
+    }
+
+    #[test]
+    pub fn free_after_thread_exit() {
+        let ptr = allocate(12 << 10);
+        let superblock_addr = get_from_objects(0, ptr as usize).unwrap();
+        let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+        assert!(free_after_exit(ptr));
+        assert!(!free_after_exit(0x10 as Ptr));
+        flush_pending_frees();
+        assert!(superblock.free_list.iter().any(|(addr, _)| addr == ptr as usize));
+    }
+
+    #[test]
+    pub fn purge() {
+        // out of size class lists, no one else allocates from it
+        let size = *MAXIMUM_SIZE;
+        let superblock = unsafe { &*SuperBlock::new(15, size as u32, 0, 0) };
+        let objects = (0..4).map(|_| superblock.allocate().unwrap()).collect::<Vec<_>>();
+        assert_eq!(superblock.purge(), 0);
+        for addr in objects {
+            superblock.dealloc(addr);
+        }
+        let released = superblock.purge();
+        assert!(released >= size * 3, "released {}", released);
+        assert_eq!(superblock.purged.load(Relaxed) as usize, released);
+        // nothing more to release until allocated from
+        assert_eq!(superblock.purge(), 0);
+        let addr = superblock.allocate().unwrap();
+        assert_eq!(superblock.purged.load(Relaxed), 0);
+        superblock.dealloc(addr);
+    }
+
+    #[test]
+    pub fn superblocks_over_budget() {
+        let size = *MAXIMUM_SIZE;
+        let superblock = unsafe { &*SuperBlock::new(15, size as u32, 0, 0) };
+        let objects = (0..4).map(|_| superblock.allocate().unwrap()).collect::<Vec<_>>();
+        let purges = stats::get(&stats::OVERFLOW_PURGES);
+        let previous = config::options().free_list_budget().to_string();
+        let _budget = TestOption::set("free_list_budget", "4K", previous);
+        for addr in objects {
+            superblock.dealloc(addr);
+        }
+        // left to the next allocation slow path
+        assert!(OVER_BUDGET.load(Relaxed));
+        purge_over_budget();
+        assert!(stats::get(&stats::OVERFLOW_PURGES) > purges);
+    }
+
+    #[test]
+    pub fn prereserved_superblocks() {
+        let previous = config::options().prereserved_spans(size_class_index_from_size(32 << 10));
+        let _spans = TestOption::set("prereserve.32K", "2", previous.to_string());
+        let created = prereserve();
+        // superblocks pooled on nodes may be taken by CPUs or recycled for other classes right
+        // away, only their creation is checked
+        assert_eq!(created, 2 * *NUM_NUMA_NODES as usize);
+    }
+
+    #[test]
+    pub fn metadata_overhead() {
+        // superblocks in no size class, filled by this test alone
+        let size = 64;
+        let numa = lookup_numa();
+        let superblocks = (0..16)
+            .map(|_| unsafe { &*SuperBlock::new(0, size as u32, 0, numa) })
+            .collect::<Vec<_>>();
+        let mut objects = vec![];
+        for superblock in superblocks.iter() {
+            while let Some(addr) = superblock.allocate() {
+                objects.push((superblock, addr));
+            }
+        }
+        let metadata = superblocks.len() * superblock_header_size();
+        // metadata shall stay within 10% of live objects
+        assert!(!objects.is_empty());
+        assert!(metadata * 10 < size * objects.len(), "metadata bytes: {}", metadata);
+        for (superblock, addr) in objects {
+            superblock.dealloc(addr);
+        }
+    }
+
     #[test]
     pub fn application() {
         let map = lfmap::WordMap::<SkyhooksAllocator, AddressHasher>::with_capacity(64);
@@ -369,4 +1227,17 @@ mod test {
             assert_eq!(map.remove(i), Some(i * 2), "index: {}", i);
         }
     }
+
+    #[bench]
+    fn same_size_loop(b: &mut Bencher) {
+        let mut objects = Vec::with_capacity(64);
+        b.iter(|| {
+            for _ in 0..64 {
+                objects.push(allocate(48));
+            }
+            for ptr in objects.drain(..) {
+                free(ptr);
+            }
+        });
+    }
 }
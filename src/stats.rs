@@ -0,0 +1,364 @@
+// Allocator statistics
+// Counters are relaxed atomics, cheap enough to be always enabled
+
+use crate::collections::seqlock::SeqLock;
+use crate::extents::{ExtentOp, ExtentReason, NUM_EXTENT_OPS, NUM_EXTENT_REASONS};
+use crate::generic_heap::{log_2_of, size_class_index_from_size, MAXIMUM_SMALL_SIZE, NUM_SIZE_CLASS};
+use crate::strict;
+use crate::utils::SYS_PAGE_SIZE;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+// mmap failures that triggered reclamation
+pub static OOM_EVENTS: AtomicUsize = AtomicUsize::new(0);
+// mmap failures survived by retrying after reclamation
+pub static OOM_RECOVERED: AtomicUsize = AtomicUsize::new(0);
+// memory pressure notifications from the application
+pub static PRESSURE_NOTIFICATIONS: AtomicUsize = AtomicUsize::new(0);
+// bytes released to the OS by reclamation
+pub static RECLAIMED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// purges triggered by free lists over their budget
+pub static OVERFLOW_PURGES: AtomicUsize = AtomicUsize::new(0);
+// purges triggered by the decay timer, see reclaim
+pub static DECAY_PURGES: AtomicUsize = AtomicUsize::new(0);
+// bytes of superblock pages released to the OS and not allocated from since
+pub static DECOMMITTED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// requests of allocator instances over the growth limit, see growth
+pub static THROTTLED_GROWTH: AtomicUsize = AtomicUsize::new(0);
+// bytes held by the allocator for itself: superblock headers, list buffers, fixed vectors and
+// per node and per CPU meta. Maps from external crates are not included
+pub static METADATA_BYTES: AtomicUsize = AtomicUsize::new(0);
+// memory mappings of the allocator and their bytes, see mmap
+pub static MAPPED_SEGMENTS: AtomicUsize = AtomicUsize::new(0);
+pub static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// live objects of the bump heap and their bytes, which are large objects and objects allocated
+// inside the allocator
+pub static LARGE_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+pub static LARGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+// bytes of all failed allocations
+pub static FAILED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// 1 when the NUMA system calls are refused and the heaps run as on a single node, see utils
+pub static NUMA_DEGRADED: AtomicUsize = AtomicUsize::new(0);
+
+// Buckets of failed large allocations, by powers of two from twice the largest size class. The
+// last bucket takes everything larger
+pub const LARGE_FAILURE_BUCKETS: usize = 10;
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+lazy_static! {
+    // failed allocations of each size class followed by the large buckets, see failure_bucket
+    static ref FAILED_ALLOCATIONS: [AtomicUsize; NUM_SIZE_CLASS + LARGE_FAILURE_BUCKETS] =
+        Default::default();
+    // bytes mapped, unmapped and released by reason, see extents
+    static ref EXTENT_BYTES: [[AtomicUsize; NUM_EXTENT_REASONS]; NUM_EXTENT_OPS] =
+        Default::default();
+    // last snapshot taken, readers always see a whole one
+    static ref SNAPSHOT: SeqLock<StatsSnapshot> = SeqLock::new(StatsSnapshot::default());
+}
+
+// Snapshot of the heap, see generic_heap::heap_stats
+#[derive(Clone, Debug, Default)]
+pub struct HeapStats {
+    // bytes of live objects, small objects are counted by their size classes
+    pub allocated_bytes: usize,
+    // resident set of the whole process
+    pub resident_bytes: usize,
+    pub mapped_bytes: usize,
+    pub mapped_segments: usize,
+    pub metadata_bytes: usize,
+    // bytes released to the OS since start, and bytes of superblocks still released
+    pub reclaimed_bytes: usize,
+    pub decommitted_bytes: usize,
+    // live small objects of each size class
+    pub live_objects: [usize; NUM_SIZE_CLASS],
+    pub large_objects: usize,
+    // failed allocations of each size class and of large sizes, see failure_bucket. Failures of
+    // large sizes alone point to fragmented address space rather than running out of memory
+    pub failed_small: [usize; NUM_SIZE_CLASS],
+    pub failed_large: [usize; LARGE_FAILURE_BUCKETS],
+    pub failed_bytes: usize,
+    // bytes mapped, unmapped and released since start, by ExtentOp and ExtentReason
+    pub extent_bytes: [[usize; NUM_EXTENT_REASONS]; NUM_EXTENT_OPS],
+    // free bytes in superblocks of each CPU, which only the CPU allocates from
+    pub cpu_cache_bytes: Vec<usize>,
+    // superblocks of each size class by how full they are
+    pub class_spans: [ClassSpans; NUM_SIZE_CLASS],
+    // running as on a single node for the NUMA system calls are refused
+    pub numa_degraded: bool,
+}
+
+// Superblocks of a size class, many partial or empty ones with low occupancy are fragmenting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassSpans {
+    pub total: usize,
+    // without room for another object
+    pub full: usize,
+    pub partial: usize,
+    // without live objects
+    pub empty: usize,
+    // bytes of live objects and of the spans
+    pub used_bytes: usize,
+    pub span_bytes: usize,
+}
+
+// Plain copy of all counters for monitoring agents in other languages. Fields are native endian
+// u64 and only ever appended, readers shall check `size` before reading fields of newer versions
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub version: u64,
+    // bytes of the struct
+    pub size: u64,
+    pub allocated_bytes: u64,
+    pub resident_bytes: u64,
+    pub mapped_bytes: u64,
+    pub mapped_segments: u64,
+    pub metadata_bytes: u64,
+    pub reclaimed_bytes: u64,
+    pub decommitted_bytes: u64,
+    pub large_objects: u64,
+    // free bytes in superblocks of all CPUs
+    pub cpu_cache_bytes: u64,
+    pub oom_events: u64,
+    pub oom_recovered: u64,
+    pub pressure_notifications: u64,
+    pub overflow_purges: u64,
+    pub decay_purges: u64,
+    pub throttled_growth: u64,
+    pub failed_bytes: u64,
+    pub live_objects: [u64; NUM_SIZE_CLASS],
+    pub failed_small: [u64; NUM_SIZE_CLASS],
+    pub failed_large: [u64; LARGE_FAILURE_BUCKETS],
+    // 1 when the NUMA system calls are refused
+    pub numa_degraded: u64,
+}
+
+// Snapshot of the heap stats and the counters, published for concurrent readers. Stats are
+// collected under the writer, so concurrent publishers never put an older snapshot over a newer
+pub fn publish<F: FnOnce() -> HeapStats>(collect: F) -> StatsSnapshot {
+    let snapshot = publish_into(&SNAPSHOT, collect);
+    probe_event!(
+        stats_snapshot,
+        snapshot.allocated_bytes,
+        snapshot.resident_bytes,
+        snapshot.mapped_bytes,
+        snapshot.metadata_bytes
+    );
+    snapshot
+}
+
+fn publish_into<F: FnOnce() -> HeapStats>(
+    lock: &SeqLock<StatsSnapshot>,
+    collect: F,
+) -> StatsSnapshot {
+    lock.write(|published| {
+        *published = snapshot_of(&collect());
+        *published
+    })
+}
+
+fn snapshot_of(heap: &HeapStats) -> StatsSnapshot {
+    let mut snapshot = StatsSnapshot {
+        version: SNAPSHOT_VERSION,
+        size: mem::size_of::<StatsSnapshot>() as u64,
+        allocated_bytes: heap.allocated_bytes as u64,
+        resident_bytes: heap.resident_bytes as u64,
+        mapped_bytes: heap.mapped_bytes as u64,
+        mapped_segments: heap.mapped_segments as u64,
+        metadata_bytes: heap.metadata_bytes as u64,
+        reclaimed_bytes: heap.reclaimed_bytes as u64,
+        decommitted_bytes: heap.decommitted_bytes as u64,
+        large_objects: heap.large_objects as u64,
+        cpu_cache_bytes: heap.cpu_cache_bytes.iter().sum::<usize>() as u64,
+        oom_events: get(&OOM_EVENTS) as u64,
+        oom_recovered: get(&OOM_RECOVERED) as u64,
+        pressure_notifications: get(&PRESSURE_NOTIFICATIONS) as u64,
+        overflow_purges: get(&OVERFLOW_PURGES) as u64,
+        decay_purges: get(&DECAY_PURGES) as u64,
+        throttled_growth: get(&THROTTLED_GROWTH) as u64,
+        failed_bytes: heap.failed_bytes as u64,
+        numa_degraded: heap.numa_degraded as u64,
+        ..StatsSnapshot::default()
+    };
+    copy_counts(&mut snapshot.live_objects, &heap.live_objects);
+    copy_counts(&mut snapshot.failed_small, &heap.failed_small);
+    copy_counts(&mut snapshot.failed_large, &heap.failed_large);
+    snapshot
+}
+
+// Last published snapshot, all zero before the first
+pub fn published() -> StatsSnapshot {
+    SNAPSHOT.read()
+}
+
+fn copy_counts(to: &mut [u64], from: &[usize]) {
+    for (to, from) in to.iter_mut().zip(from.iter()) {
+        *to = *from as u64;
+    }
+}
+
+#[inline]
+pub fn incr(counter: &AtomicUsize) {
+    counter.fetch_add(1, Relaxed);
+}
+
+#[inline]
+pub fn add(counter: &AtomicUsize, value: usize) {
+    counter.fetch_add(value, Relaxed);
+}
+
+#[inline]
+pub fn sub(counter: &AtomicUsize, value: usize) {
+    counter.fetch_sub(value, Relaxed);
+}
+
+#[inline]
+pub fn get(counter: &AtomicUsize) -> usize {
+    counter.load(Relaxed)
+}
+
+// Account an allocation of `size` bytes the heaps could not serve
+#[cold]
+pub fn count_failure(size: usize) {
+    incr(&FAILED_ALLOCATIONS[failure_bucket(size)]);
+    add(&FAILED_BYTES, size);
+}
+
+// Size class of small sizes, NUM_SIZE_CLASS plus the large bucket otherwise. Large bucket `i`
+// counts sizes up to `MAXIMUM_SMALL_SIZE << (i + 1)`
+pub fn failure_bucket(size: usize) -> usize {
+    if size <= MAXIMUM_SMALL_SIZE {
+        return size_class_index_from_size(size.max(1));
+    }
+    let ceil_log = log_2_of(size - 1) + 1;
+    let bucket = ceil_log - log_2_of(MAXIMUM_SMALL_SIZE) - 1;
+    NUM_SIZE_CLASS + bucket.min(LARGE_FAILURE_BUCKETS - 1)
+}
+
+pub fn collect_failures(heap: &mut HeapStats) {
+    for (count, failed) in heap.failed_small.iter_mut().zip(FAILED_ALLOCATIONS.iter()) {
+        *count = get(failed);
+    }
+    let large = &FAILED_ALLOCATIONS[NUM_SIZE_CLASS..];
+    for (count, failed) in heap.failed_large.iter_mut().zip(large.iter()) {
+        *count = get(failed);
+    }
+    heap.failed_bytes = get(&FAILED_BYTES);
+}
+
+#[inline]
+pub fn count_extent(op: ExtentOp, reason: ExtentReason, size: usize) {
+    add(&EXTENT_BYTES[op as usize][reason as usize], size);
+}
+
+pub fn extent_bytes(op: ExtentOp, reason: ExtentReason) -> usize {
+    get(&EXTENT_BYTES[op as usize][reason as usize])
+}
+
+pub fn collect_extents(heap: &mut HeapStats) {
+    for (bytes, counters) in heap.extent_bytes.iter_mut().zip(EXTENT_BYTES.iter()) {
+        for (bytes, counter) in bytes.iter_mut().zip(counters.iter()) {
+            *bytes = get(counter);
+        }
+    }
+}
+
+impl ClassSpans {
+    // Live bytes over span bytes, in percent
+    pub fn occupancy(&self) -> f64 {
+        if self.span_bytes == 0 {
+            0.0
+        } else {
+            self.used_bytes as f64 * 100.0 / self.span_bytes as f64
+        }
+    }
+}
+
+// Utilization of every size class as a table, see ClassSpans
+pub fn write_class_report<W: Write>(heap: &HeapStats, writer: &mut W) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{:>6} {:>8} {:>8} {:>8} {:>8} {:>9}",
+        "class", "spans", "full", "partial", "empty", "occupancy"
+    )?;
+    for (tier, spans) in heap.class_spans.iter().enumerate() {
+        writeln!(
+            writer,
+            "{:>6} {:>8} {:>8} {:>8} {:>8} {:>8.1}%",
+            2 << tier,
+            spans.total,
+            spans.full,
+            spans.partial,
+            spans.empty,
+            spans.occupancy()
+        )?;
+    }
+    Ok(())
+}
+
+// Resident set size of the process from procfs, zero if unavailable
+pub fn resident_bytes() -> usize {
+    if strict::is_enabled() {
+        return 0;
+    }
+    fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .map_or(0, |pages| pages * *SYS_PAGE_SIZE)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stats::*;
+
+    #[test]
+    pub fn failure_buckets() {
+        assert_eq!(failure_bucket(0), 0);
+        assert_eq!(failure_bucket(24), 4);
+        assert_eq!(failure_bucket(MAXIMUM_SMALL_SIZE), NUM_SIZE_CLASS - 1);
+        assert_eq!(failure_bucket(MAXIMUM_SMALL_SIZE + 1), NUM_SIZE_CLASS);
+        assert_eq!(failure_bucket(2 << 20), NUM_SIZE_CLASS + 4);
+        assert_eq!(failure_bucket((2 << 20) + 1), NUM_SIZE_CLASS + 5);
+        assert_eq!(failure_bucket(1 << 60), NUM_SIZE_CLASS + LARGE_FAILURE_BUCKETS - 1);
+    }
+
+    #[test]
+    pub fn snapshot_layout() {
+        let mut heap = HeapStats::default();
+        heap.allocated_bytes = 42;
+        heap.live_objects[3] = 7;
+        heap.failed_large[9] = 1;
+        let lock = SeqLock::new(StatsSnapshot::default());
+        let snapshot = publish_into(&lock, || heap);
+        assert_eq!(lock.read(), snapshot);
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert_eq!(snapshot.size as usize, (19 + NUM_SIZE_CLASS * 2 + LARGE_FAILURE_BUCKETS) * 8);
+        assert_eq!(snapshot.allocated_bytes, 42);
+        assert_eq!(snapshot.live_objects[3], 7);
+        assert_eq!(snapshot.failed_large[9], 1);
+    }
+
+    #[test]
+    pub fn class_report() {
+        let mut heap = HeapStats::default();
+        heap.class_spans[3] = ClassSpans {
+            total: 4,
+            full: 1,
+            partial: 2,
+            empty: 1,
+            used_bytes: 3 << 20,
+            span_bytes: 4 << 20,
+        };
+        let mut report = vec![];
+        write_class_report(&heap, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), NUM_SIZE_CLASS + 1);
+        assert_eq!(lines[0], " class    spans     full  partial    empty occupancy");
+        assert_eq!(lines[1], "     2        0        0        0        0      0.0%");
+        assert_eq!(lines[4], "    16        4        1        2        1     75.0%");
+    }
+}
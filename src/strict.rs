@@ -0,0 +1,178 @@
+// Strict syscall mode for sandboxes with tight seccomp policies
+// Once switched on by the `strict_syscalls` option, the allocator makes no system calls but mmap,
+// munmap and madvise:
+//   - CPUs are picked from thread ids instead of sched_getcpu
+//   - nothing is read from procfs or sysfs, the resident set in stats is zero
+//   - pages are neither bound to NUMA nodes nor locked in memory. Allocations on nodes other than
+//     node 0 fail, instances without swap keep their pages swappable
+//   - options mapping shared memory, writing files, walking stacks, or fencing other threads are
+//     switched off and refused: sample_interval, stats_segment, perf_map, profile_interval,
+//     huge_alloc_log, single_thread, backoff_yields, which parks threads with futex, and hardened
+//     and unmap_delay, which need mprotect
+// CPU and NUMA topology and the random keys are read when the mode is switched on, so put it in
+// NULLOC_CONF or set it before installing the filter.
+//
+// Policies still need clock_gettime, which the vDSO serves without a system call on most clock
+// sources, and sched_yield for contended lock-free operations. Growth limits with the block
+// policy sleep with nanosleep. Without strict mode the allocator also calls, for writing policies:
+//   init:     open, read, getdents64 and close on /sys/devices/system/node, sysinfo,
+//             get_mempolicy and mbind to probe NUMA
+//   steady:   sched_getcpu, mbind on NUMA machines, mlock2 for the journal and instances without
+//             swap, read of /proc/self/statm for stats
+//   options:  shm_open, ftruncate and a thread for sample_interval and stats_segment, open and
+//             write for perf_map, mprotect for hardened and unmap_delay, membarrier for
+//             single_thread, futex for backoff_yields, sigaction for crash_handler
+
+use crate::collections::backoff;
+use crate::utils::{NUM_CPU, NUM_NUMA_NODES, SYS_CPU_NODE, SYS_PAGE_SIZE};
+#[cfg(unix)]
+use crate::shared_stats;
+use crate::{audit, hardened, perf_map, profile, quarantine, sampling, thread_mode};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Returns false when hardened mode is on, its guard pages cannot be made without mprotect
+pub fn enable(on: bool) -> bool {
+    if !on {
+        ENABLED.store(false, Relaxed);
+        return true;
+    }
+    if hardened::is_enabled() {
+        return false;
+    }
+    // everything read from the system later on
+    let _ = (*NUM_NUMA_NODES, *NUM_CPU, *SYS_PAGE_SIZE, SYS_CPU_NODE.len());
+    sampling::disable();
+    #[cfg(unix)]
+    shared_stats::disable();
+    perf_map::disable();
+    profile::set_interval(0);
+    audit::set_threshold(0);
+    quarantine::set_delay(0);
+    backoff::set_yields(0);
+    thread_mode::leave();
+    ENABLED.store(true, Relaxed);
+    true
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod test {
+    use crate::api::{nu_free, nu_malloc};
+    use crate::strict::*;
+    use libc::*;
+
+    // exit status of the child when the filter cannot be installed
+    const NO_SECCOMP: c_int = 77;
+    const SECCOMP_MODE_FILTER: c_ulong = 2;
+    const SECCOMP_RET_KILL: u32 = 0;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    // offsets in struct seccomp_data
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+    // the allocator, the clock fallback of the vDSO, and the exit of the child
+    const ALLOWED: [c_long; 6] =
+        [SYS_mmap, SYS_munmap, SYS_madvise, SYS_clock_gettime, SYS_exit, SYS_exit_group];
+
+    #[repr(C)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter { code, jt: 0, jf: 0, k }
+    }
+
+    fn jump_if(k: u32, jt: u8) -> SockFilter {
+        SockFilter {
+            code: BPF_JMP_JEQ_K,
+            jt,
+            jf: 0,
+            k,
+        }
+    }
+
+    // Kill the process on any system call but the allowed ones
+    fn filter(allowed: &[c_long]) -> Vec<SockFilter> {
+        let count = allowed.len();
+        let mut program = vec![
+            stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+            jump_if(AUDIT_ARCH_X86_64, 1),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL),
+            stmt(BPF_LD_W_ABS, NR_OFFSET),
+        ];
+        for (i, nr) in allowed.iter().enumerate() {
+            // past the comparisons left and the kill
+            program.push(jump_if(*nr as u32, (count - i) as u8));
+        }
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_KILL));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        program
+    }
+
+    unsafe fn install_filter() -> bool {
+        let program = filter(&ALLOWED);
+        let prog = SockFprog {
+            len: program.len() as u16,
+            filter: program.as_ptr(),
+        };
+        prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+            && prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog, 0, 0) == 0
+    }
+
+    #[test]
+    pub fn allocate_under_seccomp() {
+        unsafe {
+            let pid = fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                // only the child runs in strict mode, other tests go on in the parent
+                if !enable(true) || !install_filter() {
+                    _exit(NO_SECCOMP);
+                }
+                let mut objects = Vec::new();
+                for _ in 0..4 {
+                    // small, bump heap and mapped objects
+                    for shift in 3..28 {
+                        let ptr = nu_malloc(1 << shift);
+                        *(ptr as *mut u8) = 1;
+                        objects.push(ptr);
+                    }
+                    for ptr in objects.drain(..) {
+                        nu_free(ptr);
+                    }
+                }
+                _exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(waitpid(pid, &mut status, 0), pid);
+            if WIFEXITED(status) && WEXITSTATUS(status) == NO_SECCOMP {
+                return;
+            }
+            assert!(
+                WIFEXITED(status) && WEXITSTATUS(status) == 0,
+                "child killed by signal {}",
+                WTERMSIG(status)
+            );
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Memory accounting per tenant
+// Allocator instances can be labeled with a tenant. Allocations and frees through labeled
+// instances are aggregated per label, so multi-tenant servers can report and limit memory per
+// tenant without wrapping the allocator. Labels are registered once and never removed, up to
+// MAX_TENANTS labels of at most MAX_LABEL_LEN bytes
+
+use crate::collections::backoff::Backoff;
+use crate::collections::fixvec::FixedVec;
+use crate::mmap_heap::MmapAllocator;
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+pub const MAX_TENANTS: usize = 256;
+pub const MAX_LABEL_LEN: usize = 48;
+
+const SLOT_FREE: usize = 0;
+const SLOT_WRITING: usize = 1;
+const SLOT_READY: usize = 2;
+
+lazy_static! {
+    static ref TENANTS: FixedVec<Tenant, MmapAllocator> = FixedVec::new(MAX_TENANTS);
+}
+
+// all zero for free slot
+struct Tenant {
+    state: AtomicUsize,
+    label_len: AtomicUsize,
+    label: UnsafeCell<[u8; MAX_LABEL_LEN]>,
+    live_bytes: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    freed_bytes: AtomicUsize,
+    // zero for unlimited
+    limit: AtomicUsize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TenantId(usize);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TenantStats {
+    pub live_bytes: usize,
+    pub allocated_bytes: usize,
+    pub freed_bytes: usize,
+    pub limit: usize,
+}
+
+impl Tenant {
+    fn label(&self) -> &[u8] {
+        let len = self.label_len.load(Relaxed);
+        unsafe { &(*self.label.get())[..len] }
+    }
+
+    fn stats(&self) -> TenantStats {
+        TenantStats {
+            live_bytes: self.live_bytes.load(Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Relaxed),
+            freed_bytes: self.freed_bytes.load(Relaxed),
+            limit: self.limit.load(Relaxed),
+        }
+    }
+}
+
+// Id of the label, registering it on first use
+pub fn register(label: &str) -> Option<TenantId> {
+    let bytes = label.as_bytes();
+    if bytes.is_empty() || bytes.len() > MAX_LABEL_LEN {
+        return None;
+    }
+    let backoff = Backoff::new();
+    for index in 0..MAX_TENANTS {
+        let tenant = &TENANTS[index];
+        loop {
+            match tenant.state.compare_and_swap(SLOT_FREE, SLOT_WRITING, Acquire) {
+                SLOT_FREE => {
+                    unsafe {
+                        (*tenant.label.get())[..bytes.len()].copy_from_slice(bytes);
+                    }
+                    tenant.label_len.store(bytes.len(), Relaxed);
+                    tenant.state.store(SLOT_READY, Release);
+                    return Some(TenantId(index));
+                }
+                SLOT_WRITING => backoff.snooze(),
+                _ => break,
+            }
+        }
+        if tenant.label() == bytes {
+            return Some(TenantId(index));
+        }
+    }
+    warn!("Too many tenants, cannot register {}", label);
+    None
+}
+
+pub fn find(label: &str) -> Option<TenantId> {
+    let bytes = label.as_bytes();
+    (0..MAX_TENANTS)
+        .take_while(|&index| TENANTS[index].state.load(Acquire) != SLOT_FREE)
+        .find(|&index| {
+            let tenant = &TENANTS[index];
+            tenant.state.load(Acquire) == SLOT_READY && tenant.label() == bytes
+        })
+        .map(TenantId)
+}
+
+// Account an allocation to the tenant, false if it would exceed the limit of the tenant
+#[inline]
+pub fn charge(id: TenantId, size: usize) -> bool {
+    let tenant = &TENANTS[id.0];
+    let live = tenant.live_bytes.fetch_add(size, Relaxed) + size;
+    let limit = tenant.limit.load(Relaxed);
+    if limit != 0 && live > limit {
+        tenant.live_bytes.fetch_sub(size, Relaxed);
+        return false;
+    }
+    tenant.allocated_bytes.fetch_add(size, Relaxed);
+    true
+}
+
+#[inline]
+pub fn discharge(id: TenantId, size: usize) {
+    let tenant = &TENANTS[id.0];
+    tenant.live_bytes.fetch_sub(size, Relaxed);
+    tenant.freed_bytes.fetch_add(size, Relaxed);
+}
+
+pub fn set_limit(id: TenantId, limit: usize) {
+    TENANTS[id.0].limit.store(limit, Relaxed);
+}
+
+pub fn stats(id: TenantId) -> TenantStats {
+    TENANTS[id.0].stats()
+}
+
+// Visit stats of all registered tenants
+pub fn for_each<F: FnMut(&str, TenantStats)>(mut f: F) {
+    for index in 0..MAX_TENANTS {
+        let tenant = &TENANTS[index];
+        match tenant.state.load(Acquire) {
+            SLOT_FREE => return,
+            SLOT_READY => {
+                if let Ok(label) = std::str::from_utf8(tenant.label()) {
+                    f(label, tenant.stats());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bump_heap::AllocatorInstance;
+    use crate::mmap_heap::MmapAllocator;
+    use crate::tenant::*;
+    use std::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    pub fn labels() {
+        let id = register("test-labels").unwrap();
+        assert_eq!(register("test-labels"), Some(id));
+        assert_eq!(find("test-labels"), Some(id));
+        assert_ne!(register("test-labels-other"), Some(id));
+        assert_eq!(find("test-labels-none"), None);
+        assert_eq!(register(""), None);
+        let mut found = false;
+        for_each(|label, _| found |= label == "test-labels");
+        assert!(found);
+    }
+
+    #[test]
+    pub fn accounting() {
+        let id = register("test-accounting").unwrap();
+        let instance = AllocatorInstance::<MmapAllocator>::with_tenant(id);
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        unsafe {
+            let ptr = instance.alloc(layout);
+            let allocated = stats(id);
+            assert!(allocated.live_bytes >= 1000);
+            instance.dealloc(ptr, layout);
+            let freed = stats(id);
+            assert_eq!(freed.live_bytes, 0);
+            assert_eq!(freed.freed_bytes, allocated.allocated_bytes);
+
+            set_limit(id, 1024);
+            let ptr = instance.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!(instance.alloc(layout).is_null());
+            instance.dealloc(ptr, layout);
+            assert_eq!(stats(id).live_bytes, 0);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Single thread fast mode
+// While only one thread is using the allocator, bump pointers and superblock reservations are
+// updated by plain loads and stores instead of CAS loops. The mode ends as soon as another thread
+// reaches any of the exclusive paths. That thread issues a process wide memory barrier
+// (membarrier) and waits for the owner thread to leave its current exclusive section, so the
+// owner never pays for a fence on its own fast path.
+
+use crate::collections::backoff::Backoff;
+use crate::strict;
+use crate::utils::current_thread_id;
+use std::fs::read_dir;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{compiler_fence, AtomicBool, AtomicUsize};
+
+const MODE_MULTI: usize = 0;
+const MODE_SINGLE: usize = 1;
+const MODE_LEAVING: usize = 2;
+// the mode is taken, the owner is not published yet
+const MODE_DECLARING: usize = 3;
+
+#[cfg(target_os = "linux")]
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED: libc::c_int = 1 << 3;
+#[cfg(target_os = "linux")]
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: libc::c_int = 1 << 4;
+
+static MODE: AtomicUsize = AtomicUsize::new(MODE_MULTI);
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+static IN_EXCLUSIVE: AtomicBool = AtomicBool::new(false);
+
+// Run `f` without synchronization with other threads if current thread owns the single thread mode
+// Returns None when the caller shall take the concurrent path
+#[inline]
+pub fn exclusive<R, F: FnOnce() -> R>(f: F) -> Option<R> {
+    match MODE.load(Relaxed) {
+        MODE_MULTI => None,
+        MODE_SINGLE if OWNER.load(Relaxed) == current_thread_id() => {
+            IN_EXCLUSIVE.store(true, Relaxed);
+            // paired with the process barrier in leave
+            compiler_fence(SeqCst);
+            if MODE.load(Relaxed) != MODE_SINGLE {
+                IN_EXCLUSIVE.store(false, Relaxed);
+                return None;
+            }
+            let res = f();
+            compiler_fence(SeqCst);
+            IN_EXCLUSIVE.store(false, Release);
+            Some(res)
+        }
+        _ => {
+            // another thread is here, the process is not single threaded anymore
+            leave();
+            None
+        }
+    }
+}
+
+// Declare current thread as the only thread that allocates
+// Caller must ensure no other thread is in the middle of an allocation
+pub fn declare() -> bool {
+    if strict::is_enabled() || !register_process_barrier() {
+        return false;
+    }
+    // only the thread winning the mode may set the owner, before the mode is single
+    if MODE.compare_and_swap(MODE_MULTI, MODE_DECLARING, SeqCst) != MODE_MULTI {
+        return false;
+    }
+    OWNER.store(current_thread_id(), Relaxed);
+    MODE.store(MODE_SINGLE, SeqCst);
+    true
+}
+
+// Enter single thread mode only if the process has only one thread
+pub fn detect() -> bool {
+    if num_process_threads() == Some(1) {
+        declare()
+    } else {
+        false
+    }
+}
+
+pub fn leave() {
+    let backoff = Backoff::new();
+    loop {
+        match MODE.compare_and_swap(MODE_SINGLE, MODE_LEAVING, SeqCst) {
+            MODE_MULTI => return,
+            MODE_SINGLE => {
+                process_barrier();
+                while IN_EXCLUSIVE.load(Acquire) {
+                    backoff.snooze();
+                }
+                MODE.store(MODE_MULTI, SeqCst);
+                return;
+            }
+            _ => {
+                // other thread is declaring or leaving, wait until it is done
+                backoff.snooze();
+            }
+        }
+    }
+}
+
+// The process is left with only the forking thread, which may not be the owner
+pub fn reinit_after_fork() {
+    IN_EXCLUSIVE.store(false, Relaxed);
+    MODE.store(MODE_MULTI, SeqCst);
+}
+
+pub fn is_single_thread() -> bool {
+    MODE.load(Relaxed) == MODE_SINGLE
+}
+
+fn num_process_threads() -> Option<usize> {
+    read_dir("/proc/self/task").ok().map(|dir| dir.count())
+}
+
+#[cfg(target_os = "linux")]
+fn register_process_barrier() -> bool {
+    unsafe {
+        libc::syscall(
+            libc::SYS_membarrier,
+            MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED,
+            0,
+        ) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn register_process_barrier() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn process_barrier() {
+    unsafe {
+        libc::syscall(libc::SYS_membarrier, MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_barrier() {}
+
+#[cfg(test)]
+mod test {
+    use crate::thread_mode::*;
+
+    #[test]
+    pub fn multi_threaded_process() {
+        // test harness always runs with more than one thread
+        assert!(!detect());
+        assert!(!is_single_thread());
+        assert_eq!(exclusive(|| 1), None);
+    }
+
+    #[test]
+    pub fn declare_once() {
+        // a thread losing the mode must not become the owner
+        MODE.store(MODE_DECLARING, SeqCst);
+        OWNER.store(0, SeqCst);
+        assert!(!declare());
+        assert_eq!(OWNER.load(SeqCst), 0);
+        MODE.store(MODE_MULTI, SeqCst);
+    }
+}
@@ -0,0 +1,225 @@
+// Request sizes sampled during warmup for tuning size classes
+// For a warmup window started by `nu_sample_sizes` or the `size_warmup` option, allocations take
+// the slow path and keep a reservoir of RESERVOIR_SIZE request sizes, a uniform sample of all
+// requests of the window (algorithm R). `nu_suggest_size_classes` estimates the bytes requested
+// from every size class in the window and suggests superblock sizes and prereserved superblocks
+// for the classes in use, as NULLOC_CONF options to start the next run of the application with:
+//
+//   span_size.16:64K,prereserve.16:4,span_size.256:1M,prereserve.256:4
+//
+// Size classes are powers of two, the suggestion tunes their superblocks. Busy classes get
+// spans holding a quarter of their warmup demand, up to MAX_SPAN, and superblocks for all of it
+// up front. Classes seldom used get small spans, so they strand little memory. Sizes are written
+// into the reservoir without synchronization, a racing thread may overwrite a sample, which
+// leaves the sample uniform enough for tuning.
+
+use crate::generic_heap::{
+    self, size_class_index_from_size, MAXIMUM_SMALL_SIZE, NUM_SIZE_CLASS,
+};
+use crate::reclaim::elapsed_ms;
+use std::cell::UnsafeCell;
+use std::cmp::{max, min};
+use std::fmt::Write;
+use std::ptr;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+pub const RESERVOIR_SIZE: usize = 1024;
+pub const MAX_SPAN: usize = 4 << 20;
+const MIN_SPAN: usize = 16 << 10;
+const MAX_PRERESERVE: usize = 16;
+
+static WARMING: AtomicBool = AtomicBool::new(false);
+// milliseconds since start when the window ends
+static WINDOW_END: AtomicUsize = AtomicUsize::new(0);
+static RESERVOIR: SizeReservoir = SizeReservoir::new();
+
+pub struct SizeReservoir {
+    samples: UnsafeCell<[usize; RESERVOIR_SIZE]>,
+    // requests seen
+    seen: AtomicUsize,
+}
+
+unsafe impl Sync for SizeReservoir {}
+
+// Suggested superblocks of a size class
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SuggestedClass {
+    // object size of the class
+    pub class: usize,
+    // estimated requests in the warmup window
+    pub requests: usize,
+    pub span_size: usize,
+    pub prereserve: usize,
+}
+
+impl SizeReservoir {
+    pub const fn new() -> Self {
+        Self {
+            samples: UnsafeCell::new([0; RESERVOIR_SIZE]),
+            seen: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, size: usize) {
+        let index = self.seen.fetch_add(1, Relaxed);
+        let slot = if index < RESERVOIR_SIZE {
+            index
+        } else {
+            // replace a sample with probability RESERVOIR_SIZE / (index + 1)
+            let slot = mix(index as u64) as usize % (index + 1);
+            if slot >= RESERVOIR_SIZE {
+                return;
+            }
+            slot
+        };
+        unsafe {
+            ptr::write_volatile(&mut (*self.samples.get())[slot], size);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.seen.store(0, Relaxed);
+    }
+
+    pub fn seen(&self) -> usize {
+        self.seen.load(Relaxed)
+    }
+
+    // Size classes requested in the sample, smallest first
+    pub fn suggest(&self) -> Vec<SuggestedClass> {
+        let seen = self.seen();
+        let sampled = min(seen, RESERVOIR_SIZE);
+        let mut counts = [0usize; NUM_SIZE_CLASS];
+        for slot in 0..sampled {
+            let size = unsafe { ptr::read_volatile(&(*self.samples.get())[slot]) };
+            if size > 0 && size <= MAXIMUM_SMALL_SIZE {
+                counts[size_class_index_from_size(size)] += 1;
+            }
+        }
+        let mut classes = Vec::new();
+        for (tier, count) in counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let class = 2 << tier;
+            let requests = count * seen / sampled;
+            let demand = requests * class;
+            let min_span = max(class << 2, MIN_SPAN);
+            let span_size = max(min(next_power_of_2(demand / 4), MAX_SPAN), min_span);
+            classes.push(SuggestedClass {
+                class,
+                requests,
+                span_size,
+                prereserve: min(demand / span_size, MAX_PRERESERVE),
+            });
+        }
+        classes
+    }
+}
+
+// Start sampling request sizes for `window_ms` milliseconds, over the previous sample
+pub fn start_warmup(window_ms: usize) {
+    RESERVOIR.reset();
+    WINDOW_END.store(elapsed_ms() + window_ms, Relaxed);
+    WARMING.store(window_ms > 0, Relaxed);
+    generic_heap::refresh_fast_path();
+}
+
+#[inline]
+pub fn is_warming() -> bool {
+    WARMING.load(Relaxed)
+}
+
+#[inline]
+pub fn on_malloc(size: usize) {
+    if is_warming() {
+        record_in_window(size);
+    }
+}
+
+fn record_in_window(size: usize) {
+    if elapsed_ms() >= WINDOW_END.load(Relaxed) {
+        WARMING.store(false, Relaxed);
+        generic_heap::refresh_fast_path();
+        return;
+    }
+    RESERVOIR.record(size);
+}
+
+pub fn suggest() -> Vec<SuggestedClass> {
+    RESERVOIR.suggest()
+}
+
+// Suggested classes as options of NULLOC_CONF
+pub fn to_conf(classes: &[SuggestedClass]) -> String {
+    let mut conf = String::new();
+    for suggestion in classes {
+        let class = format_size(suggestion.class);
+        if !conf.is_empty() {
+            conf.push(',');
+        }
+        let _ = write!(conf, "span_size.{}:{}", class, format_size(suggestion.span_size));
+        if suggestion.prereserve > 0 {
+            let _ = write!(conf, ",prereserve.{}:{}", class, suggestion.prereserve);
+        }
+    }
+    conf
+}
+
+fn format_size(size: usize) -> String {
+    if size >= 1 << 20 && size % (1 << 20) == 0 {
+        format!("{}M", size >> 20)
+    } else if size >= 1 << 10 && size % (1 << 10) == 0 {
+        format!("{}K", size >> 10)
+    } else {
+        format!("{}", size)
+    }
+}
+
+fn next_power_of_2(n: usize) -> usize {
+    max(n, 1).next_power_of_two()
+}
+
+// splitmix64 finalizer, the reservoir needs no better randomness
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Options;
+    use crate::tuning::*;
+
+    #[test]
+    pub fn suggest_classes() {
+        let reservoir = SizeReservoir::new();
+        for i in 0..100_000 {
+            // mostly 64 byte objects, some pages, a few 256 byte ones
+            let size = match i % 1000 {
+                0 => 200,
+                1..=100 => 4000,
+                _ => 50,
+            };
+            reservoir.record(size);
+        }
+        reservoir.record(1 << 30);
+        assert_eq!(reservoir.seen(), 100_001);
+        let classes = reservoir.suggest();
+        let busy = classes.iter().find(|c| c.class == 64).unwrap();
+        let pages = classes.iter().find(|c| c.class == 4096).unwrap();
+        assert!(busy.requests > 80_000 && busy.requests < 95_000);
+        assert_eq!(pages.span_size, MAX_SPAN);
+        assert!(busy.prereserve > 0);
+        if let Some(rare) = classes.iter().find(|c| c.class == 256) {
+            assert!(rare.span_size < busy.span_size);
+        }
+        assert!(classes.iter().all(|c| c.class <= MAXIMUM_SMALL_SIZE));
+        let conf = to_conf(&classes);
+        assert!(conf.starts_with("span_size.64:"));
+        // valid options for the next run
+        for pair in conf.split(',') {
+            let mut kv = pair.splitn(2, ':');
+            assert!(Options::new().set(kv.next().unwrap(), kv.next().unwrap()), "{}", pair);
+        }
+    }
+}
@@ -1,15 +1,19 @@
 use crate::bump_heap::BumpAllocator;
+use crate::os;
+use crate::stats;
+use crate::strict;
 use crate::{Ptr, Size};
 use alloc::alloc::Global;
 use core::alloc::{Alloc, GlobalAlloc, Layout};
 use core::mem;
+use core::ptr;
 use core::ptr::NonNull;
+use core::sync::atomic::Ordering::Relaxed;
 use lazy_init::Lazy;
-use lfmap::hash;
-use libc::{sysconf, _SC_PAGESIZE};
+use lfmap::{hash, Map, WordMap};
+use libc::time;
 use regex::Regex;
 use seahash::SeaHasher;
-use std::cmp::min;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fs::read_dir;
@@ -30,17 +34,19 @@ const HASH_MAGIC_NUMBER_2: usize = 123456789;
 const HASH_MAGIC_NUMBER_3: usize = 362436069;
 
 lazy_static! {
-    pub static ref SYS_PAGE_SIZE: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
+    pub static ref SYS_PAGE_SIZE: usize = os::page_size();
     pub static ref SYS_NODE_CPUS: HashMap<u16, NodeCPUsVec> = node_topology();
     pub static ref SYS_CPU_NODE: HashMap<u16, u16> = cpu_topology();
     pub static ref NUM_NUMA_NODES: u16 = num_numa_nodes();
-    pub static ref NUM_CPU: u16 = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as u16 };
+    pub static ref NUM_CPU: u16 = os::cpu_count() as u16;
     pub static ref SYS_TOTAL_MEM: usize = total_memory();
-    pub static ref LOG_FILE: Mutex<File> = Mutex::new(
-        File::create(&format!("skyhooks.{}.log", process::id())).unwrap());
+    static ref ADDRESS_HASH_KEY: (u64, u64) = address_hash_key();
+    pub static ref LOG_FILE: Mutex<File> = Mutex::new(create_log_file());
 }
 
-// Address hasher for cache locality
+// Keyed multiply-shift hasher for addresses. Keys come from the kernel once per process, so
+// allocation patterns chosen by an adversary cannot pile addresses into the same buckets of the
+// internal maps. High bits of the product are the well mixed ones and are folded into low bits
 pub struct AddressHasher {
     num: u64,
 }
@@ -57,8 +63,9 @@ impl Hasher for AddressHasher {
 
     #[inline(always)]
     fn write_usize(&mut self, i: usize) {
-        let eliminate_zeros = min(i.trailing_zeros(), 4);
-        self.num = (i >> eliminate_zeros) as u64
+        let (seed, multiplier) = *ADDRESS_HASH_KEY;
+        let product = (i as u64 ^ seed).wrapping_mul(multiplier);
+        self.num = product ^ (product >> 32);
     }
 }
 
@@ -68,13 +75,43 @@ impl Default for AddressHasher {
     }
 }
 
+// Random bytes the system provides to every process where there are any.
+// Falls back to the stack address and time when absent
+fn address_hash_key() -> (u64, u64) {
+    let key = os::random_key().unwrap_or_else(|| {
+        let local = 0u64;
+        let stack = &local as *const _ as u64;
+        [stack, stack.rotate_left(32) ^ unsafe { time(ptr::null_mut()) } as u64]
+    });
+    // multiplier must be odd
+    (key[0], key[1] | 1)
+}
+
+// Longest and mean length of non-empty buckets when the keys are hashed into `buckets` buckets,
+// for checking hash quality against allocation patterns
+pub fn bucket_lengths<H: Hasher + Default, I: Iterator<Item = usize>>(
+    keys: I,
+    buckets: usize,
+) -> (usize, f64) {
+    let mut lengths = vec![0usize; buckets];
+    for key in keys {
+        let mut hasher = H::default();
+        hasher.write_usize(key);
+        lengths[(hasher.finish() % buckets as u64) as usize] += 1;
+    }
+    let used = lengths.iter().filter(|len| **len > 0).count();
+    let total = lengths.iter().sum::<usize>();
+    let max = lengths.iter().cloned().max().unwrap_or(0);
+    (max, total as f64 / used.max(1) as f64)
+}
+
 pub fn align_padding(len: usize, align: usize) -> usize {
     let len_rounded_up = len.wrapping_add(align).wrapping_sub(1) & !align.wrapping_sub(1);
     len_rounded_up.wrapping_sub(len)
 }
 
 pub fn current_thread_id() -> usize {
-    unsafe { libc::pthread_self() as usize }
+    os::thread_id()
 }
 
 pub fn cpu_topology() -> HashMap<u16, u16> {
@@ -137,10 +174,17 @@ pub fn node_topology() -> HashMap<u16, NodeCPUsVec> {
     }
 }
 
+// Nodes of the machine, or a single node when the NUMA system calls are refused, so memory is
+// never bound and all CPUs share the meta of node 0
 pub fn num_numa_nodes() -> u16 {
     let mut vec = SYS_CPU_NODE.iter().map(|(_, v)| *v).collect::<Vec<_>>();
     vec.sort();
     vec.dedup();
+    if vec.len() > 1 && !os::numa_syscalls_available() {
+        warn!("NUMA system calls are refused, falling back to a single node");
+        stats::NUMA_DEGRADED.store(1, Relaxed);
+        return 1;
+    }
     vec.len() as u16
 }
 
@@ -149,20 +193,16 @@ pub fn total_memory() -> usize {
     mem_info.avail as usize * 1024 // in bytes
 }
 
-#[cfg(target_os = "linux")]
+// CPU the thread runs on, or a stable pick from the thread id where the system cannot tell
 pub fn current_cpu() -> u16 {
-    unsafe { libc::sched_getcpu() as u16 }
+    let cpu = if strict::is_enabled() { None } else { os::current_cpu() };
+    cpu.unwrap_or_else(|| (current_thread_id() % (*NUM_CPU) as usize) as u16)
 }
 
 pub fn cpu_id_from_tid(tid: usize) -> u16 {
     (hash::<SeaHasher>(tid) % (*NUM_CPU) as usize) as u16
 }
 
-#[cfg(not(target_os = "linux"))]
-pub fn current_cpu() -> u16 {
-    (current_thread_id() % (*NUM_CPU) as usize) as u16
-}
-
 #[cfg(target_os = "linux")]
 pub fn current_numa() -> u16 {
     let cpu = current_cpu();
@@ -171,6 +211,9 @@ pub fn current_numa() -> u16 {
 
 #[cfg(target_os = "linux")]
 pub fn numa_from_cpu_id(cpu_id: u16) -> u16 {
+    if *NUM_NUMA_NODES <= 1 {
+        return 0;
+    }
     SYS_CPU_NODE.get(&cpu_id).map(|x| *x).unwrap_or(0)
 }
 
@@ -218,6 +261,7 @@ pub fn alloc_mem<A: Alloc + Default>(size: usize) -> usize {
     let mut a = A::default();
     let align = 16;
     let layout = Layout::from_size_align(size, align).unwrap();
+    stats::add(&stats::METADATA_BYTES, size);
     // must be all zeroed
     unsafe { a.alloc_zeroed(layout) }.unwrap().as_ptr() as usize
 }
@@ -227,6 +271,7 @@ pub fn dealloc_mem<A: Alloc + Default>(ptr: usize, size: usize) {
     let mut a = A::default();
     let align = 16;
     let layout = Layout::from_size_align(size, align).unwrap();
+    stats::sub(&stats::METADATA_BYTES, size);
     unsafe { a.dealloc(NonNull::<u8>::new(ptr as *mut u8).unwrap(), layout) }
 }
 
@@ -253,11 +298,39 @@ pub fn upper_power_of_2(mut v: usize) -> usize {
 }
 
 pub fn log(action: &'static str, size: usize) {
-    if cfg!(debug_assertions) && env::var("LOG") == Ok(String::from("1")) {
+    if logging() {
         LOG_FILE.lock().unwrap().write_all(format!("{}, {} \n", action, size).as_bytes());
     }
 }
 
+fn logging() -> bool {
+    cfg!(debug_assertions) && env::var("LOG") == Ok(String::from("1"))
+}
+
+fn create_log_file() -> File {
+    File::create(&format!("skyhooks.{}.log", process::id())).unwrap()
+}
+
+// Only for the child process after fork. The log of the parent may be locked by a thread that is
+// gone, the child logs into a file of its own
+pub unsafe fn reinit_log_after_fork() {
+    if logging() {
+        let log_file = &*LOG_FILE as *const Mutex<File> as *mut Mutex<File>;
+        ptr::write(log_file, Mutex::new(create_log_file()));
+    }
+}
+
+// Only for the child process after fork. Other threads may have left the map in the middle of an
+// update or a resize, it is rebuilt from its entries and the old one is leaked
+pub unsafe fn reinit_map_after_fork<A: Alloc + Default, H: Hasher + Default>(map: &WordMap<A, H>) {
+    let entries = map.entries();
+    let rebuilt = WordMap::<A, H>::with_capacity(entries.len().max(16).next_power_of_two());
+    for (key, value) in entries {
+        rebuilt.insert(key, value);
+    }
+    ptr::write(map as *const WordMap<A, H> as *mut WordMap<A, H>, rebuilt);
+}
+
 #[repr(align(4096))]
 pub struct LazyWrapper<T: Sync> {
     inner: Lazy<T>,
@@ -271,6 +344,11 @@ impl<T: Sync> LazyWrapper<T> {
             init: create,
         }
     }
+
+    // get without creating
+    pub fn get_if_created(&self) -> Option<&T> {
+        self.inner.get()
+    }
 }
 
 impl<T: Sync> Deref for LazyWrapper<T> {
@@ -312,6 +390,34 @@ mod test {
         println!("current numa {}", numa);
     }
 
+    #[test]
+    fn numa_fallback() {
+        // nodes of all CPUs have meta, also when running as on a single node
+        for cpu in 0..*super::NUM_CPU {
+            assert!(super::numa_from_cpu_id(cpu) < *super::NUM_NUMA_NODES);
+            // superblocks of every CPU come from the meta of a node that exists
+            let ptr = crate::small_heap::allocate_on_cpu(100, cpu);
+            let meta = crate::small_heap::meta_of(ptr).unwrap();
+            assert_eq!(meta.numa, super::numa_from_cpu_id(cpu));
+            assert!(crate::small_heap::free(ptr));
+        }
+        if crate::stats::get(&crate::stats::NUMA_DEGRADED) != 0 {
+            assert_eq!(*super::NUM_NUMA_NODES, 1);
+            assert!(!crate::os::numa_syscalls_available());
+        }
+    }
+
+    #[test]
+    fn address_hash_buckets() {
+        // addresses of large objects at the same offset in their extents
+        let strided = (1..4096usize).map(|i| i << 20);
+        let (max, mean) = super::bucket_lengths::<AddressHasher, _>(strided, 1024);
+        assert!(max < 32, "longest bucket {}, mean {}", max, mean);
+        let objects = (1..4096usize).map(|i| 0x7f00_0000_0000 + i * 64);
+        let (max, _) = super::bucket_lengths::<AddressHasher, _>(objects, 1024);
+        assert!(max < 32, "longest bucket {}", max);
+    }
+
     #[bench]
     fn get_cpu(b: &mut Bencher) {
         b.iter(|| {
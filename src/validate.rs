@@ -0,0 +1,141 @@
+// Argument validation for the C entry points
+// Sizes beyond isize::MAX cannot be objects and mostly come from negative values or overflowed
+// arithmetic in C, like malloc(-1) or malloc(n * size). They are rejected with the errno C
+// callers expect before reaching the heaps, where rounding them up would overflow.
+//
+// The `max_alloc` option lowers the limit for single allocations, so sizes computed wrong fail
+// fast with a line on stderr instead of mapping terabytes and taking the host down with them.
+// Allocations of the heaps beyond the small size classes check it as well, for Rust callers
+
+use crate::journal::write_line;
+use crate::Size;
+use core::mem;
+use libc::{c_int, EINVAL, ENOMEM};
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+const MAXIMUM_SIZE: Size = isize::max_value() as Size;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(MAXIMUM_SIZE);
+
+// Largest size of a single allocation, 0 to lift the limit
+pub fn set_limit(bytes: Size) {
+    let limit = if bytes == 0 { MAXIMUM_SIZE } else { bytes.min(MAXIMUM_SIZE) };
+    LIMIT.store(limit, Relaxed);
+}
+
+#[inline]
+pub fn size(size: Size) -> Result<Size, c_int> {
+    if size > LIMIT.load(Relaxed) {
+        Err(over_limit(size))
+    } else {
+        Ok(size)
+    }
+}
+
+#[cold]
+fn over_limit(size: Size) -> c_int {
+    let limit = LIMIT.load(Relaxed);
+    if limit != MAXIMUM_SIZE {
+        write_line(
+            &mut |line: &[u8]| unsafe {
+                libc::write(libc::STDERR_FILENO, line.as_ptr() as *const libc::c_void, line.len());
+            },
+            |line| {
+                write!(
+                    line,
+                    "nulloc: allocation of {} bytes refused, over max_alloc of {} bytes\n",
+                    size, limit
+                )
+            },
+        );
+    }
+    ENOMEM
+}
+
+// Total bytes of an array for calloc
+#[inline]
+pub fn array(nmemb: Size, size: Size) -> Result<Size, c_int> {
+    nmemb.checked_mul(size).ok_or(ENOMEM).and_then(self::size)
+}
+
+// Alignment for aligned_alloc and memalign, a power of two
+#[inline]
+pub fn alignment(align: Size) -> Result<Size, c_int> {
+    if align.is_power_of_two() {
+        Ok(align)
+    } else {
+        Err(EINVAL)
+    }
+}
+
+// Alignment for posix_memalign, also a multiple of the pointer size
+#[inline]
+pub fn posix_alignment(align: Size) -> Result<Size, c_int> {
+    if align < mem::size_of::<*mut u8>() {
+        return Err(EINVAL);
+    }
+    alignment(align)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::validate::*;
+    use crate::{aligned_alloc, calloc, free, malloc, posix_memalign, realloc, Ptr, NULL_PTR};
+    use errno::errno;
+
+    #[test]
+    pub fn arguments() {
+        assert_eq!(size(0), Ok(0));
+        assert_eq!(size(MAXIMUM_SIZE), Ok(MAXIMUM_SIZE));
+        assert_eq!(size(MAXIMUM_SIZE + 1), Err(ENOMEM));
+        assert_eq!(size(-1isize as Size), Err(ENOMEM));
+        assert_eq!(array(3, 4), Ok(12));
+        assert_eq!(array(0, Size::max_value()), Ok(0));
+        assert_eq!(array(Size::max_value(), 2), Err(ENOMEM));
+        assert_eq!(array(MAXIMUM_SIZE / 2 + 1, 2), Err(ENOMEM));
+        assert_eq!(alignment(64), Ok(64));
+        assert_eq!(alignment(0), Err(EINVAL));
+        assert_eq!(alignment(24), Err(EINVAL));
+        assert_eq!(posix_alignment(4), Err(EINVAL));
+        assert_eq!(posix_alignment(8), Ok(8));
+    }
+
+    #[test]
+    pub fn pathological_calls() {
+        unsafe {
+            assert_eq!(malloc(-1isize as usize), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            assert_eq!(malloc(MAXIMUM_SIZE + 1), NULL_PTR);
+            // n * size wrapping around to a small number in C
+            assert_eq!(calloc(1 << 62, 8), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            assert_eq!(aligned_alloc(48, 64), NULL_PTR);
+            assert_eq!(errno().0, EINVAL);
+            let mut ptr: Ptr = NULL_PTR;
+            assert_eq!(posix_memalign(&mut ptr, 2, 64), EINVAL);
+            assert_eq!(posix_memalign(&mut ptr, 48, 64), EINVAL);
+            assert_eq!(posix_memalign(&mut ptr, 64, -1isize as usize), ENOMEM);
+            assert_eq!(ptr, NULL_PTR);
+            // the object survives a failed realloc
+            let obj = malloc(16) as *mut u8;
+            *obj = 42;
+            assert_eq!(realloc(obj as Ptr, -16isize as usize), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            assert_eq!(*obj, 42);
+            free(obj as Ptr);
+        }
+    }
+
+    #[test]
+    pub fn limit() {
+        // beyond anything other tests allocate meanwhile
+        set_limit(1 << 44);
+        assert_eq!(size(1 << 44), Ok(1 << 44));
+        assert_eq!(size((1 << 44) + 1), Err(ENOMEM));
+        set_limit(0);
+        assert_eq!(size(1 << 45), Ok(1 << 45));
+        assert_eq!(size(MAXIMUM_SIZE + 1), Err(ENOMEM));
+    }
+}
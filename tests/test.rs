@@ -1 +1,146 @@
+// Soak test of the stats against ground truth kept by the test itself
+// Worker threads allocate and free objects of random sizes through the API and count the
+// objects and usable bytes they hold. At every checkpoint all of them stop, pending frees are
+// flushed, and the live objects and bytes of nu_heap_stats, less what was live before the
+// workers started, are compared with the sums of the workers. Objects the allocator makes for
+// itself in the meantime are counted as live too, within TOLERANCE_BYTES and TOLERANCE_OBJECTS
+// or a hundredth of the truth.
+//
+// Runs for a second by default, set NULLOC_SOAK_SECS to soak for longer:
+//   NULLOC_SOAK_SECS=3600 cargo test --release --test test soak
+// This process only allocates through the API here, so nothing else moves the stats.
 
+use rand::{thread_rng, Rng};
+use skyhooks::api::*;
+use skyhooks::Ptr;
+use std::env;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKERS: usize = 4;
+const OPS_PER_CHECKPOINT: usize = 20_000;
+const MAX_HELD: usize = 2_000;
+const TOLERANCE_BYTES: usize = 1 << 20;
+const TOLERANCE_OBJECTS: usize = 64;
+
+// Objects and usable bytes held by the workers
+#[derive(Default)]
+struct Truth {
+    objects: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+fn live() -> (usize, usize) {
+    let heap = nu_heap_stats();
+    let objects = heap.live_objects.iter().sum::<usize>() + heap.large_objects;
+    (objects, heap.allocated_bytes)
+}
+
+fn random_size<R: Rng>(rng: &mut R) -> usize {
+    match rng.gen_range(0, 100) {
+        0..=79 => rng.gen_range(1, 4096),
+        80..=96 => rng.gen_range(4096, 64 << 10),
+        _ => rng.gen_range(64 << 10, 4 << 20),
+    }
+}
+
+fn work(truth: &Truth, held: &mut Vec<(Ptr, usize)>) {
+    let mut rng = thread_rng();
+    for _ in 0..OPS_PER_CHECKPOINT {
+        if held.len() < MAX_HELD && (held.is_empty() || rng.gen_bool(0.5)) {
+            let ptr = unsafe { nu_malloc(random_size(&mut rng)) };
+            assert!(!ptr.is_null());
+            let usable = unsafe { nu_malloc_usable_size(ptr) };
+            truth.objects.fetch_add(1, Relaxed);
+            truth.bytes.fetch_add(usable, Relaxed);
+            held.push((ptr, usable));
+        } else {
+            let (ptr, usable) = held.swap_remove(rng.gen_range(0, held.len()));
+            unsafe { nu_free(ptr) };
+            truth.objects.fetch_sub(1, Relaxed);
+            truth.bytes.fetch_sub(usable, Relaxed);
+        }
+    }
+}
+
+fn check(name: &str, reported: usize, truth: usize, tolerance: usize) {
+    let tolerance = tolerance.max(truth / 100);
+    assert!(
+        reported + tolerance >= truth && reported <= truth + tolerance,
+        "{} drifted: {} reported, {} live",
+        name,
+        reported,
+        truth
+    );
+}
+
+#[test]
+fn soak_stats_against_ground_truth() {
+    let secs = env::var("NULLOC_SOAK_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(1);
+    let (base_objects, base_bytes) = live();
+    let truth = Arc::new(Truth::default());
+    let stop = Arc::new(AtomicBool::new(false));
+    // workers and the checker meet at every checkpoint, once to stop and once to go on
+    let checkpoint = Arc::new(Barrier::new(WORKERS + 1));
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let (truth, stop, checkpoint) = (truth.clone(), stop.clone(), checkpoint.clone());
+            thread::spawn(move || {
+                let mut held = Vec::with_capacity(MAX_HELD);
+                while !stop.load(Relaxed) {
+                    work(&truth, &mut held);
+                    checkpoint.wait();
+                    checkpoint.wait();
+                }
+                for (ptr, usable) in held {
+                    unsafe { nu_free(ptr) };
+                    truth.objects.fetch_sub(1, Relaxed);
+                    truth.bytes.fetch_sub(usable, Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let mut checkpoints = 0;
+    loop {
+        checkpoint.wait();
+        // frees queued to other nodes return to their superblocks
+        nu_notify_memory_pressure(1);
+        let (objects, bytes) = live();
+        check(
+            "live objects",
+            objects.saturating_sub(base_objects),
+            truth.objects.load(Relaxed),
+            TOLERANCE_OBJECTS,
+        );
+        check(
+            "live bytes",
+            bytes.saturating_sub(base_bytes),
+            truth.bytes.load(Relaxed),
+            TOLERANCE_BYTES,
+        );
+        checkpoints += 1;
+        if start.elapsed() >= Duration::from_secs(secs) {
+            stop.store(true, Relaxed);
+        }
+        checkpoint.wait();
+        if stop.load(Relaxed) {
+            break;
+        }
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert!(checkpoints > 0);
+    assert_eq!(truth.objects.load(Relaxed), 0);
+    nu_notify_memory_pressure(1);
+    let (objects, bytes) = live();
+    check("objects after the soak", objects.saturating_sub(base_objects), 0, TOLERANCE_OBJECTS);
+    check("bytes after the soak", bytes.saturating_sub(base_bytes), 0, TOLERANCE_BYTES);
+}